@@ -0,0 +1,123 @@
+//! Procedural macros for the [`opa`](https://docs.rs/opa) crate,
+//! these are re-exported by it with the `macros` feature.
+
+#![warn(clippy::pedantic)]
+
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{parse_macro_input, DeriveInput, LitStr, Path, Type};
+
+/// Implement `opa::PolicyDecision` for a marker type.
+///
+/// The `path` is the `.` or `/` separated path to the decision,
+/// it is validated when the macro is expanded.
+///
+/// The implementation refers to the `opa` crate as `::opa`, if it is
+/// renamed or re-exported, its path can be set with `crate = path`.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// #[opa::policy_decision(path = "example.allow", input = MyInput, output = bool)]
+/// struct Allow;
+///
+/// #[my_opa::policy_decision(crate = my_opa, path = "example.allow", input = MyInput, output = bool)]
+/// struct RenamedAllow;
+/// ```
+#[proc_macro_attribute]
+pub fn policy_decision(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let mut path: Option<LitStr> = None;
+    let mut input: Option<Type> = None;
+    let mut output: Option<Type> = None;
+    let mut krate: Option<Path> = None;
+
+    let parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("path") {
+            path = Some(meta.value()?.parse()?);
+        } else if meta.path.is_ident("input") {
+            input = Some(meta.value()?.parse()?);
+        } else if meta.path.is_ident("output") {
+            output = Some(meta.value()?.parse()?);
+        } else if meta.path.is_ident("crate") {
+            krate = Some(meta.value()?.parse()?);
+        } else {
+            return Err(meta.error("expected `path`, `input`, `output` or `crate`"));
+        }
+        Ok(())
+    });
+    parse_macro_input!(attr with parser);
+
+    let item = parse_macro_input!(item as DeriveInput);
+
+    match expand(&item, path, input, output, krate) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.into_compile_error().into(),
+    }
+}
+
+fn expand(
+    item: &DeriveInput,
+    path: Option<LitStr>,
+    input: Option<Type>,
+    output: Option<Type>,
+    krate: Option<Path>,
+) -> syn::Result<proc_macro2::TokenStream> {
+    let missing = |name: &str| {
+        syn::Error::new_spanned(
+            &item.ident,
+            format!("the `{name}` of the policy decision is required"),
+        )
+    };
+
+    let path = path.ok_or_else(|| missing("path"))?;
+    let input = input.ok_or_else(|| missing("input"))?;
+    let output = output.ok_or_else(|| missing("output"))?;
+
+    validate_path(&path)?;
+
+    let krate = krate.unwrap_or_else(|| syn::parse_quote!(::opa));
+
+    let ident = &item.ident;
+    let (impl_generics, ty_generics, where_clause) = item.generics.split_for_impl();
+
+    Ok(quote! {
+        #item
+
+        impl #impl_generics #krate::PolicyDecision for #ident #ty_generics #where_clause {
+            const POLICY_PATH: &'static str = #path;
+            type Input = #input;
+            type Output = #output;
+        }
+    })
+}
+
+/// Check that the path consists of `.` or `/` separated
+/// Rego identifiers, with an optional leading `/`.
+fn validate_path(path: &LitStr) -> syn::Result<()> {
+    let value = path.value();
+
+    let valid = value
+        .trim_start_matches('/')
+        .split(['.', '/'])
+        .all(|segment| {
+            segment
+                .chars()
+                .next()
+                .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+                && segment
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_')
+        });
+
+    if valid {
+        return Ok(());
+    }
+
+    Err(syn::Error::new(
+        path.span(),
+        format!(
+            "invalid policy path `{value}`, expected `.` or `/` separated identifiers, \
+             e.g. `example.allow`"
+        ),
+    ))
+}
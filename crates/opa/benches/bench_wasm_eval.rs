@@ -1,4 +1,4 @@
-use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
 use opa::{bundle::Bundle, wasm::Opa};
 use serde_json::{json, Value};
 
@@ -41,6 +41,19 @@ fn criterion_benchmark(c: &mut Criterion) {
         })
     });
 
+    let mut buf = Vec::new();
+
+    c.bench_function("eval_project_permissions_with_buf", |b| {
+        b.iter(|| {
+            opa.eval_with_buf::<_, Value>(
+                black_box("example.project_permissions"),
+                black_box(&input),
+                &mut buf,
+            )
+            .unwrap()
+        })
+    });
+
     c.bench_function("eval_with_context", |b| {
         b.iter(|| {
             opa.eval_context(black_box(&input))
@@ -57,5 +70,153 @@ fn criterion_benchmark(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, criterion_benchmark);
+fn set_data_benchmark(c: &mut Criterion) {
+    let bundle = Bundle::from_bytes(include_bytes!(
+        "../../../examples/src/bin/wasm_bundle/example.tar.gz"
+    ))
+    .unwrap();
+    let wasm_bytes = bundle.wasm_policies.first().unwrap().bytes.clone();
+
+    let users: serde_json::Map<String, Value> = (0..20_000)
+        .map(|i| {
+            (
+                format!("user-{i}"),
+                json!({
+                    "projects": {
+                        format!("project-{i}"): {
+                            "roles": ["owner", "member"]
+                        }
+                    }
+                }),
+            )
+        })
+        .collect();
+    let data = json!({ "users": users, "projects": {} });
+
+    let mut group = c.benchmark_group("set_data_large");
+    group.sample_size(10);
+
+    for (name, initial_pages) in [("default_memory", None), ("preallocated_memory", Some(512))] {
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || {
+                    let mut builder = Opa::new();
+                    if let Some(pages) = initial_pages {
+                        builder = builder.initial_memory_pages(pages);
+                    }
+                    builder.build(&wasm_bytes).unwrap()
+                },
+                |mut opa| opa.set_data(black_box(&data)).unwrap(),
+                BatchSize::PerIteration,
+            )
+        });
+    }
+
+    // Serializing the data once for all instances.
+    let data_bytes = serde_json::to_vec(&data).unwrap();
+    group.bench_function("serialized_data", |b| {
+        b.iter_batched(
+            || Opa::new().build(&wasm_bytes).unwrap(),
+            |mut opa| opa.set_data_bytes(black_box(&data_bytes)).unwrap(),
+            BatchSize::PerIteration,
+        )
+    });
+
+    group.finish();
+}
+
+fn input_scratch_benchmark(c: &mut Criterion) {
+    let bundle = Bundle::from_bytes(include_bytes!(
+        "../../../examples/src/bin/wasm_bundle/example.tar.gz"
+    ))
+    .unwrap();
+    let wasm_bytes = bundle.wasm_policies.first().unwrap().bytes.clone();
+
+    let input = json!({
+        "user_id": "test",
+        "project_id": "test",
+        "padding": "x".repeat(64 * 1024),
+    });
+
+    let mut group = c.benchmark_group("eval_fixed_size_input");
+
+    for (name, scratch_bytes) in [("default", 0), ("input_scratch", 128 * 1024)] {
+        let mut opa = Opa::new()
+            .input_scratch_bytes(scratch_bytes)
+            .build(&wasm_bytes)
+            .unwrap();
+        opa.set_data(&json!({})).unwrap();
+
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                opa.eval_context(black_box(&input))
+                    .unwrap()
+                    .eval::<Value>(black_box("example.project_permissions"))
+                    .unwrap()
+            })
+        });
+    }
+
+    group.finish();
+}
+
+fn instantiation_benchmark(c: &mut Criterion) {
+    let bundle = Bundle::from_bytes(include_bytes!(
+        "../../../examples/src/bin/wasm_bundle/example.tar.gz"
+    ))
+    .unwrap();
+
+    // The module is precompiled, so that only instantiation is measured.
+    let precompiled = |engine: &wasmtime::Engine| {
+        let module =
+            wasmtime::Module::new(engine, &bundle.wasm_policies.first().unwrap().bytes).unwrap();
+        let mut bundle = bundle.clone();
+        // SAFETY: The module was compiled by the same engine.
+        unsafe { bundle.set_wasmtime_bytes(module.serialize().unwrap().into()) };
+        bundle
+    };
+
+    let mut group = c.benchmark_group("instantiation");
+
+    let engine = wasmtime::Engine::default();
+    let on_demand = precompiled(&engine);
+    group.bench_function("on_demand", |b| {
+        b.iter(|| {
+            Opa::new()
+                .with_engine(engine.clone())
+                .build_from_bundle(black_box(&on_demand))
+                .unwrap()
+        })
+    });
+
+    #[cfg(feature = "wasm-pooling")]
+    {
+        let mut pooling = wasmtime::PoolingAllocationConfig::default();
+        pooling.instance_count(16).instance_memories(0);
+        let engine = Opa::new()
+            .with_pooling_allocator(pooling)
+            .unwrap()
+            .engine()
+            .clone();
+        let pooling = precompiled(&engine);
+        group.bench_function("pooling", |b| {
+            b.iter(|| {
+                Opa::new()
+                    .with_engine(engine.clone())
+                    .build_from_bundle(black_box(&pooling))
+                    .unwrap()
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    criterion_benchmark,
+    set_data_benchmark,
+    input_scratch_benchmark,
+    instantiation_benchmark
+);
 criterion_main!(benches);
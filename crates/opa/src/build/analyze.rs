@@ -0,0 +1,195 @@
+use anyhow::anyhow;
+use serde_json::Value;
+use std::{collections::BTreeSet, fs, process::Command};
+use which::which;
+
+/// Builtins used for Rego operators such as `:=`, `==` or `in`,
+/// they are not reported by [`analyze`].
+const OPERATORS: &[&str] = &[
+    "assign",
+    "eq",
+    "equal",
+    "neq",
+    "lt",
+    "lte",
+    "gt",
+    "gte",
+    "plus",
+    "minus",
+    "mul",
+    "div",
+    "rem",
+    "and",
+    "or",
+    "internal.member_2",
+    "internal.member_3",
+];
+
+/// The builtins and imports referenced by a policy, see [`analyze`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct PolicyAnalysis {
+    /// The package of the policy, e.g. `example.policy`.
+    pub package: String,
+    /// The builtin functions called by the policy, e.g. `http.send`.
+    ///
+    /// Operators are not included.
+    pub builtins: BTreeSet<String>,
+    /// The imported documents and packages, e.g. `data.lib.users` or `rego.v1`.
+    pub imports: BTreeSet<String>,
+}
+
+impl PolicyAnalysis {
+    /// Analyze the JSON AST of a policy as printed by `opa parse --format json`.
+    ///
+    /// Calls of functions that are defined in the policy itself
+    /// or imported from other packages are not reported as builtins.
+    #[must_use]
+    pub fn from_ast(ast: &Value) -> Self {
+        let package = ref_path(&ast["package"]["path"])
+            .map(|path| path.trim_start_matches("data.").to_string())
+            .unwrap_or_default();
+
+        let mut imports = BTreeSet::new();
+        // Names that refer to functions that are not builtins.
+        let mut local_names = BTreeSet::new();
+
+        for import in ast["imports"].as_array().into_iter().flatten() {
+            let Some(path) = ref_path(&import["path"]["value"]) else {
+                continue;
+            };
+
+            if path.starts_with("data.") {
+                let name = import["alias"]
+                    .as_str()
+                    .or_else(|| path.rsplit('.').next())
+                    .unwrap_or_default();
+                local_names.insert(name.to_string());
+            }
+
+            imports.insert(path);
+        }
+
+        for rule in ast["rules"].as_array().into_iter().flatten() {
+            let head = &rule["head"];
+            let name = head["name"]
+                .as_str()
+                .map(ToString::to_string)
+                .or_else(|| head["ref"][0]["value"].as_str().map(ToString::to_string));
+
+            if let Some(name) = name {
+                local_names.insert(name);
+            }
+        }
+
+        let mut builtins = BTreeSet::new();
+        collect_calls(&ast["rules"], &mut builtins);
+
+        builtins.retain(|name: &String| {
+            let root = name.split('.').next().unwrap_or_default();
+            !OPERATORS.contains(&name.as_str())
+                && !local_names.contains(root)
+                && root != "data"
+                && root != "input"
+        });
+
+        Self {
+            package,
+            builtins,
+            imports,
+        }
+    }
+}
+
+/// List the builtins called and the packages imported by a Rego policy.
+///
+/// This can be used before building the policy to find out which builtins
+/// it depends on, builtins that are not implemented natively by OPA's WASM
+/// target cannot be evaluated by [`wasm::Opa`](crate::wasm::Opa).
+///
+/// The policy is parsed with `opa parse`.
+///
+/// # Errors
+///
+/// The `opa` binary must be found in any of the system paths **at runtime**.
+///
+/// Syntax errors in the policy are also returned.
+pub fn analyze(source: &str) -> Result<PolicyAnalysis, anyhow::Error> {
+    let opa_executable = which("opa")?;
+
+    let dir = tempfile::tempdir()?;
+    let policy_path = dir.path().join("policy.rego");
+    fs::write(&policy_path, source)?;
+
+    let out = Command::new(opa_executable)
+        .args(["parse", "--format", "json"])
+        .arg(&policy_path)
+        .output()?;
+
+    if !out.status.success() {
+        let o = String::from_utf8_lossy(&out.stdout).to_string()
+            + String::from_utf8_lossy(&out.stderr).as_ref();
+        return Err(anyhow!("opa error: {o}"));
+    }
+
+    let ast: Value = serde_json::from_slice(&out.stdout)?;
+
+    Ok(PolicyAnalysis::from_ast(&ast))
+}
+
+/// Collect the names of all functions called anywhere in the AST.
+fn collect_calls(value: &Value, calls: &mut BTreeSet<String>) {
+    match value {
+        Value::Object(object) => {
+            // Calls are either `call` terms or expressions
+            // with multiple terms where the first one is the operator.
+            let operator = match (object.get("type"), object.get("value"), object.get("terms")) {
+                (Some(Value::String(ty)), Some(Value::Array(terms)), _) if ty == "call" => {
+                    terms.first()
+                }
+                (_, _, Some(Value::Array(terms))) => terms.first(),
+                _ => None,
+            };
+
+            if let Some(name) = operator
+                .filter(|op| op["type"] == "ref")
+                .and_then(|op| ref_path(&op["value"]))
+            {
+                calls.insert(name);
+            }
+
+            for value in object.values() {
+                collect_calls(value, calls);
+            }
+        }
+        Value::Array(values) => {
+            for value in values {
+                collect_calls(value, calls);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// The `.` separated path of a static reference, e.g. `http.send`.
+fn ref_path(terms: &Value) -> Option<String> {
+    let terms = terms.as_array()?;
+    let (head, rest) = terms.split_first()?;
+
+    if head["type"] != "var" {
+        return None;
+    }
+
+    let mut path = head["value"].as_str()?.to_string();
+
+    for term in rest {
+        if term["type"] != "string" {
+            return None;
+        }
+
+        path.push('.');
+        path.push_str(term["value"].as_str()?);
+    }
+
+    Some(path)
+}
@@ -0,0 +1,88 @@
+use crate::bundle::{Bundle, RegoEvaluator};
+use anyhow::anyhow;
+use serde::Deserialize;
+use serde_json::Value;
+use std::{fs, process::Command};
+use which::which;
+
+/// A [`RegoEvaluator`] that evaluates the Rego policies of a bundle with `opa eval`.
+///
+/// For every evaluation the bundle is written to a temporary directory
+/// and `opa eval` is run with it in a blocking manner, this is meant
+/// for tools and tests rather than for evaluating decisions at scale.
+///
+/// The `opa` binary must be found in any of the system paths **at runtime**.
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct OpaEval;
+
+impl OpaEval {
+    #[must_use]
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl RegoEvaluator for OpaEval {
+    fn eval(
+        &self,
+        bundle: &Bundle,
+        policy: &str,
+        input: &Value,
+    ) -> Result<Option<Value>, anyhow::Error> {
+        let opa_executable = which("opa")?;
+
+        let dir = tempfile::tempdir()?;
+        let bundle_dir = dir.path().join("bundle");
+        let input_path = dir.path().join("input.json");
+
+        bundle.extract_to(&bundle_dir)?;
+        fs::write(&input_path, serde_json::to_vec(input)?)?;
+
+        let query = format!(
+            "data.{}",
+            crate::normalize_policy_path(policy).replace('/', ".")
+        );
+
+        let out = Command::new(opa_executable)
+            .args(["eval", "--format", "json", "--bundle"])
+            .arg(&bundle_dir)
+            .arg("--input")
+            .arg(&input_path)
+            .arg(&query)
+            .output()?;
+
+        if !out.status.success() {
+            let o = String::from_utf8_lossy(&out.stdout).to_string()
+                + String::from_utf8_lossy(&out.stderr).as_ref();
+            return Err(anyhow!("opa error: {o}"));
+        }
+
+        let output: EvalOutput = serde_json::from_slice(&out.stdout)?;
+
+        Ok(output
+            .result
+            .into_iter()
+            .next()
+            .and_then(|result| result.expressions.into_iter().next())
+            .map(|expression| expression.value))
+    }
+}
+
+/// The output of `opa eval --format json`, the result
+/// is missing if the decision is undefined.
+#[derive(Deserialize)]
+struct EvalOutput {
+    #[serde(default)]
+    result: Vec<EvalResult>,
+}
+
+#[derive(Deserialize)]
+struct EvalResult {
+    expressions: Vec<EvalExpression>,
+}
+
+#[derive(Deserialize)]
+struct EvalExpression {
+    value: Value,
+}
@@ -0,0 +1,61 @@
+use anyhow::anyhow;
+use std::{fs, process::Command};
+use thiserror::Error;
+use which::which;
+
+/// The error returned by [`check_format`] if a policy is not formatted.
+#[derive(Debug, Clone, Error)]
+#[error("the policy is not formatted:\n{diff}")]
+#[non_exhaustive]
+pub struct FormatDiff {
+    /// The changes `opa fmt` would make, in the unified diff format.
+    pub diff: String,
+}
+
+/// Check whether a Rego policy is formatted the same way as `opa fmt` formats it.
+///
+/// This is meant for enforcing the formatting of policies in tests
+/// or build scripts, it is not part of [`WasmPolicyBuilder::compile`](super::WasmPolicyBuilder::compile).
+///
+/// The policy is checked with `opa fmt --diff`.
+///
+/// # Errors
+///
+/// If the policy is not formatted, a [`FormatDiff`] error is returned
+/// with the diff that can be retrieved with [`anyhow::Error::downcast_ref`].
+///
+/// The `opa` binary must be found in any of the system paths **at runtime**.
+///
+/// Syntax errors in the policy are also returned.
+pub fn check_format(source: &str) -> Result<(), anyhow::Error> {
+    let opa_executable = which("opa")?;
+
+    let dir = tempfile::tempdir()?;
+    let policy_path = dir.path().join("policy.rego");
+    fs::write(&policy_path, source)?;
+
+    let out = Command::new(opa_executable)
+        .args(["fmt", "--diff"])
+        .arg(&policy_path)
+        .output()?;
+
+    let diff = String::from_utf8_lossy(&out.stdout);
+
+    if !out.status.success() && !is_diff(&diff) {
+        let o = diff.to_string() + String::from_utf8_lossy(&out.stderr).as_ref();
+        return Err(anyhow!("opa error: {o}"));
+    }
+
+    if diff.trim().is_empty() {
+        return Ok(());
+    }
+
+    Err(FormatDiff {
+        diff: diff.into_owned(),
+    }
+    .into())
+}
+
+fn is_diff(output: &str) -> bool {
+    output.lines().any(|line| line.starts_with("@@"))
+}
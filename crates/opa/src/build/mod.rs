@@ -1,6 +1,8 @@
 use anyhow::anyhow;
 use std::{
-    env, fs,
+    env,
+    ffi::OsString,
+    fs,
     io::Write,
     num::NonZeroUsize,
     path::{Path, PathBuf},
@@ -8,14 +10,33 @@ use std::{
 };
 use which::which;
 
-use crate::bundle::Bundle;
+use crate::{bundle::Bundle, version::parse_version};
+
+mod analyze;
+mod eval;
+mod format;
+#[cfg(feature = "wasmtime-cranelift")]
+mod query;
+
+pub use analyze::{analyze, PolicyAnalysis};
+pub use eval::OpaEval;
+pub use format::{check_format, FormatDiff};
+
+#[cfg(feature = "wasmtime-cranelift")]
+pub use query::{compile_query, query, QUERY_ENTRYPOINT};
+
+/// The environment variable that enables [`WasmPolicyBuilder::keep_intermediates`].
+const KEEP_INTERMEDIATES_ENV: &str = "OPA_KEEP_INTERMEDIATES";
+
+/// The first version of `opa` that can build WASM modules.
+const MIN_OPA_VERSION: &str = "0.20.0";
 
 pub fn policy(name: impl Into<String>) -> WasmPolicyBuilder {
     WasmPolicyBuilder::new(name)
 }
 
 /// Specify how the WASM module should be precompiled.
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AotMode {
     /// Use a `wasmtime` executable to compile the module.
     ///
@@ -30,6 +51,7 @@ pub enum AotMode {
     None,
 }
 
+#[allow(clippy::derivable_impls)]
 impl Default for AotMode {
     fn default() -> Self {
         Self::None
@@ -37,16 +59,39 @@ impl Default for AotMode {
 }
 
 #[cfg(feature = "wasmtime-aot")]
-#[derive(Default)]
+#[derive(Debug, Clone, Default)]
 struct WasmTimeAotOptions {
     mode: AotMode,
 }
 
+#[derive(Debug, Clone)]
+struct OpaVersionOptions {
+    check: bool,
+    min: String,
+    max: Option<String>,
+}
+
+impl Default for OpaVersionOptions {
+    fn default() -> Self {
+        Self {
+            check: true,
+            min: MIN_OPA_VERSION.to_string(),
+            max: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct WasmPolicyBuilder {
     name: String,
     paths: Vec<String>,
     entrypoints: Vec<String>,
     opt_level: Option<NonZeroUsize>,
+    bundle_mode: bool,
+    deny_warnings: bool,
+    rego_version: Option<u8>,
+    keep_intermediates: bool,
+    opa_version: OpaVersionOptions,
     #[cfg(feature = "wasmtime-aot")]
     aot: WasmTimeAotOptions,
 }
@@ -58,6 +103,11 @@ impl WasmPolicyBuilder {
             paths: Vec::default(),
             entrypoints: Vec::default(),
             opt_level: None,
+            bundle_mode: false,
+            deny_warnings: false,
+            rego_version: None,
+            keep_intermediates: false,
+            opa_version: OpaVersionOptions::default(),
             #[cfg(feature = "wasmtime-aot")]
             aot: WasmTimeAotOptions::default(),
         }
@@ -87,6 +137,13 @@ impl WasmPolicyBuilder {
         self
     }
 
+    /// Add a `.rego` file or a directory to the policy sources.
+    ///
+    /// Directories are searched recursively for `.rego` files and
+    /// `data.json` or `data.yaml` data files unless [`Self::bundle_mode`] is enabled.
+    /// Like with `opa build <dir>`, data files are loaded at the path
+    /// of their directory relative to the source directory, and the
+    /// data is included in the bundle.
     #[must_use]
     pub fn add_source(mut self, path: impl Into<String>) -> Self {
         self.paths.push(path.into());
@@ -103,6 +160,90 @@ impl WasmPolicyBuilder {
         self
     }
 
+    /// Pass the sources to `opa build` as bundle directories (`--bundle`).
+    ///
+    /// By default directory sources are searched for `.rego` files
+    /// and every file is passed to `opa` individually. In bundle mode
+    /// each source must be a directory and is treated as a bundle root
+    /// instead, so that packages importing each other across directories
+    /// are resolved by `opa` along with any data files in the directory.
+    ///
+    /// Individual `.rego` files are not accepted as sources in bundle mode.
+    #[must_use]
+    pub fn bundle_mode(mut self, enabled: bool) -> Self {
+        self.bundle_mode = enabled;
+        self
+    }
+
+    /// Build the policy in strict mode (`--strict`), where issues such as
+    /// deprecated builtins, unused imports or unused local variables
+    /// that are otherwise ignored fail the build.
+    ///
+    /// The issues reported by `opa` are included in the returned error.
+    #[must_use]
+    pub fn deny_warnings(mut self, enabled: bool) -> Self {
+        self.deny_warnings = enabled;
+        self
+    }
+
+    /// Set the Rego version the policies are written in.
+    ///
+    /// OPA 1.0 parses policies as Rego v1 by default, policies
+    /// written in Rego v0 require `0` here (`--v0-compatible`).
+    /// `1` passes `--rego-v1` to `opa build`, by default no flag is passed
+    /// and the default of the installed `opa` version is used.
+    ///
+    /// Only `0` and `1` are supported, other versions fail the build.
+    #[must_use]
+    pub fn rego_version(mut self, version: u8) -> Self {
+        self.rego_version = Some(version);
+        self
+    }
+
+    /// Print the commands used to build the policy as cargo warnings
+    /// and keep the intermediate files passed to them, so that a failing
+    /// build can be reproduced by running the commands manually.
+    ///
+    /// This is also enabled by setting the `OPA_KEEP_INTERMEDIATES`
+    /// environment variable to anything other than `0` or `false`.
+    ///
+    /// Only the executables and their arguments are printed,
+    /// environment variables are not.
+    #[must_use]
+    pub fn keep_intermediates(mut self, enabled: bool) -> Self {
+        self.keep_intermediates = enabled;
+        self
+    }
+
+    /// Check the version of the `opa` executable before building the policy,
+    /// enabled by default.
+    ///
+    /// The version must be within [`Self::min_opa_version`] and
+    /// [`Self::max_opa_version`], otherwise the build fails.
+    #[must_use]
+    pub fn check_opa_version(mut self, enabled: bool) -> Self {
+        self.opa_version.check = enabled;
+        self
+    }
+
+    /// The minimum supported version of `opa`, e.g. `0.50.0`.
+    ///
+    /// Defaults to the first version that supports building WASM modules.
+    #[must_use]
+    pub fn min_opa_version(mut self, version: impl Into<String>) -> Self {
+        self.opa_version.min = version.into();
+        self
+    }
+
+    /// The maximum supported version of `opa`, e.g. `0.70.0`.
+    ///
+    /// There is no maximum version by default.
+    #[must_use]
+    pub fn max_opa_version(mut self, version: impl Into<String>) -> Self {
+        self.opa_version.max = Some(version.into());
+        self
+    }
+
     #[must_use]
     #[allow(clippy::missing_panics_doc)]
     pub fn opt_level(mut self, level: usize) -> Self {
@@ -130,16 +271,22 @@ impl WasmPolicyBuilder {
             return Err(anyhow!("no entrypoints provided"));
         }
 
-        let opa_executable = which("opa")?;
+        if let Some(version) = self.rego_version.filter(|v| *v > 1) {
+            return Err(anyhow!("unsupported Rego version {version}"));
+        }
+
+        println!("cargo:rerun-if-env-changed={KEEP_INTERMEDIATES_ENV}");
+        let keep_intermediates = self.keep_intermediates
+            || env::var(KEEP_INTERMEDIATES_ENV).is_ok_and(|v| v != "0" && v != "false");
 
         let root_dir = env::var("CARGO_MANIFEST_DIR")?;
         let out_dir = env::var("OUT_DIR")?;
         println!("cargo:rustc-env=OUT_DIR={out_dir}");
         let out_dir = Path::new(&out_dir).join("opa");
 
-        let mut opa_cmd = Command::new(&opa_executable);
-
         let mut input_paths = Vec::new();
+        let mut data_paths = Vec::new();
+        let mut bundle_paths = Vec::new();
 
         for path in self.paths {
             let p = Path::new(&path);
@@ -150,24 +297,73 @@ impl WasmPolicyBuilder {
                 Path::new(&root_dir).join(p)
             };
 
-            if input_file_path.is_dir() {
+            if self.bundle_mode {
+                if !input_file_path.is_dir() {
+                    return Err(anyhow!(
+                        "`{}` is not a directory, only directories are allowed in bundle mode",
+                        input_file_path.display()
+                    ));
+                }
+
+                println!("cargo:rerun-if-changed={}", input_file_path.display());
+                bundle_paths.push(input_file_path.canonicalize()?);
+            } else if input_file_path.is_dir() {
                 for entry in walkdir::WalkDir::new(&input_file_path)
                     .into_iter()
                     .filter_map(Result::ok)
                 {
+                    if is_data_file(entry.path()) {
+                        let prefix = entry
+                            .path()
+                            .parent()
+                            .and_then(|dir| dir.strip_prefix(&input_file_path).ok())
+                            .map(|dir| {
+                                dir.components()
+                                    .map(|c| c.as_os_str().to_string_lossy())
+                                    .collect::<Vec<_>>()
+                                    .join(".")
+                            })
+                            .unwrap_or_default();
+
+                        data_paths.push((prefix, entry.path().to_path_buf()));
+                        continue;
+                    }
+
+                    #[allow(clippy::unnecessary_map_or)]
                     if !entry.path().extension().map_or(false, |s| s == "rego") {
                         continue;
                     }
+
+                    if is_empty_policy(entry.path())? {
+                        println!(
+                            "cargo:warning=skipping empty policy file `{}`",
+                            entry.path().display()
+                        );
+                        continue;
+                    }
+
                     input_paths.push(entry.path().into());
                 }
             } else {
+                if input_file_path.is_file() && is_empty_policy(&input_file_path)? {
+                    return Err(anyhow!(
+                        "the policy file `{}` is empty",
+                        input_file_path.display()
+                    ));
+                }
+
                 input_paths.push(input_file_path);
             }
         }
 
+        if input_paths.is_empty() && bundle_paths.is_empty() {
+            return Err(anyhow!("no policy files found in the sources"));
+        }
+
         for path in &mut input_paths {
-            println!("cargo:rerun-if-changed={}", path.to_str().unwrap());
+            println!("cargo:rerun-if-changed={}", path.display());
 
+            #[allow(clippy::unnecessary_map_or)]
             if !path.extension().map_or(false, |s| s == "rego") {
                 return Err(anyhow!("the policy file must have `.rego` extension"));
             }
@@ -175,16 +371,28 @@ impl WasmPolicyBuilder {
             *path = path.canonicalize()?;
         }
 
+        for (_, path) in &mut data_paths {
+            println!("cargo:rerun-if-changed={}", path.display());
+            *path = path.canonicalize()?;
+        }
+
+        let opa_executable = which("opa")?;
+
+        if self.opa_version.check {
+            check_opa_version(
+                &opa_executable,
+                &self.opa_version.min,
+                self.opa_version.max.as_deref(),
+            )?;
+        }
+
+        let mut opa_cmd = Command::new(&opa_executable);
+
         let output_file_name = self.name;
-        let output_file_path = out_dir.join(&format!("{output_file_name}.tar.gz"));
+        let output_file_path = out_dir.join(format!("{output_file_name}.tar.gz"));
 
-        opa_cmd.args([
-            "build",
-            "-t",
-            "wasm",
-            "-o",
-            output_file_path.to_str().unwrap(),
-        ]);
+        opa_cmd.args(["build", "-t", "wasm", "-o"]);
+        opa_cmd.arg(&output_file_path);
 
         if let Some(opt) = self.opt_level {
             opa_cmd.arg("-O");
@@ -193,19 +401,62 @@ impl WasmPolicyBuilder {
 
         for entrypoint in self.entrypoints {
             opa_cmd.arg("-e");
-            opa_cmd.arg(&entrypoint.replace('.', "/"));
+            opa_cmd.arg(entrypoint.replace('.', "/"));
+        }
+
+        if self.bundle_mode {
+            opa_cmd.arg("-b");
+        }
+
+        if self.deny_warnings {
+            opa_cmd.arg("--strict");
+        }
+
+        match self.rego_version {
+            Some(0) => {
+                opa_cmd.arg("--v0-compatible");
+            }
+            Some(1) => {
+                opa_cmd.arg("--rego-v1");
+            }
+            _ => {}
+        }
+
+        for input_path in input_paths.into_iter().chain(bundle_paths) {
+            opa_cmd.arg(input_path);
         }
 
-        for input_path in input_paths {
-            opa_cmd.arg(input_path.to_str().unwrap());
+        // Nested data files are passed with a `<prefix>:<path>` argument
+        // so that they are loaded at the path of their directory.
+        for (prefix, data_path) in data_paths {
+            if prefix.is_empty() {
+                opa_cmd.arg(data_path);
+            } else {
+                let mut arg = OsString::from(format!("{prefix}:"));
+                arg.push(data_path);
+                opa_cmd.arg(arg);
+            }
         }
 
         fs::create_dir_all(&out_dir)?;
+
+        if keep_intermediates {
+            println!("cargo:warning=running `{}`", command_line(&opa_cmd));
+        }
+
         let out = opa_cmd.output()?;
 
         if !out.status.success() {
             let o = String::from_utf8_lossy(&out.stdout).to_string()
                 + String::from_utf8_lossy(&out.stderr).as_ref();
+
+            if self.rego_version != Some(0) && is_rego_v0_error(&o) {
+                return Err(anyhow!(
+                    "opa error: the policy appears to be written in Rego v0, \
+                     which requires `rego_version(0)` to be set: {o}"
+                ));
+            }
+
             return Err(anyhow!("opa error: {o}"));
         }
 
@@ -213,27 +464,50 @@ impl WasmPolicyBuilder {
         {
             let cwasm_output_path = out_dir.join(format!("{output_file_name}.cwasm"));
 
+            // Build scripts run on the host, so the module is compiled for
+            // the host as well, the triple is checked before it is loaded
+            // in case the crate is cross-compiled.
+            let target = match self.aot.mode {
+                AotMode::None => String::new(),
+                _ => env::var("HOST")?,
+            };
+            fs::write(
+                out_dir.join(format!("{output_file_name}.cwasm.target")),
+                target,
+            )?;
+
             match self.aot.mode {
                 AotMode::Executable => {
-                    let mut bundle = Bundle::from_file(&output_file_path).unwrap();
+                    let mut bundle = Bundle::from_file(&output_file_path)?;
+                    let wasm_policy = bundle
+                        .wasm_policies
+                        .pop()
+                        .ok_or_else(|| anyhow!("the built bundle contains no WASM module"))?;
 
-                    let mut f = tempfile::NamedTempFile::new().unwrap();
+                    let mut f = tempfile::NamedTempFile::new()?;
 
-                    f.write_all(&bundle.wasm_policies.pop().unwrap().bytes)
-                        .unwrap();
+                    f.write_all(&wasm_policy.bytes)?;
 
                     let p = f.into_temp_path();
 
+                    // The temporary file is only removed when `_temp_path` is dropped.
+                    let (wasm_path, _temp_path) = if keep_intermediates {
+                        (p.keep()?, None)
+                    } else {
+                        (p.to_path_buf(), Some(p))
+                    };
+
                     let wasmtime_executable = which("wasmtime")?;
 
                     let mut wasmtime_cmd = Command::new(wasmtime_executable);
 
-                    wasmtime_cmd.args([
-                        "compile",
-                        "-o",
-                        cwasm_output_path.to_str().unwrap(),
-                        p.to_str().unwrap(),
-                    ]);
+                    wasmtime_cmd.args(["compile", "-o"]);
+                    wasmtime_cmd.arg(&cwasm_output_path);
+                    wasmtime_cmd.arg(&wasm_path);
+
+                    if keep_intermediates {
+                        println!("cargo:warning=running `{}`", command_line(&wasmtime_cmd));
+                    }
 
                     let out = wasmtime_cmd.output()?;
 
@@ -250,12 +524,16 @@ impl WasmPolicyBuilder {
                         wasmtime::Config::default()
                             .cranelift_opt_level(wasmtime::OptLevel::SpeedAndSize),
                     )?;
-                    let m = engine.precompile_module(&bundle.wasm_policies.pop().unwrap().bytes)?;
+                    let wasm_policy = bundle
+                        .wasm_policies
+                        .pop()
+                        .ok_or_else(|| anyhow!("the built bundle contains no WASM module"))?;
+                    let m = engine.precompile_module(&wasm_policy.bytes)?;
                     std::fs::write(cwasm_output_path, m)?;
                 }
                 AotMode::None => {
                     // Still create the file as the `include_policy!` macro expects it:
-                    std::fs::File::create(cwasm_output_path).unwrap();
+                    std::fs::File::create(cwasm_output_path)?;
                 }
             }
         }
@@ -263,3 +541,93 @@ impl WasmPolicyBuilder {
         Ok(())
     }
 }
+
+/// Whether the policy file has no content other than whitespace and comments,
+/// which `opa build` rejects with a confusing error.
+///
+/// A file with only a package declaration is not considered empty.
+fn is_empty_policy(path: &Path) -> Result<bool, anyhow::Error> {
+    let source = fs::read_to_string(path)?;
+
+    Ok(source.lines().all(|line| {
+        let line = line.trim();
+        line.is_empty() || line.starts_with('#')
+    }))
+}
+
+/// Check that the version of the `opa` executable
+/// is within the given inclusive range.
+fn check_opa_version(
+    opa_executable: &Path,
+    min_version: &str,
+    max_version: Option<&str>,
+) -> Result<(), anyhow::Error> {
+    let parse = |version: &str| {
+        parse_version(version).ok_or_else(|| anyhow!("invalid OPA version `{version}`"))
+    };
+
+    let min = parse(min_version)?;
+    let max = max_version.map(parse).transpose()?;
+
+    let out = Command::new(opa_executable).arg("version").output()?;
+
+    if !out.status.success() {
+        let o = String::from_utf8_lossy(&out.stdout).to_string()
+            + String::from_utf8_lossy(&out.stderr).as_ref();
+
+        return Err(anyhow!("failed to determine the version of `opa`: {o}"));
+    }
+
+    let output = String::from_utf8_lossy(&out.stdout);
+
+    let version = output
+        .lines()
+        .find_map(|line| line.strip_prefix("Version:"))
+        .map(str::trim)
+        .ok_or_else(|| anyhow!("failed to determine the version of `opa`: {output}"))?;
+
+    let detected = parse(version)?;
+
+    if detected < min || max.is_some_and(|max| detected > max) {
+        let required = match max_version {
+            Some(max_version) => format!(">= {min_version} and <= {max_version}"),
+            None => format!(">= {min_version}"),
+        };
+
+        return Err(anyhow!(
+            "opa version {version} is not supported, the required version is {required}"
+        ));
+    }
+
+    Ok(())
+}
+
+/// The command line of the command that can be pasted into a shell.
+fn command_line(cmd: &Command) -> String {
+    std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|arg| {
+            let arg = arg.to_string_lossy();
+            if arg.is_empty() || arg.contains(|c: char| c.is_whitespace() || c == '\'') {
+                format!("'{}'", arg.replace('\'', r"'\''"))
+            } else {
+                arg.into_owned()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Whether `opa` failed to parse a policy because of
+/// syntax that is only valid in Rego v0.
+fn is_rego_v0_error(output: &str) -> bool {
+    output.contains("rego_parse_error")
+        && ["`if` keyword is required", "`contains` keyword is required"]
+            .iter()
+            .any(|message| output.contains(message))
+}
+
+fn is_data_file(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name == "data.json" || name == "data.yaml")
+}
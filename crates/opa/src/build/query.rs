@@ -0,0 +1,129 @@
+use crate::{bundle::Bundle, wasm::Opa};
+use anyhow::anyhow;
+use bytes::Bytes;
+use std::{fs, process::Command};
+use which::which;
+
+/// The entrypoint of policies built with [`query`].
+pub const QUERY_ENTRYPOINT: &str = "adhoc.result";
+
+/// Compile an ad-hoc Rego query to WASM at runtime.
+///
+/// The query must be a single Rego expression on a single line, it is
+/// assigned to the value of a partial set rule, so evaluating
+/// [`QUERY_ENTRYPOINT`] returns all values the expression produces:
+///
+/// ```rust,ignore
+/// let mut opa = opa::build::query("data.users[_].name")?;
+/// opa.set_data(&data)?;
+/// let names: Vec<String> = opa.eval(QUERY_ENTRYPOINT, &input)?;
+/// ```
+///
+/// Every call invokes `opa`, use [`compile_query`] to keep
+/// the compiled module of queries that are used again.
+///
+/// # Errors
+///
+/// The `opa` binary must be found in any of the system paths **at runtime**.
+///
+/// Invalid queries and WASM errors are also returned, queries
+/// with unbalanced brackets are rejected before `opa` is invoked.
+pub fn query(query: &str) -> Result<Opa, anyhow::Error> {
+    Opa::new().build(compile_query(query)?)
+}
+
+/// Same as [`query`], but the compiled WASM module is returned,
+/// it can be cached and built with [`OpaBuilder::build`](crate::wasm::OpaBuilder::build).
+///
+/// # Errors
+///
+/// See [`query`].
+pub fn compile_query(query: &str) -> Result<Bytes, anyhow::Error> {
+    check_query(query)?;
+
+    let opa_executable = which("opa")?;
+
+    let dir = tempfile::tempdir()?;
+    let policy_path = dir.path().join("query.rego");
+    let output_file_path = dir.path().join("query.tar.gz");
+
+    fs::write(
+        &policy_path,
+        format!(
+            "package adhoc\n\nimport rego.v1\n\nresult contains __value if {{\n    __value := {query}\n}}\n"
+        ),
+    )?;
+
+    let out = Command::new(opa_executable)
+        .args([
+            "build",
+            "-t",
+            "wasm",
+            "-e",
+            &QUERY_ENTRYPOINT.replace('.', "/"),
+        ])
+        .arg("-o")
+        .arg(&output_file_path)
+        .arg(&policy_path)
+        .output()?;
+
+    if !out.status.success() {
+        let o = String::from_utf8_lossy(&out.stdout).to_string()
+            + String::from_utf8_lossy(&out.stderr).as_ref();
+        return Err(anyhow!("opa error: {o}"));
+    }
+
+    Ok(Bundle::from_file(&output_file_path)?
+        .wasm_policies
+        .pop()
+        .ok_or_else(|| anyhow!("the compiled query contains no WASM module"))?
+        .bytes)
+}
+
+/// Check that the query cannot change the structure of the generated policy,
+/// it must be on a single line with balanced brackets outside of strings.
+fn check_query(query: &str) -> Result<(), anyhow::Error> {
+    let invalid = |reason: &str| Err(anyhow!("invalid query `{query}`: {reason}"));
+
+    if query.trim().is_empty() {
+        return invalid("the query is empty");
+    }
+
+    if query.contains(['\n', '\r']) {
+        return invalid("the query must be on a single line");
+    }
+
+    let mut brackets = Vec::new();
+    let mut chars = query.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => loop {
+                match chars.next() {
+                    Some('\\') => {
+                        chars.next();
+                    }
+                    Some('"') => break,
+                    Some(_) => {}
+                    None => return invalid("unterminated string"),
+                }
+            },
+            '`' if !chars.any(|c| c == '`') => return invalid("unterminated string"),
+            // The rest of the line is a comment.
+            '#' => break,
+            '(' => brackets.push(')'),
+            '[' => brackets.push(']'),
+            '{' => brackets.push('}'),
+            ')' | ']' | '}' if brackets.pop() != Some(c) => {
+                return invalid("unbalanced brackets");
+            }
+            _ => {}
+        }
+    }
+
+    if !brackets.is_empty() {
+        return invalid("unbalanced brackets");
+    }
+
+    Ok(())
+}
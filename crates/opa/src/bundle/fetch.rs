@@ -0,0 +1,62 @@
+use super::{Bundle, Error};
+use reqwest::IntoUrl;
+
+/// Options for downloading bundles with [`Bundle::from_url`].
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct FetchOptions {
+    /// Username and optional password for basic authentication.
+    pub basic_auth: Option<(String, Option<String>)>,
+    /// Token for bearer authentication.
+    pub bearer_token: Option<String>,
+}
+
+impl FetchOptions {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Use basic authentication.
+    #[must_use]
+    pub fn basic_auth(mut self, username: impl Into<String>, password: Option<String>) -> Self {
+        self.basic_auth = Some((username.into(), password));
+        self
+    }
+
+    /// Use bearer authentication.
+    #[must_use]
+    pub fn bearer_token(mut self, token: impl Into<String>) -> Self {
+        self.bearer_token = Some(token.into());
+        self
+    }
+}
+
+impl Bundle {
+    /// Download the bundle from the given URL.
+    ///
+    /// Bundles are expected to be a `.tar.gz` format.
+    ///
+    /// # Errors
+    ///
+    /// Errors are returned if the request fails or the bundle is invalid.
+    pub async fn from_url(
+        url: impl IntoUrl,
+        client: &reqwest::Client,
+        options: &FetchOptions,
+    ) -> Result<Self, Error> {
+        let mut req = client.get(url);
+
+        if let Some((username, password)) = &options.basic_auth {
+            req = req.basic_auth(username, password.as_ref());
+        }
+
+        if let Some(token) = &options.bearer_token {
+            req = req.bearer_auth(token);
+        }
+
+        let bytes = req.send().await?.error_for_status()?.bytes().await?;
+
+        Self::from_bytes(bytes)
+    }
+}
@@ -0,0 +1,93 @@
+use std::{
+    cell::Cell,
+    io::{self, Read},
+    rc::Rc,
+};
+
+/// Limits enforced while reading bundles with
+/// [`Bundle::from_reader_with_limits`](super::Bundle::from_reader_with_limits).
+///
+/// No limits are set by default.
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct BundleLimits {
+    /// The maximum size of the decompressed archive in bytes.
+    pub max_total_bytes: Option<u64>,
+    /// The maximum size of a single file within the bundle in bytes.
+    pub max_file_bytes: Option<u64>,
+    /// The maximum number of entries within the bundle.
+    pub max_files: Option<usize>,
+}
+
+impl BundleLimits {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the maximum size of the decompressed archive in bytes.
+    #[must_use]
+    pub fn max_total_bytes(mut self, max: u64) -> Self {
+        self.max_total_bytes = Some(max);
+        self
+    }
+
+    /// Set the maximum size of a single file within the bundle in bytes.
+    #[must_use]
+    pub fn max_file_bytes(mut self, max: u64) -> Self {
+        self.max_file_bytes = Some(max);
+        self
+    }
+
+    /// Set the maximum number of entries within the bundle.
+    #[must_use]
+    pub fn max_files(mut self, max: usize) -> Self {
+        self.max_files = Some(max);
+        self
+    }
+}
+
+/// A reader that fails after reading more than the given number of bytes.
+///
+/// The shared flag is set when the limit is exceeded, so that the
+/// error can be told apart from other i/o errors after `tar` wrapped it.
+pub(super) struct LimitedReader<R> {
+    inner: R,
+    remaining: Option<u64>,
+    exceeded: Rc<Cell<bool>>,
+}
+
+impl<R> LimitedReader<R> {
+    pub(super) fn new(inner: R, limit: Option<u64>) -> (Self, Rc<Cell<bool>>) {
+        let exceeded = Rc::new(Cell::new(false));
+        (
+            Self {
+                inner,
+                remaining: limit,
+                exceeded: exceeded.clone(),
+            },
+            exceeded,
+        )
+    }
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(remaining) = self.remaining else {
+            return self.inner.read(buf);
+        };
+
+        // Read one byte past the limit to detect exceeding it.
+        let max = usize::try_from(remaining.saturating_add(1)).unwrap_or(usize::MAX);
+        let len = buf.len().min(max);
+        let n = self.inner.read(&mut buf[..len])?;
+
+        if n as u64 > remaining {
+            self.exceeded.set(true);
+            return Err(io::Error::other("the bundle size limit was exceeded"));
+        }
+
+        self.remaining = Some(remaining - n as u64);
+        Ok(n)
+    }
+}
@@ -1,6 +1,7 @@
 use std::path::PathBuf;
 
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Manifest {
@@ -10,6 +11,20 @@ pub struct Manifest {
     pub roots: Vec<String>,
     #[serde(default)]
     pub wasm: Vec<Wasm>,
+    /// Arbitrary metadata of the bundle, e.g. the
+    /// configuration of a discovery bundle.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Map<String, Value>>,
+    /// The Rego version of the policies in the bundle,
+    /// `0` for Rego v0 and `1` for Rego v1.
+    ///
+    /// OPA assumes Rego v1 if it is not set since OPA 1.0.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rego_version: Option<u8>,
+    /// Any other fields of the manifest that are not
+    /// explicitly supported.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -0,0 +1,178 @@
+use super::{manifest::Manifest, Bundle, BundleData};
+use serde_json::Value;
+use std::{collections::HashMap, path::PathBuf};
+use thiserror::Error;
+
+/// Conflicts between bundles returned by [`Bundle::merge`].
+#[derive(Debug, Error)]
+pub enum MergeError {
+    #[error("the policy `{}` exists in both bundles with different contents", .0.display())]
+    PolicyConflict(PathBuf),
+    #[error("the schema `{}` exists in both bundles with different contents", .0.display())]
+    SchemaConflict(PathBuf),
+    #[error("the bundles contain different values at the data path `{0}`")]
+    DataConflict(String),
+    #[error("the manifest roots `{0}` and `{1}` overlap")]
+    RootConflict(String, String),
+    /// The raw data of a bundle read with
+    /// [`Bundle::from_reader_lazy_data`] could not be parsed.
+    #[error("the data of a bundle is invalid: {0}")]
+    InvalidData(#[source] super::Error),
+}
+
+impl Bundle {
+    /// Merge another bundle into this one.
+    ///
+    /// - Rego policies and schemas are combined, files present in both
+    ///   bundles must have the same contents.
+    /// - Data documents are merged recursively, values present in both
+    ///   bundles must be equal unless both of them are objects.
+    /// - WASM policies are concatenated.
+    /// - Manifest roots are combined, a root must not be nested
+    ///   within a different root of the other bundle.
+    ///   The revision and metadata of this bundle are kept if set.
+    ///
+    /// # Errors
+    ///
+    /// A [`MergeError`] is returned for the first conflict found,
+    /// this bundle is not modified in that case.
+    pub fn merge(&mut self, mut other: Bundle) -> Result<(), MergeError> {
+        check_files(&self.rego_policies, &other.rego_policies)
+            .map_err(MergeError::PolicyConflict)?;
+        check_files(&self.schemas, &other.schemas).map_err(MergeError::SchemaConflict)?;
+
+        if let (Some(manifest), Some(other_manifest)) = (&self.manifest, &other.manifest) {
+            check_roots(&manifest.roots, &other_manifest.roots)?;
+        }
+
+        self.data_lazy().map_err(MergeError::InvalidData)?;
+        other.data_lazy().map_err(MergeError::InvalidData)?;
+
+        if let BundleData::Parsed(other_data) = other.data {
+            match &mut self.data {
+                BundleData::Parsed(data) => {
+                    let mut merged = data.clone();
+                    merge_data(&mut merged, other_data, &mut Vec::new())?;
+                    *data = merged;
+                }
+                BundleData::None | BundleData::Raw(_) => {
+                    self.data = BundleData::Parsed(other_data);
+                }
+            }
+        }
+
+        self.manifest = match (self.manifest.take(), other.manifest) {
+            (Some(mut manifest), Some(other_manifest)) => {
+                merge_manifest(&mut manifest, other_manifest);
+                Some(manifest)
+            }
+            (manifest, other_manifest) => manifest.or(other_manifest),
+        };
+
+        #[cfg(feature = "wasmtime-aot")]
+        {
+            // A precompiled module only stays valid if the
+            // WASM policies of a single bundle are used.
+            let has_wasm = !self.wasm_policies.is_empty() || self.wasmtime_bytes.is_some();
+            let other_has_wasm = !other.wasm_policies.is_empty() || other.wasmtime_bytes.is_some();
+
+            if !has_wasm {
+                self.wasmtime_bytes = other.wasmtime_bytes;
+                self.wasmtime_target = other.wasmtime_target;
+            } else if other_has_wasm {
+                self.wasmtime_bytes = None;
+                self.wasmtime_target = None;
+            }
+        }
+
+        self.rego_policies.extend(other.rego_policies);
+        self.schemas.extend(other.schemas);
+        self.wasm_policies.extend(other.wasm_policies);
+
+        Ok(())
+    }
+}
+
+/// Returns the first path that exists in both with different contents.
+fn check_files<T: PartialEq>(
+    files: &HashMap<PathBuf, T>,
+    other: &HashMap<PathBuf, T>,
+) -> Result<(), PathBuf> {
+    match other
+        .iter()
+        .find(|(path, contents)| files.get(*path).is_some_and(|c| c != *contents))
+    {
+        Some((path, _)) => Err(path.clone()),
+        None => Ok(()),
+    }
+}
+
+fn check_roots(roots: &[String], other: &[String]) -> Result<(), MergeError> {
+    for root in roots {
+        for other_root in other {
+            let a = root_segments(root);
+            let b = root_segments(other_root);
+
+            if a != b && (a.starts_with(&b) || b.starts_with(&a)) {
+                return Err(MergeError::RootConflict(root.clone(), other_root.clone()));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn root_segments(root: &str) -> Vec<&str> {
+    root.split('/').filter(|s| !s.is_empty()).collect()
+}
+
+fn merge_data(data: &mut Value, other: Value, path: &mut Vec<String>) -> Result<(), MergeError> {
+    match (data, other) {
+        (Value::Object(data), Value::Object(other)) => {
+            for (key, value) in other {
+                match data.get_mut(&key) {
+                    Some(existing) => {
+                        path.push(key);
+                        merge_data(existing, value, path)?;
+                        path.pop();
+                    }
+                    None => {
+                        data.insert(key, value);
+                    }
+                }
+            }
+            Ok(())
+        }
+        (data, other) if *data == other => Ok(()),
+        _ => Err(MergeError::DataConflict(path.join("/"))),
+    }
+}
+
+fn merge_manifest(manifest: &mut Manifest, other: Manifest) {
+    if manifest.revision.is_empty() {
+        manifest.revision = other.revision;
+    }
+
+    extend_unique(&mut manifest.roots, other.roots);
+    manifest.wasm.extend(other.wasm);
+
+    if manifest.metadata.is_none() {
+        manifest.metadata = other.metadata;
+    }
+
+    if manifest.rego_version.is_none() {
+        manifest.rego_version = other.rego_version;
+    }
+
+    for (key, value) in other.extra {
+        manifest.extra.entry(key).or_insert(value);
+    }
+}
+
+fn extend_unique<T: PartialEq>(items: &mut Vec<T>, other: Vec<T>) {
+    for item in other {
+        if !items.contains(&item) {
+            items.push(item);
+        }
+    }
+}
@@ -1,16 +1,45 @@
 use self::manifest::Manifest;
 use bytes::Bytes;
-use flate2::read::GzDecoder;
-use serde_json::Value;
+use flate2::{read::GzDecoder, write::GzEncoder};
+use serde_json::{Map, Value};
 use std::{
-    collections::HashMap,
-    io::Read,
-    path::{Path, PathBuf},
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    fs,
+    io::{Read, Write},
+    path::{Component, Path, PathBuf},
 };
-use tar::Archive;
+use tar::{Archive, Header};
 use thiserror::Error;
 
+#[cfg(feature = "http")]
+pub use fetch::FetchOptions;
+pub use limits::BundleLimits;
+pub use merge::MergeError;
+pub use rego::RegoEvaluator;
+pub use summary::{BundleSummary, RegoPolicySummary, WasmPolicySummary};
+pub use write::{BundleWriteOptions, Compression};
+
+#[cfg(feature = "http")]
+mod fetch;
+mod limits;
 pub mod manifest;
+mod merge;
+mod rego;
+#[cfg(feature = "bundle-signing")]
+pub mod signing;
+mod summary;
+mod write;
+
+/// The data of a bundle, the raw `data.json` is kept for
+/// bundles read with [`Bundle::from_reader_lazy_data`].
+#[derive(Debug, Clone, Default)]
+enum BundleData {
+    #[default]
+    None,
+    Parsed(Value),
+    Raw(Bytes),
+}
 
 /// An OPA bundle created by `opa build`.
 #[derive(Debug, Clone)]
@@ -18,8 +47,8 @@ pub struct Bundle {
     /// The manifest of the bundle, if any.
     pub manifest: Option<Manifest>,
 
-    /// The OPA generated data that was bundled during build.
-    pub data: Option<Value>,
+    /// The OPA generated data that was bundled during build, see [`Bundle::data`].
+    data: BundleData,
 
     /// All `.rego` policy files with their respective paths within
     /// the bundle.
@@ -31,8 +60,21 @@ pub struct Bundle {
     /// only if it was listed in the manifest.
     pub wasm_policies: Vec<WasmPolicy>,
 
+    /// JSON schemas within the `schemas` directory of the bundle
+    /// with their respective paths within the bundle.
+    ///
+    /// The schema for the input document is expected
+    /// at `schemas/input.json`, see [`Bundle::input_schema`],
+    /// and the schema for the data document at `schemas/data.json`,
+    /// see [`Bundle::data_schema`].
+    pub schemas: HashMap<PathBuf, Value>,
+
     #[cfg(feature = "wasmtime-aot")]
     pub(crate) wasmtime_bytes: Option<Bytes>,
+
+    /// The target triple the precompiled module was compiled for, if known.
+    #[cfg(feature = "wasmtime-aot")]
+    pub(crate) wasmtime_target: Option<String>,
 }
 
 impl Bundle {
@@ -65,28 +107,146 @@ impl Bundle {
     /// # Errors
     ///
     /// Errors are returned if the bundle is invalid or on i/o error.
+    ///
+    /// # Remarks
+    ///
+    /// No limits are enforced on the size of the bundle,
+    /// use [`Self::from_reader_with_limits`] for untrusted input.
     pub fn from_reader(reader: impl Read) -> Result<Self, Error> {
-        let gz = GzDecoder::new(reader);
-        let mut ar = Archive::new(gz);
+        Self::from_reader_with_limits(reader, &BundleLimits::default())
+    }
 
+    /// Same as [`Self::from_reader`], but reading stops with an error
+    /// as soon as any of the given limits are exceeded.
+    ///
+    /// Sizes are checked against the decompressed contents,
+    /// so compressed archives that expand to huge sizes are rejected early.
+    ///
+    /// # Errors
+    ///
+    /// Errors are returned if the bundle is invalid, a limit is exceeded or on i/o error.
+    pub fn from_reader_with_limits(
+        reader: impl Read,
+        limits: &BundleLimits,
+    ) -> Result<Self, Error> {
+        Self::read_limited(reader, limits, false)
+    }
+
+    /// Same as [`Self::from_reader_with_limits`], but `data.json` is
+    /// kept as raw bytes instead of being parsed.
+    ///
+    /// Parsing huge data documents into a [`Value`] is slow and takes
+    /// several times the memory of the JSON itself. The bytes can be
+    /// set for WASM instances without ever parsing them with
+    /// [`Opa::set_data_bytes`](crate::wasm::Opa::set_data_bytes), which
+    /// also happens when building instances from the bundle, or parsed
+    /// on first use with [`Self::data_lazy`].
+    ///
+    /// Bundles that also contain `data.json` files in subdirectories
+    /// are merged into [`Self::data`] as usual.
+    ///
+    /// # Errors
+    ///
+    /// See [`Self::from_reader_with_limits`].
+    pub fn from_reader_lazy_data(reader: impl Read, limits: &BundleLimits) -> Result<Self, Error> {
+        Self::read_limited(reader, limits, true)
+    }
+
+    fn read_limited(
+        reader: impl Read,
+        limits: &BundleLimits,
+        lazy_data: bool,
+    ) -> Result<Self, Error> {
+        let (reader, exceeded) =
+            limits::LimitedReader::new(GzDecoder::new(reader), limits.max_total_bytes);
+
+        Self::read_archive(Archive::new(reader), limits, lazy_data).map_err(|err| {
+            if exceeded.get() {
+                Error::LimitExceeded(format!(
+                    "more than {} bytes",
+                    limits.max_total_bytes.unwrap_or_default()
+                ))
+            } else {
+                err
+            }
+        })
+    }
+
+    #[allow(clippy::too_many_lines)]
+    fn read_archive(
+        mut ar: Archive<impl Read>,
+        limits: &BundleLimits,
+        lazy_data: bool,
+    ) -> Result<Self, Error> {
         let mut manifest: Option<Manifest> = None;
         let mut data: Option<Value> = None;
+        let mut data_bytes: Option<Bytes> = None;
         let mut rego_policies: HashMap<PathBuf, String> = HashMap::default();
         let mut wasm_policies: Vec<WasmPolicy> = Vec::new();
+        let mut schemas: HashMap<PathBuf, Value> = HashMap::default();
 
         let mut wasm_files: HashMap<PathBuf, Bytes> = HashMap::default();
+        // Data files in subdirectories with the path of their directory.
+        let mut nested_data: Vec<(Vec<String>, Value)> = Vec::new();
 
-        for entry in ar.entries()? {
+        for (i, entry) in ar.entries()?.enumerate() {
             let mut entry = entry?;
 
+            if limits.max_files.is_some_and(|max| i >= max) {
+                return Err(Error::LimitExceeded(format!(
+                    "more than {} files",
+                    limits.max_files.unwrap_or_default()
+                )));
+            }
+
             let path = entry.path()?;
 
-            match path.to_str() {
-                Some("/.manifest") => {
-                    manifest = Some(serde_json::from_reader(entry).map_err(Error::InvalidData)?);
+            if let Some(max) = limits.max_file_bytes {
+                if entry.size() > max {
+                    return Err(Error::LimitExceeded(format!(
+                        "`{}` is larger than {max} bytes",
+                        path.display()
+                    )));
                 }
-                Some("/data.json") => {
-                    data = Some(serde_json::from_reader(entry).map_err(Error::InvalidManifest)?);
+            }
+
+            match path.to_str().map(|p| p.trim_start_matches('/')) {
+                Some(".manifest") => {
+                    let path = path.into_owned();
+                    manifest = Some(
+                        serde_json::from_reader(entry)
+                            .map_err(|source| Error::InvalidManifest { path, source })?,
+                    );
+                }
+                Some("data.json") if lazy_data => {
+                    let mut bytes = Vec::new();
+                    entry.read_to_end(&mut bytes)?;
+                    data_bytes = Some(bytes.into());
+                }
+                Some("data.json") => {
+                    let path = path.into_owned();
+                    data = Some(
+                        serde_json::from_reader(entry)
+                            .map_err(|source| Error::InvalidData { path, source })?,
+                    );
+                }
+                Some(s) if s.starts_with("schemas/") && has_ext(s, "json") => {
+                    let path = path.into_owned();
+                    let schema =
+                        serde_json::from_reader(entry).map_err(|source| Error::InvalidData {
+                            path: path.clone(),
+                            source,
+                        })?;
+                    schemas.insert(path, schema);
+                }
+                Some(s) if s.ends_with("/data.json") => {
+                    let data_path = path_segments(s.trim_end_matches("/data.json"));
+                    let path = path.into_owned();
+                    nested_data.push((
+                        data_path,
+                        serde_json::from_reader(entry)
+                            .map_err(|source| Error::InvalidData { path, source })?,
+                    ));
                 }
                 Some(s) if has_ext(s, "rego") => {
                     let mut s = String::new();
@@ -98,15 +258,30 @@ impl Bundle {
                     let mut s = Vec::new();
                     let p = path.into_owned();
                     entry.read_to_end(&mut s)?;
-                    wasm_files.insert(p, s.into());
+                    wasm_files.insert(p.strip_prefix("/").unwrap_or(&p).into(), s.into());
                 }
                 _ => {}
             }
         }
 
+        if !nested_data.is_empty() {
+            if let Some(bytes) = data_bytes.take() {
+                data = Some(parse_data(&bytes)?);
+            }
+        }
+
+        for (data_path, value) in nested_data {
+            insert_data(
+                data.get_or_insert_with(|| Value::Object(Map::new())),
+                &data_path,
+                value,
+            );
+        }
+
         if let Some(m) = &manifest {
             for wasm_manifest in &m.wasm {
-                if let Some(b) = wasm_files.get(&wasm_manifest.module) {
+                let module = &wasm_manifest.module;
+                if let Some(b) = wasm_files.get(module.strip_prefix("/").unwrap_or(module)) {
                     wasm_policies.push(WasmPolicy {
                         entrypoint: wasm_manifest.entrypoint.clone(),
                         bytes: b.clone(),
@@ -117,14 +292,279 @@ impl Bundle {
 
         Ok(Self {
             manifest,
-            data,
+            data: match (data, data_bytes) {
+                (Some(data), _) => BundleData::Parsed(data),
+                (None, Some(bytes)) => BundleData::Raw(bytes),
+                (None, None) => BundleData::None,
+            },
             rego_policies,
             wasm_policies,
+            schemas,
             #[cfg(feature = "wasmtime-aot")]
             wasmtime_bytes: None,
+            #[cfg(feature = "wasmtime-aot")]
+            wasmtime_target: None,
         })
     }
 
+    /// Read only the revision from the manifest of a bundle.
+    ///
+    /// Reading stops at the manifest, so this is cheaper than
+    /// [`Self::from_reader`] when the manifest is not the last file.
+    ///
+    /// `None` is returned if the bundle has no manifest.
+    ///
+    /// # Errors
+    ///
+    /// Errors are returned if the bundle or the manifest is invalid or on i/o error.
+    pub fn peek_revision(reader: impl Read) -> Result<Option<String>, Error> {
+        let mut ar = Archive::new(GzDecoder::new(reader));
+
+        for entry in ar.entries()? {
+            let entry = entry?;
+            let path = entry.path()?;
+
+            if path.to_str().map(|p| p.trim_start_matches('/')) == Some(".manifest") {
+                let path = path.into_owned();
+                let manifest: Manifest = serde_json::from_reader(entry)
+                    .map_err(|source| Error::InvalidManifest { path, source })?;
+                return Ok(Some(manifest.revision));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// The OPA generated data that was bundled during build.
+    ///
+    /// This is `None` until the data is parsed for bundles read
+    /// with [`Self::from_reader_lazy_data`], see [`Self::data_lazy`].
+    #[must_use]
+    pub fn data(&self) -> Option<&Value> {
+        match &self.data {
+            BundleData::Parsed(data) => Some(data),
+            BundleData::None | BundleData::Raw(_) => None,
+        }
+    }
+
+    /// Same as [`Self::data`], but the data can be modified.
+    #[must_use]
+    pub fn data_mut(&mut self) -> Option<&mut Value> {
+        match &mut self.data {
+            BundleData::Parsed(data) => Some(data),
+            BundleData::None | BundleData::Raw(_) => None,
+        }
+    }
+
+    /// Set or remove the data of the bundle,
+    /// replacing the raw `data.json` if it was not parsed.
+    pub fn set_data(&mut self, data: Option<Value>) {
+        self.data = data.map_or(BundleData::None, BundleData::Parsed);
+    }
+
+    /// The raw `data.json` of a bundle read with
+    /// [`Self::from_reader_lazy_data`] until it is parsed.
+    #[must_use]
+    pub fn data_bytes(&self) -> Option<&Bytes> {
+        match &self.data {
+            BundleData::Raw(bytes) => Some(bytes),
+            BundleData::None | BundleData::Parsed(_) => None,
+        }
+    }
+
+    /// The data of the bundle, parsing the raw `data.json`
+    /// of a bundle read with [`Self::from_reader_lazy_data`]
+    /// on first use.
+    ///
+    /// The raw bytes are dropped after they were parsed.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the data is not valid JSON,
+    /// the raw bytes are kept in this case.
+    pub fn data_lazy(&mut self) -> Result<Option<&Value>, Error> {
+        if let BundleData::Raw(bytes) = &self.data {
+            self.data = BundleData::Parsed(parse_data(bytes)?);
+        }
+
+        Ok(self.data())
+    }
+
+    /// The number of WASM modules in the bundle.
+    #[must_use]
+    pub fn wasm_policy_count(&self) -> usize {
+        self.wasm_policies.len()
+    }
+
+    /// The JSON schema of the input document, if any.
+    #[must_use]
+    pub fn input_schema(&self) -> Option<&Value> {
+        self.schemas
+            .iter()
+            .find(|(path, _)| relative_path(path) == "schemas/input.json")
+            .map(|(_, schema)| schema)
+    }
+
+    /// The JSON schema of the data document, if any.
+    #[must_use]
+    pub fn data_schema(&self) -> Option<&Value> {
+        self.schemas
+            .iter()
+            .find(|(path, _)| relative_path(path) == "schemas/data.json")
+            .map(|(_, schema)| schema)
+    }
+
+    /// Write the bundle in the `.tar.gz` format.
+    ///
+    /// WASM modules are only written if they are listed in the manifest.
+    ///
+    /// # Errors
+    ///
+    /// Errors are returned on i/o error.
+    pub fn to_writer(&self, writer: impl Write) -> Result<(), Error> {
+        self.to_writer_with(writer, &BundleWriteOptions::default())
+    }
+
+    /// Same as [`Self::to_writer`] with the given options.
+    ///
+    /// # Errors
+    ///
+    /// See [`Self::to_writer`].
+    pub fn to_writer_with(
+        &self,
+        writer: impl Write,
+        options: &BundleWriteOptions,
+    ) -> Result<(), Error> {
+        let mut files = self.files()?;
+
+        if options.deterministic {
+            // The manifest is kept first for `Bundle::peek_revision`.
+            files.sort_by(|(a, _), (b, _)| (a != ".manifest", a).cmp(&(b != ".manifest", b)));
+        }
+
+        write_archive(writer, &files, options.compression)
+    }
+
+    /// Write the bundle to bytes in the `.tar.gz` format.
+    ///
+    /// # Errors
+    ///
+    /// See [`Self::to_writer`].
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        self.to_bytes_with(&BundleWriteOptions::default())
+    }
+
+    /// Same as [`Self::to_bytes`] with the given options.
+    ///
+    /// # Errors
+    ///
+    /// See [`Self::to_writer`].
+    pub fn to_bytes_with(&self, options: &BundleWriteOptions) -> Result<Vec<u8>, Error> {
+        let mut bytes = Vec::new();
+        self.to_writer_with(&mut bytes, options)?;
+        Ok(bytes)
+    }
+
+    /// Write the files of the bundle to a directory, the inverse of
+    /// extracting the `.tar.gz` archive of the bundle.
+    ///
+    /// The manifest, data, policies, schemas and WASM modules are written
+    /// at their paths within the bundle, missing directories are created
+    /// and existing files are overwritten.
+    ///
+    /// # Errors
+    ///
+    /// Errors are returned on i/o error, or if a path
+    /// within the bundle would be outside of the directory.
+    pub fn extract_to(&self, dir: impl AsRef<Path>) -> Result<(), Error> {
+        let dir = dir.as_ref();
+        let files = self.files()?;
+
+        // Nothing is written if any of the paths are invalid.
+        if let Some((path, _)) = files.iter().find(|(path, _)| {
+            !Path::new(path)
+                .components()
+                .all(|c| matches!(c, Component::Normal(_)))
+        }) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("the path `{path}` is outside of the bundle"),
+            )
+            .into());
+        }
+
+        for (path, content) in files {
+            let file_path = dir.join(path);
+
+            if let Some(parent) = file_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            fs::write(file_path, content)?;
+        }
+
+        Ok(())
+    }
+
+    /// All files of the bundle with their paths relative to the bundle root.
+    fn files(&self) -> Result<Vec<BundleFile<'_>>, Error> {
+        let mut files: Vec<BundleFile> = Vec::new();
+
+        if let Some(manifest) = &self.manifest {
+            files.push((
+                ".manifest".into(),
+                serde_json::to_vec(manifest)
+                    .map_err(std::io::Error::from)?
+                    .into(),
+            ));
+
+            let mut modules = HashSet::new();
+
+            for wasm_manifest in &manifest.wasm {
+                let module = relative_path(&wasm_manifest.module);
+
+                if modules.contains(&module) {
+                    continue;
+                }
+
+                if let Some(policy) = self
+                    .wasm_policies
+                    .iter()
+                    .find(|p| p.entrypoint == wasm_manifest.entrypoint)
+                {
+                    files.push((module.clone(), policy.bytes.as_ref().into()));
+                    modules.insert(module);
+                }
+            }
+        }
+
+        match &self.data {
+            BundleData::Parsed(data) => files.push((
+                "data.json".into(),
+                serde_json::to_vec(data)
+                    .map_err(std::io::Error::from)?
+                    .into(),
+            )),
+            BundleData::Raw(bytes) => files.push(("data.json".into(), bytes.as_ref().into())),
+            BundleData::None => {}
+        }
+
+        for (path, policy) in &self.rego_policies {
+            files.push((relative_path(path), policy.as_bytes().into()));
+        }
+
+        for (path, schema) in &self.schemas {
+            files.push((
+                relative_path(path),
+                serde_json::to_vec(schema)
+                    .map_err(std::io::Error::from)?
+                    .into(),
+            ));
+        }
+
+        Ok(files)
+    }
+
     // Set a precompiled WASM module for the bundle, intended
     // for precompiled bundles from the build script.
     //
@@ -133,6 +573,25 @@ impl Bundle {
     #[doc(hidden)]
     pub unsafe fn set_wasmtime_bytes(&mut self, bytes: Bytes) {
         self.wasmtime_bytes = Some(bytes);
+        self.wasmtime_target = None;
+    }
+
+    // Same as `set_wasmtime_bytes`, but the module is only used
+    // if the host matches the target triple it was compiled for.
+    #[cfg(feature = "wasmtime-aot")]
+    #[doc(hidden)]
+    pub unsafe fn set_wasmtime_bytes_for_target(&mut self, bytes: Bytes, target: &str) {
+        self.wasmtime_bytes = Some(bytes);
+        self.wasmtime_target = Some(target.trim().to_string());
+    }
+}
+
+impl TryFrom<Vec<u8>> for Bundle {
+    type Error = Error;
+
+    /// Same as [`Bundle::from_bytes`].
+    fn try_from(bytes: Vec<u8>) -> Result<Self, Self::Error> {
+        Self::from_bytes(bytes)
     }
 }
 
@@ -142,20 +601,109 @@ pub struct WasmPolicy {
     pub bytes: Bytes,
 }
 
+fn path_segments(path: &str) -> Vec<String> {
+    path.split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(ToString::to_string)
+        .collect()
+}
+
+fn parse_data(bytes: &[u8]) -> Result<Value, Error> {
+    serde_json::from_slice(bytes).map_err(|source| Error::InvalidData {
+        path: "data.json".into(),
+        source,
+    })
+}
+
+/// Insert the value at the path, like OPA, objects
+/// at the same path are merged.
+fn insert_data(data: &mut Value, path: &[String], value: Value) {
+    let Some((key, rest)) = path.split_first() else {
+        match (data, value) {
+            (Value::Object(data), Value::Object(value)) => {
+                for (key, value) in value {
+                    insert_data(data.entry(key).or_insert(Value::Null), &[], value);
+                }
+            }
+            (data, value) => *data = value,
+        }
+        return;
+    };
+
+    if !data.is_object() {
+        *data = Value::Object(Map::new());
+    }
+
+    if let Value::Object(data) = data {
+        insert_data(
+            data.entry(key.clone())
+                .or_insert_with(|| Value::Object(Map::new())),
+            rest,
+            value,
+        );
+    }
+}
+
 fn has_ext(filename: &str, ext: &str) -> bool {
     filename
         .rsplit('.')
         .next()
-        .map(|e| e.eq_ignore_ascii_case(ext))
-        == Some(true)
+        .is_some_and(|e| e.eq_ignore_ascii_case(ext))
+}
+
+/// A file path relative to the bundle root and its contents.
+type BundleFile<'a> = (String, Cow<'a, [u8]>);
+
+fn relative_path(path: &Path) -> String {
+    path.to_string_lossy().trim_start_matches('/').to_string()
+}
+
+fn write_archive(
+    writer: impl Write,
+    files: &[BundleFile],
+    compression: Compression,
+) -> Result<(), Error> {
+    let mut ar = tar::Builder::new(GzEncoder::new(writer, compression));
+
+    for (path, content) in files {
+        let mut header = Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        ar.append_data(&mut header, path, content.as_ref())?;
+    }
+
+    ar.into_inner()?.finish()?;
+    Ok(())
 }
 
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("invalid bundle: {0}")]
     Io(#[from] std::io::Error),
-    #[error("invalid manifest: {0}")]
-    InvalidManifest(serde_json::Error),
-    #[error("invalid data file: {0}")]
-    InvalidData(serde_json::Error),
+    #[error("invalid manifest `{}`: {source}", path.display())]
+    InvalidManifest {
+        /// The path of the manifest entry within the bundle.
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+    #[error("invalid data file `{}`: {source}", path.display())]
+    InvalidData {
+        /// The path of the data entry within the bundle.
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+    #[error("the bundle exceeds the size limits: {0}")]
+    LimitExceeded(String),
+    #[cfg(feature = "http")]
+    #[error("failed to download bundle: {0}")]
+    Http(#[from] reqwest::Error),
+    #[cfg(feature = "bundle-signing")]
+    #[error("invalid bundle signature: {0}")]
+    InvalidSignature(String),
+    #[cfg(feature = "bundle-signing")]
+    #[error("invalid key: {0}")]
+    InvalidKey(String),
+    #[cfg(feature = "bundle-signing")]
+    #[error("signature error: {0}")]
+    Jwt(#[from] jsonwebtoken::errors::Error),
 }
@@ -0,0 +1,29 @@
+use super::Bundle;
+use serde_json::Value;
+
+/// An evaluator for the Rego policies of a bundle, e.g. for bundles
+/// that are built without WASM modules.
+///
+/// The policies are available in [`Bundle::rego_policies`] by their paths
+/// within the bundle and the data in [`Bundle::data`], evaluators that
+/// need the files of the bundle can write them with [`Bundle::extract_to`].
+///
+/// A reference implementation that runs `opa eval` is available
+/// with the `build` feature as `opa::build::OpaEval`.
+pub trait RegoEvaluator {
+    /// Evaluate the decision at the policy path with the given input,
+    /// returning `None` if the decision is undefined.
+    ///
+    /// The policy path is either a package name such as `example.allow`
+    /// or a path such as `example/allow`.
+    ///
+    /// # Errors
+    ///
+    /// Errors of the evaluator are returned as-is.
+    fn eval(
+        &self,
+        bundle: &Bundle,
+        policy: &str,
+        input: &Value,
+    ) -> Result<Option<Value>, anyhow::Error>;
+}
@@ -0,0 +1,503 @@
+//! Bundle signing and verification.
+//!
+//! The signature format follows the
+//! [OPA bundle signing specification](https://www.openpolicyagent.org/docs/latest/management-bundles/#signing),
+//! so signed bundles are accepted by OPA servers configured with bundle verification.
+use super::{relative_path, write_archive, Bundle, BundleFile, Compression, Error};
+use flate2::read::GzDecoder;
+use jsonwebtoken::{
+    jwk::{AlgorithmParameters, EllipticCurve, Jwk},
+    Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    io::{Read, Write},
+    path::Path,
+};
+use tar::Archive;
+
+/// The name of the file that contains the signatures of a bundle.
+pub const SIGNATURES_FILE: &str = ".signatures.json";
+
+const HASH_ALGORITHM: &str = "SHA-256";
+
+/// A key used for signing bundles.
+pub struct SigningKey {
+    algorithm: Algorithm,
+    key: EncodingKey,
+    key_id: Option<String>,
+}
+
+impl SigningKey {
+    /// A shared secret key for `HS256` signatures.
+    #[must_use]
+    pub fn hs256(secret: impl AsRef<[u8]>) -> Self {
+        Self {
+            algorithm: Algorithm::HS256,
+            key: EncodingKey::from_secret(secret.as_ref()),
+            key_id: None,
+        }
+    }
+
+    /// A PEM encoded RSA private key for `RS256` signatures.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the key is not a valid RSA PEM.
+    pub fn rs256_pem(pem: impl AsRef<[u8]>) -> Result<Self, Error> {
+        Ok(Self {
+            algorithm: Algorithm::RS256,
+            key: EncodingKey::from_rsa_pem(pem.as_ref())?,
+            key_id: None,
+        })
+    }
+
+    /// A PEM encoded RSA or EC private key for `RS256` or `ES256`
+    /// signatures respectively, the algorithm is chosen based on the key.
+    ///
+    /// RSA keys can be in the PKCS#1 (`RSA PRIVATE KEY`) or PKCS#8
+    /// (`PRIVATE KEY`) format, EC keys must be P-256 keys in the PKCS#8 format.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the PEM is not a supported private key.
+    pub fn from_pem(pem: impl AsRef<[u8]>) -> Result<Self, Error> {
+        let pem = pem.as_ref();
+
+        if !matches!(pem_label(pem), Some("PRIVATE KEY" | "RSA PRIVATE KEY")) {
+            return Err(Error::InvalidKey(
+                "expected a PEM encoded private key".into(),
+            ));
+        }
+
+        let (algorithm, key) = if let Ok(key) = EncodingKey::from_rsa_pem(pem) {
+            (Algorithm::RS256, key)
+        } else if let Ok(key) = EncodingKey::from_ec_pem(pem) {
+            (Algorithm::ES256, key)
+        } else {
+            return Err(Error::InvalidKey(
+                "only RSA and EC private keys are supported".into(),
+            ));
+        };
+
+        Ok(Self {
+            algorithm,
+            key,
+            key_id: None,
+        })
+    }
+
+    /// Set the key ID that is included in the signature.
+    ///
+    /// OPA uses it to select the verification key.
+    #[must_use]
+    pub fn with_key_id(mut self, key_id: impl Into<String>) -> Self {
+        self.key_id = Some(key_id.into());
+        self
+    }
+}
+
+/// A key used for verifying bundle signatures.
+pub struct VerificationKey {
+    algorithm: Algorithm,
+    key: DecodingKey,
+}
+
+impl VerificationKey {
+    /// A shared secret key for `HS256` signatures.
+    #[must_use]
+    pub fn hs256(secret: impl AsRef<[u8]>) -> Self {
+        Self {
+            algorithm: Algorithm::HS256,
+            key: DecodingKey::from_secret(secret.as_ref()),
+        }
+    }
+
+    /// A PEM encoded RSA public key for `RS256` signatures.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the key is not a valid RSA PEM.
+    pub fn rs256_pem(pem: impl AsRef<[u8]>) -> Result<Self, Error> {
+        Ok(Self {
+            algorithm: Algorithm::RS256,
+            key: DecodingKey::from_rsa_pem(pem.as_ref())?,
+        })
+    }
+
+    /// A PEM encoded RSA or EC public key for `RS256` or `ES256`
+    /// signatures respectively, the algorithm is chosen based on the key.
+    ///
+    /// RSA keys can be in the PKCS#1 (`RSA PUBLIC KEY`) or PKCS#8
+    /// (`PUBLIC KEY`) format, EC keys must be P-256 keys in the PKCS#8 format.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the PEM is not a supported public key.
+    pub fn from_pem(pem: impl AsRef<[u8]>) -> Result<Self, Error> {
+        let pem = pem.as_ref();
+
+        if !matches!(pem_label(pem), Some("PUBLIC KEY" | "RSA PUBLIC KEY")) {
+            return Err(Error::InvalidKey(
+                "expected a PEM encoded public key".into(),
+            ));
+        }
+
+        let (algorithm, key) = if let Ok(key) = DecodingKey::from_rsa_pem(pem) {
+            (Algorithm::RS256, key)
+        } else if let Ok(key) = DecodingKey::from_ec_pem(pem) {
+            (Algorithm::ES256, key)
+        } else {
+            return Err(Error::InvalidKey(
+                "only RSA and EC public keys are supported".into(),
+            ));
+        };
+
+        Ok(Self { algorithm, key })
+    }
+
+    /// A public key or shared secret in the JWK format, e.g. from
+    /// the JWKS endpoint of a key server.
+    ///
+    /// `RSA` keys are used for `RS256`, `EC` keys with the P-256 curve for
+    /// `ES256` and `oct` secrets for `HS256` signatures. If the key
+    /// specifies an algorithm with `alg`, it must match the key type.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the JWK is invalid, the key type is
+    /// not supported or the algorithm does not match the key type.
+    pub fn from_jwk(jwk: &Value) -> Result<Self, Error> {
+        let jwk = Jwk::deserialize(jwk)
+            .map_err(|err| Error::InvalidKey(format!("invalid JWK: {err}")))?;
+
+        let algorithm = match &jwk.algorithm {
+            AlgorithmParameters::RSA(_) => Algorithm::RS256,
+            AlgorithmParameters::EllipticCurve(params) if params.curve == EllipticCurve::P256 => {
+                Algorithm::ES256
+            }
+            AlgorithmParameters::EllipticCurve(params) => {
+                return Err(Error::InvalidKey(format!(
+                    "unsupported curve {:?}, only P-256 keys are supported",
+                    params.curve
+                )))
+            }
+            AlgorithmParameters::OctetKey(_) => Algorithm::HS256,
+            AlgorithmParameters::OctetKeyPair(_) => {
+                return Err(Error::InvalidKey(
+                    "only RSA, EC and oct keys are supported".into(),
+                ))
+            }
+        };
+
+        if let Some(alg) = jwk.common.algorithm {
+            if alg != algorithm {
+                return Err(Error::InvalidKey(format!(
+                    "the key cannot be used with {alg:?}, only with {algorithm:?}"
+                )));
+            }
+        }
+
+        let key = match &jwk.algorithm {
+            // The secret is base64url encoded without padding,
+            // but `jsonwebtoken` expects standard base64.
+            AlgorithmParameters::OctetKey(params) => {
+                let mut secret = params.value.replace('-', "+").replace('_', "/");
+                while secret.len() % 4 != 0 {
+                    secret.push('=');
+                }
+                DecodingKey::from_base64_secret(&secret)?
+            }
+            _ => DecodingKey::from_jwk(&jwk)?,
+        };
+
+        Ok(Self { algorithm, key })
+    }
+}
+
+/// The label of the first PEM block, e.g. `PUBLIC KEY`.
+fn pem_label(pem: &[u8]) -> Option<&str> {
+    std::str::from_utf8(pem)
+        .ok()?
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("-----BEGIN "))?
+        .strip_suffix("-----")
+}
+
+/// The contents of the signatures file.
+#[derive(Debug, Serialize, Deserialize)]
+struct Signatures {
+    signatures: Vec<String>,
+}
+
+/// The JWT claims of a bundle signature.
+#[derive(Debug, Serialize, Deserialize)]
+struct Claims {
+    files: Vec<FileInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    keyid: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct FileInfo {
+    name: String,
+    hash: String,
+    algorithm: String,
+}
+
+impl Bundle {
+    /// Write the signed bundle in the `.tar.gz` format.
+    ///
+    /// The hashes of all files are signed with the given key
+    /// and included in the bundle as `.signatures.json`.
+    ///
+    /// # Errors
+    ///
+    /// Errors are returned on i/o or signing errors.
+    pub fn to_writer_signed(&self, writer: impl Write, key: &SigningKey) -> Result<(), Error> {
+        let mut files = self.files()?;
+        let signatures = sign_files(&files, key)?;
+        files.insert(
+            0,
+            (
+                SIGNATURES_FILE.into(),
+                serde_json::to_vec(&signatures)
+                    .map_err(std::io::Error::from)?
+                    .into(),
+            ),
+        );
+        write_archive(writer, &files, Compression::default())
+    }
+
+    /// Write the signed bundle to bytes in the `.tar.gz` format.
+    ///
+    /// # Errors
+    ///
+    /// See [`Self::to_writer_signed`].
+    pub fn to_bytes_signed(&self, key: &SigningKey) -> Result<Vec<u8>, Error> {
+        let mut bytes = Vec::new();
+        self.to_writer_signed(&mut bytes, key)?;
+        Ok(bytes)
+    }
+
+    /// Load the bundle from the given reader and verify its signature.
+    ///
+    /// Bundles are expected to be a `.tar.gz` format.
+    ///
+    /// Every file in the bundle must be listed in the signature with a matching hash,
+    /// and every file listed in the signature must be present in the bundle.
+    ///
+    /// # Errors
+    ///
+    /// Errors are returned if the bundle is invalid, the signature is
+    /// missing or invalid, or on i/o error.
+    pub fn from_reader_verified(
+        mut reader: impl Read,
+        key: &VerificationKey,
+    ) -> Result<Self, Error> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+
+        verify(&bytes, key)?;
+
+        Self::from_bytes(bytes)
+    }
+}
+
+fn sign_files(files: &[BundleFile], key: &SigningKey) -> Result<Signatures, Error> {
+    let files = files
+        .iter()
+        .map(|(name, content)| {
+            Ok(FileInfo {
+                name: name.clone(),
+                hash: hash_file(name, content)?,
+                algorithm: HASH_ALGORITHM.into(),
+            })
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    let mut header = Header::new(key.algorithm);
+    header.kid.clone_from(&key.key_id);
+
+    let token = jsonwebtoken::encode(
+        &header,
+        &Claims {
+            files,
+            keyid: key.key_id.clone(),
+        },
+        &key.key,
+    )?;
+
+    Ok(Signatures {
+        signatures: vec![token],
+    })
+}
+
+fn verify(bundle: &[u8], key: &VerificationKey) -> Result<(), Error> {
+    let mut ar = Archive::new(GzDecoder::new(bundle));
+
+    let mut signatures: Option<Signatures> = None;
+    let mut files: HashMap<String, Vec<u8>> = HashMap::new();
+
+    for entry in ar.entries()? {
+        let mut entry = entry?;
+
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+
+        let name = relative_path(&entry.path()?);
+
+        if name == SIGNATURES_FILE {
+            signatures = Some(serde_json::from_reader(entry).map_err(|source| {
+                Error::InvalidSignature(format!("invalid signatures file: {source}"))
+            })?);
+        } else {
+            let mut content = Vec::new();
+            entry.read_to_end(&mut content)?;
+            files.insert(name, content);
+        }
+    }
+
+    let signatures =
+        signatures.ok_or_else(|| Error::InvalidSignature("the bundle is not signed".into()))?;
+
+    let [token] = signatures.signatures.as_slice() else {
+        return Err(Error::InvalidSignature(
+            "exactly one signature is expected".into(),
+        ));
+    };
+
+    let mut validation = Validation::new(key.algorithm);
+    validation.required_spec_claims.clear();
+    validation.validate_exp = false;
+
+    let claims = jsonwebtoken::decode::<Claims>(token, &key.key, &validation)?.claims;
+
+    for file in &claims.files {
+        if !file.algorithm.eq_ignore_ascii_case(HASH_ALGORITHM) {
+            return Err(Error::InvalidSignature(format!(
+                "unsupported hash algorithm `{}` for `{}`",
+                file.algorithm, file.name
+            )));
+        }
+
+        let name = file.name.trim_start_matches('/');
+
+        let content = files.remove(name).ok_or_else(|| {
+            Error::InvalidSignature(format!("file `{name}` is missing from the bundle"))
+        })?;
+
+        if hash_file(name, &content)? != file.hash {
+            return Err(Error::InvalidSignature(format!(
+                "hash mismatch for `{name}`"
+            )));
+        }
+    }
+
+    if let Some(name) = files.keys().next() {
+        return Err(Error::InvalidSignature(format!(
+            "file `{name}` is not included in the signature"
+        )));
+    }
+
+    Ok(())
+}
+
+/// Hash a file the same way as OPA does.
+///
+/// Structured documents are parsed and hashed in a normalized
+/// form with sorted keys, all other files are hashed as-is.
+fn hash_file(name: &str, content: &[u8]) -> Result<String, Error> {
+    let mut hasher = Sha256::new();
+
+    if is_structured_doc(name) {
+        let value: Value =
+            serde_json::from_slice(content).map_err(|source| Error::InvalidData {
+                path: name.into(),
+                source,
+            })?;
+        hash_value(&value, &mut hasher);
+    } else {
+        hasher.update(content);
+    }
+
+    Ok(hasher.finalize().iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{b:02x}");
+        s
+    }))
+}
+
+fn is_structured_doc(name: &str) -> bool {
+    matches!(
+        Path::new(name).file_name().and_then(|n| n.to_str()),
+        Some("data.json" | ".manifest" | SIGNATURES_FILE)
+    )
+}
+
+// Numbers are written as they are formatted by `serde_json`,
+// which can differ from the original representation for floats.
+fn hash_value(value: &Value, hasher: &mut Sha256) {
+    match value {
+        Value::Object(map) => {
+            let mut keys: Vec<&String> = map.keys().collect();
+            keys.sort_unstable();
+
+            hasher.update(b"{");
+            for (i, key) in keys.into_iter().enumerate() {
+                if i > 0 {
+                    hasher.update(b",");
+                }
+                hash_str(key, hasher);
+                hasher.update(b":");
+                hash_value(&map[key], hasher);
+            }
+            hasher.update(b"}");
+        }
+        Value::Array(values) => {
+            hasher.update(b"[");
+            for (i, value) in values.iter().enumerate() {
+                if i > 0 {
+                    hasher.update(b",");
+                }
+                hash_value(value, hasher);
+            }
+            hasher.update(b"]");
+        }
+        Value::String(s) => hash_str(s, hasher),
+        Value::Null => hasher.update(b"null"),
+        Value::Bool(b) => hasher.update(if *b { &b"true"[..] } else { &b"false"[..] }),
+        Value::Number(n) => hasher.update(n.to_string()),
+    }
+}
+
+// Strings are escaped the same way as Go's `encoding/json`
+// does without HTML escaping.
+fn hash_str(s: &str, hasher: &mut Sha256) {
+    let mut out = String::with_capacity(s.len() + 2);
+
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\u{2028}' | '\u{2029}' => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+
+    hasher.update(out);
+}
@@ -0,0 +1,77 @@
+use super::{manifest::Manifest, Bundle};
+use serde::Serialize;
+use std::path::PathBuf;
+
+/// A compact description of the contents of a bundle,
+/// see [`Bundle::summary`].
+///
+/// It can be serialized e.g. for structured logs,
+/// the contents of the files are not included.
+#[derive(Debug, Clone, Serialize)]
+#[non_exhaustive]
+pub struct BundleSummary {
+    /// The manifest of the bundle, if any.
+    pub manifest: Option<Manifest>,
+    /// Whether the bundle contains data.
+    pub has_data: bool,
+    /// The `.rego` policy files sorted by their paths.
+    pub rego_policies: Vec<RegoPolicySummary>,
+    /// The WASM module policies in the order of the bundle.
+    pub wasm_policies: Vec<WasmPolicySummary>,
+    /// The paths of the JSON schemas, sorted.
+    pub schemas: Vec<PathBuf>,
+}
+
+/// A `.rego` policy file in a [`BundleSummary`].
+#[derive(Debug, Clone, Serialize)]
+#[non_exhaustive]
+pub struct RegoPolicySummary {
+    /// The path of the file within the bundle.
+    pub path: PathBuf,
+    /// The size of the source in bytes.
+    pub size: usize,
+}
+
+/// A WASM module policy in a [`BundleSummary`].
+#[derive(Debug, Clone, Serialize)]
+#[non_exhaustive]
+pub struct WasmPolicySummary {
+    /// The entrypoint of the policy.
+    pub entrypoint: String,
+    /// The size of the module in bytes.
+    pub size: usize,
+}
+
+impl Bundle {
+    /// Describe the contents of the bundle without the contents of its files.
+    #[must_use]
+    pub fn summary(&self) -> BundleSummary {
+        let mut rego_policies: Vec<RegoPolicySummary> = self
+            .rego_policies
+            .iter()
+            .map(|(path, source)| RegoPolicySummary {
+                path: path.clone(),
+                size: source.len(),
+            })
+            .collect();
+        rego_policies.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let mut schemas: Vec<PathBuf> = self.schemas.keys().cloned().collect();
+        schemas.sort();
+
+        BundleSummary {
+            manifest: self.manifest.clone(),
+            has_data: !matches!(self.data, super::BundleData::None),
+            rego_policies,
+            wasm_policies: self
+                .wasm_policies
+                .iter()
+                .map(|policy| WasmPolicySummary {
+                    entrypoint: policy.entrypoint.clone(),
+                    size: policy.bytes.len(),
+                })
+                .collect(),
+            schemas,
+        }
+    }
+}
@@ -0,0 +1,36 @@
+pub use flate2::Compression;
+
+/// Options for writing bundles with [`Bundle::to_writer_with`](super::Bundle::to_writer_with).
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct BundleWriteOptions {
+    /// The gzip compression level.
+    pub compression: Compression,
+    /// Write the files in a stable order, so that
+    /// the same bundle is always written to the same bytes.
+    pub deterministic: bool,
+}
+
+impl BundleWriteOptions {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the gzip compression level.
+    ///
+    /// E.g. [`Compression::fast`] for transient bundles
+    /// or [`Compression::best`] for published ones.
+    #[must_use]
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Write the files in a stable order.
+    #[must_use]
+    pub fn deterministic(mut self, deterministic: bool) -> Self {
+        self.deterministic = deterministic;
+        self
+    }
+}
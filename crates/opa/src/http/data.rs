@@ -1,8 +1,13 @@
 use crate::PolicyDecision;
 
-use super::{Decision, Error, Opa};
-use serde::{de::DeserializeOwned, Serialize};
-use std::borrow::Cow;
+use super::{send_delete, Decision, DecisionOptions, DeltaOp, Error, Opa, PatchOp, ResponseExt};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::{
+    fs::{self, File},
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+};
 
 /// Routes for the [OPA Data API](https://www.openpolicyagent.org/docs/latest/rest-api/#data-api).
 impl Opa {
@@ -12,27 +17,200 @@ impl Opa {
         path: impl AsRef<str>,
         document: &impl Serialize,
     ) -> Result<(), Error> {
+        self.put_document(path.as_ref(), serde_json::to_vec(document)?, None)
+            .await
+    }
+
+    /// Same as [`Opa::set_document`], but the document is read from a JSON file.
+    ///
+    /// The file is checked to be valid JSON before it is uploaded,
+    /// with the `http-stream` feature its contents are streamed
+    /// instead of being read into memory.
+    pub async fn set_document_from_file(
+        &self,
+        path: impl AsRef<str>,
+        file: impl AsRef<Path>,
+    ) -> Result<(), Error> {
+        let file = file.as_ref();
+        validate_json_file(file)?;
+
+        #[cfg(feature = "http-stream")]
+        {
+            let f = tokio::fs::File::open(file).await?;
+            let len = f.metadata().await?.len();
+            let body = reqwest::Body::wrap_stream(tokio_util::io::ReaderStream::new(f));
+            self.put_document(path.as_ref(), body, Some(len)).await
+        }
+
+        #[cfg(not(feature = "http-stream"))]
+        {
+            self.put_document(path.as_ref(), fs::read(file)?, None)
+                .await
+        }
+    }
+
+    /// Upload all `.json` files in a directory and its subdirectories
+    /// with [`Opa::set_document_from_file`].
+    ///
+    /// The data path of each document is the path of the file
+    /// relative to `dir` without the extension,
+    /// e.g. `dir/users/admins.json` is uploaded to `users/admins`.
+    ///
+    /// All files are validated before any of them are uploaded.
+    pub async fn set_documents_from_dir(&self, dir: impl AsRef<Path>) -> Result<(), Error> {
+        let dir = dir.as_ref();
+
+        let mut files = Vec::new();
+        json_files(dir, &mut files)?;
+        files.sort();
+
+        for file in &files {
+            validate_json_file(file)?;
+        }
+
+        for file in files {
+            let path = file
+                .strip_prefix(dir)
+                .unwrap_or(&file)
+                .with_extension("")
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+
+            self.set_document_from_file(path, &file).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Same as [`Opa::set_document`], but an object document is uploaded
+    /// with multiple requests that are at most `max_chunk_bytes` each.
+    ///
+    /// The object at the path is replaced with an empty one first,
+    /// then each of its values is uploaded under `path/key`.
+    /// Values that exceed the size are split further if they are objects.
+    ///
+    /// # Errors
+    ///
+    /// The document must be an object, and every value that is
+    /// not an object must fit in a single chunk.
+    /// No requests are made if the document cannot be split.
+    pub async fn set_document_chunked(
+        &self,
+        path: impl AsRef<str>,
+        document: &impl Serialize,
+        max_chunk_bytes: usize,
+    ) -> Result<(), Error> {
+        let document = serde_json::to_value(document)?;
+        if !document.is_object() {
+            return Err(Error::NotAnObject);
+        }
+
+        let mut chunks = Vec::new();
+        chunk_document(&mut Vec::new(), document, max_chunk_bytes, &mut chunks)?;
+
+        let base = self.data_url.join(path.as_ref().trim_matches('/'))?;
+
+        for (keys, body) in chunks {
+            let mut url = base.clone();
+            url.path_segments_mut()
+                .map_err(|()| url::ParseError::RelativeUrlWithCannotBeABaseBase)?
+                .pop_if_empty()
+                .extend(&keys);
+
+            self.client
+                .put(url)
+                .header("Content-Type", "application/json")
+                .body(body)
+                .send()
+                .await?
+                .opa_error_for_status()
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn put_document(
+        &self,
+        path: &str,
+        body: impl Into<reqwest::Body>,
+        content_length: Option<u64>,
+    ) -> Result<(), Error> {
+        let mut req = self
+            .client
+            .put(self.data_url.join(path)?)
+            .header("Content-Type", "application/json");
+
+        if let Some(len) = content_length {
+            req = req.header("Content-Length", len);
+        }
+
+        req.body(body).send().await?.opa_error_for_status().await?;
+
+        Ok(())
+    }
+
+    /// Apply the JSON Patch operations to the document at the path,
+    /// an empty path patches the root document.
+    ///
+    /// All operations are validated before the request is made,
+    /// every invalid operation is listed in the returned [`Error::InvalidPatch`].
+    ///
+    /// Endpoint for: <https://www.openpolicyagent.org/docs/latest/rest-api/#patch-a-document>
+    pub async fn patch_document(
+        &self,
+        path: impl AsRef<str>,
+        patches: &[PatchOp],
+    ) -> Result<(), Error> {
+        validate_patches(patches)?;
+
+        let path = path.as_ref().trim_matches('/');
+        let url = if path.is_empty() {
+            self.data_url.as_str().trim_end_matches('/').parse()?
+        } else {
+            self.data_url.join(path)?
+        };
+
         self.client
-            .put(self.data_url.join(path.as_ref())?)
-            .header("Content-Type", "application/json")
-            .json(document)
+            .patch(url)
+            .header("Content-Type", "application/json-patch+json")
+            .body(serde_json::to_vec(patches)?)
             .send()
             .await?
-            .error_for_status()?;
+            .opa_error_for_status()
+            .await?;
 
         Ok(())
     }
 
+    /// Apply the data operations of a delta bundle to the root document.
+    ///
+    /// This allows pushing incremental data updates to a server
+    /// instead of uploading the full data every time.
+    pub async fn apply_delta(&self, patches: &[DeltaOp]) -> Result<(), Error> {
+        self.patch_document("", patches).await
+    }
+
     /// Endpoint for: <https://www.openpolicyagent.org/docs/latest/rest-api/#delete-a-document>
     pub async fn delete_document(&self, path: impl AsRef<str>) -> Result<(), Error> {
-        self.client
-            .delete(self.data_url.join(path.as_ref())?)
-            .header("Content-Type", "application/json")
-            .send()
-            .await?
-            .error_for_status()?;
+        self.delete_document_impl(path.as_ref(), false).await
+    }
 
-        Ok(())
+    /// Same as [`Opa::delete_document`], but a document that does not exist
+    /// is not an error.
+    pub async fn delete_document_if_exists(&self, path: impl AsRef<str>) -> Result<(), Error> {
+        self.delete_document_impl(path.as_ref(), true).await
+    }
+
+    async fn delete_document_impl(&self, path: &str, ignore_not_found: bool) -> Result<(), Error> {
+        let request = self
+            .client
+            .delete(self.data_url.join(path)?)
+            .header("Content-Type", "application/json");
+
+        send_delete(request, ignore_not_found).await
     }
 
     /// Same as [`Opa::get_decision`] with an alternative API.
@@ -43,10 +221,37 @@ impl Opa {
         self.get_decision(P::POLICY_PATH, input).await
     }
 
+    /// Same as [`Opa::try_get_decision`] with an alternative API.
+    pub async fn try_decide<P: PolicyDecision>(
+        &self,
+        input: &P::Input,
+    ) -> Result<Option<P::Output>, Error> {
+        self.try_get_decision(P::POLICY_PATH, input).await
+    }
+
+    /// Same as [`Opa::decide`], also returning the raw JSON result.
+    ///
+    /// The response is only deserialized once into JSON,
+    /// the typed output is deserialized from it. This is useful
+    /// when the decision has to be logged or forwarded as-is.
+    pub async fn get_decision_raw_and_typed<P: PolicyDecision>(
+        &self,
+        input: &P::Input,
+    ) -> Result<Decision<(P::Output, Value)>, Error> {
+        let decision: Decision<Value> = self.get_decision(P::POLICY_PATH, input).await?;
+        let output = P::Output::deserialize(&decision.result)?;
+
+        Ok(Decision {
+            result: (output, decision.result),
+            decision_id: decision.decision_id,
+            metrics: decision.metrics,
+        })
+    }
+
     /// Get a decision document based on a policy.
     ///
     /// The given policy path is either a package name such as `example.policy.allow` or a
-    /// path such as `example/policy/allow`.
+    /// path such as `example/policy/allow`, leading slashes are ignored.
     ///
     /// Endpoint for: <https://www.openpolicyagent.org/docs/latest/rest-api/#get-a-document-with-input>
     pub async fn get_decision<I, R>(&self, policy: &str, input: &I) -> Result<Decision<R>, Error>
@@ -54,28 +259,289 @@ impl Opa {
         I: Serialize,
         R: DeserializeOwned,
     {
-        #[derive(Serialize)]
-        struct InputRequest<'a, T> {
-            input: &'a T,
-        }
+        self.get_decision_with_options(policy, input, &DecisionOptions::default())
+            .await
+    }
 
-        let policy_path = if policy.contains('.') {
-            Cow::Owned(policy.replace('.', "/"))
-        } else {
-            Cow::Borrowed(policy)
-        };
+    /// Same as [`Opa::get_decision`] with additional options,
+    /// e.g. to request the [metrics](Decision::metrics) of the evaluation.
+    ///
+    /// Endpoint for: <https://www.openpolicyagent.org/docs/latest/rest-api/#get-a-document-with-input>
+    pub async fn get_decision_with_options<I, R>(
+        &self,
+        policy: &str,
+        input: &I,
+        options: &DecisionOptions,
+    ) -> Result<Decision<R>, Error>
+    where
+        I: Serialize,
+        R: DeserializeOwned,
+    {
+        let policy_path = crate::normalize_policy_path(policy);
 
         let res: Decision<R> = self
             .client
             .post(self.data_url.join(&policy_path)?)
+            .query(&options.query())
             .header("Content-Type", "application/json")
-            .json(&InputRequest { input })
+            .body(self.input_request_body(input)?)
             .send()
             .await?
-            .error_for_status()?
+            .opa_error_for_status()
+            .await?
             .json()
             .await?;
 
         Ok(res)
     }
+
+    /// Get the documents of all rules in a package at once, e.g. for the package
+    /// `example` the result is an object with a field for every rule such as
+    /// `allow` and `roles`, which can be deserialized to a struct.
+    ///
+    /// The package is either `.` or `/` separated, see [`Opa::get_decision`].
+    ///
+    /// Endpoint for: <https://www.openpolicyagent.org/docs/latest/rest-api/#get-a-document-with-input>
+    pub async fn get_package_decision<I, R>(
+        &self,
+        package: &str,
+        input: &I,
+    ) -> Result<Decision<R>, Error>
+    where
+        I: Serialize,
+        R: DeserializeOwned,
+    {
+        // The data API returns the whole package document for a package path.
+        self.get_decision(package.trim_end_matches('/'), input)
+            .await
+    }
+
+    /// Get the decisions of multiple policies concurrently with [`Opa::get_decision`].
+    ///
+    /// The results are returned in the order of the queries, each one of
+    /// them is independent of the others.
+    pub async fn get_decisions<I, R>(
+        &self,
+        queries: &[(String, I)],
+    ) -> Vec<Result<Decision<R>, Error>>
+    where
+        I: Serialize,
+        R: DeserializeOwned,
+    {
+        futures_util::future::join_all(
+            queries
+                .iter()
+                .map(|(policy, input)| self.get_decision(policy, input)),
+        )
+        .await
+    }
+
+    /// Get a decision document based on a policy, returning `None`
+    /// if the decision is undefined.
+    ///
+    /// OPA omits the `result` field from the response entirely for undefined
+    /// decisions, this is checked before the result is deserialized so that
+    /// undefined decisions can be told apart from deserialization errors.
+    ///
+    /// Endpoint for: <https://www.openpolicyagent.org/docs/latest/rest-api/#get-a-document-with-input>
+    pub async fn try_get_decision<I, R>(&self, policy: &str, input: &I) -> Result<Option<R>, Error>
+    where
+        I: Serialize,
+        R: DeserializeOwned,
+    {
+        let policy_path = crate::normalize_policy_path(policy);
+
+        let mut res: Map<String, Value> = self
+            .client
+            .post(self.data_url.join(&policy_path)?)
+            .header("Content-Type", "application/json")
+            .body(self.input_request_body(input)?)
+            .send()
+            .await?
+            .opa_error_for_status()
+            .await?
+            .json()
+            .await?;
+
+        match res.remove("result") {
+            Some(result) => Ok(Some(serde_json::from_value(result)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Same as [`Opa::try_get_decision`], but an undefined decision
+    /// is returned as [`Error::Undefined`].
+    ///
+    /// This is meant for fail-closed authorization, where an undefined
+    /// decision must never be mistaken for an allowed one.
+    ///
+    /// Endpoint for: <https://www.openpolicyagent.org/docs/latest/rest-api/#get-a-document-with-input>
+    pub async fn get_decision_required<I, R>(&self, policy: &str, input: &I) -> Result<R, Error>
+    where
+        I: Serialize,
+        R: DeserializeOwned,
+    {
+        self.try_get_decision(policy, input)
+            .await?
+            .ok_or_else(|| Error::Undefined(policy.to_string()))
+    }
+
+    /// Same as [`Opa::get_decision_stream`] with an alternative API.
+    #[cfg(feature = "http-stream")]
+    pub async fn decide_stream<P>(&self, input: &P::Input) -> Result<Decision<P::Output>, Error>
+    where
+        P: PolicyDecision,
+        P::Output: Send + 'static,
+    {
+        self.get_decision_stream(P::POLICY_PATH, input).await
+    }
+
+    /// Same as [`Opa::get_decision`], but the result is deserialized
+    /// incrementally from the response body.
+    ///
+    /// The response is not buffered in memory, the result document is
+    /// deserialized directly from the response stream on a blocking thread,
+    /// reducing peak memory usage for large results.
+    ///
+    /// This must be called within a Tokio runtime.
+    ///
+    /// Endpoint for: <https://www.openpolicyagent.org/docs/latest/rest-api/#get-a-document-with-input>
+    #[cfg(feature = "http-stream")]
+    pub async fn get_decision_stream<I, R>(
+        &self,
+        policy: &str,
+        input: &I,
+    ) -> Result<Decision<R>, Error>
+    where
+        I: Serialize,
+        R: DeserializeOwned + Send + 'static,
+    {
+        use futures_util::TryStreamExt;
+        use tokio_util::io::{StreamReader, SyncIoBridge};
+
+        let policy_path = crate::normalize_policy_path(policy);
+
+        let stream = self
+            .client
+            .post(self.data_url.join(&policy_path)?)
+            .header("Content-Type", "application/json")
+            .body(self.input_request_body(input)?)
+            .send()
+            .await?
+            .opa_error_for_status()
+            .await?
+            .bytes_stream()
+            .map_err(std::io::Error::other);
+
+        let reader = SyncIoBridge::new(StreamReader::new(stream));
+
+        match tokio::task::spawn_blocking(move || serde_json::from_reader(reader)).await {
+            Ok(res) => Ok(res?),
+            Err(err) => std::panic::resume_unwind(err.into_panic()),
+        }
+    }
+}
+
+impl Opa {
+    /// The body of a request with the input, modified
+    /// by the input transform if there is one.
+    fn input_request_body<I: Serialize>(&self, input: &I) -> Result<Vec<u8>, Error> {
+        Ok(match &self.input_transform {
+            Some(transform) => serde_json::to_vec(&InputRequest {
+                input: &transform.apply(input)?,
+            })?,
+            None => serde_json::to_vec(&InputRequest { input })?,
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct InputRequest<'a, T> {
+    input: &'a T,
+}
+
+fn validate_json_file(path: &Path) -> Result<(), Error> {
+    let reader = BufReader::new(File::open(path)?);
+    serde_json::from_reader::<_, serde::de::IgnoredAny>(reader)
+        .map(|_| ())
+        .map_err(|source| Error::InvalidDocument {
+            path: path.to_path_buf(),
+            source,
+        })
+}
+
+fn json_files(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+
+        if path.is_dir() {
+            json_files(&path, files)?;
+        } else if path.extension().is_some_and(|ext| ext == "json") {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+/// Split a document into serialized chunks with the keys
+/// of their paths relative to the document.
+fn chunk_document(
+    keys: &mut Vec<String>,
+    document: Value,
+    max_chunk_bytes: usize,
+    chunks: &mut Vec<(Vec<String>, Vec<u8>)>,
+) -> Result<(), Error> {
+    let bytes = serde_json::to_vec(&document)?;
+
+    if bytes.len() <= max_chunk_bytes {
+        chunks.push((keys.clone(), bytes));
+        return Ok(());
+    }
+
+    match document {
+        Value::Object(object) => {
+            chunks.push((keys.clone(), b"{}".to_vec()));
+
+            for (key, value) in object {
+                keys.push(key);
+                chunk_document(keys, value, max_chunk_bytes, chunks)?;
+                keys.pop();
+            }
+
+            Ok(())
+        }
+        _ => Err(Error::ChunkTooLarge {
+            path: keys.join("/"),
+            size: bytes.len(),
+        }),
+    }
+}
+
+fn validate_patches(patches: &[PatchOp]) -> Result<(), Error> {
+    let errors: Vec<String> = patches
+        .iter()
+        .enumerate()
+        .filter_map(|(i, patch)| {
+            let path = patch.path();
+
+            if !path.starts_with('/') {
+                Some(format!(
+                    "operation {i}: the path `{path}` must start with `/`"
+                ))
+            } else if path == "/" && matches!(patch, PatchOp::Remove { .. }) {
+                Some(format!(
+                    "operation {i}: the root document cannot be removed"
+                ))
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::InvalidPatch(errors))
+    }
 }
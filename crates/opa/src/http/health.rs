@@ -1,4 +1,4 @@
-use super::{Error, Opa};
+use super::{Error, Opa, ResponseExt};
 
 impl Opa {
     /// Basic health-checking.
@@ -7,7 +7,8 @@ impl Opa {
             .get(self.health_url.clone())
             .send()
             .await?
-            .error_for_status()?;
+            .opa_error_for_status()
+            .await?;
         Ok(())
     }
 }
@@ -2,6 +2,10 @@
 
 use reqwest::Url;
 use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, OnceLock},
+};
 use thiserror::Error;
 use uuid::Uuid;
 
@@ -9,16 +13,77 @@ mod data;
 mod health;
 mod policy;
 mod query;
+mod status;
+#[cfg(feature = "test-util")]
+pub mod testing;
+mod version;
 
-#[derive(Debug, Deserialize)]
+pub use status::{BundleStatus, Status};
+pub use version::OpaVersion;
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct Decision<T> {
     /// The result document of the decision.
     pub result: T,
     /// Unique identifier of the decision.
     pub decision_id: Option<Uuid>,
+    /// Performance metrics of the evaluation, only returned if requested
+    /// with [`DecisionOptions`].
+    ///
+    /// The timers are in nanoseconds, the available metrics depend on the
+    /// OPA version.
+    #[serde(default)]
+    pub metrics: Option<BTreeMap<String, serde_json::Value>>,
+}
+
+/// Additional options for [`Opa::get_decision_with_options`].
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct DecisionOptions {
+    /// Return the performance metrics of the evaluation.
+    pub metrics: bool,
+    /// Return detailed timings of the evaluation phases,
+    /// this implies `metrics`.
+    ///
+    /// Instrumentation adds overhead to the evaluation,
+    /// it should only be used for diagnosing slow policies.
+    pub instrument: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl DecisionOptions {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[must_use]
+    pub fn metrics(mut self, metrics: bool) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    #[must_use]
+    pub fn instrument(mut self, instrument: bool) -> Self {
+        self.instrument = instrument;
+        self
+    }
+
+    fn query(&self) -> Vec<(&'static str, &'static str)> {
+        let mut query = Vec::new();
+
+        if self.metrics {
+            query.push(("metrics", "true"));
+        }
+
+        if self.instrument {
+            query.push(("instrument", "true"));
+        }
+
+        query
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Policy {
     /// The identifier of the policy.
     pub id: String,
@@ -36,14 +101,101 @@ impl Policy {
     }
 }
 
+/// The response of [`Opa::set_policy_with_response`].
+#[derive(Debug, Clone, Default, Deserialize)]
+#[non_exhaustive]
+pub struct SetPolicyResponse {
+    /// The identifier of the policy that was set.
+    #[serde(skip)]
+    pub id: String,
+    /// The AST of the parsed policy, if returned by the server.
+    #[serde(default)]
+    pub ast: Option<serde_json::Value>,
+    /// Performance metrics of the request, if returned by the server.
+    #[serde(default)]
+    pub metrics: Option<BTreeMap<String, serde_json::Value>>,
+    /// Any other fields of the response, e.g. warnings.
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// A JSON Patch operation for [`Opa::patch_document`].
+///
+/// The operations of delta bundles (`upsert`, `remove` and `replace`)
+/// are also deserialized into this type, `upsert` being [`PatchOp::Add`].
+///
+/// Paths are JSON pointers relative to the patched document, e.g. `/users/alice`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum PatchOp {
+    /// Create or overwrite the value at the path.
+    #[serde(alias = "upsert")]
+    Add {
+        path: String,
+        value: serde_json::Value,
+    },
+    /// Remove the value at the path.
+    Remove { path: String },
+    /// Overwrite an existing value at the path.
+    Replace {
+        path: String,
+        value: serde_json::Value,
+    },
+}
+
+/// The operations of a delta bundle for [`Opa::apply_delta`].
+pub type DeltaOp = PatchOp;
+
+impl PatchOp {
+    /// The JSON pointer path of the operation.
+    #[must_use]
+    pub fn path(&self) -> &str {
+        match self {
+            PatchOp::Add { path, .. }
+            | PatchOp::Remove { path }
+            | PatchOp::Replace { path, .. } => path,
+        }
+    }
+}
+
+/// A client for the OPA REST API.
+///
+/// The client is `Send + Sync`, clones share the connection pool
+/// of the underlying [`reqwest::Client`], so a clone can be created for
+/// every concurrent request handler instead of wrapping it in an `Arc`.
+///
+/// Any shared state added to the client must be `Send + Sync` as well.
 #[derive(Debug, Clone)]
 pub struct Opa {
     policy_url: Url,
-    #[allow(dead_code)]
     query_url: Url,
     data_url: Url,
     health_url: Url,
+    status_url: Url,
+    config_url: Url,
     client: reqwest::Client,
+    /// The version of the server, shared by clones.
+    version_cache: Option<Arc<OnceLock<OpaVersion>>>,
+    input_transform: Option<InputTransform>,
+}
+
+/// A function that modifies the inputs of decisions,
+/// see [`Opa::with_input_transform`].
+#[derive(Clone)]
+struct InputTransform(Arc<dyn Fn(&mut serde_json::Value) + Send + Sync>);
+
+impl core::fmt::Debug for InputTransform {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("InputTransform").finish_non_exhaustive()
+    }
+}
+
+impl InputTransform {
+    fn apply(&self, input: &impl Serialize) -> Result<serde_json::Value, serde_json::Error> {
+        let mut input = serde_json::to_value(input)?;
+        (self.0)(&mut input);
+        Ok(input)
+    }
 }
 
 impl Opa {
@@ -58,13 +210,19 @@ impl Opa {
         let query_url = base_url.clone();
         let data_url = base_url.join("/v1/data/")?;
         let health_url = base_url.join("/health")?;
+        let status_url = base_url.join("/v1/status")?;
+        let config_url = base_url.join("/v1/config")?;
 
         Ok(Self {
             policy_url,
             query_url,
             data_url,
             health_url,
+            status_url,
+            config_url,
             client: reqwest::Client::default(),
+            version_cache: None,
+            input_transform: None,
         })
     }
 
@@ -73,6 +231,33 @@ impl Opa {
         self.client = client;
         self
     }
+
+    /// Modify the input of every decision before it is sent,
+    /// e.g. to add a tenant or trace id to all inputs.
+    ///
+    /// The transform applies to [`Opa::get_decision`], [`Opa::decide`],
+    /// [`Opa::get_default_decision`] and their variants. The input has to
+    /// be converted to a [`serde_json::Value`] for the transform, which adds
+    /// the cost of an intermediate copy of the input to every decision.
+    #[must_use]
+    pub fn with_input_transform(
+        mut self,
+        transform: Arc<dyn Fn(&mut serde_json::Value) + Send + Sync>,
+    ) -> Self {
+        self.input_transform = Some(InputTransform(transform));
+        self
+    }
+
+    /// Only request the version of the server once in [`Opa::version`],
+    /// the cached version is shared with clones created afterwards.
+    ///
+    /// Disabled by default, as the server might be upgraded
+    /// while the client is in use.
+    #[must_use]
+    pub fn cache_version(mut self, enabled: bool) -> Self {
+        self.version_cache = enabled.then(Arc::default);
+        self
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -86,4 +271,122 @@ pub enum Error {
     Url(#[from] url::ParseError),
     #[error("{0}")]
     Http(#[from] reqwest::Error),
+    #[error("invalid response: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid JSON document {path:?}: {source}")]
+    InvalidDocument {
+        path: std::path::PathBuf,
+        source: serde_json::Error,
+    },
+    #[error("{0}")]
+    Opa(#[from] OpaApiError),
+    #[error("invalid patch: {}", .0.join(", "))]
+    InvalidPatch(Vec<String>),
+    #[error("the decision at `{0}` is undefined")]
+    Undefined(String),
+    #[error(
+        "timed out waiting for revision `{expected}` of bundle `{bundle}`, the active revision is {}",
+        .active.as_deref().map_or_else(|| "unknown".to_string(), |r| format!("`{r}`"))
+    )]
+    RevisionTimeout {
+        bundle: String,
+        expected: String,
+        active: Option<String>,
+    },
+    #[error("the server did not report its version")]
+    MissingVersion,
+    #[error("the document must be an object to be uploaded in chunks")]
+    NotAnObject,
+    #[error("the value at `{path}` is {size} bytes and cannot be split into smaller chunks")]
+    ChunkTooLarge { path: String, size: usize },
+    #[cfg(feature = "build")]
+    #[error("invalid policy: {0}")]
+    Check(String),
+}
+
+/// An error response returned by the OPA REST API.
+///
+/// See: <https://www.openpolicyagent.org/docs/latest/rest-api/#errors>
+#[derive(Debug, Clone, Deserialize, Error)]
+#[error("OPA error ({status}, {code}): {message}{}", format_details(.errors))]
+#[non_exhaustive]
+pub struct OpaApiError {
+    /// The HTTP status code of the response.
+    #[serde(skip)]
+    pub status: u16,
+    /// The error code, e.g. `invalid_parameter`.
+    pub code: String,
+    /// The error message.
+    pub message: String,
+    /// Additional errors, e.g. compilation errors of a policy.
+    #[serde(default)]
+    pub errors: Vec<OpaApiErrorDetail>,
+}
+
+/// A single error within an [`OpaApiError`].
+#[derive(Debug, Clone, Deserialize)]
+#[non_exhaustive]
+pub struct OpaApiErrorDetail {
+    pub code: String,
+    pub message: String,
+    pub location: Option<OpaApiErrorLocation>,
+}
+
+/// The location of an [`OpaApiErrorDetail`] in a policy.
+#[derive(Debug, Clone, Deserialize)]
+#[non_exhaustive]
+pub struct OpaApiErrorLocation {
+    pub file: String,
+    pub row: u32,
+    pub col: u32,
+}
+
+fn format_details(errors: &[OpaApiErrorDetail]) -> String {
+    errors
+        .iter()
+        .map(|err| match &err.location {
+            Some(loc) => format!("\n{}:{}:{}: {}", loc.file, loc.row, loc.col, err.message),
+            None => format!("\n{}", err.message),
+        })
+        .collect()
+}
+
+/// Send a delete request, a missing resource is
+/// not an error if `ignore_not_found` is set.
+async fn send_delete(
+    request: reqwest::RequestBuilder,
+    ignore_not_found: bool,
+) -> Result<(), Error> {
+    let res = request.send().await?;
+
+    if ignore_not_found && res.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(());
+    }
+
+    res.opa_error_for_status().await?;
+
+    Ok(())
+}
+
+trait ResponseExt: Sized {
+    /// Same as [`reqwest::Response::error_for_status`], but unsuccessful
+    /// responses are turned into [`Error::Opa`] if OPA returned its error format.
+    async fn opa_error_for_status(self) -> Result<Self, Error>;
+}
+
+impl ResponseExt for reqwest::Response {
+    async fn opa_error_for_status(self) -> Result<Self, Error> {
+        let Err(status_err) = self.error_for_status_ref() else {
+            return Ok(self);
+        };
+
+        let status = self.status().as_u16();
+
+        match self.json::<OpaApiError>().await {
+            Ok(err) => Err(OpaApiError { status, ..err }.into()),
+            Err(_) => Err(status_err.into()),
+        }
+    }
 }
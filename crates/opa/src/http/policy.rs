@@ -1,36 +1,156 @@
-use super::{Error, Opa, OpaResponse, Policy};
+use super::{send_delete, Error, Opa, OpaResponse, Policy, ResponseExt, SetPolicyResponse};
+use serde_json::Value;
 
 /// Routes for the [OPA Policy API](https://www.openpolicyagent.org/docs/latest/rest-api/#policy-api).
 impl Opa {
     pub async fn set_policy(&self, policy: Policy) -> Result<(), Error> {
-        self.client
-            .put(self.policy_url.join(&policy.id)?)
-            .header("Content-Type", "text/plain")
-            .body(policy.raw)
-            .send()
+        self.put_bytes(self.policy_url.join(&policy.id)?, "text/plain", policy.raw)
+            .await?;
+        Ok(())
+    }
+
+    /// Same as [`Opa::set_policy`], also returning the response of the server,
+    /// e.g. to confirm what the server understood.
+    ///
+    /// An empty response body is returned as a response without any details.
+    pub async fn set_policy_with_response(
+        &self,
+        policy: Policy,
+    ) -> Result<SetPolicyResponse, Error> {
+        let body = self
+            .put_bytes(self.policy_url.join(&policy.id)?, "text/plain", policy.raw)
             .await?
-            .error_for_status()?;
+            .bytes()
+            .await?;
+
+        let mut response: SetPolicyResponse = if body.iter().all(u8::is_ascii_whitespace) {
+            SetPolicyResponse::default()
+        } else {
+            serde_json::from_slice(&body)?
+        };
+        response.id = policy.id;
+
+        Ok(response)
+    }
 
+    /// Same as [`Opa::set_policy`], but the policy is sent as-is
+    /// with the given content type, e.g. for proxies in front of OPA
+    /// that expect a specific content type.
+    pub async fn set_policy_raw(
+        &self,
+        id: &str,
+        content_type: &str,
+        body: impl Into<Vec<u8>>,
+    ) -> Result<(), Error> {
+        self.put_bytes(self.policy_url.join(id)?, content_type, body.into())
+            .await?;
         Ok(())
     }
 
-    pub async fn delete_policy(&self, policy_id: &str) -> Result<(), Error> {
+    /// Upload a bundle in the `.tar.gz` format to the given path of the server
+    /// with the `application/gzip` content type.
+    ///
+    /// OPA itself does not accept bundles over its REST API, this is meant
+    /// for bundle servers that OPA downloads bundles from, e.g. the
+    /// path of a bundle built with [`Bundle::to_bytes`](crate::bundle::Bundle::to_bytes).
+    /// Use [`Opa::reload`] to upload the policies and data to OPA directly.
+    ///
+    /// Error responses in the OPA format are returned as [`Error::Opa`].
+    pub async fn upload_bundle_bytes(&self, path: &str, bytes: &[u8]) -> Result<(), Error> {
+        self.put_bytes(
+            self.query_url.join(path)?,
+            "application/gzip",
+            bytes.to_vec(),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn put_bytes(
+        &self,
+        url: url::Url,
+        content_type: &str,
+        body: impl Into<reqwest::Body>,
+    ) -> Result<reqwest::Response, Error> {
         self.client
-            .delete(self.policy_url.join(policy_id)?)
+            .put(url)
+            .header("Content-Type", content_type)
+            .body(body)
             .send()
             .await?
-            .error_for_status()?;
+            .opa_error_for_status()
+            .await
+    }
+
+    /// Same as [`Opa::set_policy`], but the policy is checked with `opa check` first
+    /// if the `opa` binary is found in any of the system paths.
+    ///
+    /// Syntax and compile errors are returned as [`Error::Check`] without
+    /// making a request. If `opa` is not available, the policy is only
+    /// validated by the server.
+    ///
+    /// The check runs `opa` on a blocking thread of the Tokio runtime,
+    /// so this must be called within a Tokio runtime.
+    #[cfg(feature = "build")]
+    pub async fn set_policy_checked(&self, policy: Policy) -> Result<(), Error> {
+        let raw = policy.raw.clone();
+
+        match tokio::task::spawn_blocking(move || check_policy(&raw)).await {
+            Ok(res) => res?,
+            Err(err) => std::panic::resume_unwind(err.into_panic()),
+        }
+
+        self.set_policy(policy).await
+    }
+
+    /// Replace the given data documents and policies.
+    ///
+    /// All data documents are set before any of the policies, so that
+    /// the new policies are never evaluated against old data. Policies
+    /// that are evaluated in the meantime may still see the new data,
+    /// the requests are not atomic and the first error stops the reload.
+    ///
+    /// Updating policies and data atomically requires uploading them as a
+    /// single bundle that the server loads with its bundle API instead.
+    pub async fn reload(&self, policies: &[Policy], data: &[(String, Value)]) -> Result<(), Error> {
+        for (path, document) in data {
+            self.set_document(path, document).await?;
+        }
+
+        for policy in policies {
+            self.set_policy(policy.clone()).await?;
+        }
 
         Ok(())
     }
 
+    pub async fn delete_policy(&self, policy_id: &str) -> Result<(), Error> {
+        self.delete_policy_impl(policy_id, false).await
+    }
+
+    /// Same as [`Opa::delete_policy`], but a policy that does not exist
+    /// is not an error.
+    pub async fn delete_policy_if_exists(&self, policy_id: &str) -> Result<(), Error> {
+        self.delete_policy_impl(policy_id, true).await
+    }
+
+    async fn delete_policy_impl(
+        &self,
+        policy_id: &str,
+        ignore_not_found: bool,
+    ) -> Result<(), Error> {
+        let request = self.client.delete(self.policy_url.join(policy_id)?);
+        send_delete(request, ignore_not_found).await
+    }
+
     pub async fn get_policy(&self, policy_id: &str) -> Result<Policy, Error> {
         let res: OpaResponse<Policy> = self
             .client
             .get(self.policy_url.join(policy_id)?)
             .send()
             .await?
-            .error_for_status()?
+            .opa_error_for_status()
+            .await?
             .json()
             .await?;
 
@@ -43,10 +163,37 @@ impl Opa {
             .get(self.policy_url.clone())
             .send()
             .await?
-            .error_for_status()?
+            .opa_error_for_status()
+            .await?
             .json()
             .await?;
 
         Ok(res.result)
     }
 }
+
+/// Check the policy with `opa check` if `opa` is available.
+#[cfg(feature = "build")]
+fn check_policy(raw: &str) -> Result<(), Error> {
+    let Ok(opa_executable) = which::which("opa") else {
+        return Ok(());
+    };
+
+    let dir = tempfile::tempdir()?;
+    let policy_path = dir.path().join("policy.rego");
+    std::fs::write(&policy_path, raw)?;
+
+    let out = std::process::Command::new(opa_executable)
+        .arg("check")
+        .arg(&policy_path)
+        .output()?;
+
+    if !out.status.success() {
+        return Err(Error::Check(
+            String::from_utf8_lossy(&out.stdout).to_string()
+                + String::from_utf8_lossy(&out.stderr).as_ref(),
+        ));
+    }
+
+    Ok(())
+}
@@ -1,4 +1,34 @@
-use super::Opa;
+use super::{Error, Opa, ResponseExt};
+use serde::{de::DeserializeOwned, Serialize};
 
 /// Routes for the [OPA Query API](https://www.openpolicyagent.org/docs/latest/rest-api/#query-api).
-impl Opa {}
+impl Opa {
+    /// Get the default decision (`data.system.main` unless configured otherwise in OPA).
+    ///
+    /// The input is sent without being wrapped in an `input` field
+    /// and the result is returned without being wrapped in a `result` field.
+    ///
+    /// Endpoint for: <https://www.openpolicyagent.org/docs/latest/rest-api/#execute-a-simple-query>
+    pub async fn get_default_decision<I, R>(&self, input: &I) -> Result<R, Error>
+    where
+        I: Serialize,
+        R: DeserializeOwned,
+    {
+        let res: R = self
+            .client
+            .post(self.query_url.join("/")?)
+            .header("Content-Type", "application/json")
+            .body(match &self.input_transform {
+                Some(transform) => serde_json::to_vec(&transform.apply(input)?)?,
+                None => serde_json::to_vec(input)?,
+            })
+            .send()
+            .await?
+            .opa_error_for_status()
+            .await?
+            .json()
+            .await?;
+
+        Ok(res)
+    }
+}
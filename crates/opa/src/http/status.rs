@@ -0,0 +1,111 @@
+use super::{Error, Opa, OpaResponse, ResponseExt};
+use serde::Deserialize;
+use serde_json::Value;
+use std::{collections::BTreeMap, time::Duration};
+
+/// The interval between status requests in [`Opa::wait_for_revision`].
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The status of an OPA server.
+#[derive(Debug, Clone, Deserialize)]
+#[non_exhaustive]
+pub struct Status {
+    /// The status of the bundles by name.
+    #[serde(default)]
+    pub bundles: BTreeMap<String, BundleStatus>,
+    /// The status of the plugins by name.
+    #[serde(default)]
+    pub plugins: BTreeMap<String, Value>,
+}
+
+/// The status of a single bundle within [`Status`].
+#[derive(Debug, Clone, Deserialize)]
+#[non_exhaustive]
+pub struct BundleStatus {
+    pub name: String,
+    /// The revision of the bundle that is currently active,
+    /// if the bundle has been activated and has a revision.
+    #[serde(default)]
+    pub active_revision: Option<String>,
+    #[serde(default)]
+    pub last_successful_activation: Option<String>,
+}
+
+impl Opa {
+    /// Get the status of the server.
+    ///
+    /// Endpoint for: <https://www.openpolicyagent.org/docs/latest/rest-api/#status-api>
+    pub async fn status(&self) -> Result<Status, Error> {
+        let res: OpaResponse<Status> = self
+            .client
+            .get(self.status_url.clone())
+            .send()
+            .await?
+            .opa_error_for_status()
+            .await?
+            .json()
+            .await?;
+
+        Ok(res.result)
+    }
+
+    /// Wait until the given revision of a bundle is active on the server.
+    ///
+    /// The status is polled until the revision matches or the timeout
+    /// elapses, in which case [`Error::RevisionTimeout`] is returned
+    /// with the last active revision.
+    ///
+    /// Connection errors and server errors are retried, e.g. while the
+    /// server is restarting, any other error is returned immediately.
+    ///
+    /// This must be called within a Tokio runtime.
+    pub async fn wait_for_revision(
+        &self,
+        bundle: &str,
+        expected: &str,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        let mut active = None;
+
+        let timed_out = |active| Error::RevisionTimeout {
+            bundle: bundle.to_string(),
+            expected: expected.to_string(),
+            active,
+        };
+
+        loop {
+            // A request that hangs must not outlive the deadline.
+            let Ok(res) = tokio::time::timeout_at(deadline, self.status()).await else {
+                return Err(timed_out(active));
+            };
+
+            match res {
+                Ok(mut status) => {
+                    active = status
+                        .bundles
+                        .remove(bundle)
+                        .and_then(|bundle| bundle.active_revision);
+
+                    if active.as_deref() == Some(expected) {
+                        return Ok(());
+                    }
+                }
+                Err(Error::Http(err))
+                    if err.is_connect()
+                        || err.is_timeout()
+                        || err.status().is_some_and(|s| s.is_server_error()) => {}
+                Err(Error::Opa(err)) if err.status >= 500 => {}
+                Err(err) => return Err(err),
+            }
+
+            let now = tokio::time::Instant::now();
+
+            if now >= deadline {
+                return Err(timed_out(active));
+            }
+
+            tokio::time::sleep(POLL_INTERVAL.min(deadline - now)).await;
+        }
+    }
+}
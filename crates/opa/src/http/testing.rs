@@ -0,0 +1,664 @@
+//! Utilities for testing OPA integrations without a real OPA server.
+//!
+//! [`MockOpa`] is a minimal in-process HTTP server that implements the parts
+//! of the OPA REST API used by [`Opa`]:
+//!
+//! ```rust,ignore
+//! let mock = MockOpa::builder()
+//!     .decision("example.allow", json!(true))
+//!     .decision_fn("example.is_admin", |input| Some(json!(input["role"] == "admin")))
+//!     .start()?;
+//!
+//! let opa = mock.client();
+//! let decision = opa.get_decision::<_, bool>("example.allow", &input).await?;
+//! ```
+//!
+//! Any other response, e.g. an error or an endpoint that is not
+//! implemented, can be set with [`MockOpaBuilder::response`].
+use super::{Opa, PatchOp};
+use serde_json::{json, Map, Value};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+type DecisionFn = Box<dyn Fn(&Value) -> Option<Value> + Send + Sync>;
+
+/// Evaluate a decision with both the WASM and the HTTP backend
+/// and panic if they disagree, returning the output otherwise.
+///
+/// Undefined decisions only agree with undefined decisions.
+///
+/// # Panics
+///
+/// If either evaluation fails, the results differ,
+/// or the result cannot be deserialized into the output type.
+#[cfg(any(feature = "wasmtime-cranelift", feature = "wasmtime-aot"))]
+pub async fn assert_backends_agree<P: crate::PolicyDecision>(
+    wasm: &mut crate::wasm::Opa,
+    http: &Opa,
+    input: &P::Input,
+) -> Option<P::Output> {
+    let wasm_result: Option<Value> = wasm
+        .eval_all_results(P::POLICY_PATH, input)
+        .unwrap_or_else(|err| panic!("WASM evaluation of `{}` failed: {err:#}", P::POLICY_PATH))
+        .pop();
+
+    let http_result: Option<Value> = http
+        .try_get_decision(P::POLICY_PATH, input)
+        .await
+        .unwrap_or_else(|err| panic!("HTTP decision of `{}` failed: {err}", P::POLICY_PATH));
+
+    assert_eq!(
+        wasm_result,
+        http_result,
+        "the WASM (left) and HTTP (right) decisions of `{}` differ",
+        P::POLICY_PATH
+    );
+
+    wasm_result.map(|result| {
+        serde_json::from_value(result).unwrap_or_else(|err| {
+            panic!(
+                "invalid output of `{}` for the decision type: {err}",
+                P::POLICY_PATH
+            )
+        })
+    })
+}
+
+/// A request received by [`MockOpa`].
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct MockRequest {
+    /// The HTTP method, e.g. `POST`.
+    pub method: String,
+    /// The request path, e.g. `/v1/data/example/allow`.
+    pub path: String,
+    /// The request headers with their names and values.
+    pub headers: Vec<(String, String)>,
+    /// The request body.
+    pub body: Vec<u8>,
+}
+
+impl MockRequest {
+    /// The value of the first header with the given name, ignoring case.
+    #[must_use]
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// A response of [`MockOpa`] set with [`MockOpaBuilder::response`].
+#[derive(Debug, Clone)]
+pub struct MockResponse {
+    status: u16,
+    body: Vec<u8>,
+    delay: Option<Duration>,
+}
+
+impl MockResponse {
+    /// A `200 OK` response with the JSON body.
+    #[must_use]
+    pub fn json(body: &Value) -> Self {
+        Self::bytes(body.to_string())
+    }
+
+    /// A `200 OK` response with the raw body, e.g. a bundle or invalid JSON.
+    #[must_use]
+    pub fn bytes(body: impl Into<Vec<u8>>) -> Self {
+        Self {
+            status: 200,
+            body: body.into(),
+            delay: None,
+        }
+    }
+
+    /// Respond with the given status code instead.
+    #[must_use]
+    pub fn status(mut self, status: u16) -> Self {
+        self.status = status;
+        self
+    }
+
+    /// Wait before responding, e.g. for testing timeouts.
+    #[must_use]
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = Some(delay);
+        self
+    }
+
+    fn error(status: u16, code: &str, message: &str) -> Self {
+        Self::json(&json!({ "code": code, "message": message })).status(status)
+    }
+}
+
+/// Builder for [`MockOpa`].
+#[derive(Default)]
+pub struct MockOpaBuilder {
+    data: Value,
+    decisions: HashMap<String, DecisionFn>,
+    policies: BTreeMap<String, String>,
+    unhealthy: bool,
+    responses: HashMap<(String, String), VecDeque<MockResponse>>,
+}
+
+impl core::fmt::Debug for MockOpaBuilder {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("MockOpaBuilder")
+            .field("data", &self.data)
+            .field("decisions", &self.decisions.keys())
+            .field("policies", &self.policies)
+            .field("unhealthy", &self.unhealthy)
+            .field("responses", &self.responses)
+            .finish()
+    }
+}
+
+impl MockOpaBuilder {
+    /// Set the document at the given policy path, it is
+    /// returned as the decision regardless of the input.
+    ///
+    /// Documents can also be changed later with [`Opa::set_document`].
+    #[must_use]
+    pub fn decision(mut self, path: &str, result: Value) -> Self {
+        set_at(&mut self.data, &segments(path), result);
+        self
+    }
+
+    /// Decide based on the input at the given policy path.
+    ///
+    /// Returning `None` results in an undefined decision.
+    /// Decision functions take precedence over documents.
+    #[must_use]
+    pub fn decision_fn<F>(mut self, path: &str, f: F) -> Self
+    where
+        F: Fn(&Value) -> Option<Value> + Send + Sync + 'static,
+    {
+        self.decisions.insert(segments(path).join("/"), Box::new(f));
+        self
+    }
+
+    /// Add a policy that is returned by the policy API.
+    #[must_use]
+    pub fn policy(mut self, id: impl Into<String>, raw: impl Into<String>) -> Self {
+        self.policies.insert(id.into(), raw.into());
+        self
+    }
+
+    /// Make the health endpoint report an unhealthy server.
+    #[must_use]
+    pub fn unhealthy(mut self) -> Self {
+        self.unhealthy = true;
+        self
+    }
+
+    /// Respond to requests with the given method and path with the response
+    /// instead of handling them, the path does not include the query string.
+    #[must_use]
+    pub fn response(self, method: &str, path: &str, response: MockResponse) -> Self {
+        self.responses(method, path, [response])
+    }
+
+    /// Same as [`Self::response`], but the responses are returned in order,
+    /// the last one is repeated for any further requests.
+    #[must_use]
+    pub fn responses(
+        mut self,
+        method: &str,
+        path: &str,
+        responses: impl IntoIterator<Item = MockResponse>,
+    ) -> Self {
+        self.responses.insert(
+            (method.to_string(), path.to_string()),
+            responses.into_iter().collect(),
+        );
+        self
+    }
+
+    /// Start the server on a random local port.
+    ///
+    /// # Errors
+    ///
+    /// Errors are returned if the server could not be started.
+    pub fn start(self) -> io::Result<MockOpa> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+
+        let state = Arc::new(Mutex::new(State {
+            data: match self.data {
+                Value::Null => Value::Object(Map::new()),
+                data => data,
+            },
+            decisions: self.decisions,
+            policies: self.policies,
+            unhealthy: self.unhealthy,
+            responses: self.responses,
+            requests: Vec::new(),
+        }));
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        let handle = {
+            let state = state.clone();
+            let stopped = stopped.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if stopped.load(Ordering::SeqCst) {
+                        break;
+                    }
+
+                    if let Ok(stream) = stream {
+                        let state = state.clone();
+                        thread::spawn(move || {
+                            // Errors only mean that the client went away.
+                            let _ = serve_connection(stream, &state);
+                        });
+                    }
+                }
+            })
+        };
+
+        Ok(MockOpa {
+            addr,
+            state,
+            stopped,
+            handle: Some(handle),
+        })
+    }
+}
+
+/// A minimal in-process mock of the OPA REST API.
+///
+/// The following endpoints are supported:
+///
+/// - `/v1/data/*`: getting decisions and documents, setting and deleting documents
+/// - `/v1/policies/*`: getting, listing, setting and deleting policies
+/// - `/health`
+/// - `/`: the default decision at `system/main`
+///
+/// Responses set with [`MockOpaBuilder::response`] take precedence.
+///
+/// The server is stopped when the value is dropped.
+pub struct MockOpa {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    stopped: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl core::fmt::Debug for MockOpa {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("MockOpa")
+            .field("addr", &self.addr)
+            .finish_non_exhaustive()
+    }
+}
+
+impl MockOpa {
+    /// Create a new [`MockOpaBuilder`].
+    #[must_use]
+    pub fn builder() -> MockOpaBuilder {
+        MockOpaBuilder::default()
+    }
+
+    /// The base URL of the server.
+    #[must_use]
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// An [`Opa`] client pointed at the server.
+    ///
+    /// # Panics
+    ///
+    /// Never panics, the server URL is always valid.
+    #[must_use]
+    pub fn client(&self) -> Opa {
+        Opa::new(self.url()).unwrap()
+    }
+
+    /// The current data document of the server.
+    #[must_use]
+    pub fn data(&self) -> Value {
+        self.state().data.clone()
+    }
+
+    /// All requests received so far.
+    #[must_use]
+    pub fn requests(&self) -> Vec<MockRequest> {
+        self.state().requests.clone()
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Drop for MockOpa {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+
+        // Wake up the listener so that it notices the shutdown.
+        if TcpStream::connect(self.addr).is_ok() {
+            if let Some(handle) = self.handle.take() {
+                let _ = handle.join();
+            }
+        }
+    }
+}
+
+struct State {
+    data: Value,
+    decisions: HashMap<String, DecisionFn>,
+    policies: BTreeMap<String, String>,
+    unhealthy: bool,
+    responses: HashMap<(String, String), VecDeque<MockResponse>>,
+    requests: Vec<MockRequest>,
+}
+
+fn serve_connection(stream: TcpStream, state: &Mutex<State>) -> io::Result<()> {
+    let mut reader = BufReader::new(stream);
+
+    loop {
+        let mut request_line = String::new();
+        if reader.read_line(&mut request_line)? == 0 {
+            return Ok(());
+        }
+
+        let mut headers = Vec::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line)?;
+            let line = line.trim_end();
+
+            if line.is_empty() {
+                break;
+            }
+
+            if let Some((name, value)) = line.split_once(':') {
+                headers.push((name.to_string(), value.trim().to_string()));
+            }
+        }
+
+        let content_length = headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+            .and_then(|(_, value)| value.parse().ok())
+            .unwrap_or_default();
+
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body)?;
+
+        let mut parts = request_line.split_whitespace();
+        let request = MockRequest {
+            method: parts.next().unwrap_or_default().to_string(),
+            path: parts.next().unwrap_or_default().to_string(),
+            headers,
+            body,
+        };
+
+        let response = {
+            let mut state = state.lock().unwrap_or_else(PoisonError::into_inner);
+            let response =
+                set_response(&mut state, &request).unwrap_or_else(|| handle(&mut state, &request));
+            state.requests.push(request);
+            response
+        };
+
+        if let Some(delay) = response.delay {
+            thread::sleep(delay);
+        }
+
+        let reason = reqwest::StatusCode::from_u16(response.status)
+            .ok()
+            .and_then(|status| status.canonical_reason())
+            .unwrap_or_default();
+        let stream = reader.get_mut();
+        write!(
+            stream,
+            "HTTP/1.1 {} {reason}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n",
+            response.status,
+            response.body.len()
+        )?;
+        stream.write_all(&response.body)?;
+        stream.flush()?;
+
+        if response.status >= 500 {
+            return stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// The next response set with [`MockOpaBuilder::response`] for the request, if any.
+fn set_response(state: &mut State, request: &MockRequest) -> Option<MockResponse> {
+    let path = request.path.split('?').next().unwrap_or_default();
+    let responses = state
+        .responses
+        .get_mut(&(request.method.clone(), path.to_string()))?;
+
+    if responses.len() > 1 {
+        responses.pop_front()
+    } else {
+        responses.front().cloned()
+    }
+}
+
+fn handle(state: &mut State, request: &MockRequest) -> MockResponse {
+    let path = request.path.split('?').next().unwrap_or_default();
+    let body = || serde_json::from_slice::<Value>(&request.body);
+
+    if let Some(data_path) = path.strip_prefix("/v1/data") {
+        let segments = segments(data_path);
+
+        return match request.method.as_str() {
+            "GET" | "POST" => {
+                let input = body()
+                    .ok()
+                    .and_then(|mut body| body.get_mut("input").map(Value::take))
+                    .unwrap_or(Value::Null);
+                decide(state, &segments, &input).map_or_else(
+                    || MockResponse::json(&json!({})),
+                    |result| MockResponse::json(&json!({ "result": result })),
+                )
+            }
+            "PUT" => match body() {
+                Ok(document) => {
+                    set_at(&mut state.data, &segments, document);
+                    MockResponse::json(&json!({}))
+                }
+                Err(err) => MockResponse::error(400, "invalid_parameter", &err.to_string()),
+            },
+            "PATCH" => match serde_json::from_slice::<Vec<PatchOp>>(&request.body) {
+                Ok(patches) => patch(&mut state.data, &segments, &patches),
+                Err(err) => MockResponse::error(400, "invalid_parameter", &err.to_string()),
+            },
+            "DELETE" => {
+                if remove_at(&mut state.data, &segments).is_some() {
+                    MockResponse::json(&json!({}))
+                } else {
+                    MockResponse::error(
+                        404,
+                        "resource_not_found",
+                        &format!("storage_not_found_error: {data_path}: document missing"),
+                    )
+                }
+            }
+            _ => MockResponse::error(405, "invalid_parameter", "method not allowed"),
+        };
+    }
+
+    if let Some(id) = path.strip_prefix("/v1/policies") {
+        let id = id.trim_start_matches('/');
+
+        return match (request.method.as_str(), id) {
+            ("GET", "") => MockResponse::json(&json!({
+                "result": state
+                    .policies
+                    .iter()
+                    .map(|(id, raw)| json!({ "id": id, "raw": raw }))
+                    .collect::<Vec<_>>()
+            })),
+            ("GET", id) => match state.policies.get(id) {
+                Some(raw) => MockResponse::json(&json!({ "result": { "id": id, "raw": raw } })),
+                None => policy_not_found(id),
+            },
+            ("PUT", id) => {
+                let raw = String::from_utf8_lossy(&request.body).into_owned();
+                state.policies.insert(id.to_string(), raw);
+                MockResponse::json(&json!({}))
+            }
+            ("DELETE", id) => match state.policies.remove(id) {
+                Some(_) => MockResponse::json(&json!({})),
+                None => policy_not_found(id),
+            },
+            _ => MockResponse::error(405, "invalid_parameter", "method not allowed"),
+        };
+    }
+
+    match (request.method.as_str(), path) {
+        ("GET", "/health") if state.unhealthy => MockResponse::json(&json!({})).status(500),
+        ("GET", "/health") => MockResponse::json(&json!({})),
+        ("POST", "/") => {
+            let input = body().unwrap_or(Value::Null);
+            match decide(state, &["system", "main"], &input) {
+                Some(result) => MockResponse::json(&result),
+                None => MockResponse::error(
+                    404,
+                    "undefined_document",
+                    "document missing or undefined: data.system.main",
+                ),
+            }
+        }
+        _ => MockResponse::error(404, "resource_not_found", "not found"),
+    }
+}
+
+/// Apply the patches to the document at the given path,
+/// the data is only changed if all of them succeed.
+fn patch(data: &mut Value, segments: &[&str], patches: &[PatchOp]) -> MockResponse {
+    let mut new_data = data.clone();
+
+    for patch in patches {
+        let pointer: Vec<String> = patch
+            .path()
+            .split('/')
+            .skip(1)
+            .filter(|s| !s.is_empty())
+            .map(|s| s.replace("~1", "/").replace("~0", "~"))
+            .collect();
+        let path: Vec<&str> = segments
+            .iter()
+            .copied()
+            .chain(pointer.iter().map(String::as_str))
+            .collect();
+
+        let found = match patch {
+            PatchOp::Add { value, .. } => {
+                set_at(&mut new_data, &path, value.clone());
+                true
+            }
+            PatchOp::Remove { .. } => remove_at(&mut new_data, &path).is_some(),
+            PatchOp::Replace { value, .. } => match get_at_mut(&mut new_data, &path) {
+                Some(target) => {
+                    *target = value.clone();
+                    true
+                }
+                None => false,
+            },
+        };
+
+        if !found {
+            return MockResponse::error(
+                404,
+                "resource_not_found",
+                &format!(
+                    "storage_not_found_error: {}: document missing",
+                    patch.path()
+                ),
+            );
+        }
+    }
+
+    *data = new_data;
+    MockResponse::bytes(Vec::new()).status(204)
+}
+
+fn policy_not_found(id: &str) -> MockResponse {
+    MockResponse::error(
+        404,
+        "resource_not_found",
+        &format!("storage_not_found_error: policy id \"{id}\""),
+    )
+}
+
+fn decide(state: &State, segments: &[&str], input: &Value) -> Option<Value> {
+    match state.decisions.get(&segments.join("/")) {
+        Some(f) => f(input),
+        None => get_at(&state.data, segments).cloned(),
+    }
+}
+
+/// The segments of a `.` or `/` separated path.
+fn segments(path: &str) -> Vec<&str> {
+    path.split(['.', '/']).filter(|s| !s.is_empty()).collect()
+}
+
+fn get_at<'v>(value: &'v Value, segments: &[&str]) -> Option<&'v Value> {
+    segments
+        .iter()
+        .try_fold(value, |value, segment| value.get(segment))
+}
+
+fn get_at_mut<'v>(value: &'v mut Value, segments: &[&str]) -> Option<&'v mut Value> {
+    segments
+        .iter()
+        .try_fold(value, |value, segment| value.get_mut(segment))
+}
+
+fn set_at(value: &mut Value, segments: &[&str], document: Value) {
+    let Some((last, parents)) = segments.split_last() else {
+        *value = document;
+        return;
+    };
+
+    let mut value = value;
+    for segment in parents {
+        if !value.is_object() {
+            *value = Value::Object(Map::new());
+        }
+        value = value
+            .as_object_mut()
+            .unwrap()
+            .entry(*segment)
+            .or_insert_with(|| Value::Object(Map::new()));
+    }
+
+    if !value.is_object() {
+        *value = Value::Object(Map::new());
+    }
+    value
+        .as_object_mut()
+        .unwrap()
+        .insert((*last).to_string(), document);
+}
+
+fn remove_at(value: &mut Value, segments: &[&str]) -> Option<Value> {
+    let Some((last, parents)) = segments.split_last() else {
+        return Some(std::mem::replace(value, Value::Object(Map::new())));
+    };
+
+    parents
+        .iter()
+        .try_fold(value, |value, segment| value.get_mut(segment))?
+        .as_object_mut()?
+        .remove(*last)
+}
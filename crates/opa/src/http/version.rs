@@ -0,0 +1,86 @@
+use super::{Error, Opa, OpaResponse, ResponseExt};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+/// The version of an OPA server, see [`Opa::version`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct OpaVersion {
+    /// The version, e.g. `0.60.0`.
+    pub version: String,
+    /// The commit the server was built from, if reported
+    /// with the `build_commit` label.
+    pub commit: Option<String>,
+    /// The build timestamp of the server, if reported
+    /// with the `build_timestamp` label.
+    pub build: Option<String>,
+}
+
+impl OpaVersion {
+    /// The major, minor and patch numbers of the version.
+    ///
+    /// A leading `v` and any pre-release or build suffix are ignored,
+    /// so `v0.61.0-dev` is `(0, 61, 0)`, and missing minor and patch
+    /// numbers are 0. `None` is returned for other versions.
+    #[must_use]
+    pub fn numbers(&self) -> Option<(u64, u64, u64)> {
+        crate::version::parse_version(&self.version)
+    }
+
+    /// Whether the version is the given version or newer,
+    /// e.g. for detecting features of the server.
+    ///
+    /// Versions that cannot be parsed are never new enough.
+    #[must_use]
+    pub fn is_at_least(&self, major: u64, minor: u64, patch: u64) -> bool {
+        self.numbers()
+            .is_some_and(|numbers| numbers >= (major, minor, patch))
+    }
+}
+
+#[derive(Deserialize)]
+struct Config {
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+}
+
+impl Opa {
+    /// Get the version of the server.
+    ///
+    /// The version is read from the labels of the configuration
+    /// of the server, which always include the version.
+    ///
+    /// The version is only requested once if caching is enabled
+    /// with [`Opa::cache_version`].
+    ///
+    /// Endpoint for: <https://www.openpolicyagent.org/docs/latest/rest-api/#config-api>
+    pub async fn version(&self) -> Result<OpaVersion, Error> {
+        if let Some(version) = self.version_cache.as_ref().and_then(|c| c.get()) {
+            return Ok(version.clone());
+        }
+
+        let res: OpaResponse<Config> = self
+            .client
+            .get(self.config_url.clone())
+            .send()
+            .await?
+            .opa_error_for_status()
+            .await?
+            .json()
+            .await?;
+
+        let mut labels = res.result.labels;
+        let version = OpaVersion {
+            version: labels.remove("version").ok_or(Error::MissingVersion)?,
+            commit: labels.remove("build_commit"),
+            build: labels.remove("build_timestamp"),
+        };
+
+        if let Some(cache) = &self.version_cache {
+            // Another request might have been faster, both are the same.
+            let _ = cache.set(version.clone());
+        }
+
+        Ok(version)
+    }
+}
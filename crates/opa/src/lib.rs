@@ -1,6 +1,7 @@
 #![warn(clippy::pedantic)]
 
 use serde::{de::DeserializeOwned, Serialize};
+use std::borrow::Cow;
 
 #[cfg(feature = "bundle")]
 pub mod bundle;
@@ -14,8 +15,17 @@ pub mod wasm;
 #[cfg(feature = "build")]
 pub mod build;
 
+#[cfg(any(feature = "build", feature = "http"))]
+mod version;
+
+#[cfg(feature = "macros")]
+pub use opa_macros::policy_decision;
+
 /// A helper trait for defining strongly-typed input/decision pairs
 /// for given policies.
+///
+/// With the `macros` feature it can be implemented with
+/// the `policy_decision` attribute instead.
 pub trait PolicyDecision {
     /// A `.` or `/` separated path to the policy decision.
     const POLICY_PATH: &'static str;
@@ -27,6 +37,66 @@ pub trait PolicyDecision {
     type Output: DeserializeOwned;
 }
 
+/// Normalize a `.` or `/` separated policy path, such as
+/// [`PolicyDecision::POLICY_PATH`], to the `/` separated form used by OPA.
+///
+/// Leading slashes are removed, so `example.allow`, `example/allow`
+/// and `/example/allow` all refer to the same policy.
+///
+/// # Example
+///
+/// ```rust
+/// assert_eq!(opa::normalize_policy_path("/example.policy/allow"), "example/policy/allow");
+/// ```
+#[must_use]
+pub fn normalize_policy_path(path: &str) -> Cow<'_, str> {
+    let path = path.trim_start_matches('/');
+
+    if path.contains('.') {
+        Cow::Owned(path.replace('.', "/"))
+    } else {
+        Cow::Borrowed(path)
+    }
+}
+
+/// Evaluate a single decision of a policy in a bundle.
+///
+/// The bundle is parsed and its WASM module is compiled for every call,
+/// this is only meant for scripts and tests. Build a [`wasm::Opa`] instance
+/// once and reuse it for repeated evaluations instead.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let permissions: Vec<String> = opa::evaluate(
+///     include_bytes!("example.tar.gz"),
+///     &data,
+///     "example.project_permissions",
+///     &input,
+/// )?;
+/// ```
+///
+/// # Errors
+///
+/// Errors are returned if the bundle is invalid or the evaluation fails,
+/// see [`wasm::Opa::eval`].
+#[cfg(all(feature = "bundle", feature = "wasmtime-cranelift"))]
+pub fn evaluate<I, O>(
+    bundle_bytes: &[u8],
+    data: &serde_json::Value,
+    entrypoint: &str,
+    input: &I,
+) -> Result<O, anyhow::Error>
+where
+    I: Serialize,
+    O: DeserializeOwned,
+{
+    let bundle = bundle::Bundle::from_bytes(bundle_bytes)?;
+    let mut opa = wasm::Opa::new().build_from_bundle(&bundle)?;
+    opa.set_data(data)?;
+    opa.eval(entrypoint, input)
+}
+
 /// Include a bundle built at compile-time.
 ///
 /// # Example
@@ -57,7 +127,7 @@ macro_rules! include_policy {
             $name,
             ".tar.gz"
         )))
-        .unwrap();
+        .expect(concat!("the bundle of the policy `", $name, "` is invalid"));
 
         $crate::include_aot!($name, bundle);
 
@@ -76,11 +146,17 @@ pub mod private {
 macro_rules! include_aot {
     ($name:literal, $bundle:ident) => {
         let b = include_bytes!(concat!(env!("OUT_DIR"), "/opa/", $name, ".cwasm"));
-        
+        let target = include_str!(concat!(env!("OUT_DIR"), "/opa/", $name, ".cwasm.target"));
+
         if !b.is_empty() {
             // SAFETY: The WASM module was compiled by
             // this library in a build script, so it is correct.
-            unsafe { $bundle.set_wasmtime_bytes($crate::private::bytes::Bytes::from(&b[..])) }
+            unsafe {
+                $bundle.set_wasmtime_bytes_for_target(
+                    $crate::private::bytes::Bytes::from(&b[..]),
+                    target,
+                )
+            }
         }
     };
 }
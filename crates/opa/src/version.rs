@@ -0,0 +1,20 @@
+/// Parse a `major.minor.patch` version, e.g. of `opa`.
+///
+/// A leading `v` and any pre-release or build suffix are ignored,
+/// so `v0.61.0-dev` is `(0, 61, 0)`. Missing minor and patch
+/// numbers are 0, more than three numbers are invalid.
+pub(crate) fn parse_version(version: &str) -> Option<(u64, u64, u64)> {
+    let version = version.trim().trim_start_matches('v');
+    let version = version.split(['-', '+']).next()?;
+
+    let mut parts = version.split('.').map(str::parse);
+    let major = parts.next()?.ok()?;
+    let minor = parts.next().unwrap_or(Ok(0)).ok()?;
+    let patch = parts.next().unwrap_or(Ok(0)).ok()?;
+
+    if parts.next().is_some() {
+        return None;
+    }
+
+    Some((major, minor, patch))
+}
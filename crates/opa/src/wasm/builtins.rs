@@ -0,0 +1,166 @@
+//! Builtins that are implemented by the host instead of the module.
+//!
+//! <https://www.openpolicyagent.org/docs/latest/wasm/#builtins>
+
+use anyhow::Context as _;
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    sync::{Mutex, MutexGuard, PoisonError},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use wasmtime::{Caller, Extern, Memory, WasmParams, WasmResults};
+
+/// A handler for a builtin function used by a policy,
+/// see [`OpaBuilder::builtin`](super::OpaBuilder::builtin).
+///
+/// It is called with the context of the instance and the arguments
+/// of the builtin and returns its result, see
+/// [`OpaBuilder::builtin_with_context`](super::OpaBuilder::builtin_with_context).
+pub type BuiltinHandler<C = ()> =
+    Box<dyn Fn(&C, &[Value]) -> Result<Value, anyhow::Error> + Send + Sync>;
+
+/// Builtins implemented by this crate, these never need a handler.
+const HOST_BUILTINS: &[&str] = &["time.now_ns"];
+
+/// The state of the builtins of an instance, shared
+/// with the builtin functions of the linker.
+pub(super) struct Builtins<C> {
+    /// The names of the builtins used by the module by their ids.
+    names: Mutex<HashMap<u32, String>>,
+    handlers: HashMap<String, BuiltinHandler<C>>,
+    /// The time returned by `time.now_ns` instead of the current time.
+    fixed_time: Mutex<Option<i64>>,
+}
+
+impl<C> core::fmt::Debug for Builtins<C> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Builtins")
+            .field("names", &self.names)
+            .field("handlers", &self.handlers.keys().collect::<Vec<_>>())
+            .field("fixed_time", &self.fixed_time)
+            .finish()
+    }
+}
+
+impl<C> Builtins<C> {
+    pub(super) fn new(
+        handlers: HashMap<String, BuiltinHandler<C>>,
+        fixed_time: Option<i64>,
+    ) -> Self {
+        Self {
+            names: Mutex::default(),
+            handlers,
+            fixed_time: Mutex::new(fixed_time),
+        }
+    }
+
+    /// Set the ids of the builtins used by the module,
+    /// as returned by its `builtins` export.
+    pub(super) fn set_ids(&self, ids: &HashMap<String, u32>) {
+        *lock(&self.names) = ids.iter().map(|(name, id)| (*id, name.clone())).collect();
+    }
+
+    pub(super) fn set_fixed_time(&self, nanos: i64) {
+        *lock(&self.fixed_time) = Some(nanos);
+    }
+
+    /// The sorted names of the builtins used by the module that are
+    /// neither implemented by this crate nor in the given handlers.
+    pub(super) fn missing<H>(&self, handlers: &HashMap<String, H>) -> Vec<String> {
+        let mut missing: Vec<_> = lock(&self.names)
+            .values()
+            .filter(|name| !HOST_BUILTINS.contains(&name.as_str()) && !handlers.contains_key(*name))
+            .cloned()
+            .collect();
+        missing.sort_unstable();
+        missing
+    }
+
+    /// Same as [`Self::missing`] with the handlers of the instance.
+    pub(super) fn missing_handlers(&self) -> Vec<String> {
+        self.missing(&self.handlers)
+    }
+
+    /// Call the builtin with the id and the addresses of the arguments,
+    /// returning the address of the result or 0 if the builtin is not supported.
+    pub(super) fn call(
+        &self,
+        caller: &mut Caller<'_, C>,
+        memory: Memory,
+        id: u32,
+        args: &[u32],
+    ) -> Result<u32, anyhow::Error> {
+        let Some(name) = lock(&self.names).get(&id).cloned() else {
+            return Ok(0);
+        };
+
+        if let Some(handler) = self.handlers.get(&name) {
+            let args = args
+                .iter()
+                .map(|addr| read_value(caller, memory, *addr))
+                .collect::<Result<Vec<_>, _>>()?;
+            let result = handler(caller.data(), &args)
+                .with_context(|| format!("builtin `{name}` failed"))?;
+            return write_value(caller, memory, &serde_json::to_vec(&result)?);
+        }
+
+        if name != "time.now_ns" {
+            return Ok(0);
+        }
+
+        let nanos = match *lock(&self.fixed_time) {
+            Some(nanos) => nanos,
+            None => i64::try_from(SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos())?,
+        };
+
+        write_value(caller, memory, nanos.to_string().as_bytes())
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Get an exported function of the calling module.
+fn export<C, P: WasmParams, R: WasmResults>(
+    caller: &mut Caller<'_, C>,
+    name: &str,
+) -> Result<wasmtime::TypedFunc<P, R>, anyhow::Error> {
+    caller
+        .get_export(name)
+        .and_then(Extern::into_func)
+        .ok_or_else(|| anyhow::anyhow!("the module does not export `{name}`"))?
+        .typed::<P, R, _>(&*caller)
+}
+
+/// Read the value at the address in the memory of the calling module.
+fn read_value<C>(
+    caller: &mut Caller<'_, C>,
+    memory: Memory,
+    addr: u32,
+) -> Result<Value, anyhow::Error> {
+    let opa_json_dump = export::<_, (u32,), u32>(caller, "opa_json_dump")?;
+    let json_addr = opa_json_dump.call(&mut *caller, (addr,))?;
+
+    let json = super::null_terminated_str(&memory.data(&*caller)[json_addr as usize..])
+        .ok_or_else(|| anyhow::anyhow!("invalid string in memory"))?;
+
+    super::from_json_slice(json.as_bytes(), format_args!("builtin argument"))
+}
+
+/// Parse the JSON into a value in the memory of the calling module.
+fn write_value<C>(
+    caller: &mut Caller<'_, C>,
+    memory: Memory,
+    json: &[u8],
+) -> Result<u32, anyhow::Error> {
+    let opa_malloc = export::<_, (u32,), u32>(caller, "opa_malloc")?;
+    let opa_json_parse = export::<_, (u32, u32), u32>(caller, "opa_json_parse")?;
+
+    let len = u32::try_from(json.len())?;
+    let addr = opa_malloc.call(&mut *caller, (len,))?;
+    memory.write(&mut *caller, addr as usize, json)?;
+
+    opa_json_parse.call(&mut *caller, (addr, len))
+}
@@ -0,0 +1,107 @@
+use super::Opa;
+use crate::PolicyDecision;
+use lru::LruCache;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    num::NonZeroUsize,
+};
+
+/// An [`Opa`] instance that caches evaluation results.
+///
+/// Results are cached by a hash of the entrypoint and the serialized input,
+/// the least recently used results are evicted when the cache is full.
+/// Entrypoints are normalized with [`normalize_policy_path`](crate::normalize_policy_path),
+/// so `example.allow` and `example/allow` share their results.
+///
+/// Cached results are never returned after the data was changed
+/// with [`CachingOpa::set_data`].
+#[derive(Debug)]
+pub struct CachingOpa {
+    opa: Opa,
+    cache: LruCache<u64, Value>,
+}
+
+impl CachingOpa {
+    /// Wrap the OPA instance with a cache of the given size.
+    #[must_use]
+    pub fn new(opa: Opa, capacity: NonZeroUsize) -> Self {
+        Self {
+            opa,
+            cache: LruCache::new(capacity),
+        }
+    }
+
+    /// Same as [`Opa::set_data`], all cached results are invalidated.
+    ///
+    /// # Errors
+    ///
+    /// Internal WASM errors are returned.
+    pub fn set_data(&mut self, data: &impl Serialize) -> Result<(), anyhow::Error> {
+        self.cache.clear();
+        self.opa.set_data(data)
+    }
+
+    /// Same as [`Opa::eval`], returning a cached result if available.
+    ///
+    /// # Errors
+    ///
+    /// See [`Opa::eval`].
+    pub fn eval<I, O>(&mut self, entrypoint: &str, input: &I) -> Result<O, anyhow::Error>
+    where
+        I: Serialize,
+        O: DeserializeOwned,
+    {
+        let mut hasher = DefaultHasher::new();
+        crate::normalize_policy_path(entrypoint).hash(&mut hasher);
+        serde_json::to_vec(input)?.hash(&mut hasher);
+        let key = hasher.finish();
+
+        if let Some(value) = self.cache.get(&key) {
+            return Ok(O::deserialize(value)?);
+        }
+
+        let value: Value = self.opa.eval(entrypoint, input)?;
+        let result = O::deserialize(&value)?;
+        self.cache.put(key, value);
+
+        Ok(result)
+    }
+
+    /// Same as [`Self::eval`] with an alternative API.
+    ///
+    /// # Errors
+    ///
+    /// See [`Opa::decide`].
+    pub fn decide<P: PolicyDecision>(
+        &mut self,
+        input: &P::Input,
+    ) -> Result<P::Output, anyhow::Error> {
+        self.eval(P::POLICY_PATH, input)
+    }
+
+    /// Remove all cached results.
+    pub fn clear_cache(&mut self) {
+        self.cache.clear();
+    }
+
+    /// The number of cached results.
+    #[must_use]
+    pub fn cached_len(&self) -> usize {
+        self.cache.len()
+    }
+
+    /// The wrapped OPA instance.
+    #[must_use]
+    pub fn inner(&self) -> &Opa {
+        &self.opa
+    }
+
+    /// Return the wrapped OPA instance.
+    #[must_use]
+    pub fn into_inner(self) -> Opa {
+        self.opa
+    }
+}
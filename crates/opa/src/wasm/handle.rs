@@ -0,0 +1,187 @@
+use super::{metrics::PhaseTimer, Addr, EvalContext, EvalStrategy, OpaOutput, OpaWithContext};
+use anyhow::anyhow;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::value::RawValue;
+use std::{
+    mem::ManuallyDrop,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+static NEXT_INSTANCE_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Identifies the instance and the data that handles were created with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct HandleScope {
+    instance: u64,
+    generation: u64,
+}
+
+impl HandleScope {
+    pub(super) fn new() -> Self {
+        Self {
+            instance: NEXT_INSTANCE_ID.fetch_add(1, Ordering::Relaxed),
+            generation: 0,
+        }
+    }
+
+    /// Invalidate all handles created so far.
+    pub(super) fn invalidate(&mut self) {
+        self.generation += 1;
+    }
+}
+
+/// A handle to a value in the WASM memory of an [`Opa`] instance,
+/// see [`Opa::eval_to_handle`].
+///
+/// A handle is only valid for the instance that created it, until the
+/// data of the instance is set again with [`Opa::set_data`] or
+/// [`Opa::set_data_bytes`]. Using an invalid handle returns an error.
+#[derive(Debug, Clone, Copy)]
+pub struct OpaValue {
+    addr: Addr,
+    scope: HandleScope,
+}
+
+impl<C> OpaWithContext<C> {
+    /// Evaluate a policy at the entrypoint and keep the result in the
+    /// WASM memory, returning a handle to it.
+    ///
+    /// Only the last result of the result set is kept, same as [`Self::eval`].
+    ///
+    /// The handle can be used as the data with [`Self::set_data_from_handle`]
+    /// or as the input with [`Self::eval_context_with_handle`] for another
+    /// evaluation, so the result is never deserialized and serialized again
+    /// on the host.
+    ///
+    /// The memory of the result is kept until the data is set again,
+    /// including the memory used for the evaluation itself. Creating
+    /// many handles without setting the data grows the memory.
+    ///
+    /// # Errors
+    ///
+    /// The entrypoint must exist and the evaluation must produce at least one result.
+    ///
+    /// Data must be set at least once beforehand with [`Self::set_data`], otherwise evaluation will always fail.
+    pub fn eval_to_handle<I: Serialize>(
+        &mut self,
+        entrypoint: &str,
+        input: &I,
+    ) -> Result<OpaValue, anyhow::Error> {
+        let out_addr = match self.eval_strategy {
+            EvalStrategy::SinglePass => {
+                let input = serde_json::to_vec(input)?;
+                self.eval_once_output(entrypoint, &input, &mut PhaseTimer::new(None))?
+            }
+            EvalStrategy::Context => {
+                let mut ctx = EvalContext::create(self, input)?;
+                let out_addr = ctx.eval_output(entrypoint)?;
+                // The heap must not be reset, the result is kept.
+                let _ = ManuallyDrop::new(ctx);
+                out_addr
+            }
+        };
+
+        match self.keep_result(out_addr) {
+            Ok(addr) => Ok(OpaValue {
+                addr,
+                scope: self.handle_scope,
+            }),
+            Err(err) => {
+                self.set_heap_ptr(self.input_heap_ptr)?;
+                Err(err)
+            }
+        }
+    }
+
+    /// Use the value of the handle as the data.
+    ///
+    /// Unlike [`Self::set_data`], this keeps the other handles of the instance valid.
+    ///
+    /// # Errors
+    ///
+    /// The handle must be valid for this instance.
+    pub fn set_data_from_handle(&mut self, value: &OpaValue) -> Result<(), anyhow::Error> {
+        self.check_handle(value)?;
+        self.data_addr = Some(value.addr);
+        self.base_data = None;
+        Ok(())
+    }
+
+    /// Same as [`Self::eval_context`], but the value of the handle is used as the input.
+    ///
+    /// # Errors
+    ///
+    /// The handle must be valid for this instance, and the module
+    /// must support [`EvalStrategy::Context`] evaluation.
+    ///
+    /// Data must be set at least once beforehand with [`Self::set_data`], otherwise evaluation will always fail.
+    pub fn eval_context_with_handle(
+        &mut self,
+        input: &OpaValue,
+    ) -> Result<EvalContext<'_, C>, anyhow::Error> {
+        self.check_handle(input)?;
+        EvalContext::create_with_input_addr(self, input.addr)
+    }
+
+    /// Deserialize the value of the handle.
+    ///
+    /// # Errors
+    ///
+    /// The handle must be valid for this instance.
+    ///
+    /// Deserialization errors are also returned.
+    pub fn read_handle<O: DeserializeOwned>(
+        &mut self,
+        value: &OpaValue,
+    ) -> Result<O, anyhow::Error> {
+        self.check_handle(value)?;
+
+        let result = self.json_at(value.addr);
+        self.set_heap_ptr(self.input_heap_ptr)?;
+        result
+    }
+
+    fn check_handle(&self, value: &OpaValue) -> Result<(), anyhow::Error> {
+        if value.scope == self.handle_scope {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "the value handle is not valid for this instance or its current data"
+            ))
+        }
+    }
+
+    /// Parse the last result of the JSON output at the address,
+    /// and keep it in the memory by moving the input region after it.
+    fn keep_result(&mut self, addr: Addr) -> Result<Addr, anyhow::Error> {
+        let out_bytes = self
+            .bytes_at(addr)
+            .ok_or_else(|| anyhow!("invalid output returned from evaluation"))?;
+        let out_len = out_bytes.len();
+
+        let mut out: Vec<OpaOutput<&RawValue>> = serde_json::from_slice(out_bytes)?;
+        let result = out
+            .pop()
+            .ok_or_else(|| anyhow!("the query produced no results"))?
+            .result
+            .get();
+
+        let result_addr = Addr(
+            u32::from(addr)
+                + u32::try_from(result.as_ptr() as usize - out_bytes.as_ptr() as usize)?,
+        );
+        let result_len = result.len();
+
+        self.metrics.record_output(out_len);
+
+        let value_addr = self.parse_json(result_addr, result_len)?;
+        if value_addr.0 == 0 {
+            return Err(anyhow!("the result could not be parsed"));
+        }
+
+        self.input_heap_ptr = self.heap_ptr()?;
+        self.reserve_input(self.input_scratch_bytes)?;
+
+        Ok(value_addr)
+    }
+}
@@ -0,0 +1,133 @@
+use super::{metrics::PhaseTimer, Addr, EvalContext, EvalStrategy, OpaOutput, OpaWithContext};
+use anyhow::anyhow;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::value::RawValue;
+use std::{marker::PhantomData, mem::ManuallyDrop, ops::Range, vec};
+
+impl<C> OpaWithContext<C> {
+    /// Evaluate a policy that returns an array, and deserialize
+    /// its elements one by one while iterating.
+    ///
+    /// Only the last result of the result set is used, same as [`Self::eval`].
+    ///
+    /// The elements are deserialized directly from the output in the WASM
+    /// memory instead of deserializing the whole array at once, which reduces
+    /// the memory used for large results. The output must stay in the memory
+    /// until the iterator is dropped, so the iterator borrows the instance
+    /// mutably for its lifetime, the heap is reset once it is dropped.
+    ///
+    /// # Errors
+    ///
+    /// The entrypoint must exist and the evaluation must produce at least one
+    /// result that is an array. Deserialization errors of the elements
+    /// are returned by the iterator.
+    ///
+    /// Data must be set at least once beforehand with [`Self::set_data`], otherwise evaluation will always fail.
+    pub fn eval_iter<I, T>(
+        &mut self,
+        entrypoint: &str,
+        input: &I,
+    ) -> Result<EvalIter<'_, T, C>, anyhow::Error>
+    where
+        I: Serialize,
+        T: DeserializeOwned,
+    {
+        let out_addr = match self.eval_strategy {
+            EvalStrategy::SinglePass => {
+                let input = serde_json::to_vec(input)?;
+                self.eval_once_output(entrypoint, &input, &mut PhaseTimer::new(None))?
+            }
+            EvalStrategy::Context => {
+                let mut ctx = EvalContext::create(self, input)?;
+                let out_addr = ctx.eval_output(entrypoint)?;
+                // The iterator resets the heap instead.
+                let _ = ManuallyDrop::new(ctx);
+                out_addr
+            }
+        };
+
+        match self.element_ranges(out_addr) {
+            Ok(elements) => Ok(EvalIter {
+                opa: self,
+                elements: elements.into_iter(),
+                _output: PhantomData,
+            }),
+            Err(err) => {
+                self.set_heap_ptr(self.input_heap_ptr)?;
+                Err(err)
+            }
+        }
+    }
+
+    /// The memory ranges of the elements of the last result at the address.
+    fn element_ranges(&mut self, addr: Addr) -> Result<Vec<Range<usize>>, anyhow::Error> {
+        let out_bytes = self
+            .bytes_at(addr)
+            .ok_or_else(|| anyhow!("invalid output returned from evaluation"))?;
+        let out_len = out_bytes.len();
+
+        let mut out: Vec<OpaOutput<&RawValue>> = serde_json::from_slice(out_bytes)?;
+        let result = out
+            .pop()
+            .ok_or_else(|| anyhow!("the query produced no results"))?;
+
+        let elements: Vec<&RawValue> = serde_json::from_str(result.result.get())
+            .map_err(|err| anyhow!("the result is not an array: {err}"))?;
+
+        let start = out_bytes.as_ptr() as usize;
+        let ranges = elements
+            .into_iter()
+            .map(|element| {
+                let offset = element.get().as_ptr() as usize - start + usize::from(addr);
+                offset..offset + element.get().len()
+            })
+            .collect();
+
+        self.metrics.record_output(out_len);
+
+        Ok(ranges)
+    }
+}
+
+/// An iterator over the elements of an array result,
+/// see [`Opa::eval_iter`].
+pub struct EvalIter<'o, T, C = ()> {
+    opa: &'o mut OpaWithContext<C>,
+    elements: vec::IntoIter<Range<usize>>,
+    _output: PhantomData<fn() -> T>,
+}
+
+impl<T, C> core::fmt::Debug for EvalIter<'_, T, C> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("EvalIter")
+            .field("remaining", &self.elements.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T: DeserializeOwned, C> Iterator for EvalIter<'_, T, C> {
+    type Item = Result<T, anyhow::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let range = self.elements.next()?;
+        let data = self.opa.env_buffer.data(&self.opa.store);
+        Some(serde_json::from_slice(&data[range]).map_err(Into::into))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.elements.size_hint()
+    }
+}
+
+impl<T: DeserializeOwned, C> ExactSizeIterator for EvalIter<'_, T, C> {}
+
+impl<T, C> Drop for EvalIter<'_, T, C> {
+    fn drop(&mut self) {
+        if let Err(err) = self.opa.set_heap_ptr(self.opa.input_heap_ptr) {
+            #[allow(clippy::manual_assert)]
+            if !std::thread::panicking() {
+                panic!("{err:?}");
+            }
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use std::time::{Duration, Instant};
+
+/// Size and count metrics of evaluations, see [`Opa::metrics`](super::Opa::metrics).
+///
+/// Input sizes are recorded when the input is written,
+/// which happens once per [`EvalContext`](super::EvalContext).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct EvalMetrics {
+    /// The number of successful evaluations.
+    pub evaluations: u64,
+    /// The total size of all serialized inputs in bytes.
+    pub input_bytes: u64,
+    /// The total size of all serialized outputs in bytes.
+    pub output_bytes: u64,
+    /// The size of the largest serialized input in bytes.
+    pub max_input_bytes: u64,
+    /// The size of the largest serialized output in bytes.
+    pub max_output_bytes: u64,
+    /// The number of times the memory was grown to fit an input.
+    pub memory_grows: u64,
+}
+
+impl EvalMetrics {
+    pub(super) fn record_input(&mut self, len: usize) {
+        let len = len as u64;
+        self.input_bytes += len;
+        self.max_input_bytes = self.max_input_bytes.max(len);
+    }
+
+    pub(super) fn record_output(&mut self, len: usize) {
+        let len = len as u64;
+        self.evaluations += 1;
+        self.output_bytes += len;
+        self.max_output_bytes = self.max_output_bytes.max(len);
+    }
+}
+
+/// The time spent in each phase of an evaluation, see [`Opa::eval_timed`](super::Opa::eval_timed).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct EvalTiming {
+    /// Serializing the input to JSON.
+    pub serialization: Duration,
+    /// Writing the input into the WASM memory.
+    pub input: Duration,
+    /// The evaluation itself, including parsing the input in WASM.
+    pub evaluation: Duration,
+    /// Deserializing the results.
+    pub deserialization: Duration,
+    /// The total time of the evaluation.
+    pub total: Duration,
+}
+
+impl EvalTiming {
+    /// The phases as timer metrics in nanoseconds,
+    /// named like the metrics of the OPA REST API.
+    pub(super) fn timers(&self) -> [(&'static str, Duration); 5] {
+        [
+            ("timer_wasm_serialization_ns", self.serialization),
+            ("timer_wasm_input_ns", self.input),
+            ("timer_wasm_eval_ns", self.evaluation),
+            ("timer_wasm_deserialization_ns", self.deserialization),
+            ("timer_wasm_total_ns", self.total),
+        ]
+    }
+}
+
+/// Records the phases of an [`EvalTiming`], does nothing if there is none.
+pub(super) struct PhaseTimer<'t> {
+    timing: Option<(&'t mut EvalTiming, Instant)>,
+}
+
+impl<'t> PhaseTimer<'t> {
+    pub(super) fn new(timing: Option<&'t mut EvalTiming>) -> Self {
+        Self {
+            timing: timing.map(|timing| (timing, Instant::now())),
+        }
+    }
+
+    /// Add the time since the previous phase to the selected phase.
+    pub(super) fn phase(&mut self, select: impl FnOnce(&mut EvalTiming) -> &mut Duration) {
+        if let Some((timing, last)) = &mut self.timing {
+            let now = Instant::now();
+            *select(timing) += now - *last;
+            *last = now;
+        }
+    }
+}
@@ -1,31 +1,229 @@
 #![allow(clippy::cast_possible_truncation)]
 
 use crate::PolicyDecision;
-use anyhow::anyhow;
+use anyhow::{anyhow, Context as _};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::value::RawValue;
 use std::{
-    borrow::Cow, collections::HashMap, io::copy, mem::ManuallyDrop, string::String, sync::Arc,
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    io::{copy, ErrorKind, Read},
+    mem::ManuallyDrop,
+    string::String,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::Instant,
 };
+use thiserror::Error;
 use wasmtime::{Caller, Engine, Instance, Linker, Memory, MemoryType, Module, Store};
 
+mod builtins;
+#[cfg(feature = "wasm-cache")]
+mod cache;
+mod handle;
+mod iter;
+mod metrics;
+mod overlay;
+#[cfg(feature = "wasm-pooling")]
+mod pooling;
+mod replay;
+mod shared;
+
+pub use builtins::BuiltinHandler;
+#[cfg(feature = "wasm-cache")]
+pub use cache::CachingOpa;
+pub use handle::OpaValue;
+pub use iter::EvalIter;
+use metrics::PhaseTimer;
+pub use metrics::{EvalMetrics, EvalTiming};
+pub use replay::{replay, DecisionLogEntry};
+#[cfg(feature = "wasm-tokio")]
+pub use shared::AsyncSharedOpa;
+pub use shared::SharedOpa;
+
+#[cfg(feature = "wasm-schema")]
+mod schema;
+
+#[cfg(feature = "wasm-schema")]
+pub use schema::SchemaError;
+
+/// The entrypoint of the default decision.
+const DEFAULT_DECISION_ENTRYPOINT: &str = "system/main";
+
+/// The major version of the OPA WASM ABI supported by this crate.
+const SUPPORTED_ABI_MAJOR_VERSION: i32 = 1;
+
+/// The default of [`OpaBuilder::max_message_bytes`].
+const DEFAULT_MAX_MESSAGE_BYTES: usize = 64 * 1024;
+
+/// The size of the chunks read by [`Opa::set_data_from_reader`].
+const DATA_CHUNK_BYTES: usize = 64 * 1024;
+
+/// The maximum number of characters of JSON shown in deserialization errors.
+const JSON_PREVIEW_CHARS: usize = 200;
+
+/// Exports required by every supported ABI version.
+const REQUIRED_EXPORTS: &[&str] = &[
+    "entrypoints",
+    "opa_json_dump",
+    "opa_json_parse",
+    "opa_malloc",
+    "opa_heap_ptr_get",
+    "opa_heap_ptr_set",
+];
+
+/// Exports required by [`EvalStrategy::Context`].
+const CONTEXT_EXPORTS: &[&str] = &[
+    "opa_eval_ctx_new",
+    "opa_eval_ctx_set_input",
+    "opa_eval_ctx_set_data",
+    "opa_eval_ctx_set_entrypoint",
+    "opa_eval_ctx_get_result",
+    "eval",
+];
+
 type StrHandler = Box<dyn Fn(&str) + Send + Sync>;
+type ValueHandler = Box<dyn Fn(&serde_json::Value) + Send + Sync>;
+type AbortHandler = Box<dyn Fn(&str) -> AbortAction + Send + Sync>;
+
+/// Errors returned by OPA during evaluation.
+///
+/// These are wrapped in [`anyhow::Error`] and can
+/// be retrieved with [`anyhow::Error::downcast_ref`].
+#[derive(Debug, Error)]
+pub enum Error {
+    /// OPA aborted the evaluation.
+    #[error("OPA abort was called: {0}")]
+    Abort(String),
+    /// The decision at the entrypoint is undefined,
+    /// see [`Opa::eval_required`].
+    #[error("the decision at `{0}` is undefined")]
+    Undefined(String),
+}
+
+/// How policies are evaluated, see [`Opa::eval_strategy`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvalStrategy {
+    /// A single `opa_eval` call, available since ABI version 1.2.
+    SinglePass,
+    /// Separate calls to set up an evaluation context and evaluate it.
+    ///
+    /// This is used for older modules, and for modules that
+    /// report ABI version 1.2 but do not export `opa_eval`.
+    Context,
+}
+
+/// Determines what happens after an OPA abort.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AbortAction {
+    /// Panic with the abort message.
+    Panic,
+    /// Stop the evaluation and return [`Error::Abort`].
+    Error,
+    /// Ignore the abort and continue.
+    Ignore,
+}
 
-#[derive(Default)]
-pub struct OpaBuilder {
-    abort_cb: Option<StrHandler>,
+/// Builds [`OpaWithContext`] instances, see [`Opa::new`].
+///
+/// `C` is the type of the context that is passed to builtin
+/// handlers, see [`OpaWithContext::eval_with_context`].
+#[allow(clippy::struct_excessive_bools)]
+pub struct OpaBuilder<C = ()> {
+    abort_cb: Option<AbortHandler>,
     println_cb: Option<StrHandler>,
+    print_value_cb: Option<ValueHandler>,
+    buffer_initial_mem_pages: Option<u32>,
     buffer_max_mem_pages: Option<u32>,
     engine: Engine,
+    required_entrypoints: Vec<String>,
+    input_scratch_bytes: usize,
+    capture_print_output: bool,
+    auto_set_bundle_data: bool,
+    require_entrypoints_present: bool,
+    builtins: HashMap<String, BuiltinHandler<C>>,
+    require_builtins: bool,
+    fixed_time: Option<i64>,
+    max_message_bytes: Option<usize>,
+    #[cfg(feature = "wasm-schema")]
+    schemas: schema::Schemas,
+}
+
+// Not derived, as the context does not need a default for this.
+impl<C> Default for OpaBuilder<C> {
+    fn default() -> Self {
+        Self {
+            abort_cb: None,
+            println_cb: None,
+            print_value_cb: None,
+            buffer_initial_mem_pages: None,
+            buffer_max_mem_pages: None,
+            engine: Engine::default(),
+            required_entrypoints: Vec::default(),
+            input_scratch_bytes: 0,
+            capture_print_output: false,
+            auto_set_bundle_data: false,
+            require_entrypoints_present: false,
+            builtins: HashMap::default(),
+            require_builtins: false,
+            fixed_time: None,
+            max_message_bytes: None,
+            #[cfg(feature = "wasm-schema")]
+            schemas: schema::Schemas::default(),
+        }
+    }
+}
+
+impl<C> core::fmt::Debug for OpaBuilder<C> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("OpaBuilder")
+            .field("abort_cb", &self.abort_cb.is_some())
+            .field("println_cb", &self.println_cb.is_some())
+            .field("print_value_cb", &self.print_value_cb.is_some())
+            .field("buffer_initial_mem_pages", &self.buffer_initial_mem_pages)
+            .field("buffer_max_mem_pages", &self.buffer_max_mem_pages)
+            .field("required_entrypoints", &self.required_entrypoints)
+            .field("input_scratch_bytes", &self.input_scratch_bytes)
+            .field("capture_print_output", &self.capture_print_output)
+            .field("auto_set_bundle_data", &self.auto_set_bundle_data)
+            .field(
+                "require_entrypoints_present",
+                &self.require_entrypoints_present,
+            )
+            .field("builtins", &self.builtins.keys().collect::<Vec<_>>())
+            .field("require_builtins", &self.require_builtins)
+            .field("fixed_time", &self.fixed_time)
+            .field("max_message_bytes", &self.max_message_bytes)
+            .finish_non_exhaustive()
+    }
 }
 
-impl OpaBuilder {
+impl<C: Default + 'static> OpaBuilder<C> {
     /// Set a handler function for OPA aborts.
     ///
-    /// If not set, the default handler will panic on abort.
+    /// The abort is ignored after the handler returns.
+    ///
+    /// If not set, aborts are returned as [`Error::Abort`].
     #[must_use]
     pub fn on_abort<F>(mut self, f: F) -> Self
     where
         F: Fn(&str) + Send + Sync + 'static,
+    {
+        self.abort_cb = Some(Box::new(move |s| {
+            f(s);
+            AbortAction::Ignore
+        }));
+        self
+    }
+
+    /// Set a handler function for OPA aborts that decides
+    /// how the abort is handled.
+    ///
+    /// If not set, aborts are returned as [`Error::Abort`],
+    /// return [`AbortAction::Panic`] to panic instead.
+    #[must_use]
+    pub fn on_abort_result<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&str) -> AbortAction + Send + Sync + 'static,
     {
         self.abort_cb = Some(Box::new(f));
         self
@@ -41,18 +239,231 @@ impl OpaBuilder {
         self
     }
 
+    /// Log the output of the builtin `print` function with [`tracing::debug!`]
+    /// and aborts with [`tracing::error!`], both with the `opa` target.
+    ///
+    /// Aborts are still returned as [`Error::Abort`]. The events are recorded
+    /// within the span that is current when the policy is evaluated, which
+    /// can be used to add context, e.g. the policy or the request.
+    ///
+    /// Handlers set with [`Self::on_println`] or [`Self::on_abort`]
+    /// afterwards override the respective handler.
+    #[cfg(feature = "tracing")]
+    #[must_use]
+    pub fn println_to_tracing(self) -> Self {
+        self.on_println(|s| tracing::debug!(target: "opa", "{s}"))
+            .on_abort_result(|s| {
+                tracing::error!(target: "opa", "OPA abort was called: {s}");
+                AbortAction::Error
+            })
+    }
+
+    /// Set a handler for the builtin `print` function that receives
+    /// the printed values as JSON.
+    ///
+    /// Composite values printed by a policy are JSON-encoded by OPA,
+    /// these are parsed and passed to the handler as structured data.
+    /// Output that is not valid JSON, such as plain text or multiple
+    /// printed arguments, is passed as a [`serde_json::Value::String`].
+    ///
+    /// This takes precedence over [`Self::on_println`].
+    #[must_use]
+    pub fn on_print_value<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&serde_json::Value) + Send + Sync + 'static,
+    {
+        self.print_value_cb = Some(Box::new(f));
+        self
+    }
+
+    /// Capture the output of the builtin `print` function instead of
+    /// passing it to the handlers, it can be retrieved with [`Opa::take_last_output`].
+    ///
+    /// Only the output of the most recent evaluation is kept, it is
+    /// cleared at the start of every evaluation.
+    ///
+    /// This takes precedence over [`Self::on_println`] and [`Self::on_print_value`].
+    #[must_use]
+    pub fn capture_print_output(mut self, enabled: bool) -> Self {
+        self.capture_print_output = enabled;
+        self
+    }
+
+    /// Freeze the clock of the time builtins at the given
+    /// nanoseconds since the Unix epoch, e.g. for testing policies
+    /// that depend on `time.now_ns` deterministically.
+    ///
+    /// The time can be changed later with [`Opa::set_time`],
+    /// the current time is used if it is not set.
+    #[must_use]
+    pub fn fixed_time(mut self, nanos: i64) -> Self {
+        self.fixed_time = Some(nanos);
+        self
+    }
+
+    /// Set the maximum length of abort and `print` messages in bytes.
+    ///
+    /// Longer messages are truncated and end with `[truncated]`,
+    /// so that a message without a null terminator, e.g. in
+    /// corrupted memory, does not scan the entire memory.
+    ///
+    /// Defaults to 64 KiB.
+    #[must_use]
+    pub fn max_message_bytes(mut self, max: usize) -> Self {
+        self.max_message_bytes = Some(max);
+        self
+    }
+
+    /// Set the initial size of the WASM memory in 64 KiB pages.
+    ///
+    /// The memory grows as needed during [`Opa::set_data`] and evaluation,
+    /// pre-allocating it avoids growing the memory repeatedly when large
+    /// data is loaded. The initial size must not exceed [`Self::max_memory_pages`].
+    ///
+    /// Defaults to the minimum declared by the module, see [`Opa::declared_memory_pages`].
+    /// Smaller values are raised to the declared minimum.
+    #[must_use]
+    pub fn initial_memory_pages(mut self, pages: u32) -> Self {
+        self.buffer_initial_mem_pages = Some(pages);
+        self
+    }
+
+    /// Reserve memory for inputs of up to the given size in bytes
+    /// whenever data is set.
+    ///
+    /// Inputs are written to the same region after the data for every
+    /// evaluation, reserving it up front avoids growing the memory
+    /// during evaluations, larger inputs still grow it as needed.
+    ///
+    /// Defaults to 0.
+    #[must_use]
+    pub fn input_scratch_bytes(mut self, bytes: usize) -> Self {
+        self.input_scratch_bytes = bytes;
+        self
+    }
+
     #[must_use]
     pub fn max_memory_pages(mut self, opt: impl Into<Option<u32>>) -> Self {
         self.buffer_max_mem_pages = opt.into();
         self
     }
 
+    /// Set the JSON schema for [`Opa::validate_input`].
+    ///
+    /// When building from a bundle, this overrides the
+    /// `schemas/input.json` schema of the bundle.
+    #[cfg(feature = "wasm-schema")]
+    #[must_use]
+    pub fn input_schema(mut self, schema: serde_json::Value) -> Self {
+        self.schemas.input = Some(schema);
+        self
+    }
+
+    /// Set the JSON schema of the data document for [`Opa::data_schema`].
+    ///
+    /// When building from a bundle, this overrides the
+    /// `schemas/data.json` schema of the bundle.
+    #[cfg(feature = "wasm-schema")]
+    #[must_use]
+    pub fn data_schema(mut self, schema: serde_json::Value) -> Self {
+        self.schemas.data = Some(schema);
+        self
+    }
+
+    /// Set the data of the bundle with [`Opa::set_data`]
+    /// when building from a bundle with [`Self::build_from_bundle`].
+    ///
+    /// Data files in subdirectories of the bundle are placed
+    /// at the path of their directory, e.g. the data of
+    /// `example/users/data.json` is available at `data.example.users`.
+    #[cfg(feature = "bundle")]
+    #[must_use]
+    pub fn auto_set_bundle_data(mut self, enabled: bool) -> Self {
+        self.auto_set_bundle_data = enabled;
+        self
+    }
+
+    /// Fail the build if the module does not expose all of the given entrypoints.
+    ///
+    /// This catches modules built without the right `-e` flags
+    /// before the first evaluation.
+    #[must_use]
+    pub fn require_entrypoints<S, I>(mut self, entrypoints: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.required_entrypoints
+            .extend(entrypoints.into_iter().map(Into::into));
+        self
+    }
+
+    /// Fail the build if the module does not expose any entrypoints,
+    /// which happens if the policy was built without `-e`.
+    ///
+    /// Disabled by default, as modules without entrypoints are valid.
+    /// With the `tracing` feature a warning is logged for them instead.
+    #[must_use]
+    pub fn require_entrypoints_present(mut self, enabled: bool) -> Self {
+        self.require_entrypoints_present = enabled;
+        self
+    }
+
+    /// Set the handler for a builtin function that is not
+    /// implemented by the module, e.g. `http.send`.
+    ///
+    /// Builtins without a handler return undefined, see
+    /// [`Self::require_builtins`] and [`Opa::check_builtins`].
+    /// A handler for `time.now_ns` overrides the one of this crate.
+    #[must_use]
+    pub fn builtin<F>(self, name: impl Into<String>, f: F) -> Self
+    where
+        F: Fn(&[serde_json::Value]) -> Result<serde_json::Value, anyhow::Error>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.builtin_with_context(name, move |_, args| f(args))
+    }
+
+    /// Same as [`Self::builtin`], but the handler also receives the context
+    /// of the instance, see [`OpaWithContext::eval_with_context`].
+    #[must_use]
+    pub fn builtin_with_context<F>(mut self, name: impl Into<String>, f: F) -> Self
+    where
+        F: Fn(&C, &[serde_json::Value]) -> Result<serde_json::Value, anyhow::Error>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.builtins.insert(name.into(), Box::new(f));
+        self
+    }
+
+    /// Fail the build if the module uses builtins that have
+    /// no handler set with [`Self::builtin`].
+    ///
+    /// This catches policies built with capabilities the
+    /// runtime does not support before the first evaluation.
+    #[must_use]
+    pub fn require_builtins(mut self, enabled: bool) -> Self {
+        self.require_builtins = enabled;
+        self
+    }
+
     #[must_use]
     pub fn with_engine(mut self, engine: Engine) -> Self {
         self.engine = engine;
         self
     }
 
+    /// The engine the instance is built with, e.g. for compiling
+    /// modules or building other instances with the same engine.
+    #[must_use]
+    pub fn engine(&self) -> &Engine {
+        &self.engine
+    }
+
     /// Build the OPA WASM instance from a module in a bundle.
     ///
     /// # Errors
@@ -60,30 +471,117 @@ impl OpaBuilder {
     /// The bundle must contain at least one compiled WASM module.
     /// The OPA module will be initialized with any error returned.
     #[cfg(feature = "bundle")]
-    pub fn build_from_bundle(self, bundle: &crate::bundle::Bundle) -> Result<Opa, anyhow::Error> {
+    pub fn build_from_bundle(
+        self,
+        bundle: &crate::bundle::Bundle,
+    ) -> Result<OpaWithContext<C>, anyhow::Error> {
+        self.build_bundle_module(bundle, None)
+    }
+
+    /// Build the OPA WASM instance from the WASM module at the given
+    /// position in [`Bundle::wasm_policies`](crate::bundle::Bundle::wasm_policies).
+    ///
+    /// [`Self::build_from_bundle`] always uses the first module,
+    /// this allows using the others in bundles with multiple modules.
+    /// Precompiled modules in the bundle are not used.
+    ///
+    /// # Errors
+    ///
+    /// The index must be smaller than [`Bundle::wasm_policy_count`](crate::bundle::Bundle::wasm_policy_count).
+    /// The OPA module will be initialized with any error returned.
+    #[cfg(all(feature = "bundle", feature = "wasmtime-cranelift"))]
+    pub fn build_index_from_bundle(
+        self,
+        bundle: &crate::bundle::Bundle,
+        index: usize,
+    ) -> Result<OpaWithContext<C>, anyhow::Error> {
+        self.build_bundle_module(bundle, Some(index))
+    }
+
+    /// Build the instance from the module at the index, or the
+    /// default module of the bundle, and set its data if enabled.
+    #[cfg(feature = "bundle")]
+    #[cfg_attr(not(feature = "wasm-schema"), allow(unused_mut))]
+    fn build_bundle_module(
+        mut self,
+        bundle: &crate::bundle::Bundle,
+        index: Option<usize>,
+    ) -> Result<OpaWithContext<C>, anyhow::Error> {
+        #[cfg(feature = "wasm-schema")]
+        self.schemas.fill_from_bundle(bundle);
+
+        let module = self.bundle_module(bundle, index)?;
+        let auto_set_data = self.auto_set_bundle_data;
+        let mut opa = self.build_module(module)?;
+
+        if auto_set_data {
+            opa.set_bundle_data(bundle)?;
+        }
+
+        Ok(opa)
+    }
+
+    #[cfg(feature = "bundle")]
+    #[cfg_attr(not(feature = "wasmtime-cranelift"), allow(unused_variables))]
+    fn bundle_module(
+        &self,
+        bundle: &crate::bundle::Bundle,
+        index: Option<usize>,
+    ) -> Result<Module, anyhow::Error> {
+        #[cfg(feature = "wasmtime-cranelift")]
+        if let Some(index) = index {
+            let policy = bundle.wasm_policies.get(index).ok_or_else(|| {
+                anyhow!(
+                    "there is no WASM module at index {index}, the bundle contains {} WASM modules",
+                    bundle.wasm_policy_count()
+                )
+            })?;
+
+            return Module::from_binary(&self.engine, &policy.bytes);
+        }
+
         #[cfg(feature = "wasmtime-aot")]
-        {
-            match &bundle.wasmtime_bytes {
-                Some(b) => {
+        #[allow(clippy::single_match)]
+        match &bundle.wasmtime_bytes {
+            Some(b) => match bundle.wasmtime_target.as_deref() {
+                // The module is compiled from the bundle instead.
+                Some(target) if !is_host_target(target) => {
+                    #[cfg(not(feature = "wasmtime-cranelift"))]
+                    return Err(anyhow!(
+                        "the WASM module was precompiled for `{target}` but the host is `{}`, \
+                         enable the `wasmtime-cranelift` feature to compile it at runtime instead",
+                        target_lexicon::HOST
+                    ));
+                }
+                _ => {
                     // SAFETY: The bytes can be provided via
                     // an unsafe function for a bundle, if that
                     // is safe, this is safe as well.
-                    let module = unsafe { Module::deserialize(&self.engine, b)? };
-                    return self.build_module(module);
+                    return unsafe { Module::deserialize(&self.engine, b) };
                 }
-                None => {}
-            }
+            },
+            None => {}
         }
 
+        if bundle.wasm_policies.is_empty() && !bundle.rego_policies.is_empty() {
+            return Err(anyhow!(
+                "the bundle contains {} Rego policies but no WASM module, \
+                 rebuild it with `opa build -t wasm` or use the HTTP API instead",
+                bundle.rego_policies.len()
+            ));
+        }
 
         #[cfg(feature = "wasmtime-cranelift")]
         {
-            return self.build(
+            return Module::from_binary(
+                &self.engine,
                 &bundle
                     .wasm_policies
                     .first()
                     .as_ref()
-                    .ok_or_else(|| anyhow::anyhow!("the bundle must at least one WASM module"))?
+                    .ok_or_else(|| {
+                        anyhow::anyhow!("the bundle must contain at least one WASM module")
+                    })?
                     .bytes,
             );
         }
@@ -98,81 +596,102 @@ impl OpaBuilder {
     ///
     /// The OPA module will be initialized with any error returned.
     #[cfg(feature = "wasmtime-cranelift")]
-    pub fn build(self, wasm_bytes: impl AsRef<[u8]>) -> Result<Opa, anyhow::Error> {
+    pub fn build(self, wasm_bytes: impl AsRef<[u8]>) -> Result<OpaWithContext<C>, anyhow::Error> {
         let m = Module::from_binary(&self.engine, wasm_bytes.as_ref())?;
         self.build_module(m)
     }
 
+    /// Build the OPA WASM instance from a `.wasm` file,
+    /// e.g. the `policy.wasm` of a bundle built with `opa build -t wasm`.
+    ///
+    /// # Errors
+    ///
+    /// The file must be readable and contain a WASM module,
+    /// the OPA module will be initialized with any error returned.
+    #[cfg(feature = "wasmtime-cranelift")]
+    pub fn build_from_wasm_file(
+        self,
+        path: impl AsRef<std::path::Path>,
+    ) -> Result<OpaWithContext<C>, anyhow::Error> {
+        let path = path.as_ref();
+        let bytes =
+            std::fs::read(path).with_context(|| format!("failed to read `{}`", path.display()))?;
+
+        if !bytes.starts_with(b"\0asm") {
+            return Err(anyhow!(
+                "`{}` is not a WASM module, bundles must be loaded \
+                 with `Bundle::from_file` and `build_from_bundle` instead",
+                path.display()
+            ));
+        }
+
+        self.build(bytes)
+    }
+
     #[allow(clippy::needless_pass_by_value)]
-    fn build_module(self, module: Module) -> Result<Opa, anyhow::Error> {
+    fn build_module(self, module: Module) -> Result<OpaWithContext<C>, anyhow::Error> {
+        #[cfg(feature = "wasm-schema")]
+        let input_schema = self
+            .schemas
+            .input
+            .as_ref()
+            .map(schema::compile)
+            .transpose()?;
+        #[cfg(feature = "wasm-schema")]
+        let schemas = self.schemas;
+
         let engine = self.engine;
-        let mut linker = Linker::<()>::new(&engine);
-        let mut store = Store::new(&engine, ());
-        let env_buffer = Memory::new(&mut store, MemoryType::new(2, self.buffer_max_mem_pages))?;
+        let mut linker = Linker::<C>::new(&engine);
+        let mut store = Store::new(&engine, C::default());
+        let declared_memory_pages = declared_memory_pages(&module);
+        let env_buffer = Memory::new(
+            &mut store,
+            memory_type(
+                declared_memory_pages,
+                self.buffer_initial_mem_pages,
+                self.buffer_max_mem_pages,
+            )?,
+        )?;
 
-        let on_abort = Arc::<Box<dyn Fn(&str) + Send + Sync>>::from(
-            self.abort_cb.unwrap_or_else(|| Box::new(default_opa_abort)),
+        let on_abort = Arc::<AbortHandler>::from(
+            self.abort_cb
+                .unwrap_or_else(|| Box::new(|_| AbortAction::Error)),
         );
-        let on_abort1 = on_abort.clone();
-        let on_println = self
-            .println_cb
-            .unwrap_or_else(|| Box::new(default_opa_println));
+        let print_output = self
+            .capture_print_output
+            .then(|| Arc::new(Mutex::new(Vec::new())));
+        let on_println: StrHandler = match (&print_output, self.print_value_cb) {
+            (Some(print_output), _) => {
+                let print_output = print_output.clone();
+                Box::new(move |s| lock_output(&print_output).push(s.to_string()))
+            }
+            (None, Some(on_print_value)) => {
+                Box::new(move |s| on_print_value(&parse_print_value(s)))
+            }
+            (None, None) => self
+                .println_cb
+                .unwrap_or_else(|| Box::new(default_opa_println)),
+        };
 
         // https://www.openpolicyagent.org/docs/latest/wasm/#memory-buffer
         linker.define("env", "memory", env_buffer)?;
 
-        // https://www.openpolicyagent.org/docs/latest/wasm/#imports
-        linker.func_wrap(
-            "env",
-            "opa_abort",
-            move |caller: Caller<'_, ()>, addr: u32| {
-                let addr = addr as usize;
-                let mem = env_buffer.data(&caller);
-                let s = null_terminated_str(&mem[addr..]).unwrap_or("invalid string in memory");
-                on_abort1(s);
-            },
-        )?;
-        linker.func_wrap(
-            "env",
-            "opa_println",
-            move |caller: Caller<'_, ()>, addr: u32| {
-                let addr = addr as usize;
-                let mem = env_buffer.data(&caller);
-                match null_terminated_str(&mem[addr..]) {
-                    Some(s) => on_println(s),
-                    None => on_abort("invalid string in memory"),
-                }
-            },
+        define_messages(
+            &mut linker,
+            env_buffer,
+            on_abort,
+            on_println,
+            self.max_message_bytes.unwrap_or(DEFAULT_MAX_MESSAGE_BYTES),
         )?;
 
-        // TODO: builtins are not supported for now.
-        linker.func_wrap("env", "opa_builtin0", move |_id: u32, _ctx: u32| 0_u32)?;
-        linker.func_wrap(
-            "env",
-            "opa_builtin1",
-            move |_id: u32, _ctx: u32, _1: u32| 0_u32,
-        )?;
-        linker.func_wrap(
-            "env",
-            "opa_builtin2",
-            move |_id: u32, _ctx: u32, _1: u32, _2: u32| 0_u32,
-        )?;
-        linker.func_wrap(
-            "env",
-            "opa_builtin3",
-            move |_id: u32, _ctx: u32, _1: u32, _2: u32, _3: u32| 0_u32,
-        )?;
-        linker.func_wrap(
-            "env",
-            "opa_builtin4",
-            move |_id: u32, _ctx: u32, _1: u32, _2: u32, _3: u32, _4: u32| 0_u32,
-        )?;
+        let builtins = Arc::new(builtins::Builtins::new(self.builtins, self.fixed_time));
+        define_builtins(&mut linker, env_buffer, &builtins)?;
 
         let instance = linker.instantiate(&mut store, &module)?;
 
         env_buffer.data(&mut store);
 
-        let mut opa = Opa {
+        let mut opa = OpaWithContext {
             store,
             instance,
             env_buffer,
@@ -180,35 +699,91 @@ impl OpaBuilder {
             data_heap_ptr: Addr(0),
             data_addr: None,
             input_heap_ptr: Addr(0),
+            base_data: None,
+            handle_scope: handle::HandleScope::new(),
             minor_version: 0,
+            declared_memory_pages,
+            eval_strategy: EvalStrategy::Context,
+            memory_size: 0,
+            input_scratch_bytes: self.input_scratch_bytes,
+            print_output,
+            builtins,
+            metrics: EvalMetrics::default(),
+            #[cfg(feature = "wasm-schema")]
+            input_schema,
+            #[cfg(feature = "wasm-schema")]
+            schemas,
         };
 
         opa.init()?;
 
+        opa.check_entrypoints_present(self.require_entrypoints_present)?;
+        opa.check_builtin_handlers(self.require_builtins)?;
+        opa.check_entrypoints(&self.required_entrypoints)?;
+
         Ok(opa)
     }
 }
 
+/// An OPA WASM policy instance.
+///
+/// Evaluation requires `&mut self`, so a single instance
+/// can only evaluate one policy at a time. To use it from multiple
+/// threads or async tasks, either create an instance for each of them,
+/// or share one with [`SharedOpa`] at the cost of serializing evaluations.
+pub type Opa = OpaWithContext<()>;
+
+/// An OPA WASM policy instance with a context of type `C`
+/// that is passed to builtin handlers.
+///
+/// The context is set for an evaluation with [`Self::eval_with_context`],
+/// e.g. for request-scoped data such as the current tenant, and is
+/// `C::default()` otherwise. See [`Opa`] for instances without a context.
 #[derive(Debug)]
-pub struct Opa {
-    store: Store<()>,
+pub struct OpaWithContext<C> {
+    store: Store<C>,
     instance: Instance,
     env_buffer: Memory,
 
     minor_version: usize,
+    declared_memory_pages: u32,
+    eval_strategy: EvalStrategy,
 
     entrypoints: HashMap<String, u32>,
 
     data_heap_ptr: Addr,
     data_addr: Option<Addr>,
     input_heap_ptr: Addr,
+
+    /// The last known size of the memory, it never shrinks.
+    memory_size: usize,
+    input_scratch_bytes: usize,
+
+    /// The current data, read back from the instance by [`Opa::eval_with_overlay`]
+    /// and [`Opa::set_data_at`] when first needed.
+    base_data: Option<serde_json::Value>,
+
+    /// The scope of the value handles that are currently valid.
+    handle_scope: handle::HandleScope,
+
+    /// The captured `print` output of the last evaluation.
+    print_output: Option<Arc<Mutex<Vec<String>>>>,
+
+    builtins: Arc<builtins::Builtins<C>>,
+
+    metrics: EvalMetrics,
+
+    #[cfg(feature = "wasm-schema")]
+    input_schema: Option<jsonschema::JSONSchema>,
+    #[cfg(feature = "wasm-schema")]
+    schemas: schema::Schemas,
 }
 
-impl Opa {
+impl<C> OpaWithContext<C> {
     /// Create a new [`OpaBuilder`] instance.
     #[allow(clippy::new_ret_no_self)]
     #[must_use]
-    pub fn new() -> OpaBuilder {
+    pub fn new() -> OpaBuilder<C> {
         OpaBuilder::default()
     }
 
@@ -217,45 +792,561 @@ impl Opa {
         self.entrypoints.keys().map(String::as_str)
     }
 
+    /// List the names of all exports of the WASM module,
+    /// e.g. `opa_eval`, `eval` or `opa_wasm_abi_version`.
+    ///
+    /// This is useful to find out why a module is rejected
+    /// or which evaluation strategy it supports.
+    #[must_use]
+    pub fn exports(&mut self) -> Vec<String> {
+        self.instance
+            .exports(&mut self.store)
+            .map(|export| export.name().to_string())
+            .collect()
+    }
+
+    /// The minimum size of the memory in 64 KiB pages
+    /// declared by the memory import of the module.
+    ///
+    /// The memory is created with at least this many pages, OPA
+    /// modules usually declare 2 pages.
+    #[must_use]
+    pub fn declared_memory_pages(&self) -> u32 {
+        self.declared_memory_pages
+    }
+
+    /// The evaluation strategy chosen based on the exports of the module.
+    #[must_use]
+    pub fn eval_strategy(&self) -> EvalStrategy {
+        self.eval_strategy
+    }
+
+    /// The number of entrypoints exposed by the module.
+    #[must_use]
+    pub fn entrypoint_count(&self) -> usize {
+        self.entrypoints.len()
+    }
+
+    /// Whether the entrypoint exists, either in the `.` or `/` separated form.
+    #[must_use]
+    pub fn entrypoint_exists(&self, entrypoint: &str) -> bool {
+        self.entrypoints
+            .contains_key(crate::normalize_policy_path(entrypoint).as_ref())
+    }
+
+    /// Check that the entrypoints declared in the manifest of the bundle
+    /// are the same as the ones exposed by the module.
+    ///
+    /// The entrypoints of every WASM module in the manifest are compared,
+    /// so this is meant for bundles with a single module.
+    ///
+    /// # Errors
+    ///
+    /// The sorted `/` separated entrypoints that are only declared
+    /// in the manifest or only exposed by the module are returned,
+    /// use [`Self::entrypoint_exists`] to tell them apart.
+    #[cfg(feature = "bundle")]
+    pub fn verify_against_manifest(
+        &self,
+        bundle: &crate::bundle::Bundle,
+    ) -> Result<(), Vec<String>> {
+        let declared: std::collections::HashSet<String> = bundle
+            .manifest
+            .iter()
+            .flat_map(|manifest| &manifest.wasm)
+            .map(|wasm| crate::normalize_policy_path(&wasm.entrypoint).into_owned())
+            .collect();
+
+        let mut mismatched: Vec<String> = declared
+            .iter()
+            .filter(|entrypoint| !self.entrypoints.contains_key(*entrypoint))
+            .chain(
+                self.entrypoints
+                    .keys()
+                    .filter(|entrypoint| !declared.contains(*entrypoint)),
+            )
+            .cloned()
+            .collect();
+
+        if mismatched.is_empty() {
+            return Ok(());
+        }
+
+        mismatched.sort_unstable();
+        Err(mismatched)
+    }
+
+    /// All available entrypoints grouped by their top-level package.
+    ///
+    /// The package segment is removed from the entrypoints,
+    /// e.g. `example/allow` is listed as `allow` under `example`.
+    /// Entrypoints without a package are listed under an empty key.
+    #[must_use]
+    pub fn entrypoints_tree(&self) -> BTreeMap<String, Vec<String>> {
+        let mut tree: BTreeMap<String, Vec<String>> = BTreeMap::new();
+
+        for entrypoint in self.entrypoints.keys() {
+            let (package, rule) = entrypoint.split_once('/').unwrap_or(("", entrypoint));
+            tree.entry(package.to_string())
+                .or_default()
+                .push(rule.to_string());
+        }
+
+        for rules in tree.values_mut() {
+            rules.sort();
+        }
+
+        tree
+    }
+
+    /// Whether data was set with [`Self::set_data`],
+    /// evaluation always fails until it is.
+    #[must_use]
+    pub fn is_data_set(&self) -> bool {
+        self.data_addr.is_some()
+    }
+
+    /// Metrics of all evaluations since the instance was
+    /// created or [`Self::reset_metrics`] was called.
+    #[must_use]
+    pub fn metrics(&self) -> EvalMetrics {
+        self.metrics
+    }
+
+    /// Take the `print` output of the most recent evaluation,
+    /// one entry for every call of `print`.
+    ///
+    /// The output is only captured if [`OpaBuilder::capture_print_output`]
+    /// is enabled, otherwise nothing is returned.
+    pub fn take_last_output(&mut self) -> Vec<String> {
+        self.print_output
+            .as_ref()
+            .map(|output| std::mem::take(&mut *lock_output(output)))
+            .unwrap_or_default()
+    }
+
+    fn clear_print_output(&self) {
+        if let Some(output) = &self.print_output {
+            lock_output(output).clear();
+        }
+    }
+
+    /// Freeze the clock of the time builtins at the given
+    /// nanoseconds since the Unix epoch for the following evaluations.
+    ///
+    /// See [`OpaBuilder::fixed_time`].
+    pub fn set_time(&mut self, nanos: i64) {
+        self.builtins.set_fixed_time(nanos);
+    }
+
+    /// Check that every builtin used by the module is either implemented
+    /// by this crate or has a handler in `provided`.
+    ///
+    /// The builtins are listed by the `builtins` export of the module,
+    /// so this detects policies built with capabilities the runtime
+    /// does not support, the handlers of this instance are not considered.
+    ///
+    /// # Errors
+    ///
+    /// The sorted names of the missing builtins are returned.
+    pub fn check_builtins(
+        &self,
+        provided: &HashMap<String, BuiltinHandler<C>>,
+    ) -> Result<(), Vec<String>> {
+        let missing = self.builtins.missing(provided);
+
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(missing)
+        }
+    }
+
+    /// Reset all evaluation metrics.
+    pub fn reset_metrics(&mut self) {
+        self.metrics = EvalMetrics::default();
+    }
+
     /// Set or override the contextual data for OPA.
     ///
-    /// Unlike the OPA HTTP API, the entire dataset must be
-    /// provided every time and no patching is possible.
+    /// Unlike the OPA HTTP API, the entire dataset must be provided,
+    /// use [`Self::set_data_at`] to set documents at different paths.
     ///
     /// # Errors
     ///
     /// Internal WASM errors are returned.
     pub fn set_data(&mut self, data: &impl Serialize) -> Result<(), anyhow::Error> {
+        self.set_data_bytes(&serde_json::to_vec(data)?)
+    }
+
+    /// Set the data of the bundle, if any, see [`OpaBuilder::auto_set_bundle_data`].
+    ///
+    /// The raw data of bundles read with
+    /// [`Bundle::from_reader_lazy_data`](crate::bundle::Bundle::from_reader_lazy_data)
+    /// is set without parsing it.
+    #[cfg(feature = "bundle")]
+    fn set_bundle_data(&mut self, bundle: &crate::bundle::Bundle) -> Result<(), anyhow::Error> {
+        if let Some(bytes) = bundle.data_bytes() {
+            self.set_data_bytes(bytes)
+        } else if let Some(data) = bundle.data() {
+            self.set_data(data)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Same as [`Self::set_data`], but the data is already serialized as JSON.
+    ///
+    /// This avoids serializing the same data for every instance
+    /// when multiple instances are used with the same data, the data
+    /// can be serialized once and the bytes set for each instance instead.
+    ///
+    /// # Errors
+    ///
+    /// The bytes must be valid JSON, the previous data is
+    /// no longer available if they are not.
+    ///
+    /// Internal WASM errors are also returned.
+    pub fn set_data_bytes(&mut self, json: &[u8]) -> Result<(), anyhow::Error> {
+        self.set_data_with(|opa| opa.write_json_bytes(json))
+    }
+
+    /// Same as [`Self::set_data_bytes`], but the JSON is read from the reader,
+    /// e.g. a file with a large dataset.
+    ///
+    /// The JSON is copied into the WASM memory in chunks as it is read,
+    /// growing the memory as needed, without building a [`serde_json::Value`]
+    /// or buffering the whole JSON outside of the WASM memory.
+    ///
+    /// # Errors
+    ///
+    /// The JSON must be valid, the previous data is
+    /// no longer available if it is not or reading fails.
+    ///
+    /// Internal WASM errors are also returned.
+    pub fn set_data_from_reader(&mut self, reader: impl Read) -> Result<(), anyhow::Error> {
+        self.set_data_with(|opa| {
+            let len = opa.read_data(reader)?;
+            opa.set_heap_ptr(Addr(opa.data_heap_ptr.0 + len))?;
+            opa.parse_json(opa.data_heap_ptr, len as usize)
+        })
+    }
+
+    /// Set the data parsed by `write`, which returns its address.
+    fn set_data_with(
+        &mut self,
+        write: impl FnOnce(&mut Self) -> Result<Addr, anyhow::Error>,
+    ) -> Result<(), anyhow::Error> {
         self.set_heap_ptr(self.data_heap_ptr)?;
+        self.data_addr = None;
+        self.base_data = None;
+        self.handle_scope.invalidate();
+
+        let data_addr = write(self)?;
+        if data_addr.0 == 0 {
+            self.set_heap_ptr(self.data_heap_ptr)?;
+            return Err(anyhow!("the data is not valid JSON"));
+        }
 
-        self.data_addr = Some(self.write_json(data)?);
+        self.data_addr = Some(data_addr);
         self.input_heap_ptr = self.heap_ptr()?;
+        self.reserve_input(self.input_scratch_bytes)?;
+
+        Ok(())
+    }
+
+    /// Evaluate a policy at the entrypoint with the given permissions.
+    ///
+    /// OPA returns a set of results for every evaluation, only the
+    /// last one is returned here, use [`Self::eval_all_results`]
+    /// to get all of them.
+    ///
+    /// # Errors
+    ///
+    /// The entrypoint must exist and the evaluation must produce at least one result.
+    ///
+    /// Data must be set at least once beforehand with [`Self::set_data`], otherwise evaluation will always fail.
+    ///
+    /// Internal WASM errors are also returned.
+    ///
+    /// # Object Key Order
+    ///
+    /// The keys of objects in [`serde_json::Value`] results are sorted alphabetically
+    /// unless the `preserve-order` feature is enabled, in which case they keep
+    /// the order produced by the policy. [`Self::eval_raw`] always keeps the order.
+    pub fn eval<I, O>(&mut self, entrypoint: &str, input: &I) -> Result<O, anyhow::Error>
+    where
+        I: Serialize,
+        O: DeserializeOwned,
+    {
+        self.eval_all_results(entrypoint, input)?
+            .pop()
+            .ok_or_else(|| anyhow!("the query produced no results"))
+    }
+
+    /// Same as [`Self::eval`], but builtin handlers receive the given context,
+    /// e.g. the current tenant or a database handle.
+    ///
+    /// The context is only set for this evaluation, the previous
+    /// context is restored afterwards, see [`Self::context`].
+    ///
+    /// # Errors
+    ///
+    /// See [`Self::eval`].
+    pub fn eval_with_context<I, O>(
+        &mut self,
+        entrypoint: &str,
+        input: &I,
+        context: C,
+    ) -> Result<O, anyhow::Error>
+    where
+        I: Serialize,
+        O: DeserializeOwned,
+    {
+        let previous = std::mem::replace(self.store.data_mut(), context);
+        let guard = ContextGuard {
+            opa: self,
+            previous: Some(previous),
+        };
+        guard.opa.eval(entrypoint, input)
+    }
+
+    /// The context passed to builtin handlers outside of
+    /// [`Self::eval_with_context`], `C::default()` initially.
+    #[must_use]
+    pub fn context(&self) -> &C {
+        self.store.data()
+    }
+
+    /// Same as [`Self::context`], but mutable, e.g. to set
+    /// the context for multiple evaluations.
+    pub fn context_mut(&mut self) -> &mut C {
+        self.store.data_mut()
+    }
+
+    /// Same as [`Self::eval`], but an undefined decision is returned
+    /// as [`Error::Undefined`].
+    ///
+    /// This is meant for fail-closed authorization, where an undefined
+    /// decision must never be mistaken for an allowed one.
+    ///
+    /// # Errors
+    ///
+    /// See [`Self::eval`], the [`Error::Undefined`] error can be
+    /// retrieved with [`anyhow::Error::downcast_ref`].
+    pub fn eval_required<I, O>(&mut self, entrypoint: &str, input: &I) -> Result<O, anyhow::Error>
+    where
+        I: Serialize,
+        O: DeserializeOwned,
+    {
+        self.eval_all_results(entrypoint, input)?
+            .pop()
+            .ok_or_else(|| Error::Undefined(entrypoint.to_string()).into())
+    }
+
+    /// Same as [`Self::eval`], but the input is serialized into `buf`
+    /// instead of a newly allocated buffer.
+    ///
+    /// The buffer is cleared before use and keeps its capacity,
+    /// so reusing it across calls avoids an allocation for each evaluation.
+    ///
+    /// # Errors
+    ///
+    /// See [`Self::eval`].
+    pub fn eval_with_buf<I, O>(
+        &mut self,
+        entrypoint: &str,
+        input: &I,
+        buf: &mut Vec<u8>,
+    ) -> Result<O, anyhow::Error>
+    where
+        I: Serialize,
+        O: DeserializeOwned,
+    {
+        buf.clear();
+        serde_json::to_writer(&mut *buf, input)?;
+
+        let mut results = if self.eval_strategy == EvalStrategy::SinglePass {
+            self.eval_once_bytes(entrypoint, buf, &mut PhaseTimer::new(None))?
+        } else {
+            let mut ctx = EvalContext::create_from_bytes(self, buf)?;
+            let res = ctx.eval_all_results(entrypoint)?;
+            ctx.destroy()?;
+            res
+        };
+
+        results
+            .pop()
+            .ok_or_else(|| anyhow!("the query produced no results"))
+    }
+
+    /// Same as [`Self::eval`], but the result is returned
+    /// as the compact JSON produced by OPA without deserializing it.
+    ///
+    /// # Errors
+    ///
+    /// See [`Self::eval`].
+    pub fn eval_raw<I: Serialize>(
+        &mut self,
+        entrypoint: &str,
+        input: &I,
+    ) -> Result<String, anyhow::Error> {
+        let raw: Box<RawValue> = self.eval(entrypoint, input)?;
+        Ok(raw.get().to_string())
+    }
+
+    /// Same as [`Self::eval_raw`], but the JSON is pretty-printed,
+    /// e.g. for logs meant for humans.
+    ///
+    /// The result is parsed again to format it, prefer
+    /// [`Self::eval_raw`] where performance matters.
+    ///
+    /// # Errors
+    ///
+    /// See [`Self::eval`].
+    pub fn eval_raw_pretty<I: Serialize>(
+        &mut self,
+        entrypoint: &str,
+        input: &I,
+    ) -> Result<String, anyhow::Error> {
+        let result: serde_json::Value = self.eval(entrypoint, input)?;
+        Ok(serde_json::to_string_pretty(&result)?)
+    }
+
+    /// Evaluate a policy at the entrypoint with the given permissions
+    /// and return every result in the result set.
+    ///
+    /// The returned results are empty if the decision is undefined.
+    ///
+    /// # Errors
+    ///
+    /// The entrypoint must exist.
+    ///
+    /// Data must be set at least once beforehand with [`Self::set_data`], otherwise evaluation will always fail.
+    ///
+    /// Internal WASM errors are also returned.
+    pub fn eval_all_results<I, O>(
+        &mut self,
+        entrypoint: &str,
+        input: &I,
+    ) -> Result<Vec<O>, anyhow::Error>
+    where
+        I: Serialize,
+        O: DeserializeOwned,
+    {
+        if self.eval_strategy == EvalStrategy::SinglePass {
+            return self.eval_once(entrypoint, input, None);
+        }
+
+        let mut ctx = EvalContext::create(self, input)?;
+        let res = ctx.eval_all_results(entrypoint)?;
+        ctx.destroy()?;
+        Ok(res)
+    }
+
+    /// Same as [`Self::eval`], also returning the time spent in each phase of the evaluation.
+    ///
+    /// With [`EvalStrategy::Context`] the evaluation is done in multiple
+    /// steps, for these only [`EvalTiming::total`] and [`EvalTiming::evaluation`]
+    /// are recorded.
+    ///
+    /// # Errors
+    ///
+    /// See [`Self::eval`].
+    pub fn eval_timed<I, O>(
+        &mut self,
+        entrypoint: &str,
+        input: &I,
+    ) -> Result<(O, EvalTiming), anyhow::Error>
+    where
+        I: Serialize,
+        O: DeserializeOwned,
+    {
+        let start = Instant::now();
+        let mut timing = EvalTiming::default();
+
+        let mut results = if self.eval_strategy == EvalStrategy::SinglePass {
+            self.eval_once(entrypoint, input, Some(&mut timing))?
+        } else {
+            let results = self.eval_all_results(entrypoint, input)?;
+            timing.evaluation = start.elapsed();
+            results
+        };
+
+        let result = results
+            .pop()
+            .ok_or_else(|| anyhow!("the query produced no results"))?;
+        timing.total = start.elapsed();
 
-        Ok(())
+        Ok((result, timing))
     }
 
-    /// Evaluate a policy at the entrypoint with the given permissions.
+    /// Same as [`Self::eval`], also returning metrics of the evaluation
+    /// in the same format as the metrics of decisions of the REST API.
     ///
-    /// # Errors
+    /// The metrics are:
     ///
-    /// The entrypoint must exist.
+    /// - Host-derived timers of [`Self::eval_timed`] in nanoseconds,
+    ///   `timer_wasm_serialization_ns`, `timer_wasm_input_ns`, `timer_wasm_eval_ns`,
+    ///   `timer_wasm_deserialization_ns` and `timer_wasm_total_ns`.
+    /// - Native metrics of the module, these are the values of its
+    ///   exported integer globals prefixed with `opa_` after the evaluation,
+    ///   except for the ABI version. Modules built by OPA usually have none.
     ///
-    /// Data must be set at least once beforehand with [`Self::set_data`], otherwise evaluation will always fail.
+    /// # Errors
     ///
-    /// Internal WASM errors are also returned.
-    pub fn eval<I, O>(&mut self, entrypoint: &str, input: &I) -> Result<O, anyhow::Error>
+    /// See [`Self::eval`].
+    pub fn eval_with_metrics<I, O>(
+        &mut self,
+        entrypoint: &str,
+        input: &I,
+    ) -> Result<(O, BTreeMap<String, serde_json::Value>), anyhow::Error>
     where
         I: Serialize,
         O: DeserializeOwned,
     {
-        if self.minor_version >= 2 {
-            return self.eval_once(entrypoint, input);
-        }
+        let (result, timing) = self.eval_timed(entrypoint, input)?;
+
+        let mut metrics: BTreeMap<String, serde_json::Value> = timing
+            .timers()
+            .into_iter()
+            .map(|(name, duration)| {
+                let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+                (name.to_string(), nanos.into())
+            })
+            .collect();
+        metrics.extend(
+            self.native_metrics()
+                .into_iter()
+                .map(|(name, value)| (name, value.into())),
+        );
 
-        let mut ctx = EvalContext::create(self, input)?;
-        let res = ctx.eval(entrypoint)?;
-        ctx.destroy()?;
-        Ok(res)
+        Ok((result, metrics))
+    }
+
+    /// The values of the exported `opa_` integer globals
+    /// other than the ABI version.
+    fn native_metrics(&mut self) -> Vec<(String, i64)> {
+        let globals: Vec<_> = self
+            .instance
+            .exports(&mut self.store)
+            .filter(|export| {
+                export.name().starts_with("opa_") && !export.name().starts_with("opa_wasm_abi_")
+            })
+            .filter_map(|export| {
+                let name = export.name().to_string();
+                export.into_global().map(|global| (name, global))
+            })
+            .collect();
+
+        globals
+            .into_iter()
+            .filter_map(|(name, global)| match global.get(&mut self.store) {
+                wasmtime::Val::I32(value) => Some((name, value.into())),
+                wasmtime::Val::I64(value) => Some((name, value)),
+                _ => None,
+            })
+            .collect()
     }
 
     /// Create an evaluation context ([`EvalContext`]) with the given input.
@@ -268,7 +1359,7 @@ impl Opa {
     pub fn eval_context<'c>(
         &'c mut self,
         input: &impl Serialize,
-    ) -> Result<EvalContext<'c>, anyhow::Error> {
+    ) -> Result<EvalContext<'c, C>, anyhow::Error> {
         EvalContext::create(self, input)
     }
 
@@ -287,10 +1378,61 @@ impl Opa {
     ) -> Result<P::Output, anyhow::Error> {
         self.eval(P::POLICY_PATH, input)
     }
+
+    /// Evaluate the default decision at `system/main`.
+    ///
+    /// The module must have been built with the `system/main` entrypoint.
+    ///
+    /// # Errors
+    ///
+    /// See [`Self::eval`].
+    pub fn eval_default<I, O>(&mut self, input: &I) -> Result<O, anyhow::Error>
+    where
+        I: Serialize,
+        O: DeserializeOwned,
+    {
+        if !self.entrypoints.contains_key(DEFAULT_DECISION_ENTRYPOINT) {
+            return Err(anyhow!(
+                "the module has no `{DEFAULT_DECISION_ENTRYPOINT}` entrypoint, \
+                 it must be built with `-e {DEFAULT_DECISION_ENTRYPOINT}` to evaluate the default decision"
+            ));
+        }
+
+        self.eval(DEFAULT_DECISION_ENTRYPOINT, input)
+    }
 }
 
-impl Opa {
+impl<C> OpaWithContext<C> {
     fn init(&mut self) -> Result<(), anyhow::Error> {
+        // Modules that predate the version global are assumed to be compatible.
+        if let Some(major) = self
+            .instance
+            .get_global(&mut self.store, "opa_wasm_abi_version")
+            .and_then(|global| global.get(&mut self.store).i32())
+        {
+            if major != SUPPORTED_ABI_MAJOR_VERSION {
+                return Err(anyhow!(
+                    "unsupported OPA WASM ABI major version {major} \
+                     (this crate supports {SUPPORTED_ABI_MAJOR_VERSION})"
+                ));
+            }
+        }
+
+        self.minor_version = self
+            .instance
+            .get_global(&mut self.store, "opa_wasm_abi_minor_version")
+            .and_then(|global| global.get(&mut self.store).i32())
+            .and_then(|int| int.try_into().ok())
+            .unwrap_or(0);
+
+        self.eval_strategy = if self.minor_version >= 2 && self.has_export("opa_eval") {
+            EvalStrategy::SinglePass
+        } else {
+            EvalStrategy::Context
+        };
+
+        self.check_exports()?;
+
         self.data_heap_ptr = self.heap_ptr()?;
         self.input_heap_ptr = self.data_heap_ptr;
 
@@ -300,16 +1442,120 @@ impl Opa {
         let ep_addr = opa_entrypoints.call(&mut self.store, ())?;
         self.entrypoints = self.json_at(ep_addr.into())?;
 
-        self.minor_version = self
-            .instance
-            .get_global(&mut self.store, "opa_wasm_abi_minor_version")
-            .and_then(|global| global.get(&mut self.store).i32())
-            .and_then(|int| int.try_into().ok())
-            .unwrap_or(0);
+        if self.has_export("builtins") {
+            let opa_builtins = self
+                .instance
+                .get_typed_func::<(), u32, _>(&mut self.store, "builtins")?;
+            let builtins_addr = opa_builtins.call(&mut self.store, ())?;
+            let ids: HashMap<String, u32> = self.json_at(builtins_addr.into())?;
+            self.builtins.set_ids(&ids);
+        }
+
+        Ok(())
+    }
+
+    fn has_export(&mut self, name: &str) -> bool {
+        self.instance.get_export(&mut self.store, name).is_some()
+    }
+
+    /// Check that the module exports every function
+    /// required by the evaluation strategy.
+    fn check_exports(&mut self) -> Result<(), anyhow::Error> {
+        let context_exports = match self.eval_strategy {
+            EvalStrategy::SinglePass => &[],
+            EvalStrategy::Context => CONTEXT_EXPORTS,
+        };
+
+        let missing: Vec<_> = REQUIRED_EXPORTS
+            .iter()
+            .chain(context_exports)
+            .copied()
+            .filter(|name| !self.has_export(name))
+            .collect();
+
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        let hint = if self.eval_strategy == EvalStrategy::Context && self.minor_version >= 2 {
+            ", `opa_eval` is not exported either"
+        } else {
+            ""
+        };
+
+        Err(anyhow!(
+            "the module does not export `{}` required by OPA ABI version 1.{}{hint}",
+            missing.join("`, `"),
+            self.minor_version,
+        ))
+    }
+
+    /// Check that the module exposes any entrypoints, only
+    /// logging a warning if they are not required.
+    fn check_entrypoints_present(&self, required: bool) -> Result<(), anyhow::Error> {
+        const MESSAGE: &str = "the module does not expose any entrypoints, \
+                               the policy must be built with `-e` for at least one of them";
+
+        if self.entrypoint_count() > 0 {
+            return Ok(());
+        }
+
+        if required {
+            return Err(anyhow!(MESSAGE));
+        }
+
+        #[cfg(feature = "tracing")]
+        tracing::warn!(target: "opa", "{MESSAGE}");
 
         Ok(())
     }
 
+    fn check_builtin_handlers(&self, required: bool) -> Result<(), anyhow::Error> {
+        if !required {
+            return Ok(());
+        }
+
+        let missing = self.builtins.missing_handlers();
+
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        Err(anyhow!(
+            "the module uses the builtins `{}` without handlers",
+            missing.join("`, `")
+        ))
+    }
+
+    fn check_entrypoints(&self, required: &[String]) -> Result<(), anyhow::Error> {
+        let missing: Vec<_> = required
+            .iter()
+            .filter(|entrypoint| !self.entrypoint_exists(entrypoint))
+            .map(String::as_str)
+            .collect();
+
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        let mut available: Vec<_> = self.entrypoints().collect();
+        available.sort_unstable();
+
+        Err(anyhow!(
+            "the module does not expose the required entrypoints `{}` (available: `{}`), \
+             the policy must be built with `-e` for each of them",
+            missing.join("`, `"),
+            available.join("`, `")
+        ))
+    }
+
+    /// The address of the data, or an error if it was not set yet.
+    fn require_data(&self) -> Result<Addr, anyhow::Error> {
+        self.data_addr.ok_or_else(|| {
+            anyhow!("no data provided, `set_data` must be called at least once first")
+        })
+    }
+
     fn bytes_at(&self, addr: Addr) -> Option<&[u8]> {
         let data = self.env_buffer.data(&self.store);
         null_terminated_slice(&data[addr.into()..])
@@ -322,30 +1568,117 @@ impl Opa {
     }
 
     fn json_at<T: DeserializeOwned>(&mut self, addr: Addr) -> Result<T, anyhow::Error> {
+        self.json_at_with_len(addr).map(|(value, _)| value)
+    }
+
+    /// Same as [`Self::json_at`], also returning the size of the JSON in bytes.
+    fn json_at_with_len<T: DeserializeOwned>(
+        &mut self,
+        addr: Addr,
+    ) -> Result<(T, usize), anyhow::Error> {
+        let json_addr = self.dump_json(addr)?;
+        let json = self.bytes_at(json_addr).unwrap();
+        let value = from_json_slice(json, format_args!("the value at {:#x}", addr.0))?;
+
+        Ok((value, json.len()))
+    }
+
+    /// Serialize the value at the address to JSON in the memory.
+    fn dump_json(&mut self, addr: Addr) -> Result<Addr, anyhow::Error> {
         let opa_json_dump = self
             .instance
             .get_typed_func::<(u32,), u32, _>(&mut self.store, "opa_json_dump")?;
 
-        let json_addr: Addr = opa_json_dump.call(&mut self.store, (addr.into(),))?.into();
-        let json_result = serde_json::from_slice::<T>(self.bytes_at(json_addr).unwrap());
-
-        Ok(json_result?)
+        Ok(opa_json_dump.call(&mut self.store, (addr.into(),))?.into())
     }
 
     fn write_json(&mut self, value: &impl Serialize) -> Result<Addr, anyhow::Error> {
+        let json = serde_json::to_vec(value)?;
+        self.write_json_bytes(&json)
+    }
+
+    fn write_json_bytes(&mut self, json: &[u8]) -> Result<Addr, anyhow::Error> {
+        let json_bytes_addr = self.write_bytes(json)?;
+        self.parse_json(json_bytes_addr, json.len())
+    }
+
+    fn parse_json(&mut self, addr: Addr, len: usize) -> Result<Addr, anyhow::Error> {
         let opa_json_parse = self
             .instance
             .get_typed_func::<(u32, u32), u32, _>(&mut self.store, "opa_json_parse")?;
 
-        let json = serde_json::to_vec(value)?;
-        let json_size = json.len();
+        let parsed_json_addr = opa_json_parse.call(&mut self.store, (addr.into(), len as _))?;
 
-        let json_bytes_addr = self.write_bytes(json)?;
+        Ok(parsed_json_addr.into())
+    }
 
-        let parsed_json_addr =
-            opa_json_parse.call(&mut self.store, (json_bytes_addr.into(), json_size as _))?;
+    /// Write the serialized input at the start of the input region
+    /// without allocating it on the OPA heap, returning its length.
+    ///
+    /// The heap must be moved past the input before OPA allocates anything.
+    fn write_input(&mut self, input: &[u8]) -> Result<u32, anyhow::Error> {
+        let input_len = u32::try_from(input.len())
+            .map_err(|err| anyhow::anyhow!("input data is too large: {err}"))?;
 
-        Ok(parsed_json_addr.into())
+        self.metrics.record_input(input.len());
+        self.reserve_input(input.len())?;
+
+        let input_idx = self.input_heap_ptr.0 as usize;
+        let data = self.env_buffer.data_mut(&mut self.store);
+        copy(&mut &*input, &mut &mut data[input_idx..])?;
+
+        Ok(input_len)
+    }
+
+    /// Read the JSON of the data into the memory at the start of the
+    /// data region without allocating it on the OPA heap, returning its length.
+    fn read_data(&mut self, mut reader: impl Read) -> Result<u32, anyhow::Error> {
+        let start = self.data_heap_ptr.0 as usize;
+        let mut len = 0;
+
+        loop {
+            let chunk_start = start + len;
+            self.reserve_memory(chunk_start + DATA_CHUNK_BYTES)?;
+
+            let data = self.env_buffer.data_mut(&mut self.store);
+            match reader.read(&mut data[chunk_start..chunk_start + DATA_CHUNK_BYTES]) {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        u32::try_from(len).map_err(|err| anyhow::anyhow!("the data is too large: {err}"))
+    }
+
+    /// Make sure that the memory can fit an input of the given size.
+    fn reserve_input(&mut self, len: usize) -> Result<(), anyhow::Error> {
+        if self.reserve_memory(self.input_heap_ptr.0 as usize + len)? {
+            self.metrics.memory_grows += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Make sure that the memory is at least the given size,
+    /// returning whether it was grown.
+    fn reserve_memory(&mut self, end: usize) -> Result<bool, anyhow::Error> {
+        if end <= self.memory_size {
+            return Ok(false);
+        }
+
+        self.memory_size = self.env_buffer.data_size(&self.store);
+
+        if self.memory_size >= end {
+            return Ok(false);
+        }
+
+        self.env_buffer
+            .grow(&mut self.store, round_up(end - self.memory_size) as _)?;
+        self.memory_size = self.env_buffer.data_size(&self.store);
+
+        Ok(true)
     }
 
     fn write_bytes(&mut self, bytes: impl AsRef<[u8]>) -> Result<Addr, anyhow::Error> {
@@ -382,11 +1715,52 @@ impl Opa {
         Ok(())
     }
 
-    fn eval_once<I, O>(&mut self, entrypoint: &str, input: &I) -> Result<O, anyhow::Error>
+    fn eval_once<I, O>(
+        &mut self,
+        entrypoint: &str,
+        input: &I,
+        timing: Option<&mut EvalTiming>,
+    ) -> Result<Vec<O>, anyhow::Error>
     where
         I: Serialize,
         O: DeserializeOwned,
     {
+        let mut timer = PhaseTimer::new(timing);
+
+        let input_bytes = serde_json::to_vec(input)?;
+        timer.phase(|t| &mut t.serialization);
+
+        self.eval_once_bytes(entrypoint, &input_bytes, &mut timer)
+    }
+
+    /// Same as [`Self::eval_once`] with the serialized input.
+    fn eval_once_bytes<O: DeserializeOwned>(
+        &mut self,
+        entrypoint: &str,
+        input: &[u8],
+        timer: &mut PhaseTimer<'_>,
+    ) -> Result<Vec<O>, anyhow::Error> {
+        let out_addr = self.eval_once_output(entrypoint, input, timer)?;
+
+        let (out, out_len) = self.parse_output(entrypoint, out_addr)?;
+        timer.phase(|t| &mut t.deserialization);
+        self.metrics.record_output(out_len);
+
+        self.set_heap_ptr(self.input_heap_ptr)?;
+
+        Ok(out)
+    }
+
+    /// Evaluate with `opa_eval` with the serialized input,
+    /// returning the address of the JSON output.
+    ///
+    /// The heap pointer is not reset, the output must be read first.
+    fn eval_once_output(
+        &mut self,
+        entrypoint: &str,
+        input_bytes: &[u8],
+        timer: &mut PhaseTimer<'_>,
+    ) -> Result<Addr, anyhow::Error> {
         let opa_eval = self.instance.get_typed_func::<(
             u32, // reserved
             u32, // entrypoint_id
@@ -397,29 +1771,14 @@ impl Opa {
             u32, // format
         ), u32, _>(&mut self.store, "opa_eval")?;
 
-        let data_addr = self.data_addr.ok_or_else(|| {
-            anyhow!("no data provided, `set_data` must be called at least once first")
-        })?;
-
-        let input_bytes = serde_json::to_vec(input)?;
-        let input_idx = self.input_heap_ptr.0 as usize;
-
-        let input_len = u32::try_from(input_bytes.len())
-            .map_err(|err| anyhow::anyhow!("input data is too large: {err}"))?;
-
-        if self.env_buffer.data_size(&mut self.store) < input_idx + input_bytes.len() {
-            let delta = round_up(
-                input_idx + input_bytes.len() - self.env_buffer.data_size(&mut self.store),
-            );
-
-            self.env_buffer.grow(&mut self.store, delta as _)?;
-        }
+        let data_addr = self.require_data()?;
 
-        let data = self.env_buffer.data_mut(&mut self.store);
-        copy(&mut &*input_bytes, &mut &mut data[input_idx..])?;
+        let input_len = self.write_input(input_bytes)?;
+        timer.phase(|t| &mut t.input);
 
         let entrypoint = self.entrypoint_id(entrypoint)?;
 
+        self.clear_print_output();
         let out_addr = opa_eval.call(
             &mut self.store,
             (
@@ -432,17 +1791,25 @@ impl Opa {
                 0,
             ),
         )?;
+        timer.phase(|t| &mut t.evaluation);
 
-        let mut out: Vec<OpaOutput<O>> = serde_json::from_slice(
-            self.bytes_at(Addr(out_addr))
-                .ok_or_else(|| anyhow::anyhow!("invalid output returned from evaluation"))?,
-        )?;
-
-        self.set_heap_ptr(self.input_heap_ptr)?;
+        Ok(Addr(out_addr))
+    }
 
-        out.pop()
-            .map(|v| v.result)
-            .ok_or_else(|| anyhow::anyhow!("evaluation yielded no result (it's most likely a bug)"))
+    /// Deserialize the results of an evaluation from the JSON output
+    /// at the address, also returning the size of the output in bytes.
+    fn parse_output<O: DeserializeOwned>(
+        &self,
+        entrypoint: &str,
+        addr: Addr,
+    ) -> Result<(Vec<O>, usize), anyhow::Error> {
+        let out_bytes = self
+            .bytes_at(addr)
+            .ok_or_else(|| anyhow::anyhow!("invalid output returned from evaluation"))?;
+        let out: Vec<OpaOutput<O>> =
+            from_json_slice(out_bytes, format_args!("the output of `{entrypoint}`"))?;
+
+        Ok((out.into_iter().map(|v| v.result).collect(), out_bytes.len()))
     }
 
     fn heap_ptr(&mut self) -> Result<Addr, anyhow::Error> {
@@ -463,11 +1830,7 @@ impl Opa {
     }
 
     fn entrypoint_id(&mut self, entrypoint: &str) -> Result<u32, anyhow::Error> {
-        let entrypoint = if entrypoint.contains('.') {
-            Cow::Owned(entrypoint.replace('.', "/"))
-        } else {
-            Cow::Borrowed(entrypoint)
-        };
+        let entrypoint = crate::normalize_policy_path(entrypoint);
 
         self.entrypoints
             .get(entrypoint.as_ref())
@@ -476,6 +1839,16 @@ impl Opa {
     }
 }
 
+#[cfg(feature = "bundle")]
+impl TryFrom<&crate::bundle::Bundle> for Opa {
+    type Error = anyhow::Error;
+
+    /// Same as [`OpaBuilder::build_from_bundle`] with the default options.
+    fn try_from(bundle: &crate::bundle::Bundle) -> Result<Self, Self::Error> {
+        Opa::new().build_from_bundle(bundle)
+    }
+}
+
 /// An evaluation context that allows evaluating multiple
 /// entrypoints multiple times with the same input.
 ///
@@ -485,13 +1858,46 @@ impl Opa {
 /// this can be done with the [`Self::destroy`] method.
 ///
 /// Data is also freed on drop, but in this case the **context will panic on failure**.
-pub struct EvalContext<'c> {
-    opa: &'c mut Opa,
+///
+/// In debug builds, evaluations return an error if the heap of the instance
+/// was moved since the context was created, e.g. if a previous evaluation
+/// did not finish because a handler panicked.
+pub struct EvalContext<'c, C = ()> {
+    opa: &'c mut OpaWithContext<C>,
     ctx_addr: Addr,
+    /// The heap pointer between evaluations,
+    /// it must not change during the lifetime of the context.
+    #[cfg(debug_assertions)]
+    heap_ptr: Addr,
 }
 
-impl<'c> EvalContext<'c> {
-    fn create(opa: &'c mut Opa, input: &impl Serialize) -> Result<Self, anyhow::Error> {
+impl<'c, C> EvalContext<'c, C> {
+    fn create(
+        opa: &'c mut OpaWithContext<C>,
+        input: &impl Serialize,
+    ) -> Result<Self, anyhow::Error> {
+        Self::create_from_bytes(opa, &serde_json::to_vec(input)?)
+    }
+
+    /// Create the context with the serialized input.
+    fn create_from_bytes(
+        opa: &'c mut OpaWithContext<C>,
+        input: &[u8],
+    ) -> Result<Self, anyhow::Error> {
+        opa.require_data()?;
+
+        let input_len = opa.write_input(input)?;
+        opa.set_heap_ptr(Addr(opa.input_heap_ptr.0 + input_len))?;
+        let input_addr = opa.parse_json(opa.input_heap_ptr, input.len())?;
+
+        Self::create_with_input_addr(opa, input_addr)
+    }
+
+    /// Create the context with an input that is already in the memory.
+    fn create_with_input_addr(
+        opa: &'c mut OpaWithContext<C>,
+        input_addr: Addr,
+    ) -> Result<Self, anyhow::Error> {
         let opa_eval_ctx_new = opa
             .instance
             .get_typed_func::<(), u32, _>(&mut opa.store, "opa_eval_ctx_new")?;
@@ -502,12 +1908,7 @@ impl<'c> EvalContext<'c> {
             .instance
             .get_typed_func::<(u32, u32), (), _>(&mut opa.store, "opa_eval_ctx_set_data")?;
 
-        opa.set_heap_ptr(opa.input_heap_ptr)?;
-
-        let data_addr = opa.data_addr.ok_or_else(|| {
-            anyhow!("no data provided, `set_data` must be called at least once first")
-        })?;
-        let input_addr = opa.write_json(input)?;
+        let data_addr = opa.require_data()?;
 
         let ctx_addr = opa_eval_ctx_new.call(&mut opa.store, ())?;
 
@@ -515,6 +1916,8 @@ impl<'c> EvalContext<'c> {
         opa_eval_ctx_set_input.call(&mut opa.store, (ctx_addr, input_addr.into()))?;
 
         Ok(EvalContext {
+            #[cfg(debug_assertions)]
+            heap_ptr: opa.heap_ptr()?,
             opa,
             ctx_addr: ctx_addr.into(),
         })
@@ -522,15 +1925,64 @@ impl<'c> EvalContext<'c> {
 
     /// Evaluate a policy at the entrypoint.
     ///
+    /// Only the last result of the result set is returned,
+    /// see [`Self::eval_all_results`].
+    ///
     /// # Errors
     ///
-    /// The entrypoint must exist.
+    /// The entrypoint must exist and the evaluation must produce at least one result.
     ///
     /// Deserialization errors and internal WASM errors are also returned.
     pub fn eval<O>(&mut self, entrypoint: &str) -> Result<O, anyhow::Error>
     where
         O: DeserializeOwned,
     {
+        self.eval_all_results(entrypoint)?
+            .pop()
+            .ok_or_else(|| anyhow!("the query produced no results"))
+    }
+
+    /// Evaluate a policy at the entrypoint and return every result in the result set.
+    ///
+    /// # Errors
+    ///
+    /// The entrypoint must exist.
+    ///
+    /// Deserialization errors and internal WASM errors are also returned.
+    pub fn eval_all_results<O>(&mut self, entrypoint: &str) -> Result<Vec<O>, anyhow::Error>
+    where
+        O: DeserializeOwned,
+    {
+        let start_heap = self.opa.heap_ptr()?;
+
+        // An evaluation that did not finish, e.g. because of a panic
+        // in a handler, leaves the heap in an unknown state.
+        #[cfg(debug_assertions)]
+        if start_heap.0 != self.heap_ptr.0 {
+            return Err(anyhow!(
+                "the heap was moved since the evaluation context was created \
+                 (expected {:#x}, found {:#x}), the context can no longer be used",
+                self.heap_ptr.0,
+                start_heap.0
+            ));
+        }
+
+        let result = self
+            .eval_output(entrypoint)
+            .and_then(|addr| self.opa.parse_output(entrypoint, addr));
+
+        self.opa.set_heap_ptr(start_heap)?;
+
+        let (result, len) = result?;
+        self.opa.metrics.record_output(len);
+
+        Ok(result)
+    }
+
+    /// Evaluate the entrypoint, returning the address of the JSON output.
+    ///
+    /// The heap pointer is not reset, the output must be read first.
+    fn eval_output(&mut self, entrypoint: &str) -> Result<Addr, anyhow::Error> {
         let opa_eval_ctx_set_entrypoint = self.opa.instance.get_typed_func::<(u32, u32), (), _>(
             &mut self.opa.store,
             "opa_eval_ctx_set_entrypoint",
@@ -550,25 +2002,13 @@ impl<'c> EvalContext<'c> {
         opa_eval_ctx_set_entrypoint
             .call(&mut self.opa.store, (self.ctx_addr.into(), entrypoint_id))?;
 
-        let start_heap = self.opa.heap_ptr()?;
-
+        self.opa.clear_print_output();
         opa_eval.call(&mut self.opa.store, (self.ctx_addr.into(),))?;
 
         let result_addr =
             opa_eval_ctx_get_result.call(&mut self.opa.store, (self.ctx_addr.into(),))?;
 
-        // TODO: this will return an array of results (OpaOutput<_>)
-        //      I'm not sure about the reason for this, but for now we are only interested
-        //      in the first one.
-        let result: Result<Vec<OpaOutput<O>>, _> = self.opa.json_at(result_addr.into());
-
-        self.opa.set_heap_ptr(start_heap)?;
-
-        result.and_then(|mut out| {
-            out.pop()
-                .map(|r| r.result)
-                .ok_or_else(|| anyhow!("the query produced no results"))
-        })
+        self.opa.dump_json(result_addr.into())
     }
 
     /// Destroy and free the eval context.
@@ -588,7 +2028,7 @@ impl<'c> EvalContext<'c> {
     }
 }
 
-impl Drop for EvalContext<'_> {
+impl<C> Drop for EvalContext<'_, C> {
     fn drop(&mut self) {
         if let Err(err) = self.destroy_mut() {
             #[allow(clippy::manual_assert)]
@@ -599,6 +2039,21 @@ impl Drop for EvalContext<'_> {
     }
 }
 
+/// Restores the previous context of [`OpaWithContext::eval_with_context`]
+/// when dropped, even if a builtin handler panics.
+struct ContextGuard<'o, C> {
+    opa: &'o mut OpaWithContext<C>,
+    previous: Option<C>,
+}
+
+impl<C> Drop for ContextGuard<'_, C> {
+    fn drop(&mut self) {
+        if let Some(previous) = self.previous.take() {
+            *self.opa.store.data_mut() = previous;
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 #[repr(transparent)]
 struct Addr(u32);
@@ -638,8 +2093,200 @@ fn null_terminated_str(slice: &[u8]) -> Option<&str> {
         .and_then(|end| std::str::from_utf8(&slice[0..end]).ok())
 }
 
-fn default_opa_abort(error: &str) {
-    panic!("OPA abort was called: {error}");
+/// Read a message of at most `max` bytes for `opa_abort` and `opa_println`.
+///
+/// Longer messages are truncated at a character boundary, `None` is
+/// returned if the message is not valid UTF-8 or has no null terminator.
+fn message_str(slice: &[u8], max: usize) -> Option<Cow<'_, str>> {
+    let bounded = &slice[..slice.len().min(max)];
+
+    if let Some(end) = bounded.iter().position(|b| *b == 0) {
+        return std::str::from_utf8(&bounded[..end]).ok().map(Cow::Borrowed);
+    }
+
+    if bounded.len() == slice.len() {
+        return None;
+    }
+
+    let valid = match std::str::from_utf8(bounded) {
+        Ok(s) => s,
+        // Only the last character is cut off.
+        Err(err) if err.error_len().is_none() => {
+            std::str::from_utf8(&bounded[..err.valid_up_to()]).ok()?
+        }
+        Err(_) => return None,
+    };
+
+    Some(Cow::Owned(format!("{valid}... [truncated]")))
+}
+
+fn handle_abort(on_abort: &AbortHandler, error: &str) -> Result<(), anyhow::Error> {
+    match on_abort(error) {
+        AbortAction::Panic => panic!("OPA abort was called: {error}"),
+        AbortAction::Error => Err(Error::Abort(error.to_string()).into()),
+        AbortAction::Ignore => Ok(()),
+    }
+}
+
+// https://www.openpolicyagent.org/docs/latest/wasm/#imports
+fn define_messages<C: 'static>(
+    linker: &mut Linker<C>,
+    memory: Memory,
+    on_abort: Arc<AbortHandler>,
+    on_println: StrHandler,
+    max_message_bytes: usize,
+) -> Result<(), anyhow::Error> {
+    let on_abort1 = on_abort.clone();
+    linker.func_wrap(
+        "env",
+        "opa_abort",
+        move |caller: Caller<'_, C>, addr: u32| {
+            let mem = memory.data(&caller);
+            let s = message_str(&mem[addr as usize..], max_message_bytes);
+            handle_abort(
+                &on_abort1,
+                s.as_deref().unwrap_or("invalid string in memory"),
+            )
+        },
+    )?;
+    linker.func_wrap(
+        "env",
+        "opa_println",
+        move |caller: Caller<'_, C>, addr: u32| {
+            let mem = memory.data(&caller);
+            match message_str(&mem[addr as usize..], max_message_bytes) {
+                Some(s) => {
+                    on_println(&s);
+                    Ok(())
+                }
+                None => handle_abort(&on_abort, "invalid string in memory"),
+            }
+        },
+    )?;
+
+    Ok(())
+}
+
+fn define_builtins<C: 'static>(
+    linker: &mut Linker<C>,
+    memory: Memory,
+    builtins: &Arc<builtins::Builtins<C>>,
+) -> Result<(), anyhow::Error> {
+    let b = builtins.clone();
+    linker.func_wrap(
+        "env",
+        "opa_builtin0",
+        move |mut caller: Caller<'_, C>, id: u32, _ctx: u32| b.call(&mut caller, memory, id, &[]),
+    )?;
+    let b = builtins.clone();
+    linker.func_wrap(
+        "env",
+        "opa_builtin1",
+        move |mut caller: Caller<'_, C>, id: u32, _ctx: u32, a1: u32| {
+            b.call(&mut caller, memory, id, &[a1])
+        },
+    )?;
+    let b = builtins.clone();
+    linker.func_wrap(
+        "env",
+        "opa_builtin2",
+        move |mut caller: Caller<'_, C>, id: u32, _ctx: u32, a1: u32, a2: u32| {
+            b.call(&mut caller, memory, id, &[a1, a2])
+        },
+    )?;
+    let b = builtins.clone();
+    linker.func_wrap(
+        "env",
+        "opa_builtin3",
+        move |mut caller: Caller<'_, C>, id: u32, _ctx: u32, a1: u32, a2: u32, a3: u32| {
+            b.call(&mut caller, memory, id, &[a1, a2, a3])
+        },
+    )?;
+    let b = builtins.clone();
+    linker.func_wrap(
+        "env",
+        "opa_builtin4",
+        move |mut caller: Caller<'_, C>, id: u32, _ctx: u32, a1: u32, a2: u32, a3: u32, a4: u32| {
+            b.call(&mut caller, memory, id, &[a1, a2, a3, a4])
+        },
+    )?;
+
+    Ok(())
+}
+
+/// Whether code compiled for the target triple can run on this host.
+#[cfg(feature = "wasmtime-aot")]
+fn is_host_target(target: &str) -> bool {
+    target
+        .parse::<target_lexicon::Triple>()
+        .is_ok_and(|target| target == target_lexicon::HOST)
+}
+
+/// Deserialize JSON read from the memory, the error contains
+/// the size of the JSON and the beginning of it.
+///
+/// The [`serde_json::Error`] is kept as the source of the error.
+fn from_json_slice<T: DeserializeOwned>(
+    json: &[u8],
+    what: std::fmt::Arguments<'_>,
+) -> Result<T, anyhow::Error> {
+    serde_json::from_slice(json).with_context(|| {
+        let text = String::from_utf8_lossy(json);
+        let preview: String = text.chars().take(JSON_PREVIEW_CHARS).collect();
+        let ellipsis = if preview.len() < text.len() {
+            "..."
+        } else {
+            ""
+        };
+
+        format!(
+            "failed to deserialize {what} ({} bytes): {preview}{ellipsis}",
+            json.len()
+        )
+    })
+}
+
+fn parse_print_value(value: &str) -> serde_json::Value {
+    serde_json::from_str(value).unwrap_or_else(|_| serde_json::Value::String(value.to_string()))
+}
+
+fn lock_output(output: &Mutex<Vec<String>>) -> MutexGuard<'_, Vec<String>> {
+    output.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// The type of the memory, with at least as many pages as the module declares.
+fn memory_type(
+    declared_pages: u32,
+    initial_pages: Option<u32>,
+    max_pages: Option<u32>,
+) -> Result<MemoryType, anyhow::Error> {
+    let initial_pages = initial_pages.map_or(declared_pages, |pages| pages.max(declared_pages));
+
+    if let Some(max_pages) = max_pages {
+        if declared_pages > max_pages {
+            return Err(anyhow!(
+                "the module requires at least {declared_pages} memory pages, \
+                 but the maximum is {max_pages}"
+            ));
+        }
+
+        if initial_pages > max_pages {
+            return Err(anyhow!(
+                "initial memory pages ({initial_pages}) exceed the maximum memory pages ({max_pages})"
+            ));
+        }
+    }
+
+    Ok(MemoryType::new(initial_pages, max_pages))
+}
+
+/// The minimum pages of the `env.memory` import of the module, 2 if it is not declared.
+fn declared_memory_pages(module: &Module) -> u32 {
+    module
+        .imports()
+        .find(|import| import.module() == "env" && import.name() == "memory")
+        .and_then(|import| import.ty().memory().map(MemoryType::minimum))
+        .map_or(2, |pages| u32::try_from(pages).unwrap_or(u32::MAX))
 }
 
 fn default_opa_println(value: &str) {
@@ -651,6 +2298,7 @@ struct OpaOutput<R> {
     result: R,
 }
 
+#[allow(clippy::manual_is_multiple_of)]
 fn round_up(bytes: usize) -> usize {
     const PAGE_SIZE: usize = 64 * 1024;
     bytes / PAGE_SIZE + usize::from(bytes % PAGE_SIZE != 0)
@@ -0,0 +1,167 @@
+use super::{Addr, OpaWithContext};
+use anyhow::anyhow;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{Map, Value};
+
+impl<C> OpaWithContext<C> {
+    /// Evaluate a policy with the overlay deep-merged onto the current data,
+    /// without changing the data used by later evaluations.
+    ///
+    /// Objects in the overlay are merged recursively, all other values
+    /// replace the value at the same path in the data.
+    ///
+    /// The current data is read back from the instance on first use and
+    /// kept until the next [`Self::set_data`] call, the merged data is
+    /// discarded after the evaluation.
+    ///
+    /// # Errors
+    ///
+    /// See [`Self::eval`].
+    pub fn eval_with_overlay<I, O>(
+        &mut self,
+        entrypoint: &str,
+        input: &I,
+        overlay: &Value,
+    ) -> Result<O, anyhow::Error>
+    where
+        I: Serialize,
+        O: DeserializeOwned,
+    {
+        let data_addr = self.require_data()?;
+        let input_heap_ptr = self.input_heap_ptr;
+
+        let result = self.eval_overlay_data(data_addr, entrypoint, input, overlay);
+
+        self.data_addr = Some(data_addr);
+        self.input_heap_ptr = input_heap_ptr;
+        self.set_heap_ptr(input_heap_ptr)?;
+
+        result
+    }
+
+    /// Evaluate a policy with the input placed at the path of the data
+    /// instead of `input`, for policies that read it from the data, e.g.
+    /// from `data.request` with the path `request`.
+    ///
+    /// This is not how policies usually receive their input, most
+    /// policies should be evaluated with [`Self::eval`] instead.
+    ///
+    /// The path is `/` separated like for [`Self::set_data_at`], the input
+    /// is merged onto the data like the overlay of [`Self::eval_with_overlay`]
+    /// and the policy is evaluated with an empty input object.
+    ///
+    /// # Errors
+    ///
+    /// See [`Self::eval`].
+    pub fn eval_input_at<I, O>(
+        &mut self,
+        entrypoint: &str,
+        input: &I,
+        path: &str,
+    ) -> Result<O, anyhow::Error>
+    where
+        I: Serialize,
+        O: DeserializeOwned,
+    {
+        let mut overlay = serde_json::to_value(input)?;
+
+        for segment in path.rsplit('/').filter(|s| !s.is_empty()) {
+            overlay = Value::Object(Map::from_iter([(segment.to_string(), overlay)]));
+        }
+
+        self.eval_with_overlay(entrypoint, &Map::new(), &overlay)
+    }
+
+    fn eval_overlay_data<I, O>(
+        &mut self,
+        data_addr: Addr,
+        entrypoint: &str,
+        input: &I,
+        overlay: &Value,
+    ) -> Result<O, anyhow::Error>
+    where
+        I: Serialize,
+        O: DeserializeOwned,
+    {
+        let mut data = self.base_data(data_addr)?.clone();
+
+        merge(&mut data, overlay);
+
+        self.data_addr = Some(self.write_json(&data)?);
+        self.input_heap_ptr = self.heap_ptr()?;
+
+        self.eval(entrypoint, input)
+    }
+
+    /// Set the document at the path within the current data, replacing
+    /// any previous document at the path, like `PUT /v1/data/{path}`
+    /// of the OPA HTTP API.
+    ///
+    /// The path is `/` separated, e.g. `users/alice`, missing objects
+    /// along the path are created. An empty path replaces all the data.
+    ///
+    /// The current data is read back from the instance on first use and
+    /// kept until the next [`Self::set_data`] call, the whole data is
+    /// written to the instance again after every change.
+    ///
+    /// # Errors
+    ///
+    /// Every parent of the document must be an object.
+    ///
+    /// Internal WASM errors are also returned.
+    pub fn set_data_at(&mut self, path: &str, value: &impl Serialize) -> Result<(), anyhow::Error> {
+        let value = serde_json::to_value(value)?;
+
+        let mut data = match self.data_addr {
+            Some(data_addr) => self.base_data(data_addr)?.clone(),
+            None => Value::Object(Map::new()),
+        };
+
+        let mut target = &mut data;
+        for segment in path.split('/').filter(|s| !s.is_empty()) {
+            target = match target {
+                Value::Object(object) => object
+                    .entry(segment)
+                    .or_insert_with(|| Value::Object(Map::new())),
+                _ => {
+                    return Err(anyhow!(
+                        "cannot set data at `{path}`, the parent of `{segment}` is not an object"
+                    ))
+                }
+            };
+        }
+        *target = value;
+
+        self.set_data_bytes(&serde_json::to_vec(&data)?)?;
+        self.base_data = Some(data);
+
+        Ok(())
+    }
+
+    /// The current data, read back from the instance if it is not known yet.
+    fn base_data(&mut self, data_addr: Addr) -> Result<&Value, anyhow::Error> {
+        if self.base_data.is_none() {
+            let data: Value = self.json_at(data_addr)?;
+            self.set_heap_ptr(self.input_heap_ptr)?;
+            self.base_data = Some(data);
+        }
+
+        Ok(self.base_data.get_or_insert_with(Value::default))
+    }
+}
+
+fn merge(base: &mut Value, overlay: &Value) {
+    match (base, overlay) {
+        (Value::Object(base), Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(key) {
+                    Some(base_value) => merge(base_value, value),
+                    None => {
+                        base.insert(key.clone(), value.clone());
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay.clone(),
+    }
+}
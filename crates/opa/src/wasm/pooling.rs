@@ -0,0 +1,35 @@
+use super::OpaBuilder;
+use wasmtime::{Config, Engine, InstanceAllocationStrategy, PoolingAllocationConfig};
+
+impl<C> OpaBuilder<C> {
+    /// Use a new engine with the pooling instance allocator of `wasmtime`
+    /// configured by `config`.
+    ///
+    /// Instantiating modules from a pool is faster than allocating every instance
+    /// on demand, which matters when many short-lived instances are created.
+    /// The pool belongs to the engine, so the same engine must be used for all
+    /// instances, see [`Self::engine`] and [`Self::with_engine`].
+    ///
+    /// The space for all instances is reserved up front when the engine is
+    /// created, trading memory usage for speed. Building more instances than
+    /// the pool allows at the same time fails until some of them are dropped.
+    ///
+    /// The memory of a policy is created by this crate and imported by the module,
+    /// so it is not part of the pool and is not limited by it, see
+    /// [`Self::max_memory_pages`] instead. Policies do not define memories
+    /// of their own, so `instance_memories(0)` avoids reserving space for them.
+    ///
+    /// # Errors
+    ///
+    /// Errors are returned if the engine cannot be created.
+    pub fn with_pooling_allocator(
+        mut self,
+        config: PoolingAllocationConfig,
+    ) -> Result<Self, anyhow::Error> {
+        let mut engine_config = Config::new();
+        engine_config.allocation_strategy(InstanceAllocationStrategy::Pooling(config));
+
+        self.engine = Engine::new(&engine_config)?;
+        Ok(self)
+    }
+}
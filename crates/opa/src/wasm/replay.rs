@@ -0,0 +1,67 @@
+use super::Opa;
+use crate::{normalize_policy_path, PolicyDecision};
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// A decision logged by OPA's decision logs.
+///
+/// See <https://www.openpolicyagent.org/docs/latest/management-decision-logs/>.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct DecisionLogEntry {
+    /// The unique ID of the decision.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub decision_id: Option<String>,
+    /// The path of the policy decision, e.g. `example/allow`.
+    #[serde(default)]
+    pub path: String,
+    /// The input of the decision, if it was not erased.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub input: Option<Value>,
+    /// The result of the decision, missing if it was undefined.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    /// The time of the decision in RFC 3339 format.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<String>,
+    /// Any other fields of the entry, e.g. `labels` or `metrics`.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// Evaluate the input of a logged decision again, and return
+/// whether the decision is different from the logged one.
+///
+/// This can be used to check how a new version of a
+/// policy changes the decisions made for real inputs.
+///
+/// An undefined decision is only equal to another undefined decision.
+///
+/// # Errors
+///
+/// The path of the entry must be [`PolicyDecision::POLICY_PATH`] and the new
+/// result must be a valid [`PolicyDecision::Output`].
+///
+/// Evaluation errors are also returned, see [`Opa::eval`].
+pub fn replay<P: PolicyDecision>(
+    opa: &mut Opa,
+    entry: &DecisionLogEntry,
+) -> Result<bool, anyhow::Error> {
+    let path = normalize_policy_path(P::POLICY_PATH);
+
+    if normalize_policy_path(&entry.path) != path {
+        return Err(anyhow!(
+            "the decision log entry is for `{}`, not `{path}`",
+            entry.path
+        ));
+    }
+
+    let result: Option<Value> = opa.eval_all_results(&path, &entry.input)?.pop();
+
+    if let Some(result) = &result {
+        P::Output::deserialize(result)?;
+    }
+
+    Ok(result != entry.result)
+}
@@ -0,0 +1,91 @@
+use super::OpaWithContext;
+use jsonschema::JSONSchema;
+use serde_json::Value;
+use thiserror::Error;
+
+/// Errors returned by [`Opa::validate_input`].
+#[derive(Debug, Error)]
+pub enum SchemaError {
+    /// The input schema itself is invalid.
+    #[error("invalid schema: {0}")]
+    InvalidSchema(String),
+    /// The input does not match the schema.
+    #[error("the input does not match the schema: {}", .0.join(", "))]
+    InvalidInput(Vec<String>),
+}
+
+/// The JSON schemas of the documents of an instance.
+#[derive(Debug, Clone, Default)]
+pub(super) struct Schemas {
+    pub(super) input: Option<Value>,
+    pub(super) data: Option<Value>,
+}
+
+impl Schemas {
+    /// Use the schemas of the bundle unless they were set explicitly.
+    #[cfg(feature = "bundle")]
+    pub(super) fn fill_from_bundle(&mut self, bundle: &crate::bundle::Bundle) {
+        if self.input.is_none() {
+            self.input = bundle.input_schema().cloned();
+        }
+
+        if self.data.is_none() {
+            self.data = bundle.data_schema().cloned();
+        }
+    }
+}
+
+pub(super) fn compile(schema: &Value) -> Result<JSONSchema, SchemaError> {
+    JSONSchema::compile(schema).map_err(|err| SchemaError::InvalidSchema(err.to_string()))
+}
+
+impl<C> OpaWithContext<C> {
+    /// The JSON schema of the input document, if any.
+    ///
+    /// The schema is either set with [`OpaBuilder::input_schema`](super::OpaBuilder::input_schema)
+    /// or taken from `schemas/input.json` of the bundle the instance was built from.
+    ///
+    /// OPA does not embed schemas in WASM modules, so instances built
+    /// from modules outside of bundles only have explicitly set schemas.
+    #[must_use]
+    pub fn input_schema(&self) -> Option<&Value> {
+        self.schemas.input.as_ref()
+    }
+
+    /// The JSON schema of the data document, if any.
+    ///
+    /// The schema is either set with [`OpaBuilder::data_schema`](super::OpaBuilder::data_schema)
+    /// or taken from `schemas/data.json` of the bundle the instance was built from.
+    ///
+    /// The data is not validated against the schema.
+    #[must_use]
+    pub fn data_schema(&self) -> Option<&Value> {
+        self.schemas.data.as_ref()
+    }
+
+    /// Validate the input document against the input schema.
+    ///
+    /// The schema is either set with [`OpaBuilder::input_schema`](super::OpaBuilder::input_schema)
+    /// or taken from `schemas/input.json` of the bundle the instance was built from.
+    /// Validation always succeeds if there is no input schema.
+    ///
+    /// OPA only uses schemas for type checking when policies are compiled,
+    /// this allows checking inputs at runtime before evaluation.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned with all validation errors if the input does not match the schema.
+    pub fn validate_input(&self, input: &Value) -> Result<(), SchemaError> {
+        let Some(schema) = &self.input_schema else {
+            return Ok(());
+        };
+
+        schema.validate(input).map_err(|errors| {
+            SchemaError::InvalidInput(
+                errors
+                    .map(|err| format!("{err} at `{}`", err.instance_path))
+                    .collect(),
+            )
+        })
+    }
+}
@@ -0,0 +1,117 @@
+use super::Opa;
+use crate::PolicyDecision;
+use serde::{de::DeserializeOwned, Serialize};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+/// A cloneable handle to an [`Opa`] instance that can be shared between threads.
+///
+/// Evaluations are serialized with a [`Mutex`], so only a single
+/// evaluation runs at a time across all clones of the handle.
+/// This is the simplest way to use a single instance from multiple threads,
+/// separate instances should be used if throughput matters.
+///
+/// The lock is recovered if a previous evaluation panicked
+/// (e.g. with [`AbortAction::Panic`](super::AbortAction::Panic)).
+#[derive(Debug, Clone)]
+pub struct SharedOpa(Arc<Mutex<Opa>>);
+
+impl SharedOpa {
+    /// Wrap the OPA instance.
+    #[must_use]
+    pub fn new(opa: Opa) -> Self {
+        Self(Arc::new(Mutex::new(opa)))
+    }
+
+    /// Lock the instance for exclusive use,
+    /// e.g. for multiple evaluations with the same [`EvalContext`](super::EvalContext).
+    pub fn lock(&self) -> MutexGuard<'_, Opa> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Same as [`Opa::set_data`].
+    ///
+    /// # Errors
+    ///
+    /// See [`Opa::set_data`].
+    pub fn set_data(&self, data: &impl Serialize) -> Result<(), anyhow::Error> {
+        self.lock().set_data(data)
+    }
+
+    /// Same as [`Opa::eval`].
+    ///
+    /// # Errors
+    ///
+    /// See [`Opa::eval`].
+    pub fn eval<I, O>(&self, entrypoint: &str, input: &I) -> Result<O, anyhow::Error>
+    where
+        I: Serialize,
+        O: DeserializeOwned,
+    {
+        self.lock().eval(entrypoint, input)
+    }
+
+    /// Same as [`Opa::decide`].
+    ///
+    /// # Errors
+    ///
+    /// See [`Opa::decide`].
+    pub fn decide<P: PolicyDecision>(&self, input: &P::Input) -> Result<P::Output, anyhow::Error> {
+        self.lock().decide::<P>(input)
+    }
+}
+
+/// Same as [`SharedOpa`], but locked with an asynchronous [`tokio::sync::Mutex`].
+///
+/// This allows holding the lock across `.await` points in async handlers
+/// via [`AsyncSharedOpa::lock`]. Evaluation itself is still blocking.
+#[cfg(feature = "wasm-tokio")]
+#[derive(Debug, Clone)]
+pub struct AsyncSharedOpa(Arc<tokio::sync::Mutex<Opa>>);
+
+#[cfg(feature = "wasm-tokio")]
+impl AsyncSharedOpa {
+    /// Wrap the OPA instance.
+    #[must_use]
+    pub fn new(opa: Opa) -> Self {
+        Self(Arc::new(tokio::sync::Mutex::new(opa)))
+    }
+
+    /// Lock the instance for exclusive use.
+    pub async fn lock(&self) -> tokio::sync::MutexGuard<'_, Opa> {
+        self.0.lock().await
+    }
+
+    /// Same as [`Opa::set_data`].
+    ///
+    /// # Errors
+    ///
+    /// See [`Opa::set_data`].
+    pub async fn set_data(&self, data: &impl Serialize) -> Result<(), anyhow::Error> {
+        self.lock().await.set_data(data)
+    }
+
+    /// Same as [`Opa::eval`].
+    ///
+    /// # Errors
+    ///
+    /// See [`Opa::eval`].
+    pub async fn eval<I, O>(&self, entrypoint: &str, input: &I) -> Result<O, anyhow::Error>
+    where
+        I: Serialize,
+        O: DeserializeOwned,
+    {
+        self.lock().await.eval(entrypoint, input)
+    }
+
+    /// Same as [`Opa::decide`].
+    ///
+    /// # Errors
+    ///
+    /// See [`Opa::decide`].
+    pub async fn decide<P: PolicyDecision>(
+        &self,
+        input: &P::Input,
+    ) -> Result<P::Output, anyhow::Error> {
+        self.lock().await.decide::<P>(input)
+    }
+}
@@ -0,0 +1,551 @@
+use common::TestEnv;
+use std::fs;
+
+mod common;
+
+#[test]
+fn test_empty_policy_sources() {
+    let env = TestEnv::lock();
+    let _out_dir = env.set_out_dir();
+
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("empty.rego"), "").unwrap();
+    fs::write(
+        dir.path().join("blank.rego"),
+        "  \n\t\n# nothing here yet\n",
+    )
+    .unwrap();
+
+    let err = opa::build::policy("empty")
+        .add_source(dir.path().to_str().unwrap())
+        .add_entrypoint("example.allow")
+        .compile()
+        .unwrap_err();
+    assert_eq!(err.to_string(), "no policy files found in the sources");
+
+    let err = opa::build::policy("empty")
+        .add_source(dir.path().join("empty.rego").to_str().unwrap())
+        .add_entrypoint("example.allow")
+        .compile()
+        .unwrap_err();
+    assert!(err.to_string().contains("is empty"), "{err}");
+
+    // Empty files are skipped, the rest of the directory is still built.
+    fs::write(dir.path().join("package.rego"), "package example\n").unwrap();
+
+    #[cfg(unix)]
+    {
+        let args_path = dir.path().join("args");
+
+        // An `opa build` that records its arguments.
+        let script = format!(
+            "[ \"$1\" = \"build\" ] || exit 1\n\
+             echo \"$@\" > \"{}\"\n",
+            args_path.display()
+        );
+
+        env.with_fake_opa(&script, || {
+            opa::build::policy("empty")
+                .add_source(dir.path().to_str().unwrap())
+                .add_entrypoint("example.allow")
+                .check_opa_version(false)
+                .compile()
+                .unwrap();
+        });
+
+        let args = fs::read_to_string(&args_path).unwrap();
+        assert!(args.contains("package.rego"), "{args}");
+        assert!(!args.contains("empty.rego"), "{args}");
+        assert!(!args.contains("blank.rego"), "{args}");
+    }
+}
+
+#[test]
+fn test_rego_v0_policy() {
+    let env = TestEnv::lock();
+    let _out_dir = env.set_out_dir();
+
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(
+        dir.path().join("v0.rego"),
+        "package example\n\ndefault allow = false\n\nallow { input.admin }\n\nroles[role] { role := input.roles[_] }\n",
+    )
+    .unwrap();
+
+    let err = opa::build::policy("v0")
+        .add_source(dir.path().to_str().unwrap())
+        .add_entrypoint("example.allow")
+        .rego_version(2)
+        .compile()
+        .unwrap_err();
+    assert_eq!(err.to_string(), "unsupported Rego version 2");
+
+    // An `opa build` that only accepts Rego v0 with `--v0-compatible`.
+    #[cfg(unix)]
+    env.with_fake_opa(
+        "[ \"$1\" = \"build\" ] || exit 1\n\
+         case \" $* \" in *\" --v0-compatible \"*) exit 0 ;; esac\n\
+         echo \"1 error occurred: v0.rego:5: rego_parse_error: \\`if\\` keyword is required before rule body\" >&2\n\
+         exit 1\n",
+        || {
+            let policy = || {
+                opa::build::policy("v0")
+                    .add_source(dir.path().to_str().unwrap())
+                    .add_entrypoint("example.allow")
+                    .check_opa_version(false)
+            };
+
+            policy().rego_version(0).compile().unwrap();
+
+            let err = policy().compile().unwrap_err();
+            assert!(err.to_string().contains("`rego_version(0)`"), "{err}");
+        },
+    );
+}
+
+#[test]
+fn test_analyze() {
+    let source = r#"package example
+
+import data.lib.users
+import rego.v1
+
+allow if {
+    response := http.send({"method": "GET", "url": input.url})
+    response.status_code == 200
+    count(users.admins) > 0
+    is_owner(input.user)
+    users.is_active(input.user)
+}
+
+is_owner(user) if user in data.owners
+"#;
+
+    // `opa parse --format json` output of the policy above.
+    let ast = serde_json::json!({
+        "package": {
+            "path": [{ "type": "var", "value": "data" }, { "type": "string", "value": "example" }]
+        },
+        "imports": [
+            { "path": { "type": "ref", "value": [
+                { "type": "var", "value": "data" },
+                { "type": "string", "value": "lib" },
+                { "type": "string", "value": "users" }
+            ] } },
+            { "path": { "type": "ref", "value": [
+                { "type": "var", "value": "rego" },
+                { "type": "string", "value": "v1" }
+            ] } }
+        ],
+        "rules": [
+            {
+                "head": {
+                    "name": "allow",
+                    "ref": [{ "type": "var", "value": "allow" }],
+                    "value": { "type": "boolean", "value": true }
+                },
+                "body": [
+                    { "index": 0, "terms": [
+                        { "type": "ref", "value": [{ "type": "var", "value": "assign" }] },
+                        { "type": "var", "value": "response" },
+                        { "type": "call", "value": [
+                            { "type": "ref", "value": [
+                                { "type": "var", "value": "http" },
+                                { "type": "string", "value": "send" }
+                            ] },
+                            { "type": "object", "value": [] }
+                        ] }
+                    ] },
+                    { "index": 1, "terms": [
+                        { "type": "ref", "value": [{ "type": "var", "value": "equal" }] },
+                        { "type": "ref", "value": [
+                            { "type": "var", "value": "response" },
+                            { "type": "string", "value": "status_code" }
+                        ] },
+                        { "type": "number", "value": 200 }
+                    ] },
+                    { "index": 2, "terms": [
+                        { "type": "ref", "value": [{ "type": "var", "value": "gt" }] },
+                        { "type": "call", "value": [
+                            { "type": "ref", "value": [{ "type": "var", "value": "count" }] },
+                            { "type": "ref", "value": [
+                                { "type": "var", "value": "users" },
+                                { "type": "string", "value": "admins" }
+                            ] }
+                        ] },
+                        { "type": "number", "value": 0 }
+                    ] },
+                    { "index": 3, "terms": [
+                        { "type": "ref", "value": [{ "type": "var", "value": "is_owner" }] },
+                        { "type": "ref", "value": [
+                            { "type": "var", "value": "input" },
+                            { "type": "string", "value": "user" }
+                        ] }
+                    ] },
+                    { "index": 4, "terms": [
+                        { "type": "ref", "value": [
+                            { "type": "var", "value": "users" },
+                            { "type": "string", "value": "is_active" }
+                        ] },
+                        { "type": "ref", "value": [
+                            { "type": "var", "value": "input" },
+                            { "type": "string", "value": "user" }
+                        ] }
+                    ] }
+                ]
+            },
+            {
+                "head": {
+                    "name": "is_owner",
+                    "args": [{ "type": "var", "value": "user" }],
+                    "ref": [{ "type": "var", "value": "is_owner" }]
+                },
+                "body": [
+                    { "index": 0, "terms": [
+                        { "type": "ref", "value": [
+                            { "type": "var", "value": "internal" },
+                            { "type": "string", "value": "member_2" }
+                        ] },
+                        { "type": "var", "value": "user" },
+                        { "type": "ref", "value": [
+                            { "type": "var", "value": "data" },
+                            { "type": "string", "value": "owners" }
+                        ] }
+                    ] }
+                ]
+            }
+        ]
+    });
+
+    let analysis = opa::build::PolicyAnalysis::from_ast(&ast);
+    assert_eq!(analysis.package, "example");
+    assert_eq!(
+        analysis.builtins.iter().collect::<Vec<_>>(),
+        ["count", "http.send"]
+    );
+    assert_eq!(
+        analysis.imports.iter().collect::<Vec<_>>(),
+        ["data.lib.users", "rego.v1"]
+    );
+
+    // The same result is expected from `opa`, if it is available.
+    let _env = TestEnv::lock();
+    if let Ok(parsed) = opa::build::analyze(source) {
+        assert_eq!(parsed, analysis);
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn test_opa_version_check() {
+    let env = TestEnv::lock();
+    let _out_dir = env.set_out_dir();
+
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("policy.rego"), "package example\n").unwrap();
+
+    let policy = || {
+        opa::build::policy("version")
+            .add_source(dir.path().to_str().unwrap())
+            .add_entrypoint("example.allow")
+    };
+
+    // An `opa` executable that only supports `opa version`.
+    let script = "if [ \"$1\" = \"version\" ]; then\n\
+                  echo \"Version: 0.45.0\"\n\
+                  echo \"Build Commit: 0000000\"\n\
+                  exit 0\n\
+                  fi\n\
+                  echo \"fake opa\" >&2\n\
+                  exit 1\n";
+
+    env.with_fake_opa(script, || {
+        let err = policy().min_opa_version("0.50.0").compile().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "opa version 0.45.0 is not supported, the required version is >= 0.50.0"
+        );
+
+        let err = policy()
+            .min_opa_version("0.30.0")
+            .max_opa_version("0.40.0")
+            .compile()
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "opa version 0.45.0 is not supported, the required version is >= 0.30.0 and <= 0.40.0"
+        );
+
+        let err = policy().max_opa_version("latest").compile().unwrap_err();
+        assert_eq!(err.to_string(), "invalid OPA version `latest`");
+
+        // The version is supported, only the build itself fails.
+        let err = policy().max_opa_version("0.45.0").compile().unwrap_err();
+        assert!(err.to_string().starts_with("opa error: fake opa"), "{err}");
+
+        let err = policy()
+            .min_opa_version("1.0.0")
+            .check_opa_version(false)
+            .compile()
+            .unwrap_err();
+        assert!(err.to_string().starts_with("opa error: fake opa"), "{err}");
+    });
+
+    // `opa version` itself fails.
+    let script = "echo \"Version: 0.45.0\"\n\
+                  echo \"broken opa\" >&2\n\
+                  exit 1\n";
+
+    env.with_fake_opa(script, || {
+        let err = policy().compile().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "failed to determine the version of `opa`: Version: 0.45.0\nbroken opa\n"
+        );
+    });
+}
+
+#[test]
+#[cfg(unix)]
+fn test_check_format() {
+    let env = TestEnv::lock();
+
+    use opa::build::{check_format, FormatDiff};
+
+    // An `opa fmt --diff` that only accepts `allow := true`.
+    let script = "[ \"$1\" = \"fmt\" ] || exit 1\n\
+                  if grep -q \"allow:=true\" \"$3\"; then\n\
+                  echo \"--- $3\"\n\
+                  echo \"+++ $3\"\n\
+                  echo \"@@ -1,3 +1,3 @@\"\n\
+                  echo \"-allow:=true\"\n\
+                  echo \"+allow := true\"\n\
+                  elif grep -q \"allow :=\" \"$3\"; then\n\
+                  exit 0\n\
+                  else\n\
+                  echo \"1 error occurred: rego_parse_error\" >&2\n\
+                  exit 1\n\
+                  fi\n";
+
+    env.with_fake_opa(script, || {
+        check_format("package example\n\nallow := true\n").unwrap();
+
+        let err = check_format("package example\n\nallow:=true\n").unwrap_err();
+        let diff = err.downcast_ref::<FormatDiff>().unwrap();
+        assert!(
+            diff.diff.contains("-allow:=true\n+allow := true"),
+            "{}",
+            diff.diff
+        );
+
+        let err = check_format("package example\n\nallow {").unwrap_err();
+        assert!(err.downcast_ref::<FormatDiff>().is_none());
+        assert!(err.to_string().contains("rego_parse_error"), "{err}");
+    });
+}
+
+#[test]
+#[cfg(unix)]
+fn test_build_error_non_utf8_path() {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+    let env = TestEnv::lock();
+    let _out_dir = env.set_out_dir();
+
+    // The paths of the files in the sources are not necessarily UTF-8.
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(
+        dir.path().join(OsStr::from_bytes(b"policy-\xff.rego")),
+        "package example\n",
+    )
+    .unwrap();
+
+    // An `opa build` that fails with the given paths on stderr.
+    let script = "[ \"$1\" = \"build\" ] || exit 1\n\
+                  echo \"1 error occurred: $7: rego_type_error\" >&2\n\
+                  exit 1\n";
+
+    env.with_fake_opa(script, || {
+        let err = opa::build::policy("non-utf8")
+            .add_source(dir.path().to_str().unwrap())
+            .add_entrypoint("example.allow")
+            .check_opa_version(false)
+            .compile()
+            .unwrap_err();
+
+        let message = err.to_string();
+        assert!(
+            message.starts_with("opa error: 1 error occurred"),
+            "{message}"
+        );
+        assert!(message.contains("rego_type_error"), "{message}");
+    });
+}
+
+#[test]
+#[cfg(unix)]
+fn test_opa_eval_rego_bundle() {
+    let env = TestEnv::lock();
+
+    use opa::{
+        build::OpaEval,
+        bundle::{Bundle, RegoEvaluator},
+    };
+    use serde_json::json;
+
+    // A bundle with only the Rego policy and the data.
+    let mut bundle = Bundle::from_bytes(include_bytes!(
+        "../../../examples/src/bin/wasm_bundle/example.tar.gz"
+    ))
+    .unwrap();
+    bundle.wasm_policies.clear();
+    bundle.manifest = None;
+    bundle.set_data(Some(json!({ "admins": ["alice"] })));
+
+    // An `opa eval` that allows the admins in the data of the bundle.
+    let script = "[ \"$1\" = \"eval\" ] || exit 1\n\
+                  while [ $# -gt 0 ]; do\n\
+                  case \"$1\" in\n\
+                  --bundle) bundle=\"$2\"; shift 2 ;;\n\
+                  --input) input=\"$2\"; shift 2 ;;\n\
+                  --format) shift 2 ;;\n\
+                  *) query=\"$1\"; shift ;;\n\
+                  esac\n\
+                  done\n\
+                  grep -q \"package example\" \"$bundle/example.rego\" || exit 1\n\
+                  [ \"$query\" = \"data.example.allow\" ] || { echo \"undefined ref: $query\" >&2; exit 1; }\n\
+                  user=$(sed 's/.*\"user\":\"\\([a-z]*\\)\".*/\\1/' \"$input\")\n\
+                  if grep -q \"\\\"$user\\\"\" \"$bundle/data.json\"; then\n\
+                  echo '{\"result\": [{\"expressions\": [{\"value\": true, \"text\": \"data.example.allow\"}]}]}'\n\
+                  else\n\
+                  echo '{}'\n\
+                  fi\n";
+
+    env.with_fake_opa(script, || {
+        let evaluator = OpaEval::new();
+
+        let allowed = evaluator
+            .eval(&bundle, "example.allow", &json!({ "user": "alice" }))
+            .unwrap();
+        assert_eq!(allowed, Some(json!(true)));
+
+        let allowed = evaluator
+            .eval(&bundle, "example/allow", &json!({ "user": "bob" }))
+            .unwrap();
+        assert_eq!(allowed, None);
+
+        let err = evaluator
+            .eval(&bundle, "example.deny", &json!({}))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "opa error: undefined ref: data.example.deny\n"
+        );
+    });
+}
+
+#[test]
+#[cfg(unix)]
+fn test_deny_warnings() {
+    let env = TestEnv::lock();
+    let _out_dir = env.set_out_dir();
+
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("policy.rego"), "package example\n").unwrap();
+    let args_path = dir.path().join("args");
+
+    // An `opa build` that records its arguments.
+    let script = format!(
+        "[ \"$1\" = \"build\" ] || exit 1\n\
+         echo \"$@\" > \"{}\"\n\
+         echo \"fake opa\" >&2\n\
+         exit 1\n",
+        args_path.display()
+    );
+
+    let build_args = |deny_warnings: bool| {
+        env.with_fake_opa(&script, || {
+            opa::build::policy("strict")
+                .add_source(dir.path().to_str().unwrap())
+                .add_entrypoint("example.allow")
+                .check_opa_version(false)
+                .deny_warnings(deny_warnings)
+                .compile()
+                .unwrap_err();
+        });
+
+        fs::read_to_string(&args_path).unwrap()
+    };
+
+    let args = build_args(true);
+    assert!(
+        args.split_whitespace().any(|arg| arg == "--strict"),
+        "{args}"
+    );
+
+    let args = build_args(false);
+    assert!(
+        !args.split_whitespace().any(|arg| arg == "--strict"),
+        "{args}"
+    );
+}
+
+#[test]
+#[cfg(all(unix, feature = "wasmtime-cranelift"))]
+fn test_query() {
+    let env = TestEnv::lock();
+
+    use opa::build::{compile_query, query};
+
+    let dir = tempfile::tempdir().unwrap();
+    let bundle_path = dir.path().join("bundle.tar.gz");
+    fs::write(
+        &bundle_path,
+        include_bytes!("../../../examples/src/bin/wasm_bundle/example.tar.gz"),
+    )
+    .unwrap();
+    let args_path = dir.path().join("args");
+    let policy_path = dir.path().join("policy.rego");
+
+    // An `opa build` that records its arguments and the policy,
+    // and outputs the example bundle.
+    let script = format!(
+        "[ \"$1\" = \"build\" ] || exit 1\n\
+         echo \"$@\" >> \"{args}\"\n\
+         cat \"$8\" > \"{policy}\"\n\
+         cp \"{bundle}\" \"$7\"\n",
+        args = args_path.display(),
+        policy = policy_path.display(),
+        bundle = bundle_path.display(),
+    );
+
+    let q = "{\"test_query\": [x | x := data.users[_]]}";
+
+    env.with_fake_opa(&script, || {
+        query(q).unwrap();
+        // Queries are not cached.
+        let bytes = compile_query(q).unwrap();
+        opa::wasm::Opa::new().build(&bytes).unwrap();
+
+        let err = query("1\n}\nallow if { true").unwrap_err();
+        assert!(err.to_string().contains("single line"), "{err}");
+        let err = query("1 } { 1").unwrap_err();
+        assert!(err.to_string().contains("unbalanced brackets"), "{err}");
+        let err = query("\"}").unwrap_err();
+        assert!(err.to_string().contains("unterminated string"), "{err}");
+    });
+
+    let args = fs::read_to_string(&args_path).unwrap();
+    let args = args.lines().collect::<Vec<_>>();
+    assert_eq!(args.len(), 2, "{args:?}");
+    assert!(args
+        .iter()
+        .all(|args| args.starts_with("build -t wasm -e adhoc/result -o ")));
+
+    assert_eq!(
+        fs::read_to_string(&policy_path).unwrap(),
+        format!(
+            "package adhoc\n\nimport rego.v1\n\nresult contains __value if {{\n    __value := {q}\n}}\n"
+        )
+    );
+}
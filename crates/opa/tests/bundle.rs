@@ -0,0 +1,642 @@
+use opa::bundle::{
+    manifest::Manifest, Bundle, BundleLimits, BundleWriteOptions, Compression, Error, MergeError,
+};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+
+fn example_bundle() -> Bundle {
+    Bundle::from_bytes(include_bytes!(
+        "../../../examples/src/bin/wasm_bundle/example.tar.gz"
+    ))
+    .unwrap()
+}
+
+#[test]
+fn test_bundle_write_roundtrip() {
+    let bundle = example_bundle();
+    let written = Bundle::from_bytes(bundle.to_bytes().unwrap()).unwrap();
+
+    assert_eq!(written.data(), bundle.data());
+    assert_eq!(written.rego_policies.len(), bundle.rego_policies.len());
+    assert_eq!(written.wasm_policies.len(), bundle.wasm_policies.len());
+
+    for (written, original) in written.wasm_policies.iter().zip(&bundle.wasm_policies) {
+        assert_eq!(written.entrypoint, original.entrypoint);
+        assert_eq!(written.bytes, original.bytes);
+    }
+}
+
+#[test]
+fn test_bundle_extract_roundtrip() {
+    fn append_dir(ar: &mut tar::Builder<Vec<u8>>, root: &Path, dir: &Path) {
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                append_dir(ar, root, &path);
+            } else {
+                ar.append_path_with_name(&path, path.strip_prefix(root).unwrap())
+                    .unwrap();
+            }
+        }
+    }
+
+    let mut bundle = example_bundle();
+    bundle.rego_policies.insert(
+        "/nested/dir/policy.rego".into(),
+        "package nested.dir\n".into(),
+    );
+
+    let dir = tempfile::tempdir().unwrap();
+    bundle.extract_to(dir.path()).unwrap();
+
+    assert!(dir.path().join(".manifest").is_file());
+    assert!(dir.path().join("data.json").is_file());
+    assert_eq!(
+        std::fs::read_to_string(dir.path().join("nested/dir/policy.rego")).unwrap(),
+        "package nested.dir\n"
+    );
+
+    // Archive the directory the same way as `tar -czf`.
+    let mut ar = tar::Builder::new(Vec::new());
+    append_dir(&mut ar, dir.path(), dir.path());
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(&ar.into_inner().unwrap()).unwrap();
+
+    let extracted = Bundle::from_bytes(encoder.finish().unwrap()).unwrap();
+
+    assert_eq!(
+        serde_json::to_value(&extracted.manifest).unwrap(),
+        serde_json::to_value(&bundle.manifest).unwrap()
+    );
+    assert_eq!(extracted.data(), bundle.data());
+    // Bundles built by `opa` use absolute paths within the bundle.
+    let relative = |policies: &HashMap<PathBuf, String>| {
+        policies
+            .iter()
+            .map(|(path, policy)| {
+                (
+                    path.strip_prefix("/").unwrap_or(path).to_owned(),
+                    policy.clone(),
+                )
+            })
+            .collect::<HashMap<_, _>>()
+    };
+    assert_eq!(
+        relative(&extracted.rego_policies),
+        relative(&bundle.rego_policies)
+    );
+    assert_eq!(extracted.schemas, bundle.schemas);
+    assert_eq!(extracted.wasm_policies.len(), bundle.wasm_policies.len());
+
+    for (extracted, original) in extracted.wasm_policies.iter().zip(&bundle.wasm_policies) {
+        assert_eq!(extracted.entrypoint, original.entrypoint);
+        assert_eq!(extracted.bytes, original.bytes);
+    }
+
+    bundle
+        .rego_policies
+        .insert("../outside.rego".into(), "package outside\n".into());
+    let dir = tempfile::tempdir().unwrap();
+    assert!(bundle.extract_to(dir.path().join("bundle")).is_err());
+    assert!(!dir.path().join("outside.rego").exists());
+    assert!(!dir.path().join("bundle").exists());
+}
+
+#[test]
+fn test_bundle_write_compression() {
+    let bundle = example_bundle();
+
+    for level in 0..=9 {
+        let bytes = bundle
+            .to_bytes_with(&BundleWriteOptions::new().compression(Compression::new(level)))
+            .unwrap();
+        let written = Bundle::from_bytes(bytes).unwrap();
+
+        assert_eq!(written.data(), bundle.data(), "level {level}");
+        assert_eq!(
+            written.wasm_policies[0].bytes, bundle.wasm_policies[0].bytes,
+            "level {level}"
+        );
+    }
+}
+
+#[test]
+fn test_bundle_write_deterministic() {
+    let options = BundleWriteOptions::new().deterministic(true);
+
+    let a = example_bundle().to_bytes_with(&options).unwrap();
+    let b = example_bundle().to_bytes_with(&options).unwrap();
+    assert_eq!(a, b);
+}
+
+#[test]
+fn test_bundle_limits() {
+    let bytes = example_bundle().to_bytes().unwrap();
+
+    let read = |limits: BundleLimits| Bundle::from_reader_with_limits(&bytes[..], &limits);
+
+    read(
+        BundleLimits::new()
+            .max_files(100)
+            .max_file_bytes(10_000_000)
+            .max_total_bytes(100_000_000),
+    )
+    .unwrap();
+
+    for limits in [
+        BundleLimits::new().max_files(1),
+        BundleLimits::new().max_file_bytes(16),
+        BundleLimits::new().max_total_bytes(1024),
+    ] {
+        let err = read(limits.clone()).unwrap_err();
+        assert!(matches!(err, Error::LimitExceeded(_)), "{limits:?}: {err}");
+    }
+}
+
+/// The example bundle with data for many users.
+fn large_data_bundle() -> (Bundle, Value) {
+    let users: serde_json::Map<String, Value> = (0..10_000)
+        .map(|i| {
+            (
+                format!("user-{i}"),
+                json!({ "projects": { format!("project-{i}"): { "roles": ["owner"] } } }),
+            )
+        })
+        .collect();
+    let data = json!({ "users": users });
+
+    let mut bundle = example_bundle();
+    bundle.set_data(Some(data.clone()));
+
+    (bundle, data)
+}
+
+#[test]
+fn test_bundle_lazy_data() {
+    let (bundle, data) = large_data_bundle();
+    let bytes = bundle.to_bytes().unwrap();
+
+    let mut bundle = Bundle::from_reader_lazy_data(&bytes[..], &BundleLimits::default()).unwrap();
+    assert_eq!(bundle.data(), None);
+    assert!(bundle.summary().has_data);
+
+    let raw = bundle.data_bytes().unwrap();
+    assert!(raw.len() > 500_000);
+    assert_eq!(serde_json::from_slice::<Value>(raw).unwrap(), data);
+
+    // Written without parsing it.
+    let written = Bundle::from_bytes(bundle.to_bytes().unwrap()).unwrap();
+    assert_eq!(written.data(), Some(&data));
+
+    assert_eq!(bundle.data_lazy().unwrap(), Some(&data));
+    assert!(bundle.data_bytes().is_none());
+    assert_eq!(bundle.data(), Some(&data));
+
+    // Bundles are parsed as usual otherwise.
+    let bundle = Bundle::from_bytes(&bytes).unwrap();
+    assert!(bundle.data().is_some());
+    assert!(bundle.data_bytes().is_none());
+}
+
+#[test]
+fn test_bundle_replace_lazy_data() {
+    let mut bundle = example_bundle();
+    bundle.set_data(Some(json!({ "old": true })));
+    let bytes = bundle.to_bytes().unwrap();
+    let lazy = || Bundle::from_reader_lazy_data(&bytes[..], &BundleLimits::default()).unwrap();
+
+    let mut bundle = lazy();
+    bundle.set_data(Some(json!({ "new": true })));
+    assert!(bundle.data_bytes().is_none());
+
+    let written = Bundle::from_bytes(bundle.to_bytes().unwrap()).unwrap();
+    assert_eq!(written.data(), Some(&json!({ "new": true })));
+
+    let mut bundle = lazy();
+    bundle.set_data(None);
+    assert!(bundle.data_bytes().is_none());
+    assert!(!bundle.summary().has_data);
+
+    let dir = tempfile::tempdir().unwrap();
+    bundle.extract_to(dir.path()).unwrap();
+    assert!(!dir.path().join("data.json").exists());
+    assert_eq!(bundle.data_lazy().unwrap(), None);
+}
+
+#[test]
+fn test_peek_revision() {
+    struct CountingReader<'a> {
+        bytes: &'a [u8],
+        read: usize,
+    }
+
+    impl Read for CountingReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = self.bytes.read(buf)?;
+            self.read += n;
+            Ok(n)
+        }
+    }
+
+    let mut bundle = example_bundle();
+    bundle.manifest.as_mut().unwrap().revision = "rev-1".into();
+    let bytes = bundle.to_bytes().unwrap();
+
+    let mut reader = CountingReader {
+        bytes: &bytes,
+        read: 0,
+    };
+    let revision = Bundle::peek_revision(&mut reader).unwrap();
+
+    assert_eq!(revision.as_deref(), Some("rev-1"));
+    assert_eq!(
+        revision,
+        Bundle::from_bytes(&bytes)
+            .unwrap()
+            .manifest
+            .map(|m| m.revision)
+    );
+    assert!(reader.read < bytes.len());
+
+    bundle.manifest = None;
+    assert_eq!(
+        Bundle::peek_revision(&bundle.to_bytes().unwrap()[..]).unwrap(),
+        None
+    );
+}
+
+#[cfg(feature = "bundle-signing")]
+mod signing {
+    use super::example_bundle;
+    use opa::bundle::{
+        signing::{SigningKey, VerificationKey},
+        Bundle, Error,
+    };
+    use serde_json::{json, Value};
+
+    #[test]
+    fn test_sign_verify_hs256() {
+        let bytes = example_bundle()
+            .to_bytes_signed(&SigningKey::hs256("secret").with_key_id("test"))
+            .unwrap();
+
+        let bundle =
+            Bundle::from_reader_verified(&bytes[..], &VerificationKey::hs256("secret")).unwrap();
+        assert!(!bundle.wasm_policies.is_empty());
+
+        assert!(
+            Bundle::from_reader_verified(&bytes[..], &VerificationKey::hs256("other")).is_err()
+        );
+    }
+
+    #[test]
+    fn test_sign_verify_rs256() {
+        let bytes = example_bundle()
+            .to_bytes_signed(&SigningKey::rs256_pem(include_bytes!("data/rsa.pem")).unwrap())
+            .unwrap();
+
+        Bundle::from_reader_verified(
+            &bytes[..],
+            &VerificationKey::rs256_pem(include_bytes!("data/rsa.pub.pem")).unwrap(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_sign_verify_pem() {
+        for (private, public) in [
+            (
+                &include_bytes!("data/rsa.pem")[..],
+                &include_bytes!("data/rsa.pub.pem")[..],
+            ),
+            (
+                &include_bytes!("data/ec.pem")[..],
+                &include_bytes!("data/ec.pub.pem")[..],
+            ),
+        ] {
+            let bytes = example_bundle()
+                .to_bytes_signed(&SigningKey::from_pem(private).unwrap())
+                .unwrap();
+
+            Bundle::from_reader_verified(&bytes[..], &VerificationKey::from_pem(public).unwrap())
+                .unwrap();
+        }
+
+        // RSA signatures are not accepted by EC keys.
+        let bytes = example_bundle()
+            .to_bytes_signed(&SigningKey::from_pem(include_bytes!("data/rsa.pem")).unwrap())
+            .unwrap();
+        assert!(Bundle::from_reader_verified(
+            &bytes[..],
+            &VerificationKey::from_pem(include_bytes!("data/ec.pub.pem")).unwrap()
+        )
+        .is_err());
+
+        assert!(matches!(
+            SigningKey::from_pem(include_bytes!("data/rsa.pub.pem")),
+            Err(Error::InvalidKey(_))
+        ));
+        assert!(matches!(
+            VerificationKey::from_pem(include_bytes!("data/ec.pem")),
+            Err(Error::InvalidKey(_))
+        ));
+        assert!(matches!(
+            VerificationKey::from_pem("not a key"),
+            Err(Error::InvalidKey(_))
+        ));
+    }
+
+    #[test]
+    fn test_verify_jwk() {
+        for (private, jwk) in [
+            (
+                &include_bytes!("data/rsa.pem")[..],
+                &include_bytes!("data/rsa.jwk.json")[..],
+            ),
+            (
+                &include_bytes!("data/ec.pem")[..],
+                &include_bytes!("data/ec.jwk.json")[..],
+            ),
+        ] {
+            let bytes = example_bundle()
+                .to_bytes_signed(&SigningKey::from_pem(private).unwrap())
+                .unwrap();
+
+            let jwk: Value = serde_json::from_slice(jwk).unwrap();
+            Bundle::from_reader_verified(&bytes[..], &VerificationKey::from_jwk(&jwk).unwrap())
+                .unwrap();
+        }
+
+        // `c2VjcmV0Pz8_` is the base64url encoding of `secret???`.
+        let bytes = example_bundle()
+            .to_bytes_signed(&SigningKey::hs256("secret???"))
+            .unwrap();
+        let key = VerificationKey::from_jwk(&json!({
+            "kty": "oct",
+            "alg": "HS256",
+            "k": "c2VjcmV0Pz8_"
+        }))
+        .unwrap();
+        Bundle::from_reader_verified(&bytes[..], &key).unwrap();
+
+        let mut jwk: Value = serde_json::from_slice(include_bytes!("data/ec.jwk.json")).unwrap();
+        jwk["alg"] = json!("RS256");
+        let err = VerificationKey::from_jwk(&jwk).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "invalid key: the key cannot be used with RS256, only with ES256"
+        );
+
+        let err = VerificationKey::from_jwk(&json!({ "kty": "unknown" }))
+            .err()
+            .unwrap();
+        assert!(
+            err.to_string().starts_with("invalid key: invalid JWK"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_verify_unsigned() {
+        let bytes = example_bundle().to_bytes().unwrap();
+        assert!(
+            Bundle::from_reader_verified(&bytes[..], &VerificationKey::hs256("secret")).is_err()
+        );
+    }
+}
+
+#[test]
+fn test_manifest_preserves_unknown_fields() {
+    let manifest = json!({
+        "revision": "1",
+        "roots": ["discovery"],
+        "metadata": {
+            "service": "acmecorp"
+        },
+        "discovery": {
+            "name": "example/discovery"
+        }
+    });
+
+    let parsed: Manifest = serde_json::from_value(manifest.clone()).unwrap();
+
+    assert_eq!(parsed.metadata.as_ref().unwrap()["service"], "acmecorp");
+    assert_eq!(parsed.extra["discovery"]["name"], "example/discovery");
+
+    let mut serialized = serde_json::to_value(&parsed).unwrap();
+    serialized.as_object_mut().unwrap().remove("wasm");
+    assert_eq!(serialized, manifest);
+}
+
+#[test]
+fn test_manifest_rego_version() {
+    let manifest = json!({
+        "revision": "1",
+        "roots": [""],
+        "rego_version": 0
+    });
+
+    let parsed: Manifest = serde_json::from_value(manifest.clone()).unwrap();
+    assert_eq!(parsed.rego_version, Some(0));
+    assert!(!parsed.extra.contains_key("rego_version"));
+
+    let mut serialized = serde_json::to_value(&parsed).unwrap();
+    serialized.as_object_mut().unwrap().remove("wasm");
+    assert_eq!(serialized, manifest);
+
+    let parsed: Manifest = serde_json::from_value(json!({ "revision": "1" })).unwrap();
+    assert_eq!(parsed.rego_version, None);
+    assert!(serde_json::to_value(&parsed)
+        .unwrap()
+        .get("rego_version")
+        .is_none());
+}
+
+fn partial_bundle(roots: &[&str], data: Value, policies: &[(&str, &str)]) -> Bundle {
+    let mut bundle = example_bundle();
+    bundle.wasm_policies.clear();
+    bundle.set_data(Some(data));
+    bundle.rego_policies = policies
+        .iter()
+        .map(|(path, policy)| (PathBuf::from(path), policy.to_string()))
+        .collect();
+
+    let manifest = bundle.manifest.as_mut().unwrap();
+    manifest.wasm.clear();
+    manifest.roots = roots.iter().map(ToString::to_string).collect();
+
+    bundle
+}
+
+#[test]
+fn test_bundle_merge() {
+    let mut base = partial_bundle(
+        &["base"],
+        json!({"base": {"roles": ["admin"]}, "shared": {"a": 1}}),
+        &[
+            ("/base/policy.rego", "package base"),
+            ("/common.rego", "package common"),
+        ],
+    );
+    base.manifest.as_mut().unwrap().revision = "1".into();
+
+    let env = partial_bundle(
+        &["env", "base"],
+        json!({"env": {"name": "prod"}, "shared": {"a": 1, "b": 2}}),
+        &[
+            ("/env/policy.rego", "package env"),
+            ("/common.rego", "package common"),
+        ],
+    );
+
+    base.merge(env).unwrap();
+
+    assert_eq!(
+        base.data(),
+        Some(&json!({
+            "base": {"roles": ["admin"]},
+            "env": {"name": "prod"},
+            "shared": {"a": 1, "b": 2}
+        }))
+    );
+    assert_eq!(base.rego_policies.len(), 3);
+
+    let manifest = base.manifest.unwrap();
+    assert_eq!(manifest.roots, vec!["base", "env"]);
+    assert_eq!(manifest.revision, "1");
+
+    let mut merged = partial_bundle(&[], json!({}), &[]);
+    merged.manifest = None;
+    merged.merge(example_bundle()).unwrap();
+    assert_eq!(merged.wasm_policies.len(), 2);
+    assert_eq!(merged.manifest.unwrap().roots, vec![""]);
+}
+
+#[test]
+fn test_bundle_merge_conflicts() {
+    let base = || {
+        partial_bundle(
+            &["base"],
+            json!({"base": {"roles": ["admin"]}}),
+            &[("/base/policy.rego", "package base")],
+        )
+    };
+
+    let mut bundle = base();
+    let err = bundle
+        .merge(partial_bundle(
+            &["other"],
+            json!({}),
+            &[("/base/policy.rego", "package base.changed")],
+        ))
+        .unwrap_err();
+    assert!(
+        matches!(&err, MergeError::PolicyConflict(path) if path == &PathBuf::from("/base/policy.rego")),
+        "{err}"
+    );
+
+    let mut other = partial_bundle(&["other"], json!({}), &[]);
+    other
+        .schemas
+        .insert("schemas/input.json".into(), json!({"type": "string"}));
+    bundle
+        .schemas
+        .insert("schemas/input.json".into(), json!({"type": "object"}));
+    let err = bundle.merge(other).unwrap_err();
+    assert!(matches!(err, MergeError::SchemaConflict(_)), "{err}");
+
+    let mut bundle = base();
+    let err = bundle
+        .merge(partial_bundle(
+            &["other"],
+            json!({"base": {"roles": ["viewer"]}}),
+            &[],
+        ))
+        .unwrap_err();
+    assert!(
+        matches!(&err, MergeError::DataConflict(path) if path == "base/roles"),
+        "{err}"
+    );
+    assert_eq!(bundle.data(), base().data());
+
+    let mut bundle = base();
+    let err = bundle
+        .merge(partial_bundle(&["base/users"], json!({}), &[]))
+        .unwrap_err();
+    assert!(
+        matches!(&err, MergeError::RootConflict(a, b) if a == "base" && b == "base/users"),
+        "{err}"
+    );
+    assert_eq!(bundle.manifest.unwrap().roots, vec!["base"]);
+
+    let err = base()
+        .merge(partial_bundle(&[""], json!({}), &[]))
+        .unwrap_err();
+    assert!(matches!(err, MergeError::RootConflict(..)), "{err}");
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test]
+async fn test_bundle_from_url() {
+    use opa::{
+        bundle::FetchOptions,
+        http::testing::{MockOpa, MockResponse},
+    };
+
+    let mock = MockOpa::builder()
+        .response(
+            "GET",
+            "/bundles/example.tar.gz",
+            MockResponse::bytes(
+                &include_bytes!("../../../examples/src/bin/wasm_bundle/example.tar.gz")[..],
+            ),
+        )
+        .start()
+        .unwrap();
+
+    let bundle = Bundle::from_url(
+        format!("{}/bundles/example.tar.gz", mock.url()),
+        &reqwest::Client::new(),
+        &FetchOptions::new().bearer_token("secret"),
+    )
+    .await
+    .unwrap();
+    assert_eq!(bundle.data(), example_bundle().data());
+
+    let requests = mock.requests();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].method, "GET");
+    assert_eq!(requests[0].path, "/bundles/example.tar.gz");
+    assert_eq!(requests[0].header("authorization"), Some("Bearer secret"));
+}
+
+#[test]
+fn test_bundle_summary() {
+    let bundle = example_bundle();
+    let summary = bundle.summary();
+
+    assert!(summary.has_data);
+    assert_eq!(summary.wasm_policies.len(), bundle.wasm_policies.len());
+    for (summary, policy) in summary.wasm_policies.iter().zip(&bundle.wasm_policies) {
+        assert_eq!(summary.entrypoint, policy.entrypoint);
+        assert_eq!(summary.size, 132_123);
+    }
+
+    let summary = serde_json::to_value(&summary).unwrap();
+    assert_eq!(
+        summary["rego_policies"],
+        json!([{ "path": "/example.rego", "size": 90 }])
+    );
+    assert_eq!(summary["schemas"], json!([]));
+    assert_eq!(
+        summary["manifest"]["wasm"][0]["entrypoint"],
+        "example/project_permissions"
+    );
+
+    // The contents of the files are not included.
+    assert!(serde_json::to_string(&summary).unwrap().len() < 1024);
+}
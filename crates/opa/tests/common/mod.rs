@@ -0,0 +1,65 @@
+#![allow(dead_code)]
+
+use std::{
+    env,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+/// Exclusive access to the environment of the test process.
+///
+/// Tests run in parallel, so every test that reads or modifies
+/// environment variables such as `PATH` and `OUT_DIR`, including
+/// by running `opa`, must hold it.
+pub struct TestEnv {
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl TestEnv {
+    pub fn lock() -> Self {
+        static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+        Self {
+            _lock: ENV_LOCK.lock().unwrap_or_else(PoisonError::into_inner),
+        }
+    }
+
+    /// Set `OUT_DIR` to a new temporary directory
+    /// that is removed when the returned value is dropped.
+    pub fn set_out_dir(&self) -> tempfile::TempDir {
+        let out_dir = tempfile::tempdir().unwrap();
+        env::set_var("OUT_DIR", out_dir.path());
+        out_dir
+    }
+
+    /// Run `f` with a fake `opa` executable that runs the shell script
+    /// first in the system paths.
+    #[cfg(unix)]
+    pub fn with_fake_opa<T>(&self, script: &str, f: impl FnOnce() -> T) -> T {
+        use std::{ffi::OsString, fs, os::unix::fs::PermissionsExt};
+
+        /// Restores the original paths even if `f` panics.
+        struct RestorePath(OsString);
+
+        impl Drop for RestorePath {
+            fn drop(&mut self) {
+                env::set_var("PATH", &self.0);
+            }
+        }
+
+        let bin_dir = tempfile::tempdir().unwrap();
+        let opa_path = bin_dir.path().join("opa");
+        fs::write(&opa_path, format!("#!/bin/sh\n{script}")).unwrap();
+        fs::set_permissions(&opa_path, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let path = RestorePath(env::var_os("PATH").unwrap_or_default());
+        env::set_var(
+            "PATH",
+            env::join_paths(
+                std::iter::once(bin_dir.path().to_path_buf()).chain(env::split_paths(&path.0)),
+            )
+            .unwrap(),
+        );
+
+        f()
+    }
+}
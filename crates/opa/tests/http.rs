@@ -0,0 +1,1179 @@
+use opa::{
+    http::{
+        testing::{MockOpa, MockRequest, MockResponse},
+        Opa,
+    },
+    PolicyDecision,
+};
+use serde::Serialize;
+use serde_json::json;
+
+mod common;
+
+enum Allow {}
+
+#[derive(Serialize)]
+struct AllowInput {
+    user_id: String,
+}
+
+impl PolicyDecision for Allow {
+    const POLICY_PATH: &'static str = "example.allow";
+    type Input = AllowInput;
+    type Output = bool;
+}
+
+fn input() -> AllowInput {
+    AllowInput {
+        user_id: "test".into(),
+    }
+}
+
+/// The only request received by the server.
+fn single_request(mock: &MockOpa) -> MockRequest {
+    let mut requests = mock.requests();
+    assert_eq!(requests.len(), 1, "{requests:?}");
+    requests.pop().unwrap()
+}
+
+#[test]
+fn test_client_is_send_sync() {
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    assert_send_sync::<Opa>();
+    assert_send_sync::<opa::http::Error>();
+    assert_send_sync::<opa::http::Decision<serde_json::Value>>();
+}
+
+#[tokio::test]
+async fn test_get_package_decision() {
+    #[derive(Debug, serde::Deserialize)]
+    struct Example {
+        allow: bool,
+        roles: Vec<String>,
+        #[serde(default)]
+        is_test: Option<bool>,
+    }
+
+    let mock = MockOpa::builder()
+        .decision(
+            "example.policy",
+            json!({ "allow": true, "roles": ["admin", "dev"] }),
+        )
+        .start()
+        .unwrap();
+    let opa = mock.client();
+
+    let decision = opa
+        .get_package_decision::<_, Example>("example.policy", &input())
+        .await
+        .unwrap();
+    assert!(decision.result.allow);
+    assert_eq!(decision.result.roles, ["admin", "dev"]);
+    assert_eq!(decision.result.is_test, None);
+
+    let request = single_request(&mock);
+    assert_eq!(request.method, "POST");
+    assert_eq!(request.path, "/v1/data/example/policy");
+}
+
+#[tokio::test]
+async fn test_get_decision_instrumented() {
+    use opa::http::DecisionOptions;
+
+    let mock = MockOpa::builder()
+        .response(
+            "POST",
+            "/v1/data/example/allow",
+            MockResponse::json(&json!({
+                "result": true,
+                "metrics": {
+                    "counter_server_query_cache_hit": 0,
+                    "timer_eval_op_plug_ns": 6123,
+                    "timer_rego_query_eval_ns": 42500,
+                    "histogram_eval_op_plug": { "count": 3, "max": 4100, "mean": 2041 }
+                }
+            })),
+        )
+        .start()
+        .unwrap();
+    let opa = mock.client();
+
+    let decision = opa
+        .get_decision_with_options::<_, bool>(
+            "example.allow",
+            &input(),
+            &DecisionOptions::new().instrument(true),
+        )
+        .await
+        .unwrap();
+    assert!(decision.result);
+
+    let metrics = decision.metrics.unwrap();
+    assert_eq!(metrics["timer_rego_query_eval_ns"], 42500);
+    assert_eq!(metrics["histogram_eval_op_plug"]["count"], 3);
+    assert_eq!(metrics.len(), 4);
+
+    assert_eq!(
+        single_request(&mock).path,
+        "/v1/data/example/allow?instrument=true"
+    );
+
+    // Metrics are not returned unless requested.
+    let mock = MockOpa::builder()
+        .decision("example.allow", json!(true))
+        .start()
+        .unwrap();
+    let decision = mock.client().decide::<Allow>(&input()).await.unwrap();
+    assert!(decision.metrics.is_none());
+    assert_eq!(single_request(&mock).path, "/v1/data/example/allow");
+}
+
+#[tokio::test]
+async fn test_wait_for_revision() {
+    use std::time::Duration;
+
+    let status = |revision: &str| {
+        MockResponse::json(&json!({
+            "result": {
+                "bundles": {
+                    "authz": { "name": "authz", "active_revision": revision },
+                    "other": { "name": "other", "active_revision": "new" }
+                }
+            }
+        }))
+    };
+
+    let mock = MockOpa::builder()
+        .responses(
+            "GET",
+            "/v1/status",
+            [
+                status("old"),
+                MockResponse::json(&json!({})).status(503),
+                status("old"),
+                status("new"),
+            ],
+        )
+        .start()
+        .unwrap();
+
+    mock.client()
+        .wait_for_revision("authz", "new", Duration::from_secs(10))
+        .await
+        .unwrap();
+
+    let requests = mock.requests();
+    assert_eq!(requests.len(), 4);
+    assert!(requests
+        .iter()
+        .all(|r| r.method == "GET" && r.path == "/v1/status"));
+
+    let mock = MockOpa::builder()
+        .response("GET", "/v1/status", status("old"))
+        .start()
+        .unwrap();
+
+    let err = mock
+        .client()
+        .wait_for_revision("authz", "new", Duration::from_millis(300))
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        &err,
+        opa::http::Error::RevisionTimeout { active: Some(active), .. } if active == "old"
+    ));
+    assert_eq!(
+        err.to_string(),
+        "timed out waiting for revision `new` of bundle `authz`, the active revision is `old`"
+    );
+}
+
+#[tokio::test]
+async fn test_wait_for_revision_unresponsive() {
+    use std::time::Duration;
+
+    let mock = MockOpa::builder()
+        .response(
+            "GET",
+            "/v1/status",
+            MockResponse::json(&json!({})).delay(Duration::from_secs(60)),
+        )
+        .start()
+        .unwrap();
+
+    let err = tokio::time::timeout(
+        Duration::from_secs(10),
+        mock.client()
+            .wait_for_revision("authz", "new", Duration::from_millis(200)),
+    )
+    .await
+    .expect("the request was not bounded by the deadline")
+    .unwrap_err();
+    assert!(matches!(
+        err,
+        opa::http::Error::RevisionTimeout { active: None, .. }
+    ));
+}
+
+#[tokio::test]
+async fn test_input_transform() {
+    use std::sync::Arc;
+
+    let transform: Arc<dyn Fn(&mut serde_json::Value) + Send + Sync> = Arc::new(|input| {
+        input["tenant_id"] = "acme".into();
+    });
+
+    let mock = MockOpa::builder()
+        .decision("example.allow", json!(true))
+        .decision("system.main", json!(true))
+        .start()
+        .unwrap();
+    let opa = mock.client().with_input_transform(transform);
+
+    assert!(opa.decide::<Allow>(&input()).await.unwrap().result);
+
+    let allowed: bool = opa.get_default_decision(&input()).await.unwrap();
+    assert!(allowed);
+
+    let requests = mock.requests();
+    let body: serde_json::Value = serde_json::from_slice(&requests[0].body).unwrap();
+    assert_eq!(
+        body,
+        json!({ "input": { "user_id": "test", "tenant_id": "acme" } })
+    );
+
+    let body: serde_json::Value = serde_json::from_slice(&requests[1].body).unwrap();
+    assert_eq!(body, json!({ "user_id": "test", "tenant_id": "acme" }));
+}
+
+#[tokio::test]
+async fn test_version() {
+    let config = json!({
+        "result": {
+            "labels": {
+                "id": "b2b3b1f0",
+                "version": "0.61.0-dev",
+                "build_commit": "a1b2c3d"
+            },
+            "default_decision": "/system/main"
+        }
+    });
+
+    let mock = MockOpa::builder()
+        .response("GET", "/v1/config", MockResponse::json(&config))
+        .start()
+        .unwrap();
+    let opa = mock.client().cache_version(true);
+
+    let version = opa.version().await.unwrap();
+    assert_eq!(version.version, "0.61.0-dev");
+    assert_eq!(version.commit.as_deref(), Some("a1b2c3d"));
+    assert_eq!(version.build, None);
+    assert_eq!(version.numbers(), Some((0, 61, 0)));
+    assert!(version.is_at_least(0, 60, 3));
+    assert!(!version.is_at_least(1, 0, 0));
+
+    // The version is only requested once.
+    assert_eq!(opa.clone().version().await.unwrap(), version);
+    assert_eq!(single_request(&mock).path, "/v1/config");
+
+    let mock = MockOpa::builder()
+        .response(
+            "GET",
+            "/v1/config",
+            MockResponse::json(&json!({ "result": { "labels": { "id": "b2b3b1f0" } } })),
+        )
+        .start()
+        .unwrap();
+    let err = mock.client().version().await.unwrap_err();
+    assert!(matches!(err, opa::http::Error::MissingVersion), "{err}");
+}
+
+#[tokio::test]
+async fn test_set_policy_with_response() {
+    use opa::http::Policy;
+
+    let mock = MockOpa::builder()
+        .response(
+            "PUT",
+            "/v1/policies/example",
+            MockResponse::json(&json!({
+                "ast": { "package": { "path": [{ "type": "var", "value": "data" }] } },
+                "metrics": { "timer_rego_module_parse_ns": 52000 },
+                "warnings": ["deprecated keyword"]
+            })),
+        )
+        .start()
+        .unwrap();
+    let opa = mock.client();
+
+    let response = opa
+        .set_policy_with_response(Policy::new("example", "package example"))
+        .await
+        .unwrap();
+    assert_eq!(response.id, "example");
+    assert_eq!(response.ast.unwrap()["package"]["path"][0]["value"], "data");
+    assert_eq!(
+        response.metrics.unwrap()["timer_rego_module_parse_ns"],
+        52000
+    );
+    assert_eq!(response.extra["warnings"], json!(["deprecated keyword"]));
+
+    let request = single_request(&mock);
+    assert_eq!(request.method, "PUT");
+    assert_eq!(request.path, "/v1/policies/example");
+    assert_eq!(request.body, b"package example");
+
+    // OPA responds with an empty body.
+    let mock = MockOpa::builder()
+        .response("PUT", "/v1/policies/example", MockResponse::bytes(""))
+        .start()
+        .unwrap();
+
+    let response = mock
+        .client()
+        .set_policy_with_response(Policy::new("example", "package example"))
+        .await
+        .unwrap();
+    assert_eq!(response.id, "example");
+    assert!(response.ast.is_none());
+    assert!(response.metrics.is_none());
+    assert!(response.extra.is_empty());
+}
+
+#[tokio::test]
+async fn test_try_decide_defined() {
+    let mock = MockOpa::builder()
+        .decision("example.allow", json!(true))
+        .start()
+        .unwrap();
+    let opa = mock.client();
+    assert_eq!(opa.try_decide::<Allow>(&input()).await.unwrap(), Some(true));
+}
+
+#[tokio::test]
+async fn test_try_decide_undefined() {
+    let mock = MockOpa::builder()
+        .response(
+            "POST",
+            "/v1/data/example/allow",
+            MockResponse::json(&json!({ "decision_id": null })),
+        )
+        .start()
+        .unwrap();
+    let opa = mock.client();
+    assert_eq!(opa.try_decide::<Allow>(&input()).await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn test_try_decide_invalid_result() {
+    let mock = MockOpa::builder()
+        .decision("example.allow", json!("yes"))
+        .start()
+        .unwrap();
+    let opa = mock.client();
+    assert!(opa.try_decide::<Allow>(&input()).await.is_err());
+}
+
+#[tokio::test]
+async fn test_get_decision_raw_and_typed() {
+    enum Roles {}
+
+    impl PolicyDecision for Roles {
+        const POLICY_PATH: &'static str = "example.roles";
+        type Input = AllowInput;
+        type Output = Vec<String>;
+    }
+
+    let mock = MockOpa::builder()
+        .response(
+            "POST",
+            "/v1/data/example/roles",
+            MockResponse::json(&json!({
+                "result": ["admin", "dev"],
+                "decision_id": "7b3e4a4c-7b1f-4c5e-9a57-2b6c1d2e3f40"
+            })),
+        )
+        .start()
+        .unwrap();
+    let opa = mock.client();
+
+    let decision = opa
+        .get_decision_raw_and_typed::<Roles>(&input())
+        .await
+        .unwrap();
+    let (typed, raw) = decision.result;
+
+    assert_eq!(typed, vec!["admin", "dev"]);
+    assert_eq!(raw, json!(typed));
+    assert_eq!(
+        decision.decision_id.unwrap().to_string(),
+        "7b3e4a4c-7b1f-4c5e-9a57-2b6c1d2e3f40"
+    );
+
+    let mock = MockOpa::builder()
+        .decision("example.roles", json!("admin"))
+        .start()
+        .unwrap();
+    assert!(mock
+        .client()
+        .get_decision_raw_and_typed::<Roles>(&input())
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_decision_policy_paths() {
+    let mock = MockOpa::builder()
+        .decision("example.allow", json!(true))
+        .start()
+        .unwrap();
+    let opa = mock.client();
+
+    for policy in ["example.allow", "example/allow", "/example/allow"] {
+        let decision = opa.get_decision::<_, bool>(policy, &input()).await.unwrap();
+        assert!(decision.result, "{policy}");
+    }
+
+    let requests = mock.requests();
+    assert_eq!(requests.len(), 3);
+    assert!(requests
+        .iter()
+        .all(|r| r.method == "POST" && r.path == "/v1/data/example/allow"));
+}
+
+#[cfg(feature = "http-stream")]
+#[tokio::test]
+async fn test_get_decision_stream_large() {
+    let ids: Vec<String> = (0..200_000).map(|i| format!("resource-{i}")).collect();
+    let body = serde_json::to_string(&json!({ "result": ids })).unwrap();
+    assert!(body.len() > 1024 * 1024);
+
+    let mock = MockOpa::builder()
+        .response(
+            "POST",
+            "/v1/data/example/resources",
+            MockResponse::bytes(body),
+        )
+        .start()
+        .unwrap();
+    let opa = mock.client();
+
+    let decision = opa
+        .get_decision_stream::<_, Vec<String>>("example.resources", &input())
+        .await
+        .unwrap();
+    assert_eq!(decision.result, ids);
+}
+
+#[tokio::test]
+async fn test_set_document_from_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("users.json");
+    std::fs::write(&file, r#"{"alice": {"admin": true}}"#).unwrap();
+
+    let mock = MockOpa::builder().start().unwrap();
+    let opa = mock.client();
+    opa.set_document_from_file("users", &file).await.unwrap();
+
+    let request = single_request(&mock);
+    assert_eq!(request.method, "PUT");
+    assert_eq!(request.path, "/v1/data/users");
+    assert_eq!(request.body, br#"{"alice": {"admin": true}}"#);
+}
+
+#[tokio::test]
+async fn test_set_document_from_file_invalid() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("users.json");
+    std::fs::write(&file, r#"{"alice": }"#).unwrap();
+
+    let opa = Opa::new("http://127.0.0.1:1").unwrap();
+    let err = opa
+        .set_document_from_file("users", &file)
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(err, opa::http::Error::InvalidDocument { .. }),
+        "{err}"
+    );
+}
+
+#[tokio::test]
+async fn test_set_documents_from_dir() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir(dir.path().join("users")).unwrap();
+    std::fs::write(dir.path().join("users/admins.json"), "[]").unwrap();
+    std::fs::write(dir.path().join("README.md"), "not data").unwrap();
+
+    let mock = MockOpa::builder().start().unwrap();
+    let opa = mock.client();
+    opa.set_documents_from_dir(dir.path()).await.unwrap();
+
+    let request = single_request(&mock);
+    assert_eq!(request.method, "PUT");
+    assert_eq!(request.path, "/v1/data/users/admins");
+}
+
+#[tokio::test]
+async fn test_get_default_decision() {
+    let mock = MockOpa::builder()
+        .decision("system.main", json!({ "allow": true }))
+        .start()
+        .unwrap();
+    let opa = mock.client();
+
+    let decision: serde_json::Value = opa.get_default_decision(&input()).await.unwrap();
+    assert_eq!(decision, json!({ "allow": true }));
+
+    let request = single_request(&mock);
+    assert_eq!(request.method, "POST");
+    assert_eq!(request.path, "/");
+    assert_eq!(request.body, br#"{"user_id":"test"}"#);
+}
+
+#[tokio::test]
+async fn test_opa_api_error() {
+    let mock = MockOpa::builder()
+        .response(
+            "PUT",
+            "/v1/policies/example",
+            MockResponse::json(&json!({
+                "code": "invalid_parameter",
+                "message": "error(s) occurred while compiling module(s)",
+                "errors": [
+                    {
+                        "code": "rego_parse_error",
+                        "message": "unexpected eof token",
+                        "location": { "file": "example.rego", "row": 3, "col": 1 }
+                    }
+                ]
+            }))
+            .status(400),
+        )
+        .start()
+        .unwrap();
+    let opa = mock.client();
+
+    let err = opa
+        .set_policy(opa::http::Policy::new("example", "package"))
+        .await
+        .unwrap_err();
+
+    match &err {
+        opa::http::Error::Opa(api_err) => {
+            assert_eq!(api_err.status, 400);
+            assert_eq!(api_err.code, "invalid_parameter");
+            assert_eq!(api_err.errors.len(), 1);
+        }
+        _ => panic!("unexpected error: {err}"),
+    }
+    assert!(
+        err.to_string()
+            .contains("example.rego:3:1: unexpected eof token"),
+        "{err}"
+    );
+}
+
+#[tokio::test]
+async fn test_non_opa_error() {
+    let mock = MockOpa::builder()
+        .response(
+            "GET",
+            "/health",
+            MockResponse::bytes("bad gateway").status(502),
+        )
+        .start()
+        .unwrap();
+    let opa = mock.client();
+
+    match opa.health().await.unwrap_err() {
+        opa::http::Error::Http(err) => assert_eq!(err.status().unwrap().as_u16(), 502),
+        err => panic!("unexpected error: {err}"),
+    }
+}
+
+#[cfg(all(unix, feature = "build"))]
+#[test]
+fn test_set_policy_checked() {
+    use common::TestEnv;
+
+    // An `opa check` that only accepts `allow := true`.
+    let script = "[ \"$1\" = \"check\" ] || exit 1\n\
+                  grep -q \"allow := true\" \"$2\" && exit 0\n\
+                  echo \"1 error occurred: $2:3: rego_parse_error: unexpected eof token\" >&2\n\
+                  exit 1\n";
+
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    TestEnv::lock().with_fake_opa(script, || {
+        rt.block_on(async {
+            let mock = MockOpa::builder().start().unwrap();
+            let opa = mock.client();
+
+            let policy = "package example\n\nallow := true\n";
+            opa.set_policy_checked(opa::http::Policy::new("example", policy))
+                .await
+                .unwrap();
+
+            let request = single_request(&mock);
+            assert_eq!(request.method, "PUT");
+            assert_eq!(request.path, "/v1/policies/example");
+            assert_eq!(request.body, policy.as_bytes());
+
+            // No request is made for invalid policies.
+            let err = opa
+                .set_policy_checked(opa::http::Policy::new("example", "package example\n\nallow {"))
+                .await
+                .unwrap_err();
+            assert!(
+                matches!(&err, opa::http::Error::Check(message) if message.contains("rego_parse_error")),
+                "{err}"
+            );
+            assert_eq!(mock.requests().len(), 1);
+        });
+    });
+}
+
+#[tokio::test]
+async fn test_upload_bundle_bytes() {
+    let mock = MockOpa::builder()
+        .response("PUT", "/bundles/example.tar.gz", MockResponse::bytes(""))
+        .start()
+        .unwrap();
+    let opa = mock.client();
+
+    let bytes = b"\x1f\x8bnot really a bundle";
+    opa.upload_bundle_bytes("/bundles/example.tar.gz", bytes)
+        .await
+        .unwrap();
+
+    let request = single_request(&mock);
+    assert_eq!(request.method, "PUT");
+    assert_eq!(request.path, "/bundles/example.tar.gz");
+    assert_eq!(request.header("Content-Type"), Some("application/gzip"));
+    assert_eq!(request.body, bytes);
+
+    let mock = MockOpa::builder()
+        .response(
+            "PUT",
+            "/bundles/example.tar.gz",
+            MockResponse::json(&json!({ "code": "unauthorized", "message": "missing token" }))
+                .status(403),
+        )
+        .start()
+        .unwrap();
+
+    let err = mock
+        .client()
+        .upload_bundle_bytes("/bundles/example.tar.gz", bytes)
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(&err, opa::http::Error::Opa(err) if err.status == 403 && err.code == "unauthorized"),
+        "{err}"
+    );
+}
+
+#[tokio::test]
+async fn test_set_policy_raw() {
+    let mock = MockOpa::builder().start().unwrap();
+    let opa = mock.client();
+
+    let policy = "package example\n";
+    opa.set_policy_raw("example", "application/octet-stream", policy)
+        .await
+        .unwrap();
+
+    let request = single_request(&mock);
+    assert_eq!(request.method, "PUT");
+    assert_eq!(request.path, "/v1/policies/example");
+    assert_eq!(
+        request.header("Content-Type"),
+        Some("application/octet-stream")
+    );
+    assert_eq!(request.body, policy.as_bytes());
+}
+
+mod mock {
+    use super::{input, Allow};
+    use opa::http::{testing::MockOpa, DeltaOp, PatchOp, Policy};
+    use serde_json::{json, Value};
+
+    #[tokio::test]
+    async fn test_mock_decisions() {
+        let mock = MockOpa::builder()
+            .decision("example.allow", json!(true))
+            .decision_fn("example.is_test", |input| {
+                (input["user_id"] == "test").then_some(json!(true))
+            })
+            .start()
+            .unwrap();
+        let opa = mock.client();
+
+        assert!(opa.decide::<Allow>(&input()).await.unwrap().result);
+
+        let decision = opa
+            .get_decision::<_, bool>("example/is_test", &input())
+            .await
+            .unwrap();
+        assert!(decision.result);
+
+        let undefined = opa
+            .try_get_decision::<_, bool>("example.is_test", &json!({ "user_id": "other" }))
+            .await
+            .unwrap();
+        assert_eq!(undefined, None);
+
+        assert_eq!(mock.requests().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_get_decision_required() {
+        let mock = MockOpa::builder()
+            .decision_fn("example.is_test", |input| {
+                (input["user_id"] == "test").then_some(json!(true))
+            })
+            .start()
+            .unwrap();
+        let opa = mock.client();
+
+        let is_test: bool = opa
+            .get_decision_required("example.is_test", &input())
+            .await
+            .unwrap();
+        assert!(is_test);
+
+        let err = opa
+            .get_decision_required::<_, bool>("example.is_test", &json!({ "user_id": "other" }))
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, opa::http::Error::Undefined(path) if path == "example.is_test"),
+            "{err}"
+        );
+        assert_eq!(
+            err.to_string(),
+            "the decision at `example.is_test` is undefined"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_decisions() {
+        let mock = MockOpa::builder()
+            .decision("example.allow", json!(true))
+            .decision("example.roles", json!(["admin"]))
+            .decision_fn("example.user", |input| Some(input["user_id"].clone()))
+            .start()
+            .unwrap();
+        let opa = mock.client();
+
+        let decisions = opa
+            .get_decisions::<_, Value>(&[
+                ("example.user".into(), json!({ "user_id": "first" })),
+                ("example.roles".into(), json!({})),
+                ("example.allow".into(), json!({})),
+                ("example.user".into(), json!({ "user_id": "second" })),
+            ])
+            .await;
+
+        let results: Vec<Value> = decisions.into_iter().map(|d| d.unwrap().result).collect();
+        assert_eq!(
+            results,
+            [
+                json!("first"),
+                json!(["admin"]),
+                json!(true),
+                json!("second")
+            ]
+        );
+
+        let decisions = opa
+            .get_decisions::<_, bool>(&[
+                ("example.allow".into(), json!({})),
+                ("example.roles".into(), json!({})),
+            ])
+            .await;
+        assert!(decisions[0].as_ref().unwrap().result);
+        assert!(decisions[1].is_err());
+
+        assert_eq!(mock.requests().len(), 6);
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let mock = MockOpa::builder().start().unwrap();
+        let opa = mock.client();
+
+        opa.reload(
+            &[
+                Policy::new("example", "package example"),
+                Policy::new("other", "package other"),
+            ],
+            &[
+                ("users".into(), json!({ "alice": { "admin": true } })),
+                ("roles".into(), json!(["admin"])),
+            ],
+        )
+        .await
+        .unwrap();
+
+        let requests: Vec<(String, String)> = mock
+            .requests()
+            .into_iter()
+            .map(|r| (r.method, r.path))
+            .collect();
+        assert_eq!(
+            requests,
+            [
+                ("PUT".into(), "/v1/data/users".into()),
+                ("PUT".into(), "/v1/data/roles".into()),
+                ("PUT".into(), "/v1/policies/example".into()),
+                ("PUT".into(), "/v1/policies/other".into()),
+            ]
+        );
+
+        assert_eq!(mock.data()["roles"], json!(["admin"]));
+        assert_eq!(opa.list_policies().await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_mock_documents_and_policies() {
+        let mock = MockOpa::builder().start().unwrap();
+        let opa = mock.client();
+
+        opa.health().await.unwrap();
+
+        opa.set_document("users/alice", &json!({ "admin": true }))
+            .await
+            .unwrap();
+        let users = opa
+            .get_decision::<_, Value>("users", &json!({}))
+            .await
+            .unwrap();
+        assert_eq!(users.result, json!({ "alice": { "admin": true } }));
+
+        opa.delete_document("users/alice").await.unwrap();
+        assert_eq!(mock.data(), json!({ "users": {} }));
+
+        opa.set_policy(Policy::new("example", "package example"))
+            .await
+            .unwrap();
+        assert_eq!(opa.list_policies().await.unwrap().len(), 1);
+        assert!(opa.get_policy("other").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_delete_if_exists() {
+        let mock = MockOpa::builder().start().unwrap();
+        let opa = mock.client();
+
+        opa.set_document("users/alice", &json!({ "admin": true }))
+            .await
+            .unwrap();
+        opa.delete_document_if_exists("users/alice").await.unwrap();
+        assert_eq!(mock.data(), json!({ "users": {} }));
+
+        assert!(opa.delete_document("users/alice").await.is_err());
+        opa.delete_document_if_exists("users/alice").await.unwrap();
+        opa.delete_document_if_exists("missing").await.unwrap();
+
+        opa.set_policy(Policy::new("example", "package example"))
+            .await
+            .unwrap();
+        opa.delete_policy_if_exists("example").await.unwrap();
+        assert!(opa.list_policies().await.unwrap().is_empty());
+
+        assert!(opa.delete_policy("example").await.is_err());
+        opa.delete_policy_if_exists("example").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_apply_delta() {
+        let mock = MockOpa::builder()
+            .decision("users", json!({ "alice": { "admin": false } }))
+            .start()
+            .unwrap();
+        let opa = mock.client();
+
+        let deltas: Vec<DeltaOp> = serde_json::from_value(json!([
+            { "op": "upsert", "path": "/users/bob", "value": { "admin": false } },
+            { "op": "replace", "path": "/users/alice/admin", "value": true },
+        ]))
+        .unwrap();
+        opa.apply_delta(&deltas).await.unwrap();
+
+        opa.apply_delta(&[DeltaOp::Remove {
+            path: "/users/bob".into(),
+        }])
+        .await
+        .unwrap();
+
+        opa.patch_document(
+            "users",
+            &[PatchOp::Add {
+                path: "/carol".into(),
+                value: json!({ "admin": false }),
+            }],
+        )
+        .await
+        .unwrap();
+
+        let users = opa
+            .get_decision::<_, Value>("users", &json!({}))
+            .await
+            .unwrap();
+        assert_eq!(
+            users.result,
+            json!({ "alice": { "admin": true }, "carol": { "admin": false } })
+        );
+
+        assert!(opa
+            .apply_delta(&[DeltaOp::Remove {
+                path: "/users/bob".into()
+            }])
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_patches() {
+        let mock = MockOpa::builder().start().unwrap();
+
+        let err = mock
+            .client()
+            .apply_delta(&[
+                DeltaOp::Remove { path: "/".into() },
+                DeltaOp::Add {
+                    path: "/valid".into(),
+                    value: json!(1),
+                },
+                DeltaOp::Remove {
+                    path: "users".into(),
+                },
+            ])
+            .await
+            .unwrap_err();
+
+        match err {
+            opa::http::Error::InvalidPatch(errors) => assert_eq!(errors.len(), 2),
+            err => panic!("unexpected error: {err}"),
+        }
+        assert!(mock.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_mock_unhealthy() {
+        let mock = MockOpa::builder().unhealthy().start().unwrap();
+        assert!(mock.client().health().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_mock_set_document_chunked() {
+        let mock = MockOpa::builder()
+            .decision("dataset.stale", json!(true))
+            .start()
+            .unwrap();
+        let opa = mock.client();
+
+        let users: serde_json::Map<String, Value> = (0..20)
+            .map(|i| (format!("user{i}"), json!({ "roles": ["viewer", "editor"] })))
+            .collect();
+        let document = json!({
+            "users": users,
+            "settings": { "mode": "strict" },
+        });
+
+        opa.set_document_chunked("dataset", &document, 128)
+            .await
+            .unwrap();
+
+        assert_eq!(mock.data()["dataset"], document);
+
+        let requests = mock.requests();
+        assert!(requests.len() > 2);
+        for request in &requests {
+            assert_eq!(request.method, "PUT");
+            assert!(request.body.len() <= 128, "{}", request.path);
+        }
+
+        let err = opa
+            .set_document_chunked("dataset", &json!([1, 2, 3]), 128)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, opa::http::Error::NotAnObject), "{err}");
+
+        let err = opa
+            .set_document_chunked(
+                "dataset",
+                &json!({ "users": { "list": vec!["x"; 100] } }),
+                128,
+            )
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, opa::http::Error::ChunkTooLarge { path, .. } if path == "users/list"),
+            "{err}"
+        );
+        assert_eq!(mock.requests().len(), requests.len());
+    }
+
+    #[cfg(all(feature = "bundle", feature = "wasmtime-cranelift"))]
+    mod parity {
+        use opa::{
+            bundle::Bundle,
+            http::testing::{assert_backends_agree, MockOpa},
+            wasm, PolicyDecision,
+        };
+        use serde::Serialize;
+        use serde_json::{json, Value};
+
+        enum ProjectPermissions {}
+
+        #[derive(Serialize)]
+        struct ProjectInput {
+            user_id: String,
+            project_id: String,
+        }
+
+        impl PolicyDecision for ProjectPermissions {
+            const POLICY_PATH: &'static str = "example.project_permissions";
+            type Input = ProjectInput;
+            type Output = Vec<String>;
+        }
+
+        fn data() -> Value {
+            json!({
+                "users": {
+                    "test": {
+                        "projects": {
+                            "test": {
+                                "roles": ["owner"]
+                            }
+                        }
+                    }
+                },
+                "projects": {
+                    "test": {}
+                }
+            })
+        }
+
+        fn wasm_opa() -> wasm::Opa {
+            let bundle = Bundle::from_bytes(include_bytes!(
+                "../../../examples/src/bin/wasm_bundle/example.tar.gz"
+            ))
+            .unwrap();
+            let mut opa = wasm::Opa::try_from(&bundle).unwrap();
+            opa.set_data(&data()).unwrap();
+            opa
+        }
+
+        fn input(user_id: &str) -> ProjectInput {
+            ProjectInput {
+                user_id: user_id.into(),
+                project_id: "test".into(),
+            }
+        }
+
+        /// The example policy: owners can read and write their projects.
+        fn project_permissions(input: &Value) -> Option<Value> {
+            let roles = &data()["users"][input["user_id"].as_str()?]["projects"]
+                [input["project_id"].as_str()?]["roles"];
+
+            if roles
+                .as_array()
+                .is_some_and(|roles| roles.contains(&json!("owner")))
+            {
+                Some(json!(["read", "write"]))
+            } else {
+                Some(json!([]))
+            }
+        }
+
+        #[tokio::test]
+        async fn test_backends_agree() {
+            let mock = MockOpa::builder()
+                .decision_fn("example.project_permissions", project_permissions)
+                .start()
+                .unwrap();
+            let http = mock.client();
+            let mut wasm = wasm_opa();
+
+            let output =
+                assert_backends_agree::<ProjectPermissions>(&mut wasm, &http, &input("test")).await;
+            assert_eq!(output, Some(vec!["read".into(), "write".into()]));
+
+            let output =
+                assert_backends_agree::<ProjectPermissions>(&mut wasm, &http, &input("other"))
+                    .await;
+            assert_eq!(output, Some(vec![]));
+        }
+
+        #[tokio::test]
+        async fn test_set_data_at() {
+            let mock = MockOpa::builder()
+                .decision_fn("example.project_permissions", project_permissions)
+                .start()
+                .unwrap();
+            let http = mock.client();
+
+            let bundle = Bundle::from_bytes(include_bytes!(
+                "../../../examples/src/bin/wasm_bundle/example.tar.gz"
+            ))
+            .unwrap();
+            let mut wasm = wasm::Opa::try_from(&bundle).unwrap();
+
+            let documents = [
+                ("users", json!({ "test": { "projects": {} } })),
+                ("users/test/projects/test", json!({ "roles": ["owner"] })),
+                ("projects", json!({ "test": {} })),
+            ];
+
+            for (path, document) in &documents {
+                http.set_document(path, document).await.unwrap();
+                wasm.set_data_at(path, document).unwrap();
+            }
+
+            assert_eq!(mock.data(), data());
+
+            let output =
+                assert_backends_agree::<ProjectPermissions>(&mut wasm, &http, &input("test")).await;
+            assert_eq!(output, Some(vec!["read".into(), "write".into()]));
+
+            let err = wasm
+                .set_data_at("users/test/projects/test/roles/0", &json!(true))
+                .unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "cannot set data at `users/test/projects/test/roles/0`, \
+                 the parent of `0` is not an object"
+            );
+
+            // The data is unchanged after an error.
+            let output =
+                assert_backends_agree::<ProjectPermissions>(&mut wasm, &http, &input("test")).await;
+            assert_eq!(output, Some(vec!["read".into(), "write".into()]));
+        }
+
+        #[tokio::test]
+        #[should_panic(expected = "decisions of `example.project_permissions` differ")]
+        async fn test_backends_disagree() {
+            let mock = MockOpa::builder()
+                .decision("example.project_permissions", json!(["read"]))
+                .start()
+                .unwrap();
+
+            assert_backends_agree::<ProjectPermissions>(
+                &mut wasm_opa(),
+                &mock.client(),
+                &input("test"),
+            )
+            .await;
+        }
+    }
+}
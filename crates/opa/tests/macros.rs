@@ -0,0 +1,68 @@
+use opa::{bundle::Bundle, wasm::Opa, PolicyDecision};
+use serde::Serialize;
+use serde_json::json;
+
+#[derive(Serialize)]
+struct ProjectInput {
+    user_id: String,
+    project_id: String,
+}
+
+#[opa::policy_decision(
+    path = "example.project_permissions",
+    input = ProjectInput,
+    output = Vec<String>
+)]
+struct ProjectPermissions;
+
+#[opa::policy_decision(path = "/example/user_project", input = ProjectInput, output = serde_json::Value)]
+enum UserProject {}
+
+mod reexports {
+    pub use opa as renamed;
+}
+
+#[opa::policy_decision(
+    crate = crate::reexports::renamed,
+    path = "example.allow",
+    input = ProjectInput,
+    output = bool
+)]
+struct Allow;
+
+#[test]
+fn test_policy_decision() {
+    assert_eq!(
+        ProjectPermissions::POLICY_PATH,
+        "example.project_permissions"
+    );
+    assert_eq!(UserProject::POLICY_PATH, "/example/user_project");
+    assert_eq!(Allow::POLICY_PATH, "example.allow");
+
+    let mut bundle = Bundle::from_bytes(include_bytes!(
+        "../../../examples/src/bin/wasm_bundle/example.tar.gz"
+    ))
+    .unwrap();
+
+    let mut opa = Opa::new()
+        .build(bundle.wasm_policies.pop().unwrap().bytes)
+        .unwrap();
+
+    opa.set_data(&json!({
+        "users": {
+            "test": { "projects": { "test": { "roles": ["owner"] } } }
+        }
+    }))
+    .unwrap();
+
+    let input = ProjectInput {
+        user_id: "test".into(),
+        project_id: "test".into(),
+    };
+
+    let permissions = opa.decide::<ProjectPermissions>(&input).unwrap();
+    assert_eq!(permissions, ["read", "write"]);
+
+    let project = opa.decide::<UserProject>(&input).unwrap();
+    assert_eq!(project, json!({ "roles": ["owner"] }));
+}
@@ -0,0 +1,36 @@
+use opa::normalize_policy_path;
+use std::borrow::Cow;
+
+#[test]
+fn test_normalize_policy_path() {
+    assert_eq!(
+        normalize_policy_path("example.policy.allow"),
+        "example/policy/allow"
+    );
+    assert_eq!(
+        normalize_policy_path("example/policy/allow"),
+        "example/policy/allow"
+    );
+    assert_eq!(
+        normalize_policy_path("example.policy/allow"),
+        "example/policy/allow"
+    );
+    assert_eq!(
+        normalize_policy_path("/example/policy/allow"),
+        "example/policy/allow"
+    );
+    assert_eq!(normalize_policy_path("//example.allow"), "example/allow");
+    assert_eq!(normalize_policy_path(""), "");
+}
+
+#[test]
+fn test_normalize_policy_path_borrows() {
+    assert!(matches!(
+        normalize_policy_path("/example/allow"),
+        Cow::Borrowed("example/allow")
+    ));
+    assert!(matches!(
+        normalize_policy_path("example.allow"),
+        Cow::Owned(_)
+    ));
+}
@@ -1,6 +1,9 @@
-
-use opa::{bundle::Bundle, wasm::Opa};
+use opa::{
+    bundle::Bundle,
+    wasm::{replay, AbortAction, BuiltinHandler, DecisionLogEntry, Error, EvalStrategy, Opa},
+};
 use serde_json::{json, Value};
+use std::collections::HashMap;
 
 #[test]
 fn test_eval_context_reuse() {
@@ -44,3 +47,1963 @@ fn test_eval_context_reuse() {
     }
 }
 
+fn example_bundle() -> Bundle {
+    Bundle::from_bytes(include_bytes!(
+        "../../../examples/src/bin/wasm_bundle/example.tar.gz"
+    ))
+    .unwrap()
+}
+
+/// The WASM module of the example bundle.
+fn example_module() -> Vec<u8> {
+    example_bundle().wasm_policies.pop().unwrap().bytes.to_vec()
+}
+
+/// Data for the example policy where the user `test`
+/// is the owner of the project `test`.
+fn example_data() -> Value {
+    json!({
+        "users": {
+            "test": {
+                "projects": {
+                    "test": {
+                        "roles": ["owner"]
+                    }
+                }
+            }
+        },
+        "projects": {
+            "test": {}
+        }
+    })
+}
+
+/// The example policy with [`example_data`].
+fn example_opa() -> Opa {
+    let mut opa = Opa::new().build(example_module()).unwrap();
+    opa.set_data(&example_data()).unwrap();
+    opa
+}
+
+#[test]
+fn test_eval_all_results() {
+    let mut opa = Opa::new().build(example_module()).unwrap();
+
+    opa.set_data(&json!({})).unwrap();
+
+    let input = json!({
+        "user_id": "test",
+        "project_id": "test",
+    });
+
+    let results: Vec<Value> = opa
+        .eval_all_results("example.project_permissions", &input)
+        .unwrap();
+    assert_eq!(results, vec![json!([])]);
+
+    let results: Vec<Value> = opa
+        .eval_context(&input)
+        .unwrap()
+        .eval_all_results("example.project_permissions")
+        .unwrap();
+    assert_eq!(results, vec![json!([])]);
+}
+
+#[test]
+fn test_evaluate() {
+    let bundle = include_bytes!("../../../examples/src/bin/wasm_bundle/example.tar.gz");
+
+    let data = example_data();
+    let input = json!({
+        "user_id": "test",
+        "project_id": "test",
+    });
+
+    let permissions: Vec<String> =
+        opa::evaluate(bundle, &data, "example.project_permissions", &input).unwrap();
+    assert_eq!(permissions, ["read", "write"]);
+
+    assert!(opa::evaluate::<_, Value>(bundle, &data, "example.missing", &input).is_err());
+    assert!(opa::evaluate::<_, Value>(b"not a bundle", &data, "example.allow", &input).is_err());
+}
+
+#[test]
+fn test_replay() {
+    struct Allow;
+
+    impl opa::PolicyDecision for Allow {
+        const POLICY_PATH: &'static str = "test.print";
+        type Input = Value;
+        type Output = bool;
+    }
+
+    let entry: DecisionLogEntry = serde_json::from_value(json!({
+        "decision_id": "4ca636c1-55e4-417a-b1d8-4aceb67960d1",
+        "path": "test/print",
+        "input": { "user": "alice" },
+        "result": true,
+        "timestamp": "2024-01-01T00:00:00.000000000Z",
+        "labels": { "id": "e8b3a5d9", "version": "0.60.0" },
+        "metrics": { "timer_server_handler_ns": 1000 }
+    }))
+    .unwrap();
+    assert_eq!(entry.extra["labels"]["version"], "0.60.0");
+
+    // The policy before and after the change.
+    let policy = |result: &str| {
+        let mut opa = Opa::new()
+            .on_println(|_| {})
+            .build(result_module(&format!(r#"[{{"result":{result}}}]"#)))
+            .unwrap();
+        opa.set_data(&json!({})).unwrap();
+        opa
+    };
+
+    assert!(!replay::<Allow>(&mut policy("true"), &entry).unwrap());
+    assert!(replay::<Allow>(&mut policy("false"), &entry).unwrap());
+
+    // The new result is not a valid output.
+    assert!(replay::<Allow>(&mut policy(r#""yes""#), &entry).is_err());
+
+    let mut entry = entry;
+    entry.path = "other/allow".into();
+    let err = replay::<Allow>(&mut policy("true"), &entry).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "the decision log entry is for `other/allow`, not `test/print`"
+    );
+}
+
+#[test]
+fn test_eval_with_buf() {
+    let mut opa = Opa::new().build(example_module()).unwrap();
+
+    opa.set_data(&json!({
+        "users": {
+            "a": { "projects": { "p": { "roles": ["owner"] } } },
+            "b": { "projects": { "p": { "roles": ["viewer"] } } },
+        }
+    }))
+    .unwrap();
+
+    let mut buf = Vec::new();
+
+    // A shorter input after a longer one must not read stale bytes.
+    let long_input = json!({
+        "user_id": "a",
+        "project_id": "p",
+        "padding": "x".repeat(1024),
+    });
+    let short_input = json!({ "user_id": "b", "project_id": "p" });
+
+    for (input, role) in [
+        (&long_input, "owner"),
+        (&short_input, "viewer"),
+        (&long_input, "owner"),
+    ] {
+        let result: Value = opa
+            .eval_with_buf("example.user_project", input, &mut buf)
+            .unwrap();
+        assert_eq!(result, json!({ "roles": [role] }));
+        assert_eq!(buf, serde_json::to_vec(input).unwrap());
+    }
+}
+
+#[test]
+fn test_abort_error() {
+    let mut opa = Opa::new()
+        .max_memory_pages(4)
+        .on_abort_result(|_| AbortAction::Error)
+        .build(example_module())
+        .unwrap();
+
+    let data = json!({ "large": "a".repeat(1024 * 1024) });
+
+    let err = opa.set_data(&data).unwrap_err();
+    assert!(
+        matches!(err.downcast_ref(), Some(Error::Abort(_))),
+        "{err:?}"
+    );
+}
+
+#[test]
+fn test_declared_memory_pages() {
+    let bytes = example_module();
+
+    let opa = Opa::new().build(&bytes).unwrap();
+    assert_eq!(opa.declared_memory_pages(), 2);
+
+    // Raised to the declared minimum.
+    let opa = Opa::new().initial_memory_pages(1).build(&bytes).unwrap();
+    assert_eq!(opa.declared_memory_pages(), 2);
+
+    // A module with a memory import of at least 5 pages.
+    let module = MockModule {
+        context: true,
+        memory_pages: 5,
+        ..MockModule::default()
+    }
+    .build();
+
+    let mut opa = Opa::new().on_println(|_| {}).build(&module).unwrap();
+    assert_eq!(opa.declared_memory_pages(), 5);
+    opa.set_data(&json!({})).unwrap();
+    let result: bool = opa.eval("test.print", &json!({})).unwrap();
+    assert!(result);
+
+    let err = Opa::new().max_memory_pages(4).build(&module).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "the module requires at least 5 memory pages, but the maximum is 4"
+    );
+}
+
+#[test]
+fn test_abort_error_by_default() {
+    let mut opa = Opa::new()
+        .max_memory_pages(4)
+        .build(example_module())
+        .unwrap();
+
+    let err = opa
+        .set_data(&json!({ "large": "a".repeat(1024 * 1024) }))
+        .unwrap_err();
+    assert!(
+        matches!(err.downcast_ref(), Some(Error::Abort(_))),
+        "{err:?}"
+    );
+}
+
+#[cfg(feature = "wasm-cache")]
+#[test]
+fn test_caching_opa_invalidation() {
+    use opa::wasm::CachingOpa;
+    use std::{collections::HashSet, num::NonZeroUsize};
+
+    let opa = Opa::new().build(example_module()).unwrap();
+
+    let mut opa = CachingOpa::new(opa, NonZeroUsize::new(8).unwrap());
+
+    let input = json!({
+        "user_id": "test",
+        "project_id": "test",
+    });
+
+    opa.set_data(&json!({})).unwrap();
+
+    for _ in 0..3 {
+        let permissions: HashSet<String> = opa.eval("example.project_permissions", &input).unwrap();
+        assert!(permissions.is_empty());
+    }
+    assert_eq!(opa.cached_len(), 1);
+
+    opa.set_data(&example_data()).unwrap();
+    assert_eq!(opa.cached_len(), 0);
+
+    let permissions: HashSet<String> = opa.eval("example.project_permissions", &input).unwrap();
+    assert_eq!(permissions, HashSet::from(["read".into(), "write".into()]));
+}
+
+#[cfg(feature = "wasm-cache")]
+#[test]
+fn test_caching_opa_policy_path() {
+    use opa::wasm::CachingOpa;
+    use std::num::NonZeroUsize;
+
+    let opa = Opa::new().build(example_module()).unwrap();
+    let mut opa = CachingOpa::new(opa, NonZeroUsize::new(8).unwrap());
+    opa.set_data(&example_data()).unwrap();
+
+    let input = json!({
+        "user_id": "test",
+        "project_id": "test",
+    });
+
+    for entrypoint in [
+        "example.project_permissions",
+        "/example/project_permissions",
+    ] {
+        let _: Vec<String> = opa.eval(entrypoint, &input).unwrap();
+    }
+    assert_eq!(opa.cached_len(), 1);
+
+    let _: Vec<String> = opa
+        .eval(
+            "example.project_permissions",
+            &json!({ "user_id": "other" }),
+        )
+        .unwrap();
+    assert_eq!(opa.cached_len(), 2);
+}
+
+#[cfg(feature = "wasm-schema")]
+#[test]
+fn test_validate_input_bundle_schema() {
+    let mut bundle = example_bundle();
+
+    let opa = Opa::new().build_from_bundle(&bundle).unwrap();
+    opa.validate_input(&json!({ "user_id": 1 })).unwrap();
+
+    bundle.schemas.insert(
+        "schemas/input.json".into(),
+        json!({
+            "type": "object",
+            "properties": {
+                "user_id": { "type": "string" },
+                "project_id": { "type": "string" }
+            },
+            "required": ["user_id", "project_id"]
+        }),
+    );
+    let bundle = Bundle::from_bytes(bundle.to_bytes().unwrap()).unwrap();
+
+    let opa = Opa::new().build_from_bundle(&bundle).unwrap();
+
+    opa.validate_input(&json!({ "user_id": "test", "project_id": "test" }))
+        .unwrap();
+    assert!(opa.validate_input(&json!({ "user_id": 1 })).is_err());
+}
+
+#[cfg(feature = "wasm-schema")]
+#[test]
+fn test_bundle_schemas() {
+    let mut bundle = example_bundle();
+
+    let opa = Opa::new().build_from_bundle(&bundle).unwrap();
+    assert_eq!(opa.input_schema(), None);
+    assert_eq!(opa.data_schema(), None);
+
+    let input_schema = json!({
+        "type": "object",
+        "properties": { "user_id": { "type": "string" } }
+    });
+    let data_schema = json!({
+        "type": "object",
+        "properties": { "users": { "type": "object" } }
+    });
+
+    bundle
+        .schemas
+        .insert("schemas/input.json".into(), input_schema.clone());
+    bundle
+        .schemas
+        .insert("schemas/data.json".into(), data_schema.clone());
+    let bundle = Bundle::from_bytes(bundle.to_bytes().unwrap()).unwrap();
+    assert_eq!(bundle.data_schema(), Some(&data_schema));
+
+    let opa = Opa::new().build_from_bundle(&bundle).unwrap();
+    assert_eq!(opa.input_schema(), Some(&input_schema));
+    assert_eq!(opa.data_schema(), Some(&data_schema));
+
+    // Explicit schemas take precedence.
+    let opa = Opa::new()
+        .data_schema(json!({ "type": "object" }))
+        .build_from_bundle(&bundle)
+        .unwrap();
+    assert_eq!(opa.input_schema(), Some(&input_schema));
+    assert_eq!(opa.data_schema(), Some(&json!({ "type": "object" })));
+}
+
+#[test]
+fn test_eval_metrics() {
+    let mut opa = Opa::new().build(example_module()).unwrap();
+
+    opa.set_data(&json!({})).unwrap();
+
+    let small_input = json!({ "user_id": "a", "project_id": "a" });
+    let large_input = json!({ "user_id": "a".repeat(100), "project_id": "a" });
+
+    opa.eval::<_, Value>("example.project_permissions", &small_input)
+        .unwrap();
+    opa.eval::<_, Value>("example.project_permissions", &large_input)
+        .unwrap();
+
+    let mut ctx = opa.eval_context(&small_input).unwrap();
+    ctx.eval::<Value>("example.project_permissions").unwrap();
+    ctx.eval::<Value>("example.project_permissions").unwrap();
+    drop(ctx);
+
+    let small_len = serde_json::to_vec(&small_input).unwrap().len() as u64;
+    let large_len = serde_json::to_vec(&large_input).unwrap().len() as u64;
+    let output_len = br#"[{"result":[]}]"#.len() as u64;
+
+    let metrics = opa.metrics();
+    assert_eq!(metrics.evaluations, 4);
+    assert_eq!(metrics.input_bytes, 2 * small_len + large_len);
+    assert_eq!(metrics.max_input_bytes, large_len);
+    assert_eq!(metrics.output_bytes, 4 * output_len);
+    assert_eq!(metrics.max_output_bytes, output_len);
+
+    opa.reset_metrics();
+    assert_eq!(opa.metrics().evaluations, 0);
+}
+
+#[test]
+fn test_try_from_bundle() {
+    let bundle = Bundle::try_from(
+        include_bytes!("../../../examples/src/bin/wasm_bundle/example.tar.gz").to_vec(),
+    )
+    .unwrap();
+
+    let opa = Opa::try_from(&bundle).unwrap();
+    assert!(opa
+        .entrypoints()
+        .any(|e| e == "example/project_permissions"));
+
+    assert!(Bundle::try_from(b"not a bundle".to_vec()).is_err());
+}
+
+#[test]
+fn test_eval_with_overlay() {
+    use std::collections::HashSet;
+
+    let mut opa = Opa::new().build(example_module()).unwrap();
+
+    let input = json!({
+        "user_id": "test",
+        "project_id": "test",
+    });
+
+    opa.set_data(&json!({ "projects": { "test": {} } }))
+        .unwrap();
+
+    let overlay = json!({
+        "users": { "test": { "projects": { "test": { "roles": ["owner"] } } } }
+    });
+
+    for _ in 0..3 {
+        let permissions: HashSet<String> = opa
+            .eval_with_overlay("example.project_permissions", &input, &overlay)
+            .unwrap();
+        assert_eq!(permissions, HashSet::from(["read".into(), "write".into()]));
+
+        let permissions: HashSet<String> = opa.eval("example.project_permissions", &input).unwrap();
+        assert!(permissions.is_empty());
+    }
+
+    assert!(opa
+        .eval_with_overlay::<_, Value>("invalid", &input, &overlay)
+        .is_err());
+
+    let permissions: HashSet<String> = opa.eval("example.project_permissions", &input).unwrap();
+    assert!(permissions.is_empty());
+}
+
+#[test]
+fn test_build_from_rego_only_bundle() {
+    let mut bundle = example_bundle();
+    bundle.wasm_policies.clear();
+    let bundle = Bundle::from_bytes(bundle.to_bytes().unwrap()).unwrap();
+    assert!(!bundle.rego_policies.is_empty());
+
+    let err = Opa::new().build_from_bundle(&bundle).unwrap_err();
+    let message = err.to_string();
+    assert!(
+        message.contains(&format!(
+            "contains {} Rego policies",
+            bundle.rego_policies.len()
+        )),
+        "{message}"
+    );
+    assert!(message.contains("-t wasm"), "{message}");
+}
+
+#[test]
+fn test_shared_opa() {
+    use opa::wasm::SharedOpa;
+    use std::{collections::HashSet, thread};
+
+    let opa = SharedOpa::new(example_opa());
+
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let opa = opa.clone();
+            thread::spawn(move || {
+                for _ in 0..10 {
+                    let permissions: HashSet<String> = opa
+                        .eval(
+                            "example.project_permissions",
+                            &json!({ "user_id": "test", "project_id": "test" }),
+                        )
+                        .unwrap();
+                    assert_eq!(permissions, HashSet::from(["read".into(), "write".into()]));
+                }
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+#[cfg(feature = "wasm-tokio")]
+#[tokio::test]
+async fn test_async_shared_opa() {
+    use opa::wasm::AsyncSharedOpa;
+
+    let opa = AsyncSharedOpa::new(Opa::new().build(example_module()).unwrap());
+
+    opa.set_data(&json!({})).await.unwrap();
+
+    let tasks: Vec<_> = (0..4)
+        .map(|_| {
+            let opa = opa.clone();
+            tokio::spawn(async move {
+                let permissions: Vec<String> = opa
+                    .eval(
+                        "example.project_permissions",
+                        &json!({ "user_id": "test", "project_id": "test" }),
+                    )
+                    .await
+                    .unwrap();
+                assert!(permissions.is_empty());
+            })
+        })
+        .collect();
+
+    for task in tasks {
+        task.await.unwrap();
+    }
+}
+
+#[test]
+fn test_eval_default_missing_entrypoint() {
+    let mut opa = Opa::new().build(example_module()).unwrap();
+    opa.set_data(&json!({})).unwrap();
+
+    let err = opa.eval_default::<_, Value>(&json!({})).unwrap_err();
+    assert!(err.to_string().contains("system/main"), "{err}");
+}
+
+#[test]
+fn test_entrypoints_tree() {
+    let bundle = example_bundle();
+
+    let opa = Opa::try_from(&bundle).unwrap();
+
+    assert_eq!(
+        opa.entrypoints_tree(),
+        [(
+            "example".to_string(),
+            vec![
+                "project_permissions".to_string(),
+                "user_project".to_string()
+            ]
+        )]
+        .into()
+    );
+}
+
+#[test]
+fn test_is_data_set() {
+    let bundle = example_bundle();
+
+    let mut opa = Opa::try_from(&bundle).unwrap();
+    let input = json!({ "user_id": "test", "project_id": "test" });
+
+    assert!(!opa.is_data_set());
+    assert!(opa
+        .eval::<_, Value>("example.project_permissions", &input)
+        .is_err());
+
+    opa.set_data(&json!({})).unwrap();
+
+    assert!(opa.is_data_set());
+    opa.eval::<_, Value>("example.project_permissions", &input)
+        .unwrap();
+}
+
+#[test]
+fn test_require_entrypoints() {
+    let bytes = example_module();
+
+    let opa = Opa::new()
+        .require_entrypoints(["example.project_permissions", "example/user_project"])
+        .build(&bytes)
+        .unwrap();
+    assert!(opa.entrypoint_exists("example.project_permissions"));
+    assert!(opa.entrypoint_exists("example/project_permissions"));
+    assert!(!opa.entrypoint_exists("example.missing"));
+
+    let err = Opa::new()
+        .require_entrypoints(["example.project_permissions", "example.missing"])
+        .build(&bytes)
+        .unwrap_err();
+    let message = err.to_string();
+    assert!(
+        message.contains("entrypoints `example.missing`"),
+        "{message}"
+    );
+    assert!(
+        message.contains("available: `example/project_permissions`"),
+        "{message}"
+    );
+}
+
+#[test]
+fn test_eval_timed() {
+    let mut opa = Opa::new().build(example_module()).unwrap();
+    opa.set_data(&json!({})).unwrap();
+
+    let input = json!({
+        "user_id": "test",
+        "project_id": "test",
+    });
+
+    let (result, timing): (Value, _) = opa
+        .eval_timed("example.project_permissions", &input)
+        .unwrap();
+    assert_eq!(result, json!([]));
+
+    let phases = timing.serialization + timing.input + timing.evaluation + timing.deserialization;
+    assert!(phases <= timing.total, "{timing:?}");
+    assert!(!timing.evaluation.is_zero(), "{timing:?}");
+}
+
+/// A minimal module implementing the parts of the OPA ABI used by
+/// [`Opa`], the single `test/print` entrypoint prints an object
+/// and returns `true`.
+///
+/// The module reports ABI version 1.2, the evaluation is exported
+/// with `opa_eval` and/or with the evaluation context functions.
+fn abi_module(single_pass: bool, context: bool) -> Vec<u8> {
+    abi_module_with(1, single_pass, context, r#"[{"result":true}]"#)
+}
+
+/// Same as [`abi_module`] with the given ABI major version.
+fn abi_module_with_major(major: u8, single_pass: bool, context: bool) -> Vec<u8> {
+    abi_module_with(major, single_pass, context, r#"[{"result":true}]"#)
+}
+
+/// Same as [`abi_module`] with the given evaluation output.
+fn result_module(output: &str) -> Vec<u8> {
+    abi_module_with(1, true, false, output)
+}
+
+fn abi_module_with(major: u8, single_pass: bool, context: bool, output: &str) -> Vec<u8> {
+    MockModule {
+        major,
+        single_pass,
+        context,
+        output,
+        ..MockModule::default()
+    }
+    .build()
+}
+
+/// A module using `time.now_ns` that prints its value during evaluation.
+fn time_module() -> Vec<u8> {
+    MockModule {
+        print: MockPrint::Builtin("time.now_ns"),
+        ..MockModule::default()
+    }
+    .build()
+}
+
+/// A module that prints the data it is evaluated with.
+fn data_module() -> Vec<u8> {
+    MockModule {
+        print: MockPrint::Data,
+        ..MockModule::default()
+    }
+    .build()
+}
+
+/// What the mock modules print during evaluation.
+#[derive(Clone, Copy, PartialEq)]
+enum MockPrint {
+    /// A constant JSON value.
+    Constant,
+    /// The value returned by the given builtin without arguments.
+    Builtin(&'static str),
+    /// The data of a single-pass evaluation, `opa_malloc` moves the
+    /// heap for it so that the data is not overwritten by the input.
+    Data,
+}
+
+/// The WAT source of the mock modules, see [`abi_module`].
+struct MockModule<'a> {
+    major: u8,
+    single_pass: bool,
+    context: bool,
+    output: &'a str,
+    print: MockPrint,
+    /// The entrypoints of the module by their IDs.
+    entrypoints: &'a str,
+    /// The minimum size of the imported memory in pages.
+    memory_pages: u32,
+}
+
+impl Default for MockModule<'_> {
+    fn default() -> Self {
+        Self {
+            major: 1,
+            single_pass: true,
+            context: false,
+            output: r#"[{"result":true}]"#,
+            print: MockPrint::Constant,
+            entrypoints: r#"{"test/print":0}"#,
+            memory_pages: 2,
+        }
+    }
+}
+
+impl MockModule<'_> {
+    fn build(&self) -> Vec<u8> {
+        wat::parse_str(self.wat()).unwrap()
+    }
+
+    fn wat(&self) -> String {
+        let string = |s: &str| s.replace('\\', r"\\").replace('"', r#"\""#);
+
+        let mut imports = String::new();
+        let mut funcs = String::new();
+        let mut builtins = "{}".to_string();
+
+        let print = match self.print {
+            MockPrint::Constant => "(call $opa_println (i32.const 128))",
+            MockPrint::Builtin(name) => {
+                imports += r#"(import "env" "opa_builtin0" (func $opa_builtin0 (param i32 i32) (result i32)))"#;
+                funcs += r#"(func (export "builtins") (result i32) (i32.const 64))"#;
+                builtins = format!(r#"{{"{name}":0}}"#);
+                "(call $opa_println (call $opa_builtin0 (i32.const 0) (i32.const 0)))"
+            }
+            MockPrint::Data => "(call $opa_println (local.get 2))",
+        };
+
+        let malloc = if self.print == MockPrint::Data {
+            // Return the heap pointer and move it past the allocation and a null byte.
+            "(global.get $heap_ptr)
+             (global.set $heap_ptr
+               (i32.add (i32.add (global.get $heap_ptr) (local.get 0)) (i32.const 1)))"
+        } else {
+            "(i32.const 1024)"
+        };
+
+        if self.single_pass {
+            funcs += &format!(
+                r#"(func (export "opa_eval")
+                     (param i32 i32 i32 i32 i32 i32 i32) (result i32)
+                     {print}
+                     (i32.const 8192))"#
+            );
+        }
+
+        if self.context {
+            funcs += &format!(
+                r#"(func (export "opa_eval_ctx_new") (result i32) (i32.const 0))
+                   (func (export "opa_eval_ctx_set_input") (param i32 i32))
+                   (func (export "opa_eval_ctx_set_data") (param i32 i32))
+                   (func (export "opa_eval_ctx_set_entrypoint") (param i32 i32))
+                   (func (export "opa_eval_ctx_get_result") (param i32) (result i32)
+                     (i32.const 8192))
+                   (func (export "eval") (param i32) (result i32)
+                     ;; Move the heap like OPA does during evaluation.
+                     (global.set $heap_ptr (i32.add (global.get $heap_ptr) (i32.const 64)))
+                     {print}
+                     (i32.const 0))"#
+            );
+        }
+
+        format!(
+            r#"(module
+                 (import "env" "memory" (memory {memory_pages}))
+                 (import "env" "opa_println" (func $opa_println (param i32)))
+                 {imports}
+
+                 (global (export "opa_wasm_abi_version") i32 (i32.const {major}))
+                 (global (export "opa_wasm_abi_minor_version") i32 (i32.const 2))
+                 (global $heap_ptr (mut i32) (i32.const 1024))
+                 ;; A native metric.
+                 (global (export "opa_mock_metric") i32 (i32.const 7))
+
+                 (func (export "entrypoints") (result i32) (i32.const 16))
+                 (func (export "opa_json_dump") (param i32) (result i32) (local.get 0))
+                 (func (export "opa_heap_ptr_get") (result i32) (global.get $heap_ptr))
+                 (func (export "opa_heap_ptr_set") (param i32)
+                   (global.set $heap_ptr (local.get 0)))
+                 (func (export "opa_malloc") (param i32) (result i32) {malloc})
+                 (func (export "opa_json_parse") (param i32 i32) (result i32) (local.get 0))
+                 {funcs}
+
+                 (data (i32.const 16) "{entrypoints}\00")
+                 (data (i32.const 64) "{builtins}\00")
+                 (data (i32.const 128) "{print_value}\00")
+                 (data (i32.const 8192) "{output}\00"))"#,
+            memory_pages = self.memory_pages,
+            major = self.major,
+            entrypoints = string(self.entrypoints),
+            builtins = string(&builtins),
+            print_value = string(r#"{"user": {"roles": ["admin", "dev"]}}"#),
+            output = string(self.output),
+        )
+    }
+}
+
+fn print_module() -> Vec<u8> {
+    abi_module(true, false)
+}
+
+#[test]
+fn test_print_value() {
+    use std::sync::{Arc, Mutex};
+
+    let printed = Arc::new(Mutex::new(Vec::new()));
+    let printed_values = printed.clone();
+
+    let mut opa = Opa::new()
+        .on_print_value(move |value| printed_values.lock().unwrap().push(value.clone()))
+        .build(print_module())
+        .unwrap();
+    opa.set_data(&json!({})).unwrap();
+
+    let result: bool = opa.eval("test.print", &json!({})).unwrap();
+    assert!(result);
+    assert_eq!(
+        *printed.lock().unwrap(),
+        vec![json!({"user": {"roles": ["admin", "dev"]}})]
+    );
+
+    let printed = Arc::new(Mutex::new(Vec::new()));
+    let printed_strings = printed.clone();
+
+    let mut opa = Opa::new()
+        .on_println(move |s| printed_strings.lock().unwrap().push(s.to_string()))
+        .build(print_module())
+        .unwrap();
+    opa.set_data(&json!({})).unwrap();
+
+    let _: bool = opa.eval("test.print", &json!({})).unwrap();
+    assert_eq!(
+        *printed.lock().unwrap(),
+        vec![r#"{"user": {"roles": ["admin", "dev"]}}"#.to_string()]
+    );
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn test_println_to_tracing() {
+    use std::sync::{Arc, Mutex};
+    use tracing::{
+        field::{Field, Visit},
+        span, Event, Level, Metadata, Subscriber,
+    };
+
+    /// Collects the levels and messages of all events.
+    #[derive(Clone, Default)]
+    struct Collector(Arc<Mutex<Vec<(Level, String)>>>);
+
+    struct MessageVisitor<'m>(&'m mut String);
+
+    impl Visit for MessageVisitor<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            if field.name() == "message" {
+                *self.0 = format!("{value:?}");
+            }
+        }
+    }
+
+    impl Subscriber for Collector {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, _: &span::Attributes<'_>) -> span::Id {
+            span::Id::from_u64(1)
+        }
+
+        fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+
+        fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+
+        fn event(&self, event: &Event<'_>) {
+            let mut message = String::new();
+            event.record(&mut MessageVisitor(&mut message));
+            self.0
+                .lock()
+                .unwrap()
+                .push((*event.metadata().level(), message));
+        }
+
+        fn enter(&self, _: &span::Id) {}
+
+        fn exit(&self, _: &span::Id) {}
+    }
+
+    let collector = Collector::default();
+
+    tracing::subscriber::with_default(collector.clone(), || {
+        let mut opa = Opa::new()
+            .println_to_tracing()
+            .build(print_module())
+            .unwrap();
+        opa.set_data(&json!({})).unwrap();
+        let _: bool = opa.eval("test.print", &json!({})).unwrap();
+
+        let mut opa = Opa::new()
+            .max_memory_pages(4)
+            .println_to_tracing()
+            .build(example_module())
+            .unwrap();
+
+        let err = opa
+            .set_data(&json!({ "large": "a".repeat(1024 * 1024) }))
+            .unwrap_err();
+        assert!(
+            matches!(err.downcast_ref(), Some(Error::Abort(_))),
+            "{err:?}"
+        );
+    });
+
+    let events = collector.0.lock().unwrap();
+    assert_eq!(
+        events[0],
+        (
+            Level::DEBUG,
+            r#"{"user": {"roles": ["admin", "dev"]}}"#.to_string()
+        )
+    );
+    assert_eq!(events.len(), 2, "{events:?}");
+    assert_eq!(events[1].0, Level::ERROR);
+    assert!(
+        events[1].1.starts_with("OPA abort was called: "),
+        "{}",
+        events[1].1
+    );
+}
+
+#[test]
+fn test_capture_print_output() {
+    let printed = r#"{"user": {"roles": ["admin", "dev"]}}"#;
+
+    // Both the single-pass and the context evaluation strategies.
+    for module in [abi_module(true, true), abi_module(false, true)] {
+        let mut opa = Opa::new()
+            .capture_print_output(true)
+            .on_println(|s| panic!("unexpected output: {s}"))
+            .build(module)
+            .unwrap();
+        opa.set_data(&json!({})).unwrap();
+
+        let _: bool = opa.eval("test.print", &json!({})).unwrap();
+        let _: bool = opa.eval("test.print", &json!({})).unwrap();
+        assert_eq!(opa.take_last_output(), [printed]);
+        assert!(opa.take_last_output().is_empty());
+
+        let _: bool = opa.eval("test.print", &json!({})).unwrap();
+        assert_eq!(opa.take_last_output(), [printed]);
+
+        let mut ctx = opa.eval_context(&json!({})).unwrap();
+        let _: bool = ctx.eval("test.print").unwrap();
+        let _: bool = ctx.eval("test.print").unwrap();
+        drop(ctx);
+        assert_eq!(opa.take_last_output(), [printed]);
+    }
+
+    let mut opa = Opa::new().on_println(|_| {}).build(print_module()).unwrap();
+    opa.set_data(&json!({})).unwrap();
+    let _: bool = opa.eval("test.print", &json!({})).unwrap();
+    assert!(opa.take_last_output().is_empty());
+}
+
+#[test]
+fn test_eval_strategy() {
+    let opa = Opa::new().build(abi_module(true, true)).unwrap();
+    assert_eq!(opa.eval_strategy(), EvalStrategy::SinglePass);
+
+    let printed = std::sync::Arc::new(std::sync::Mutex::new(0));
+    let print_count = printed.clone();
+    let mut opa = Opa::new()
+        .on_println(move |_| *print_count.lock().unwrap() += 1)
+        .build(abi_module(false, true))
+        .unwrap();
+    assert_eq!(opa.eval_strategy(), EvalStrategy::Context);
+
+    opa.set_data(&json!({})).unwrap();
+    let result: bool = opa.eval("test/print", &json!({})).unwrap();
+    assert!(result);
+    assert_eq!(*printed.lock().unwrap(), 1);
+
+    let err = Opa::new().build(abi_module(false, false)).unwrap_err();
+    let message = err.to_string();
+    assert!(message.contains("`opa_eval_ctx_new`"), "{message}");
+    assert!(message.contains("`opa_eval` is not exported"), "{message}");
+}
+
+#[cfg(debug_assertions)]
+#[test]
+fn test_eval_context_heap_guard() {
+    use std::{
+        panic::{catch_unwind, AssertUnwindSafe},
+        sync::atomic::{AtomicBool, Ordering},
+    };
+
+    static PANIC: AtomicBool = AtomicBool::new(false);
+
+    let mut opa = Opa::new()
+        .on_println(|_| assert!(!PANIC.load(Ordering::SeqCst), "print handler panic"))
+        .build(abi_module(false, true))
+        .unwrap();
+    opa.set_data(&json!({})).unwrap();
+
+    let mut ctx = opa.eval_context(&json!({})).unwrap();
+    assert!(ctx.eval::<bool>("test/print").unwrap());
+    assert!(ctx.eval::<bool>("test/print").unwrap());
+
+    // The evaluation does not finish, so the heap is not reset.
+    PANIC.store(true, Ordering::SeqCst);
+    assert!(catch_unwind(AssertUnwindSafe(|| ctx.eval::<bool>("test/print"))).is_err());
+    PANIC.store(false, Ordering::SeqCst);
+
+    let err = ctx.eval::<bool>("test/print").unwrap_err();
+    assert!(
+        err.to_string()
+            .starts_with("the heap was moved since the evaluation context was created"),
+        "{err}"
+    );
+}
+
+#[test]
+fn test_exports() {
+    let mut opa = Opa::new().build(example_module()).unwrap();
+
+    let exports = opa.exports();
+    for name in [
+        "opa_wasm_abi_version",
+        "opa_wasm_abi_minor_version",
+        "eval",
+        "entrypoints",
+        "opa_eval_ctx_new",
+        "opa_eval_ctx_set_input",
+        "opa_eval_ctx_set_data",
+        "opa_eval_ctx_set_entrypoint",
+        "opa_eval_ctx_get_result",
+        "opa_malloc",
+        "opa_json_parse",
+        "opa_json_dump",
+        "opa_heap_ptr_get",
+        "opa_heap_ptr_set",
+    ] {
+        assert!(exports.iter().any(|e| e == name), "missing `{name}`");
+    }
+
+    let mut opa = Opa::new().build(abi_module(false, true)).unwrap();
+    assert!(!opa.exports().iter().any(|e| e == "opa_eval"));
+}
+
+#[test]
+fn test_input_scratch_bytes() {
+    let bytes = example_module();
+
+    let data = example_data();
+
+    let input = |padding: usize| {
+        json!({
+            "user_id": "test",
+            "project_id": "test",
+            "padding": "x".repeat(padding),
+        })
+    };
+
+    let mut opa = Opa::new()
+        .input_scratch_bytes(512 * 1024)
+        .build(&bytes)
+        .unwrap();
+    opa.set_data(&data).unwrap();
+    let grows = opa.metrics().memory_grows;
+
+    let expected: Value = opa.eval("example.project_permissions", &input(0)).unwrap();
+
+    for padding in [0, 300_000, 10, 300_000, 0] {
+        let result: Value = opa
+            .eval("example.project_permissions", &input(padding))
+            .unwrap();
+        assert_eq!(result, expected, "padding {padding}");
+
+        let result: Value = opa
+            .eval_context(&input(padding))
+            .unwrap()
+            .eval("example.project_permissions")
+            .unwrap();
+        assert_eq!(result, expected, "padding {padding}");
+    }
+    assert_eq!(opa.metrics().memory_grows, grows);
+
+    let mut opa = Opa::new().build(&bytes).unwrap();
+    opa.set_data(&data).unwrap();
+
+    for padding in [0, 300_000, 10, 300_000, 0] {
+        let result: Value = opa
+            .eval("example.project_permissions", &input(padding))
+            .unwrap();
+        assert_eq!(result, expected, "padding {padding}");
+    }
+    assert_eq!(opa.metrics().memory_grows, 1);
+}
+
+#[test]
+fn test_auto_set_bundle_data() {
+    let mut bundle = example_bundle();
+    bundle.set_data(None);
+
+    let dir = tempfile::tempdir().unwrap();
+    bundle.extract_to(dir.path()).unwrap();
+
+    // Data files are placed at the path of their directory.
+    let data_dir = dir.path().join("users/test");
+    std::fs::create_dir_all(&data_dir).unwrap();
+    std::fs::write(
+        data_dir.join("data.json"),
+        json!({ "projects": { "test": { "roles": ["owner"] } } }).to_string(),
+    )
+    .unwrap();
+
+    let mut ar = tar::Builder::new(flate2::write::GzEncoder::new(
+        Vec::new(),
+        flate2::Compression::default(),
+    ));
+    for path in ["users/test/data.json", ".manifest", "policy.wasm"] {
+        ar.append_path_with_name(dir.path().join(path), path)
+            .unwrap();
+    }
+    let bundle = Bundle::from_bytes(ar.into_inner().unwrap().finish().unwrap()).unwrap();
+
+    assert_eq!(
+        bundle.data(),
+        Some(&json!({ "users": { "test": { "projects": { "test": { "roles": ["owner"] } } } } }))
+    );
+
+    let input = json!({
+        "user_id": "test",
+        "project_id": "test",
+    });
+
+    let mut opa = Opa::new()
+        .auto_set_bundle_data(true)
+        .build_from_bundle(&bundle)
+        .unwrap();
+    let permissions: Vec<String> = opa.eval("example.project_permissions", &input).unwrap();
+    assert_eq!(permissions, ["read", "write"]);
+
+    let opa = Opa::new().build_from_bundle(&bundle).unwrap();
+    assert!(!opa.is_data_set());
+}
+
+#[test]
+fn test_auto_set_bundle_lazy_data() {
+    let mut bundle = example_bundle();
+    bundle.set_data(Some(example_data()));
+    let bytes = bundle.to_bytes().unwrap();
+
+    let bundle =
+        Bundle::from_reader_lazy_data(&bytes[..], &opa::bundle::BundleLimits::default()).unwrap();
+    assert!(bundle.data().is_none());
+
+    let mut opa = Opa::new()
+        .auto_set_bundle_data(true)
+        .build_from_bundle(&bundle)
+        .unwrap();
+
+    let input = json!({
+        "user_id": "test",
+        "project_id": "test",
+    });
+    let permissions: Vec<String> = opa.eval("example.project_permissions", &input).unwrap();
+    assert_eq!(permissions, ["read", "write"]);
+}
+
+#[test]
+fn test_auto_set_bundle_removed_lazy_data() {
+    let mut bundle = example_bundle();
+    bundle.set_data(Some(example_data()));
+    let bytes = bundle.to_bytes().unwrap();
+
+    let mut bundle =
+        Bundle::from_reader_lazy_data(&bytes[..], &opa::bundle::BundleLimits::default()).unwrap();
+    bundle.set_data(None);
+
+    let opa = Opa::new()
+        .auto_set_bundle_data(true)
+        .build_from_bundle(&bundle)
+        .unwrap();
+    assert!(!opa.is_data_set());
+}
+
+#[test]
+fn test_verify_against_manifest() {
+    let mut bundle = example_bundle();
+
+    let opa = Opa::new().build_from_bundle(&bundle).unwrap();
+    assert_eq!(opa.verify_against_manifest(&bundle), Ok(()));
+
+    let manifest = bundle.manifest.as_mut().unwrap();
+    manifest.wasm[0].entrypoint = "example.missing".into();
+    manifest.wasm.pop();
+
+    assert_eq!(
+        opa.verify_against_manifest(&bundle),
+        Err(vec![
+            "example/missing".to_string(),
+            "example/project_permissions".to_string(),
+            "example/user_project".to_string(),
+        ])
+    );
+    assert!(!opa.entrypoint_exists("example/missing"));
+}
+
+#[test]
+fn test_eval_with_metrics() {
+    let mut opa = Opa::new().build(example_module()).unwrap();
+    opa.set_data(&json!({})).unwrap();
+
+    let input = json!({
+        "user_id": "test",
+        "project_id": "test",
+    });
+    let (permissions, metrics): (Vec<String>, _) = opa
+        .eval_with_metrics("example.project_permissions", &input)
+        .unwrap();
+    assert!(permissions.is_empty());
+
+    let names: Vec<_> = metrics.keys().map(String::as_str).collect();
+    assert_eq!(
+        names,
+        [
+            "timer_wasm_deserialization_ns",
+            "timer_wasm_eval_ns",
+            "timer_wasm_input_ns",
+            "timer_wasm_serialization_ns",
+            "timer_wasm_total_ns",
+        ]
+    );
+    assert!(metrics["timer_wasm_total_ns"].as_u64().unwrap() > 0);
+
+    // The native metrics of the module, without the ABI version.
+    let mut opa = Opa::new()
+        .on_println(|_| {})
+        .build(abi_module(true, true))
+        .unwrap();
+    opa.set_data(&json!({})).unwrap();
+
+    let (_, metrics): (bool, _) = opa.eval_with_metrics("test.print", &json!({})).unwrap();
+    assert_eq!(metrics["opa_mock_metric"], 7);
+    assert!(!metrics.contains_key("opa_wasm_abi_version"));
+    assert_eq!(metrics.len(), 6);
+}
+
+#[test]
+fn test_set_data_from_reader() {
+    let mut opa = Opa::new().build(example_module()).unwrap();
+
+    let users: serde_json::Map<String, Value> = (0..20_000)
+        .map(|i| {
+            (
+                format!("user-{i}"),
+                json!({ "projects": { "test": { "roles": ["owner"] } } }),
+            )
+        })
+        .collect();
+
+    let mut file = tempfile::tempfile().unwrap();
+    serde_json::to_writer(&mut file, &json!({ "users": users })).unwrap();
+    std::io::Seek::rewind(&mut file).unwrap();
+    assert!(file.metadata().unwrap().len() > 1024 * 1024);
+
+    opa.set_data_from_reader(std::io::BufReader::new(file))
+        .unwrap();
+
+    for user in ["user-0", "user-19999"] {
+        let input = json!({
+            "user_id": user,
+            "project_id": "test",
+        });
+        let permissions: Vec<String> = opa.eval("example.project_permissions", &input).unwrap();
+        assert_eq!(permissions, ["read", "write"]);
+    }
+
+    let err = opa.set_data_from_reader(&b"{\"users\":"[..]).unwrap_err();
+    assert_eq!(err.to_string(), "the data is not valid JSON");
+    assert!(!opa.is_data_set());
+
+    // Smaller data is read into the same region.
+    opa.set_data_from_reader(&br#"{"users":{}}"#[..]).unwrap();
+    let input = json!({
+        "user_id": "user-0",
+        "project_id": "test",
+    });
+    let permissions: Vec<String> = opa.eval("example.project_permissions", &input).unwrap();
+    assert!(permissions.is_empty());
+}
+
+#[test]
+fn test_set_data_bytes() {
+    let bytes = example_module();
+
+    let data = serde_json::to_vec(&example_data()).unwrap();
+
+    let input = json!({
+        "user_id": "test",
+        "project_id": "test",
+    });
+
+    // The same serialized data is shared by multiple instances.
+    for _ in 0..3 {
+        let mut opa = Opa::new().build(&bytes).unwrap();
+        opa.set_data_bytes(&data).unwrap();
+
+        let permissions: Vec<String> = opa.eval("example.project_permissions", &input).unwrap();
+        assert_eq!(permissions, ["read", "write"]);
+
+        assert!(opa.set_data_bytes(b"{\"users\": ").is_err());
+        assert!(!opa.is_data_set());
+        assert!(opa
+            .eval::<_, Value>("example.project_permissions", &input)
+            .is_err());
+
+        opa.set_data_bytes(&data).unwrap();
+        let permissions: Vec<String> = opa.eval("example.project_permissions", &input).unwrap();
+        assert_eq!(permissions, ["read", "write"]);
+    }
+}
+
+#[test]
+fn test_eval_raw() {
+    let mut opa = example_opa();
+
+    let input = json!({
+        "user_id": "test",
+        "project_id": "test",
+    });
+
+    let compact = opa.eval_raw("example.user_project", &input).unwrap();
+    let pretty = opa.eval_raw_pretty("example.user_project", &input).unwrap();
+
+    assert!(!compact.contains('\n'), "{compact}");
+    assert!(pretty.contains('\n'), "{pretty}");
+    assert_eq!(
+        serde_json::from_str::<Value>(&pretty).unwrap(),
+        serde_json::from_str::<Value>(&compact).unwrap()
+    );
+    assert_eq!(
+        serde_json::from_str::<Value>(&compact).unwrap(),
+        json!({"roles": ["owner"]})
+    );
+}
+
+#[test]
+fn test_eval_required() {
+    let mut opa = Opa::new().build(example_module()).unwrap();
+
+    opa.set_data(&json!({})).unwrap();
+
+    let input = json!({
+        "user_id": "test",
+        "project_id": "test",
+    });
+
+    let err = opa
+        .eval_required::<_, Value>("example.user_project", &input)
+        .unwrap_err();
+    assert!(
+        matches!(err.downcast_ref(), Some(Error::Undefined(entrypoint)) if entrypoint == "example.user_project"),
+        "{err}"
+    );
+
+    opa.set_data(&example_data()).unwrap();
+
+    let permissions: Value = opa
+        .eval_required("example.project_permissions", &input)
+        .unwrap();
+    assert_eq!(permissions, json!(["read", "write"]));
+}
+
+#[test]
+fn test_build_index_from_bundle() {
+    let mut bundle = example_bundle();
+
+    // A bundle with an unrelated module first.
+    let example = bundle.wasm_policies.pop().unwrap();
+    bundle.wasm_policies = vec![
+        opa::bundle::WasmPolicy {
+            entrypoint: "test/print".into(),
+            bytes: print_module().into(),
+        },
+        example,
+    ];
+    assert_eq!(bundle.wasm_policy_count(), 2);
+
+    let mut opa = Opa::new()
+        .auto_set_bundle_data(true)
+        .build_index_from_bundle(&bundle, 1)
+        .unwrap();
+    assert!(opa.is_data_set());
+
+    opa.set_data(&example_data()).unwrap();
+
+    let result: Value = opa
+        .eval(
+            &bundle.wasm_policies[1].entrypoint,
+            &json!({ "user_id": "test", "project_id": "test" }),
+        )
+        .unwrap();
+    assert_eq!(result, json!({ "roles": ["owner"] }));
+
+    let opa = Opa::new().build_index_from_bundle(&bundle, 0).unwrap();
+    assert!(opa
+        .entrypoints()
+        .any(|entrypoint| entrypoint == "test/print"));
+
+    let err = Opa::new().build_index_from_bundle(&bundle, 2).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "there is no WASM module at index 2, the bundle contains 2 WASM modules"
+    );
+}
+
+#[test]
+fn test_eval_to_handle() {
+    let mut opa = Opa::new().build(example_module()).unwrap();
+
+    // The project of the first stage is the input of the second one.
+    opa.set_data(&json!({
+        "users": {
+            "test": {
+                "projects": {
+                    "test": {
+                        "user_id": "test",
+                        "project_id": "test",
+                        "roles": ["owner"]
+                    },
+                    "data": {
+                        "users": {
+                            "other": {
+                                "projects": {
+                                    "other": { "roles": ["owner"] }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }))
+    .unwrap();
+
+    let project = opa
+        .eval_to_handle(
+            "example.user_project",
+            &json!({ "user_id": "test", "project_id": "test" }),
+        )
+        .unwrap();
+    let data = opa
+        .eval_to_handle(
+            "example.user_project",
+            &json!({ "user_id": "test", "project_id": "data" }),
+        )
+        .unwrap();
+
+    // Handles are kept across evaluations.
+    for _ in 0..10 {
+        let _: Value = opa
+            .eval(
+                "example.user_project",
+                &json!({ "user_id": "test", "project_id": "test" }),
+            )
+            .unwrap();
+    }
+
+    assert_eq!(
+        opa.read_handle::<Value>(&project).unwrap(),
+        json!({ "user_id": "test", "project_id": "test", "roles": ["owner"] })
+    );
+
+    let permissions: Vec<String> = opa
+        .eval_context_with_handle(&project)
+        .unwrap()
+        .eval("example.project_permissions")
+        .unwrap();
+    assert_eq!(permissions, ["read", "write"]);
+
+    opa.set_data_from_handle(&data).unwrap();
+    let permissions: Vec<String> = opa
+        .eval(
+            "example.project_permissions",
+            &json!({ "user_id": "other", "project_id": "other" }),
+        )
+        .unwrap();
+    assert_eq!(permissions, ["read", "write"]);
+
+    // Setting the data invalidates all handles.
+    opa.set_data(&json!({})).unwrap();
+    assert!(opa.read_handle::<Value>(&project).is_err());
+    assert!(opa.set_data_from_handle(&data).is_err());
+
+    // Handles are specific to their instance.
+    let mut other = Opa::new().build(abi_module(false, true)).unwrap();
+    other.set_data(&json!({})).unwrap();
+    assert!(other.eval_context_with_handle(&project).is_err());
+
+    let result = other.eval_to_handle("test/print", &json!({})).unwrap();
+    assert!(other.eval_context_with_handle(&result).is_ok());
+
+    assert!(opa
+        .eval_to_handle(
+            "example.user_project",
+            &json!({ "user_id": "test", "project_id": "test" })
+        )
+        .is_err());
+}
+
+#[cfg(feature = "wasm-pooling")]
+#[test]
+fn test_pooling_allocator() {
+    let bytes = example_module();
+
+    let mut pooling = wasmtime::PoolingAllocationConfig::default();
+    pooling.instance_count(2).instance_memories(0);
+    let engine = Opa::new()
+        .with_pooling_allocator(pooling)
+        .unwrap()
+        .engine()
+        .clone();
+    let build = || Opa::new().with_engine(engine.clone()).build(&bytes);
+
+    let mut first = build().unwrap();
+    first.set_data(&example_data()).unwrap();
+    let permissions: Vec<String> = first
+        .eval(
+            "example.project_permissions",
+            &json!({ "user_id": "test", "project_id": "test" }),
+        )
+        .unwrap();
+    assert_eq!(permissions, ["read", "write"]);
+
+    let second = build().unwrap();
+    assert!(build().is_err());
+
+    // Instances are returned to the pool when they are dropped.
+    drop(second);
+    build().unwrap();
+}
+
+#[test]
+fn test_unsupported_abi_major_version() {
+    let err = Opa::new()
+        .build(abi_module_with_major(2, true, false))
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "unsupported OPA WASM ABI major version 2 (this crate supports 1)"
+    );
+
+    assert!(Opa::new()
+        .build(abi_module_with_major(1, true, false))
+        .is_ok());
+}
+
+#[cfg(feature = "wasmtime-aot")]
+#[test]
+fn test_precompiled_target_mismatch() {
+    let bundle = || example_bundle();
+
+    // The precompiled module is not used for other targets,
+    // so the invalid bytes are never deserialized.
+    let mut other_target = bundle();
+    unsafe {
+        other_target.set_wasmtime_bytes_for_target(
+            b"not a precompiled module"[..].into(),
+            "riscv64gc-unknown-none-elf",
+        );
+    }
+    let opa = Opa::new().build_from_bundle(&other_target).unwrap();
+    assert!(opa.entrypoint_exists("example.project_permissions"));
+
+    let mut host_target = bundle();
+    unsafe {
+        host_target.set_wasmtime_bytes_for_target(
+            b"not a precompiled module"[..].into(),
+            &target_lexicon::HOST.to_string(),
+        );
+    }
+    assert!(Opa::new().build_from_bundle(&host_target).is_err());
+}
+
+#[test]
+#[cfg(feature = "preserve-order")]
+fn test_eval_preserves_key_order() {
+    let mut opa = Opa::new()
+        .on_println(|_| {})
+        .build(result_module(
+            r#"[{"result":{"zone":"eu","allow":true,"matched":{"role":"admin","action":"read"}}}]"#,
+        ))
+        .unwrap();
+    opa.set_data(&json!({})).unwrap();
+
+    let result: Value = opa.eval("test/print", &json!({})).unwrap();
+
+    let keys: Vec<&str> = result
+        .as_object()
+        .unwrap()
+        .keys()
+        .map(String::as_str)
+        .collect();
+    assert_eq!(keys, ["zone", "allow", "matched"]);
+
+    let keys: Vec<&str> = result["matched"]
+        .as_object()
+        .unwrap()
+        .keys()
+        .map(String::as_str)
+        .collect();
+    assert_eq!(keys, ["role", "action"]);
+}
+
+#[test]
+fn test_eval_iter() {
+    let numbers: Vec<u64> = (0..10_000).collect();
+    let output = json!([{ "result": numbers }]).to_string();
+
+    let mut opa = Opa::new()
+        .on_println(|_| {})
+        .build(result_module(&output))
+        .unwrap();
+    opa.set_data(&json!({})).unwrap();
+
+    let iter = opa.eval_iter::<_, u64>("test/print", &json!({})).unwrap();
+    assert_eq!(iter.len(), numbers.len());
+
+    let sum = iter.map(Result::unwrap).sum::<u64>();
+    assert_eq!(sum, numbers.iter().sum::<u64>());
+
+    let mut iter = opa
+        .eval_iter::<_, String>("test/print", &json!({}))
+        .unwrap();
+    assert!(iter.next().unwrap().is_err());
+    drop(iter);
+
+    let result: Vec<u64> = opa.eval("test/print", &json!({})).unwrap();
+    assert_eq!(result, numbers);
+
+    let mut opa = Opa::new()
+        .on_println(|_| {})
+        .build(result_module(r#"[{"result":{"not":"an array"}}]"#))
+        .unwrap();
+    opa.set_data(&json!({})).unwrap();
+
+    let err = opa
+        .eval_iter::<_, Value>("test/print", &json!({}))
+        .unwrap_err();
+    assert!(err.to_string().contains("not an array"), "{err}");
+}
+
+#[test]
+fn test_eval_output_mismatch() {
+    let mut opa = Opa::new().build(example_module()).unwrap();
+
+    opa.set_data(&json!({
+        "users": {
+            "test": {
+                "projects": {
+                    "test": {
+                        "roles": ["owner", "x".repeat(300)]
+                    }
+                }
+            }
+        }
+    }))
+    .unwrap();
+
+    let input = json!({
+        "user_id": "test",
+        "project_id": "test",
+    });
+
+    // The result is an object with roles, not a list of roles.
+    let err = opa
+        .eval::<_, Vec<String>>("example.user_project", &input)
+        .unwrap_err();
+
+    let message = err.to_string();
+    assert!(
+        message.starts_with(
+            "failed to deserialize the output of `example.user_project` (335 bytes): \
+             [{\"result\":{\"roles\":[\"owner\",\"xxx"
+        ),
+        "{message}"
+    );
+    assert!(message.ends_with("xxx..."), "{message}");
+    assert!(err.downcast_ref::<serde_json::Error>().is_some());
+    assert!(format!("{err:#}").contains("invalid type: map, expected a sequence"));
+}
+
+#[test]
+fn test_fixed_time() {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    // The module prints the value of `time.now_ns` for every evaluation.
+    let mut opa = Opa::new()
+        .fixed_time(1_700_000_000_000_000_000)
+        .capture_print_output(true)
+        .build(time_module())
+        .unwrap();
+    opa.set_data(&json!({})).unwrap();
+
+    for _ in 0..2 {
+        let _: bool = opa.eval("test.print", &json!({})).unwrap();
+        assert_eq!(opa.take_last_output(), ["1700000000000000000"]);
+    }
+
+    opa.set_time(1_800_000_000_000_000_000);
+    let _: bool = opa.eval("test.print", &json!({})).unwrap();
+    assert_eq!(opa.take_last_output(), ["1800000000000000000"]);
+
+    // The current time is used without a fixed time.
+    let mut opa = Opa::new()
+        .capture_print_output(true)
+        .build(time_module())
+        .unwrap();
+    opa.set_data(&json!({})).unwrap();
+
+    let now = || {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos()
+    };
+    let before = now();
+    let _: bool = opa.eval("test.print", &json!({})).unwrap();
+    let after = now();
+
+    let time: u128 = opa.take_last_output()[0].parse().unwrap();
+    assert!(
+        before <= time && time <= after,
+        "{before} <= {time} <= {after}"
+    );
+}
+
+/// A module that exposes no entrypoints, like one built without `-e`.
+fn no_entrypoints_module() -> Vec<u8> {
+    MockModule {
+        entrypoints: "{}",
+        ..MockModule::default()
+    }
+    .build()
+}
+
+#[test]
+fn test_no_entrypoints() {
+    let opa = Opa::new().build(no_entrypoints_module()).unwrap();
+    assert_eq!(opa.entrypoint_count(), 0);
+
+    let opa = Opa::new().build(abi_module(true, false)).unwrap();
+    assert_eq!(opa.entrypoint_count(), 1);
+
+    let err = Opa::new()
+        .require_entrypoints_present(true)
+        .build(no_entrypoints_module())
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "the module does not expose any entrypoints, \
+         the policy must be built with `-e` for at least one of them"
+    );
+
+    Opa::new()
+        .require_entrypoints_present(true)
+        .build(abi_module(true, false))
+        .unwrap();
+}
+
+#[test]
+fn test_eval_input_at() {
+    let mut opa = Opa::new()
+        .capture_print_output(true)
+        .build(data_module())
+        .unwrap();
+    opa.set_data(&json!({ "users": ["alice"] })).unwrap();
+
+    let result: bool = opa
+        .eval_input_at("test.print", &json!({ "user": "alice" }), "request/http")
+        .unwrap();
+    assert!(result);
+
+    let data: Value = serde_json::from_str(&opa.take_last_output()[0]).unwrap();
+    assert_eq!(
+        data,
+        json!({
+            "users": ["alice"],
+            "request": { "http": { "user": "alice" } }
+        })
+    );
+
+    // The data is unchanged for later evaluations.
+    let _: bool = opa.eval("test.print", &json!({})).unwrap();
+    let data: Value = serde_json::from_str(&opa.take_last_output()[0]).unwrap();
+    assert_eq!(data, json!({ "users": ["alice"] }));
+}
+
+/// The same as [`time_module`], but using `custom.echo` instead.
+fn custom_builtin_module() -> Vec<u8> {
+    MockModule {
+        print: MockPrint::Builtin("custom.echo"),
+        ..MockModule::default()
+    }
+    .build()
+}
+
+#[test]
+fn test_check_builtins() {
+    let opa = Opa::new().build(custom_builtin_module()).unwrap();
+    assert_eq!(
+        opa.check_builtins(&HashMap::new()),
+        Err(vec!["custom.echo".to_string()])
+    );
+
+    let mut provided: HashMap<String, BuiltinHandler> = HashMap::new();
+    provided.insert("custom.echo".into(), Box::new(|_, _| Ok(json!("hello"))));
+    assert_eq!(opa.check_builtins(&provided), Ok(()));
+
+    // Implemented by the crate.
+    let opa = Opa::new().build(time_module()).unwrap();
+    assert_eq!(opa.check_builtins(&HashMap::new()), Ok(()));
+
+    let err = Opa::new()
+        .require_builtins(true)
+        .build(custom_builtin_module())
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "the module uses the builtins `custom.echo` without handlers"
+    );
+
+    let mut opa = Opa::new()
+        .require_builtins(true)
+        .builtin("custom.echo", |args| {
+            assert!(args.is_empty());
+            Ok(json!("hello"))
+        })
+        .capture_print_output(true)
+        .build(custom_builtin_module())
+        .unwrap();
+    opa.set_data(&json!({})).unwrap();
+
+    let _: bool = opa.eval("test.print", &json!({})).unwrap();
+    assert_eq!(opa.take_last_output(), [r#""hello""#]);
+}
+
+#[test]
+fn test_eval_with_context() {
+    use opa::wasm::OpaWithContext;
+
+    /// The context of a request.
+    #[derive(Debug)]
+    struct Tenant {
+        name: String,
+    }
+
+    // The mock module does not terminate the values
+    // it prints, so the names never get shorter.
+    impl Default for Tenant {
+        fn default() -> Self {
+            Self {
+                name: "none".into(),
+            }
+        }
+    }
+
+    let tenant = |name: &str| Tenant {
+        name: name.to_string(),
+    };
+
+    let mut opa = OpaWithContext::<Tenant>::new()
+        .builtin_with_context("custom.echo", |tenant, args| {
+            assert!(args.is_empty());
+            assert_ne!(tenant.name, "panic", "builtin panicked");
+            Ok(json!(tenant.name))
+        })
+        .capture_print_output(true)
+        .build(custom_builtin_module())
+        .unwrap();
+    opa.set_data(&json!({})).unwrap();
+
+    let _: bool = opa
+        .eval_with_context("test.print", &json!({}), tenant("acme"))
+        .unwrap();
+    assert_eq!(opa.take_last_output(), [r#""acme""#]);
+
+    // The previous context is restored after the evaluation.
+    let _: bool = opa.eval("test.print", &json!({})).unwrap();
+    assert_eq!(opa.take_last_output(), [r#""none""#]);
+
+    opa.context_mut().name = "other".into();
+    let _: bool = opa.eval("test.print", &json!({})).unwrap();
+    assert_eq!(opa.take_last_output(), [r#""other""#]);
+    assert_eq!(opa.context().name, "other");
+
+    // The previous context is also restored if a builtin panics.
+    let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        opa.eval_with_context::<_, bool>("test.print", &json!({}), tenant("panic"))
+    }));
+    assert!(res.is_err());
+    assert_eq!(opa.context().name, "other");
+}
+
+#[test]
+fn test_max_message_bytes() {
+    let mut opa = Opa::new()
+        .capture_print_output(true)
+        .max_message_bytes(8)
+        .build(print_module())
+        .unwrap();
+    opa.set_data(&json!({})).unwrap();
+
+    let _: bool = opa.eval("test.print", &json!({})).unwrap();
+    assert_eq!(opa.take_last_output(), [r#"{"user":... [truncated]"#]);
+
+    // The data module prints the JSON of the data, only
+    // the maximum is read from the memory.
+    let mut opa = Opa::new()
+        .capture_print_output(true)
+        .max_message_bytes(1024)
+        .build(data_module())
+        .unwrap();
+    opa.set_data(&json!("x".repeat(5000))).unwrap();
+
+    let _: bool = opa.eval("test.print", &json!({})).unwrap();
+    let output = opa.take_last_output().pop().unwrap();
+    assert_eq!(output.len(), 1024 + "... [truncated]".len());
+    assert!(output.starts_with("\"xxx"));
+    assert!(output.ends_with("x... [truncated]"));
+}
+
+#[test]
+fn test_build_from_wasm_file() {
+    let bundle_bytes = include_bytes!("../../../examples/src/bin/wasm_bundle/example.tar.gz");
+    let bundle = Bundle::from_bytes(bundle_bytes).unwrap();
+
+    let dir = tempfile::tempdir().unwrap();
+    bundle.extract_to(dir.path()).unwrap();
+
+    let mut opa = Opa::new()
+        .build_from_wasm_file(dir.path().join("policy.wasm"))
+        .unwrap();
+    opa.set_data(&json!({})).unwrap();
+    assert!(opa.entrypoint_exists("example.project_permissions"));
+
+    let bundle_path = dir.path().join("bundle.tar.gz");
+    std::fs::write(&bundle_path, bundle_bytes).unwrap();
+    let err = Opa::new().build_from_wasm_file(&bundle_path).unwrap_err();
+    assert!(
+        err.to_string().ends_with(
+            "is not a WASM module, bundles must be loaded \
+             with `Bundle::from_file` and `build_from_bundle` instead"
+        ),
+        "{err}"
+    );
+
+    let err = Opa::new()
+        .build_from_wasm_file(dir.path().join("missing.wasm"))
+        .unwrap_err();
+    assert!(err.to_string().starts_with("failed to read"), "{err}");
+}
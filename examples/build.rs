@@ -15,4 +15,17 @@ fn main() {
         .add_entrypoint("example2.project_permissions2")
         .compile()
         .unwrap();
+
+    opa::build::policy("multi")
+        .add_source("./policies/multi")
+        .add_entrypoint("a.allow")
+        .bundle_mode(true)
+        .compile()
+        .unwrap();
+
+    opa::build::policy("with_data")
+        .add_source("./policies/with_data")
+        .add_entrypoint("roles.allow")
+        .compile()
+        .unwrap();
 }
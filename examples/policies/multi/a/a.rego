@@ -0,0 +1,7 @@
+package a
+
+import data.b
+
+allow {
+    b.is_admin
+}
@@ -0,0 +1,5 @@
+package b
+
+is_admin {
+    input.role == "admin"
+}
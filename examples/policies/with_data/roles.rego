@@ -0,0 +1,5 @@
+package roles
+
+allow {
+    data.roles[input.role].admin
+}
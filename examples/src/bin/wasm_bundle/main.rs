@@ -4,22 +4,7 @@ use serde_json::{json, Value};
 fn main() -> Result<(), anyhow::Error> {
     let bundle = Bundle::from_bytes(include_bytes!("./example.tar.gz"))?;
 
-    let mut opa = Opa::new().build_from_bundle(&bundle)?;
-
-    let data = json!({
-        "users": {
-            "test": {
-                "projects": {
-                    "test": {
-                        "roles": ["owner"]
-                    }
-                }
-            }
-        },
-        "projects": {
-            "test": {}
-        }
-    });
+    let mut opa = Opa::try_from(&bundle)?;
 
     let input = json!({
         "user_id": "test",
@@ -31,7 +16,26 @@ fn main() -> Result<(), anyhow::Error> {
         println!("{}", e);
     }
 
-    opa.set_data(&data)?;
+    opa.set_data_at(
+        "users",
+        &json!({
+            "test": {
+                "projects": {
+                    "test": {
+                        "roles": ["owner"]
+                    }
+                }
+            }
+        }),
+    )?;
+
+    opa.set_data_at(
+        "projects",
+        &json!({
+            "test": {}
+        }),
+    )?;
+
     let results: Value = opa.eval("example.project_permissions", &input)?;
     println!("{}", results);
 
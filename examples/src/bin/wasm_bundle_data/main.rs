@@ -0,0 +1,16 @@
+use opa::{include_policy, wasm::Opa};
+use serde_json::json;
+
+fn main() -> Result<(), anyhow::Error> {
+    let bundle = include_policy!("with_data");
+
+    let mut opa = Opa::new().build_from_bundle(&bundle)?;
+
+    // The data was bundled from `policies/with_data/data.json`.
+    opa.set_data(bundle.data().unwrap())?;
+
+    let allowed: bool = opa.eval("roles.allow", &json!({ "role": "admin" }))?;
+    println!("admin allowed: {allowed}");
+
+    Ok(())
+}
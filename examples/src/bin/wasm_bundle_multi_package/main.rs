@@ -0,0 +1,15 @@
+use opa::{include_policy, wasm::Opa};
+use serde_json::json;
+
+fn main() -> Result<(), anyhow::Error> {
+    let bundle = include_policy!("multi");
+
+    let mut opa = Opa::new().build_from_bundle(&bundle)?;
+
+    opa.set_data(&json!({}))?;
+
+    let allowed: bool = opa.eval("a.allow", &json!({ "role": "admin" }))?;
+    println!("admin allowed: {allowed}");
+
+    Ok(())
+}